@@ -1,6 +1,7 @@
 use clonetree::{clone_tree, Options};
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use std::fs;
+use std::hint::black_box;
 use tempfile::TempDir;
 
 // Constants for benchmark configuration
@@ -0,0 +1,84 @@
+//! Bringing a grown file up to date by copying only what was appended.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::stream::StreamCopy;
+
+/// If `dest`, with metadata `metadata`, holds the first part of `src`, whose
+/// full length is `src_len`, append the rest of `src` to it, `chunk_size`
+/// bytes at a time, and return true. Returns false, leaving `dest` alone, if
+/// it isn't a shorter regular file with the same leading bytes, or can't be
+/// opened for appending.
+pub(crate) fn append_tail(
+    src: &Path,
+    src_len: u64,
+    dest: &Path,
+    metadata: &Metadata,
+    chunk_size: usize,
+) -> io::Result<bool> {
+    let dest_len = metadata.len();
+    if !metadata.is_file() || dest_len == 0 || dest_len >= src_len {
+        return Ok(false);
+    }
+    let Ok(mut out) = OpenOptions::new().read(true).append(true).open(dest) else {
+        return Ok(false);
+    };
+    let mut input = File::open(src)?;
+    if !same_prefix(&mut input, &mut out, dest_len)? {
+        return Ok(false);
+    }
+    // Both files are now positioned at the end of the shared prefix
+    StreamCopy::new(input.take(src_len - dest_len), out, chunk_size).finish()?;
+    Ok(true)
+}
+
+/// Whether the first `len` bytes of `a` and `b` are the same.
+fn same_prefix(a: &mut File, b: &mut File, len: u64) -> io::Result<bool> {
+    let mut a_buf = vec![0; 64 * 1024];
+    let mut b_buf = vec![0; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(a_buf.len() as u64) as usize;
+        a.read_exact(&mut a_buf[..n])?;
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+        remaining -= n as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn append(src: &Path, dest: &Path) -> io::Result<bool> {
+        append_tail(src, 14, dest, &dest.symlink_metadata()?, 4)
+    }
+
+    #[test]
+    fn test_append_tail() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src.log");
+        let dest = temp_dir.path().join("dest.log");
+        fs::write(&src, "one\ntwo\nthree\n")?;
+
+        fs::write(&dest, "one\n")?;
+        assert!(append(&src, &dest)?);
+        assert_eq!(fs::read_to_string(&dest)?, "one\ntwo\nthree\n");
+
+        // Rewritten rather than grown, or not shorter
+        fs::write(&dest, "uno\n")?;
+        assert!(!append(&src, &dest)?);
+        assert_eq!(fs::read_to_string(&dest)?, "uno\n");
+        fs::write(&dest, "one\ntwo\nthree\n")?;
+        assert!(!append(&src, &dest)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+//! Tar archives as clone destinations and sources.
+
+use crate::backend::{normalize, FileInfo, FileKind, TreeSink, TreeSource, Visitor};
+use crate::{
+    clone_between, empty_destination, lock_destination, root_created, trace, validate, CloneReport,
+    DirSink, DirSource, Error, Options, Outcome, Overwrite, Result,
+};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Compression applied to a tar archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// A plain `.tar`.
+    #[default]
+    None,
+    /// gzip, as in `.tar.gz` or `.tgz`.
+    Gzip,
+    /// Zstandard, as in `.tar.zst` or `.tzst`.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression implied by an archive's file name, or `None` if the
+    /// name doesn't look like a tar archive.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".tar") {
+            Some(Self::None)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Clone `src` into a tar archive at `dest`, compressed as its file name
+/// suggests (see [`Compression::from_path`]; anything else is a plain tar).
+///
+/// This is [`clone_between`] from a [`DirSource`] to a [`TarSink`]: the
+/// same filters as [`clone_tree`](crate::clone_tree) select the files, and
+/// progress reports every file as [`Outcome::Copied`]. An existing `dest` is
+/// replaced only with [`Options::overwrite`]. Symlinks are stored as links
+/// with [`Options::symlinks`]. If the clone fails, the partial archive is
+/// removed, unless [`Options::keep_going`] let it finish.
+pub fn clone_to_archive<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<CloneReport> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let _span = trace::clone_span(src, dest);
+    validate(src, dest, options)?;
+
+    let mut sink = TarSink::create(dest, Compression::from_path(dest).unwrap_or_default())?;
+    let result = clone_between(&mut DirSource::new(src), &mut sink, options);
+    // With keep_going the archive holds everything that could be read
+    if result
+        .as_ref()
+        .is_err_and(|e| !matches!(e, Error::Incomplete { .. }))
+    {
+        drop(sink);
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+/// Extract the tar archive at `src` into the directory `dest`, decompressing
+/// as its file name suggests (see [`Compression::from_path`]).
+///
+/// This is [`clone_between`] from a [`TarSource`] to a [`DirSink`], so
+/// archive members go through the same pipeline as the files of a tree in
+/// [`clone_tree`](crate::clone_tree): glob, size and time filters and
+/// [`Options::files`] select them, [`Options::overwrite`],
+/// [`Options::update`] and [`Options::on_conflict`] decide what happens to
+/// existing files, and the `preserve_*` options choose which of the
+/// archived permissions, times and ownership are restored. Symlinks are
+/// extracted only with [`Options::symlinks`]; directories are created as
+/// needed and other member types are skipped. [`Options::delete`] is not
+/// applied.
+pub fn clone_from_archive<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<CloneReport> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let _span = trace::clone_span(src, dest);
+    if !src.exists() {
+        return Err(Error::SourceNotFound {
+            path: src.to_path_buf(),
+        });
+    }
+    if src.is_dir() {
+        return Err(Error::Other(format!(
+            "Source is not an archive: {}",
+            src.display()
+        )));
+    }
+    if dest.exists() && options.overwrite == Overwrite::Never {
+        return Err(Error::DestinationExists {
+            path: dest.to_path_buf(),
+        });
+    }
+
+    if options.overwrite == Overwrite::Replace && dest.symlink_metadata().is_ok_and(|m| !m.is_dir())
+    {
+        options.discard(dest)?;
+    }
+    let created = !dest.exists();
+    std::fs::create_dir_all(dest).map_err(|source| Error::CreateDirectory {
+        path: dest.to_path_buf(),
+        source,
+    })?;
+    if created {
+        root_created(dest, Path::new(""), None, options);
+    }
+    let _dest_lock = lock_destination(dest)?;
+    if options.overwrite == Overwrite::Replace {
+        empty_destination(dest, options)?;
+    }
+    let report = clone_between(&mut TarSource::new(src), &mut DirSink::new(dest), options)?;
+    trace::event!(info, "extract finished", dest = dest.display());
+    Ok(report)
+}
+
+/// A tar archive read as a [`TreeSource`], decompressed as its file name
+/// suggests.
+#[derive(Debug, Clone)]
+pub struct TarSource {
+    path: PathBuf,
+}
+
+impl TarSource {
+    /// Read the archive at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn read_error(&self, source: io::Error) -> Error {
+        Error::Read {
+            path: self.path.clone(),
+            source,
+        }
+    }
+
+    fn visit_entries<R: Read>(&self, reader: R, visitor: &mut Visitor<'_>) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(|e| self.read_error(e))? {
+            let mut entry = entry.map_err(|e| self.read_error(e))?;
+            let path = entry.path().map_err(|e| self.read_error(e))?;
+            if path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(Error::Other(format!(
+                    "Archive path is outside the destination: {}",
+                    path.display()
+                )));
+            }
+            let path = normalize(&path);
+            let pax_mtime = pax_mtime(&mut entry).map_err(|e| self.read_error(e))?;
+
+            let header = entry.header();
+            let kind = match header.entry_type() {
+                t if t.is_file() => FileKind::File,
+                t if t.is_symlink() => match entry.link_name().map_err(|e| self.read_error(e))? {
+                    Some(target) => FileKind::Symlink(target.into_owned()),
+                    None => continue,
+                },
+                t if t.is_fifo() || t.is_character_special() || t.is_block_special() => {
+                    visitor.special_file(&path);
+                    continue;
+                }
+                // Directories are created as needed
+                _ => continue,
+            };
+            let info = FileInfo {
+                path,
+                kind,
+                len: header.size().map_err(|e| self.read_error(e))?,
+                mtime: pax_mtime.or_else(|| {
+                    header
+                        .mtime()
+                        .ok()
+                        .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                }),
+                mode: header.mode().ok(),
+                uid: header.uid().ok().and_then(|id| u32::try_from(id).ok()),
+                gid: header.gid().ok().and_then(|id| u32::try_from(id).ok()),
+            };
+            visitor.file(&info, &mut entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `mtime` PAX record of `entry`, which unlike the header keeps
+/// fractions of a second.
+fn pax_mtime<R: Read>(entry: &mut tar::Entry<'_, R>) -> io::Result<Option<SystemTime>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key() == Ok("mtime") {
+            return Ok(extension.value().ok().and_then(parse_pax_time));
+        }
+    }
+    Ok(None)
+}
+
+/// Parse a PAX time such as `1700000000.123456789`. Times before the epoch
+/// aren't supported and give `None`.
+fn parse_pax_time(value: &str) -> Option<SystemTime> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let nanos = format!("{fraction:0<9}");
+    let nanos = nanos.get(..9)?.parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos))
+}
+
+impl TreeSource for TarSource {
+    fn visit(&mut self, _options: &Options, visitor: &mut Visitor<'_>) -> Result<()> {
+        let file = File::open(&self.path).map_err(|e| self.read_error(e))?;
+        let file = BufReader::new(file);
+        match Compression::from_path(&self.path).unwrap_or_default() {
+            Compression::None => self.visit_entries(file, visitor),
+            Compression::Gzip => self.visit_entries(flate2::read::GzDecoder::new(file), visitor),
+            Compression::Zstd => {
+                let decoder = zstd::Decoder::with_buffer(file).map_err(|e| self.read_error(e))?;
+                self.visit_entries(decoder, visitor)
+            }
+        }
+    }
+}
+
+/// A tar archive written as a [`TreeSink`].
+pub struct TarSink {
+    path: PathBuf,
+    builder: Option<tar::Builder<Encoder>>,
+}
+
+impl TarSink {
+    /// Create an archive at `path`, replacing any file already there.
+    pub fn create<P: AsRef<Path>>(path: P, compression: Compression) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let write_error = |source| Error::Copy {
+            src: path.clone(),
+            dest: path.clone(),
+            source,
+        };
+        let file = BufWriter::new(File::create(&path).map_err(write_error)?);
+        let encoder = match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0).map_err(write_error)?),
+        };
+        Ok(Self {
+            builder: Some(tar::Builder::new(encoder)),
+            path,
+        })
+    }
+
+    fn write_error(&self, source: io::Error) -> Error {
+        Error::Copy {
+            src: self.path.clone(),
+            dest: self.path.clone(),
+            source,
+        }
+    }
+}
+
+impl TreeSink for TarSink {
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        _options: &Options,
+    ) -> Result<Outcome> {
+        let Some(builder) = self.builder.as_mut() else {
+            return Err(self.write_error(io::ErrorKind::BrokenPipe.into()));
+        };
+        let mut header = tar::Header::new_gnu();
+        // The header holds whole seconds, so any fraction goes in a PAX record
+        let mut pax_mtime = None;
+        if let Some(mtime) = file.mtime {
+            let since = mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            header.set_mtime(since.as_secs());
+            if since.subsec_nanos() != 0 {
+                pax_mtime = Some(format!("{}.{:09}", since.as_secs(), since.subsec_nanos()));
+            }
+        }
+        header.set_uid(file.uid.unwrap_or(0).into());
+        header.set_gid(file.gid.unwrap_or(0).into());
+        let result = match &pax_mtime {
+            Some(mtime) => builder.append_pax_extensions([("mtime", mtime.as_bytes())]),
+            None => Ok(()),
+        };
+        let result = result.and_then(|()| match &file.kind {
+            FileKind::File => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(file.mode.unwrap_or(0o644));
+                header.set_size(file.len);
+                builder.append_data(&mut header, &file.path, contents)
+            }
+            FileKind::Symlink(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(file.mode.unwrap_or(0o777));
+                header.set_size(0);
+                builder.append_link(&mut header, &file.path, target)
+            }
+        });
+        result.map_err(|source| Error::Copy {
+            src: file.path.clone(),
+            dest: self.path.join(&file.path),
+            source,
+        })?;
+        Ok(Outcome::Copied)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let Some(builder) = self.builder.take() else {
+            return Ok(());
+        };
+        builder
+            .into_inner()
+            .and_then(Encoder::finish)
+            .map_err(|e| self.write_error(e))
+    }
+}
+
+/// The file under a [`TarSink`], compressed or not.
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    /// Write any compression trailer and flush the file.
+    fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Self::Plain(file) => file,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        file.into_inner().map(drop).map_err(|e| e.into_error())
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SkipReason;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn archive_paths<R: Read>(reader: R) -> Result<Vec<PathBuf>> {
+        let mut archive = tar::Archive::new(reader);
+        let mut paths = Vec::new();
+        for entry in archive.entries()? {
+            paths.push(entry?.path()?.into_owned());
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_clone_to_archive() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("skip.log"), "log")?;
+        let options = Options::new().glob("!*.log");
+
+        let expected = vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")];
+        let tar = temp_dir.path().join("out.tar");
+        clone_to_archive(&src, &tar, &options)?;
+        assert_eq!(archive_paths(File::open(&tar)?)?, expected);
+
+        let tgz = temp_dir.path().join("out.tgz");
+        clone_to_archive(&src, &tgz, &options)?;
+        let decoder = flate2::read::GzDecoder::new(File::open(&tgz)?);
+        assert_eq!(archive_paths(decoder)?, expected);
+
+        let zst = temp_dir.path().join("out.tar.zst");
+        clone_to_archive(&src, &zst, &options)?;
+        let decoder = zstd::Decoder::new(File::open(&zst)?)?;
+        assert_eq!(archive_paths(decoder)?, expected);
+
+        let result = clone_to_archive(&src, &tar, &options);
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_from_archive() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("skip.log"), "log")?;
+        let tgz = temp_dir.path().join("src.tar.gz");
+        clone_to_archive(&src, &tgz, &Options::new())?;
+
+        let dest = temp_dir.path().join("dest");
+        let options = Options::new().glob("!*.log").preserve_times(true);
+        clone_from_archive(&tgz, &dest, &options)?;
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "a");
+        assert_eq!(fs::read_to_string(dest.join("sub/b.txt"))?, "b");
+        assert!(!dest.join("skip.log").exists());
+
+        let result = clone_from_archive(&tgz, &dest, &Options::new());
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+
+        // Updating leaves unchanged files alone and replaces modified ones
+        fs::write(dest.join("a.txt"), "changed")?;
+        let outcomes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = Options::new()
+            .overwrite(true)
+            .update(true)
+            .preserve_times(true)
+            .glob("*.txt")
+            .on_progress({
+                let outcomes = outcomes.clone();
+                move |p| {
+                    outcomes
+                        .lock()
+                        .unwrap()
+                        .push((p.path.to_path_buf(), p.outcome))
+                }
+            });
+        clone_from_archive(&tgz, &dest, &options)?;
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "a");
+        let mut outcomes = outcomes.lock().unwrap().clone();
+        outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            outcomes,
+            vec![
+                (PathBuf::from("a.txt"), Outcome::Copied),
+                (
+                    PathBuf::from("sub/b.txt"),
+                    Outcome::Skipped(SkipReason::Unchanged)
+                ),
+            ]
+        );
+
+        let result = clone_from_archive(temp_dir.path().join("missing.tar"), &dest, &options);
+        assert!(matches!(result, Err(Error::SourceNotFound { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nanosecond_mtime() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 123_456_700);
+        filetime::set_file_mtime(src.join("a.txt"), mtime)?;
+
+        let tar = temp_dir.path().join("src.tar");
+        let dest = temp_dir.path().join("dest");
+        clone_to_archive(&src, &tar, &Options::new())?;
+        clone_from_archive(&tar, &dest, &Options::new().preserve_times(true))?;
+        let metadata = fs::metadata(dest.join("a.txt"))?;
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&metadata),
+            mtime
+        );
+
+        assert_eq!(
+            parse_pax_time("5.25"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(5250))
+        );
+        assert_eq!(parse_pax_time("-5.25"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_from_path() {
+        assert_eq!(Compression::from_path("x.tar"), Some(Compression::None));
+        assert_eq!(Compression::from_path("x.TAR.GZ"), Some(Compression::Gzip));
+        assert_eq!(Compression::from_path("x.tzst"), Some(Compression::Zstd));
+        assert_eq!(Compression::from_path("x.zip"), None);
+        assert_eq!(Compression::from_path("dir"), None);
+    }
+}
@@ -0,0 +1,679 @@
+//! Pluggable tree sources and sinks.
+//!
+//! [`clone_between`] copies files from any [`TreeSource`] to any
+//! [`TreeSink`], applying the filters from [`Options`] and reporting progress
+//! along the way. Sources walk something that holds files, such as a
+//! directory ([`DirSource`]), a tar archive or a [`MemoryTree`], and hand each
+//! file to a [`Visitor`]. Sinks write the files that survive the filters. A
+//! new backend only has to implement one of the traits.
+//!
+//! Directory to directory clones keep using [`clone_tree`](crate::clone_tree),
+//! which can reflink because it sees both ends as local paths.
+
+use crate::sanitize::Rename;
+use crate::{
+    contain, create_dirs, estimate, is_excluded, longpath, preserve, progress, recorded_entries,
+    trace, validate_source, CloneConfig, CloneReport, Compare, Conflict, CreatedDirs, Error,
+    Options, Outcome, Overwrite, Preserve, Resolution, Result, Select, SkipReason, Skipped,
+    WarningKind,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// What kind of file a [`FileInfo`] describes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileKind {
+    /// A regular file, whose contents are passed alongside.
+    File,
+    /// A symbolic link to the given target. Links are only offered to sinks
+    /// with [`Options::symlinks`].
+    Symlink(PathBuf),
+}
+
+/// A file offered by a [`TreeSource`], without its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Path relative to the tree root, made only of normal components.
+    pub path: PathBuf,
+    /// Whether this is a file or a symlink.
+    pub kind: FileKind,
+    /// Size of the contents in bytes.
+    pub len: u64,
+    /// Modification time, if the source records one.
+    pub mtime: Option<SystemTime>,
+    /// Unix permission bits, if the source records them.
+    pub mode: Option<u32>,
+    /// Owning user id, if the source records one.
+    pub uid: Option<u32>,
+    /// Owning group id, if the source records one.
+    pub gid: Option<u32>,
+}
+
+impl FileInfo {
+    /// Describe a local file from its metadata.
+    pub(crate) fn from_metadata(
+        path: PathBuf,
+        kind: FileKind,
+        metadata: &std::fs::Metadata,
+    ) -> Self {
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (
+                Some(metadata.mode() & 0o7777),
+                Some(metadata.uid()),
+                Some(metadata.gid()),
+            )
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (None, None, None);
+        Self {
+            path,
+            kind,
+            len: metadata.len(),
+            mtime: metadata.modified().ok(),
+            mode,
+            uid,
+            gid,
+        }
+    }
+}
+
+/// Something to clone files from.
+pub trait TreeSource {
+    /// Pass every file in the tree to `visitor`, stopping at the first error
+    /// the visitor returns. Errors reading a single file should go through
+    /// [`Visitor::error`] so that [`Options::keep_going`] can skip them.
+    /// `options` lets a source skip work early, for instance by not
+    /// descending into excluded directories; the visitor filters every file
+    /// regardless.
+    fn visit(&mut self, options: &Options, visitor: &mut Visitor<'_>) -> Result<()>;
+}
+
+/// Something to clone files into.
+pub trait TreeSink {
+    /// Write one file, reading its contents from `contents`. Sinks decide
+    /// what [`Options::overwrite`], [`Options::update`] and the preserve
+    /// options mean for them.
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        options: &Options,
+    ) -> Result<Outcome>;
+
+    /// Complete the tree once every file has been written. This is also
+    /// called when [`Options::keep_going`] skipped some files.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Filters the files offered by a [`TreeSource`] and passes the rest on to a
+/// [`TreeSink`]. Created by [`clone_between`].
+pub struct Visitor<'a> {
+    sink: &'a mut dyn TreeSink,
+    config: &'a CloneConfig,
+    select: Select,
+    listed: Option<HashSet<PathBuf>>,
+    tracker: progress::Tracker,
+    failures: Vec<Error>,
+}
+
+impl Visitor<'_> {
+    /// Whether `file` passes the filters. Sources can check this to avoid
+    /// opening files that would be skipped anyway, passing them to
+    /// [`file`](Self::file) with empty contents so the skip is recorded.
+    pub fn wants(&self, file: &FileInfo) -> bool {
+        self.check(file).is_ok()
+    }
+
+    /// Why `file` doesn't pass the filters, if it doesn't. Files that were
+    /// never selected, such as those missing from [`Options::files`], have
+    /// no reason.
+    fn check(&self, file: &FileInfo) -> std::result::Result<(), Option<SkipReason>> {
+        if file.path.as_os_str().is_empty()
+            || self
+                .listed
+                .as_ref()
+                .is_some_and(|l| !l.contains(&file.path))
+        {
+            return Err(None);
+        }
+        if is_excluded(self.config.globs.as_ref(), &file.path) {
+            return Err(Some(SkipReason::Excluded));
+        }
+        if matches!(file.kind, FileKind::Symlink(_)) && !self.select.symlinks {
+            return Err(Some(SkipReason::NotRegular));
+        }
+        if !self.select.size_and_time(file.len, || file.mtime) {
+            return Err(Some(SkipReason::Filtered));
+        }
+        Ok(())
+    }
+
+    /// Write `file` to the sink if it passes the filters, and report
+    /// progress. Files that don't pass are recorded as skipped without
+    /// reading `contents`.
+    pub fn file(&mut self, file: &FileInfo, contents: &mut dyn Read) -> Result<()> {
+        if file
+            .path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::OutsideDestination {
+                path: file.path.clone(),
+            });
+        }
+        if let Err(reason) = self.check(file) {
+            if let Some(reason) = reason {
+                self.tracker.skip(&file.path, reason);
+            }
+            return Ok(());
+        }
+        self.tracker.start_file(file.len)?;
+        match self.sink.write(file, contents, &self.config.options) {
+            Ok(outcome) => {
+                trace::event!(debug, "wrote", path = file.path.display());
+                self.tracker
+                    .file_done(&file.path, file.len, outcome)
+                    .notify();
+                Ok(())
+            }
+            Err(error) => self.error(error),
+        }
+    }
+
+    /// Leave out a file that is neither regular nor a symlink, such as a FIFO
+    /// in an archive, warning about it unless the filters exclude it anyway.
+    #[cfg(feature = "archive")]
+    pub(crate) fn special_file(&mut self, path: &Path) {
+        if is_excluded(self.config.globs.as_ref(), path) {
+            self.tracker.skip(path, SkipReason::Excluded);
+            return;
+        }
+        self.tracker.skip(path, SkipReason::NotRegular);
+        self.config
+            .options
+            .warn(path, WarningKind::SpecialFile, None);
+    }
+
+    /// Report a failure. With [`Options::keep_going`] it is collected and
+    /// the clone carries on; otherwise it is returned to stop the source.
+    pub fn error(&mut self, error: Error) -> Result<()> {
+        if !matches!(error, Error::Cancelled) {
+            self.config
+                .options
+                .notify(|observer| observer.on_error(&error));
+        }
+        if !self.config.options.keep_going
+            || matches!(error, Error::Cancelled | Error::InvalidGlob { .. })
+        {
+            return Err(error);
+        }
+        trace::event!(warn, "continuing after error", error = error);
+        self.failures.push(error);
+        Ok(())
+    }
+}
+
+/// Clone every file from `source` that passes the filters in `options` into
+/// `sink`.
+///
+/// Glob, size and time filters, [`Options::files`] and
+/// [`Options::symlinks`] are applied here, so every backend honours them the
+/// same way. Progress is reported through [`Options::on_progress`], and with
+/// [`Options::keep_going`] failed files are collected into
+/// [`Error::Incomplete`] after the sink is finished. Returns what was done,
+/// including the files left out with [`Options::record_skipped`].
+pub fn clone_between(
+    source: &mut dyn TreeSource,
+    sink: &mut dyn TreeSink,
+    options: &Options,
+) -> Result<CloneReport> {
+    options.build()?.clone_between(source, sink)
+}
+
+impl CloneConfig {
+    /// [`clone_between`] with this config.
+    pub fn clone_between(
+        &self,
+        source: &mut dyn TreeSource,
+        sink: &mut dyn TreeSink,
+    ) -> Result<CloneReport> {
+        let options = &self.options;
+        let mut visitor = Visitor {
+            sink,
+            config: self,
+            select: Select::new(options),
+            listed: options
+                .files
+                .as_ref()
+                .map(|files| files.iter().map(|path| normalize(path)).collect()),
+            tracker: progress::Tracker::new(options),
+            failures: Vec::new(),
+        };
+        source.visit(options, &mut visitor)?;
+        visitor.sink.finish()?;
+        if !visitor.failures.is_empty() {
+            return Err(Error::Incomplete {
+                errors: visitor.failures,
+            });
+        }
+        Ok(visitor.tracker.report())
+    }
+}
+
+/// A relative path with any `.` components dropped.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// A directory on the local filesystem, read as a [`TreeSource`].
+#[derive(Debug, Clone)]
+pub struct DirSource {
+    root: PathBuf,
+}
+
+impl DirSource {
+    /// Read the tree rooted at `root`, which must be a directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: longpath::extended(root.as_ref()).into_owned(),
+        }
+    }
+}
+
+impl TreeSource for DirSource {
+    fn visit(&mut self, options: &Options, visitor: &mut Visitor<'_>) -> Result<()> {
+        validate_source(&self.root)?;
+        if options.count_first {
+            visitor.tracker.count(estimate(&self.root, options)?);
+        }
+        let skipped = Skipped {
+            log: visitor.tracker.skip_log(),
+            prefix: PathBuf::new(),
+            warning: options.warning.clone(),
+            observers: options.observers.clone(),
+        };
+        for entry in recorded_entries(&self.root, visitor.config, skipped) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    visitor.error(error)?;
+                    continue;
+                }
+            };
+            let kind = if entry.metadata.is_symlink() {
+                match std::fs::read_link(&entry.path) {
+                    Ok(target) => FileKind::Symlink(target),
+                    Err(source) => {
+                        visitor.error(Error::Read {
+                            path: entry.path,
+                            source,
+                        })?;
+                        continue;
+                    }
+                }
+            } else {
+                FileKind::File
+            };
+            let info = FileInfo::from_metadata(entry.relative_path, kind, &entry.metadata);
+            if !visitor.wants(&info) || info.kind != FileKind::File {
+                visitor.file(&info, &mut std::io::empty())?;
+                continue;
+            }
+            match File::open(&entry.path) {
+                Ok(mut file) => visitor.file(&info, &mut file)?,
+                Err(source) => visitor.error(Error::Read {
+                    path: entry.path,
+                    source,
+                })?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory on the local filesystem, written as a [`TreeSink`].
+///
+/// Existing files are handled as in [`clone_tree`](crate::clone_tree):
+/// [`Options::update`] skips files that are up to date by
+/// [`Options::compare`] ([`Compare::Hash`] falls back to size and time),
+/// [`Options::on_conflict`] is asked before anything is replaced, and the
+/// permissions, times and ownership recorded by the source are restored
+/// with the matching preserve options.
+#[derive(Debug, Clone)]
+pub struct DirSink {
+    root: PathBuf,
+    created_dirs: CreatedDirs,
+}
+
+impl DirSink {
+    /// Write into the directory `root`, creating it and any subdirectories
+    /// as needed.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: longpath::extended(root.as_ref()).into_owned(),
+            created_dirs: CreatedDirs::default(),
+        }
+    }
+
+    /// Create the directory `path` goes in, telling the observers about
+    /// each new one. The source has no directories of its own, so they get
+    /// the metadata of the new directory.
+    fn create_parent(&mut self, path: &Path, options: &Options) -> Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        if !self.created_dirs.contains(parent) {
+            let created = create_dirs(parent).map_err(|source| Error::CreateDirectory {
+                path: parent.to_path_buf(),
+                source,
+            })?;
+            for dir in &created {
+                // Parents of the root aren't part of the clone
+                let Ok(relative) = dir.strip_prefix(&self.root) else {
+                    continue;
+                };
+                if let Ok(metadata) = std::fs::metadata(dir) {
+                    options.notify(|observer| observer.on_dir_created(relative, &metadata));
+                }
+            }
+            self.created_dirs.insert(parent);
+        }
+        Ok(())
+    }
+}
+
+impl TreeSink for DirSink {
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        options: &Options,
+    ) -> Result<Outcome> {
+        let Some(path) = Rename::new(options).apply(&file.path) else {
+            return Ok(Outcome::Skipped(SkipReason::InvalidName));
+        };
+        contain::check_relative(&path)?;
+        let dest = self.root.join(path);
+        if let Some(parent) = dest.parent() {
+            contain::check_parents(&self.root, parent, &self.created_dirs)?;
+        }
+        let copy_error = |source| Error::Copy {
+            src: file.path.clone(),
+            dest: dest.clone(),
+            source,
+        };
+
+        if let Ok(existing) = std::fs::symlink_metadata(&dest) {
+            if options.update && is_up_to_date(&existing, file, options.compare) {
+                return Ok(Outcome::Skipped(SkipReason::Unchanged));
+            }
+            if options.overwrite == Overwrite::NoClobber {
+                return Err(Error::FileExists { path: dest });
+            }
+            if let Some(conflict) = &options.conflict {
+                let resolution = (conflict.0)(&Conflict {
+                    path: &file.path,
+                    src: &file.path,
+                    dest: &dest,
+                });
+                match resolution {
+                    Resolution::Overwrite => {}
+                    Resolution::Skip => return Ok(Outcome::Skipped(SkipReason::Conflict)),
+                    Resolution::Abort => return Err(Error::Cancelled),
+                }
+            }
+            options.replace_file(&dest, &existing).map_err(copy_error)?;
+        }
+
+        self.create_parent(&dest, options)?;
+        match &file.kind {
+            FileKind::File => {
+                let mut out = File::create(&dest).map_err(copy_error)?;
+                std::io::copy(contents, &mut out).map_err(copy_error)?;
+            }
+            FileKind::Symlink(target) => {
+                preserve::symlink_to(target, &dest, options.dir_links).map_err(copy_error)?
+            }
+        }
+
+        let is_symlink = matches!(file.kind, FileKind::Symlink(_));
+        if options.preserve.contains(Preserve::OWNER) {
+            if let Some(error) = preserve::owner(file.uid, file.gid, &dest)? {
+                options.warn(&file.path, WarningKind::Ownership, Some(&error));
+            }
+        }
+        if let (true, false, Some(mode)) = (
+            options.preserve.contains(Preserve::MODE),
+            is_symlink,
+            file.mode,
+        ) {
+            preserve::mode(
+                mode,
+                &dest,
+                options.preserve.contains(Preserve::SPECIAL_BITS),
+            )?;
+        }
+        if let (true, Some(mtime)) = (options.preserve.contains(Preserve::TIMES), file.mtime) {
+            let mtime = filetime::FileTime::from_system_time(mtime);
+            let set = if is_symlink {
+                filetime::set_symlink_file_times(&dest, mtime, mtime)
+            } else {
+                filetime::set_file_mtime(&dest, mtime)
+            };
+            if let Err(error) = set {
+                options.warn(&file.path, WarningKind::Times, Some(&error));
+            }
+        }
+        Ok(Outcome::Copied)
+    }
+}
+
+/// Whether an existing destination file already matches `file`. Times are
+/// compared to the second, since zip archives and tar headers store no more.
+fn is_up_to_date(existing: &std::fs::Metadata, file: &FileInfo, compare: Compare) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    };
+    let (Some(mtime), Ok(existing_mtime)) = (file.mtime, existing.modified()) else {
+        return false;
+    };
+    let (mtime, existing_mtime) = (secs(mtime), secs(existing_mtime));
+    match compare {
+        Compare::Newer => mtime <= existing_mtime,
+        Compare::Metadata | Compare::Hash => {
+            existing.is_file() && file.len == existing.len() && mtime == existing_mtime
+        }
+    }
+}
+
+/// A tree held in memory, usable as both a [`TreeSource`] and a
+/// [`TreeSink`]. Handy for tests and for building trees to write elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTree {
+    files: BTreeMap<PathBuf, (FileInfo, Vec<u8>)>,
+}
+
+impl MemoryTree {
+    /// Create an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a regular file at `path`, modified now.
+    pub fn insert<P: Into<PathBuf>, C: Into<Vec<u8>>>(&mut self, path: P, contents: C) {
+        let path = path.into();
+        let contents = contents.into();
+        let info = FileInfo {
+            path: path.clone(),
+            kind: FileKind::File,
+            len: contents.len() as u64,
+            mtime: Some(SystemTime::now()),
+            mode: None,
+            uid: None,
+            gid: None,
+        };
+        self.files.insert(path, (info, contents));
+    }
+
+    /// The contents of the file at `path`, if there is one.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&[u8]> {
+        self.files
+            .get(path.as_ref())
+            .map(|(_, contents)| contents.as_slice())
+    }
+
+    /// The files in the tree, in path order.
+    pub fn files(&self) -> impl Iterator<Item = &FileInfo> {
+        self.files.values().map(|(info, _)| info)
+    }
+
+    /// Number of files in the tree.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether the tree has no files.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+impl TreeSource for MemoryTree {
+    fn visit(&mut self, _options: &Options, visitor: &mut Visitor<'_>) -> Result<()> {
+        for (info, contents) in self.files.values() {
+            visitor.file(info, &mut contents.as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+impl TreeSink for MemoryTree {
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        options: &Options,
+    ) -> Result<Outcome> {
+        if self.files.contains_key(&file.path) {
+            match options.overwrite {
+                Overwrite::Never => {
+                    return Err(Error::DestinationExists {
+                        path: file.path.clone(),
+                    })
+                }
+                Overwrite::NoClobber => {
+                    return Err(Error::FileExists {
+                        path: file.path.clone(),
+                    })
+                }
+                Overwrite::Merge | Overwrite::Replace => {}
+            }
+        }
+        let mut data = Vec::new();
+        contents.read_to_end(&mut data)?;
+        self.files.insert(file.path.clone(), (file.clone(), data));
+        Ok(Outcome::Copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "filter")]
+    use crate::SkippedEntry;
+    #[cfg(feature = "filter")]
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_memory_round_trip() -> Result<()> {
+        let mut tree = MemoryTree::new();
+        tree.insert("a.txt", "a");
+        tree.insert("sub/b.txt", "b");
+        tree.insert("sub/skip.log", "log");
+
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path().join("dir");
+        let options = Options::new().glob("!*.log");
+        clone_between(&mut tree, &mut DirSink::new(&dir), &options)?;
+        assert_eq!(fs::read_to_string(dir.join("sub/b.txt"))?, "b");
+        assert!(!dir.join("sub/skip.log").exists());
+
+        let mut copy = MemoryTree::new();
+        let options = Options::new().files(vec![PathBuf::from("./sub/b.txt")]);
+        clone_between(&mut DirSource::new(&dir), &mut copy, &options)?;
+        assert_eq!(copy.len(), 1);
+        assert_eq!(copy.get("sub/b.txt"), Some(&b"b"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_record_skipped() -> Result<()> {
+        let mut tree = MemoryTree::new();
+        tree.insert("a.txt", "a");
+        tree.insert("skip.log", "log");
+
+        let mut copy = MemoryTree::new();
+        let options = Options::new().glob("!*.log").record_skipped(true);
+        let report = clone_between(&mut tree, &mut copy, &options)?;
+        assert_eq!(report.copied, 1);
+        assert_eq!(
+            report.skipped_entries,
+            vec![SkippedEntry {
+                path: PathBuf::from("skip.log"),
+                reason: SkipReason::Excluded,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_sink_outside() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path().join("dir");
+        let mut tree = MemoryTree::new();
+        tree.insert("../escaped.txt", "x");
+        let result = clone_between(&mut tree, &mut DirSink::new(&dir), &Options::new());
+        assert!(matches!(result, Err(Error::OutsideDestination { .. })));
+        assert!(!temp_dir.path().join("escaped.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_sink_update() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path().join("dir");
+        let mut tree = MemoryTree::new();
+        tree.insert("a.txt", "a");
+        let options = Options::new().preserve_times(true);
+        clone_between(&mut tree, &mut DirSink::new(&dir), &options)?;
+
+        let outcomes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = options.update(true).on_progress({
+            let outcomes = outcomes.clone();
+            move |p| outcomes.lock().unwrap().push(p.outcome)
+        });
+        clone_between(&mut tree, &mut DirSink::new(&dir), &options)?;
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec![Outcome::Skipped(SkipReason::Unchanged)]
+        );
+
+        Ok(())
+    }
+}
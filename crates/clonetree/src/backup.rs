@@ -0,0 +1,134 @@
+//! Keeping destination files recoverable when a clone replaces or deletes
+//! them.
+
+use crate::Options;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Whether files replaced in the destination are kept, set with
+/// [`Options::backup`](crate::Options::backup).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backup {
+    /// Replaced files are removed.
+    #[default]
+    Off,
+    /// Each replaced file is renamed to `name.~N~`, numbering up from one
+    /// past the highest backup already next to it, like
+    /// `cp --backup=numbered`. Repeated clones build up a history of the
+    /// versions they replaced.
+    Numbered,
+}
+
+impl Options {
+    /// Move the file at `path`, whose current metadata is `metadata`, out of
+    /// the way before it is replaced: rename it to its backup name, or discard
+    /// it if backups are off. Returns the backup path, if one was made.
+    pub(crate) fn replace_file(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> io::Result<Option<PathBuf>> {
+        // A directory in the way fails just as it would without backups
+        if metadata.is_dir() {
+            return std::fs::remove_file(path).map(|()| None);
+        }
+        match self.backup {
+            Backup::Off => self.discard_as(path, false).map(|()| None),
+            Backup::Numbered => {
+                let backup = numbered(path)?;
+                std::fs::rename(path, &backup)?;
+                Ok(Some(backup))
+            }
+        }
+    }
+
+    /// Delete the file or directory tree at `path`, or send it to the trash
+    /// with [`trash`](Options::trash).
+    pub(crate) fn discard(&self, path: &Path) -> io::Result<()> {
+        self.discard_as(path, path.symlink_metadata()?.is_dir())
+    }
+
+    /// [`discard`](Self::discard) a path already known to be a directory or
+    /// not.
+    fn discard_as(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        #[cfg(feature = "trash")]
+        if self.trash {
+            return trash::delete(path).map_err(io::Error::other);
+        }
+        if is_dir {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+}
+
+/// The next unused numbered backup name for `path`.
+fn numbered(path: &Path) -> io::Result<PathBuf> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no file name to back up",
+        ));
+    };
+    let name = name.to_string_lossy();
+    let prefix = format!("{name}.~");
+    let mut highest = 0;
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?.file_name();
+        let number = entry
+            .to_str()
+            .and_then(|e| e.strip_prefix(&prefix))
+            .and_then(|e| e.strip_suffix('~'))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(number) = number {
+            highest = highest.max(number);
+        }
+    }
+    let mut backup = OsString::from(path.file_name().unwrap_or_default());
+    backup.push(format!(".~{}~", highest + 1));
+    Ok(parent.join(backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_numbered() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("file.txt");
+        let numbered_options = Options::new().backup(Backup::Numbered);
+
+        for version in ["one", "two"] {
+            fs::write(&path, version)?;
+            numbered_options.replace_file(&path, &path.symlink_metadata()?)?;
+        }
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("file.txt.~1~"))?,
+            "one"
+        );
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("file.txt.~2~"))?,
+            "two"
+        );
+
+        // Numbering continues past gaps
+        fs::remove_file(temp_dir.path().join("file.txt.~1~"))?;
+        fs::write(&path, "three")?;
+        let backup = numbered_options.replace_file(&path, &path.symlink_metadata()?)?;
+        assert_eq!(backup, Some(temp_dir.path().join("file.txt.~3~")));
+
+        fs::write(&path, "four")?;
+        let metadata = path.symlink_metadata()?;
+        assert_eq!(Options::new().replace_file(&path, &metadata)?, None);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Persistent state cache for repeated syncs.
+//!
+//! The cache records, for every file written or verified by a sync, the
+//! source's size and modification time, the destination's modification time,
+//! and (when hashing) the content hash. On the next run a file whose source
+//! and destination both still match their cached state is skipped without
+//! being read.
+
+use crate::{Compare, Entry, Options, Result};
+use filetime::FileTime;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "clonetree-cache 2";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheEntry {
+    size: u64,
+    mtime: FileTime,
+    dest_mtime: FileTime,
+    /// The content hash, prefixed with the hasher's name
+    hash: Option<String>,
+}
+
+/// Cache state loaded from the previous run, and the state built up during
+/// this one. Only files seen during this run are written back, so entries for
+/// deleted files drop out.
+#[derive(Debug, Default)]
+pub(crate) struct StateCache {
+    path: PathBuf,
+    previous: HashMap<PathBuf, CacheEntry>,
+    current: HashMap<PathBuf, CacheEntry>,
+}
+
+/// Location of the cache file for `dest`: a hidden file beside it, so that the
+/// cache is never cloned over, deleted by mirroring, or copied along with the
+/// destination.
+pub(crate) fn cache_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{name}.clonetree-cache"))
+}
+
+fn parse_time(secs: &str, nanos: &str) -> Option<FileTime> {
+    Some(FileTime::from_unix_time(
+        secs.parse().ok()?,
+        nanos.parse().ok()?,
+    ))
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, CacheEntry)> {
+    let mut fields = line.splitn(7, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let mtime = parse_time(fields.next()?, fields.next()?)?;
+    let dest_mtime = parse_time(fields.next()?, fields.next()?)?;
+    let hash = match fields.next()? {
+        "-" => None,
+        hash => Some(hash.to_string()),
+    };
+    let path = PathBuf::from(fields.next()?);
+    Some((
+        path,
+        CacheEntry {
+            size,
+            mtime,
+            dest_mtime,
+            hash,
+        },
+    ))
+}
+
+/// The hash of `entry` as the cache stores it, so that a hash made with a
+/// different hasher never matches.
+fn hash_entry(entry: &Entry, options: &Options) -> Result<String> {
+    let hasher = options.content_hasher();
+    Ok(format!(
+        "{}:{}",
+        hasher.name(),
+        crate::hash::hash_file(&entry.path, hasher)?
+    ))
+}
+
+impl StateCache {
+    /// Load the cache for `dest`. A missing or unreadable cache is treated as
+    /// empty, which simply means every file is checked in full.
+    pub(crate) fn load(dest: &Path) -> Self {
+        let path = cache_path(dest);
+        let mut previous = HashMap::new();
+        if let Ok(file) = std::fs::File::open(&path) {
+            let mut lines = BufReader::new(file).lines();
+            if matches!(lines.next(), Some(Ok(header)) if header == HEADER) {
+                previous = lines
+                    .map_while(|line| line.ok())
+                    .filter_map(|line| parse_line(&line))
+                    .collect();
+            }
+        }
+        Self {
+            path,
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    /// Check whether `dest_path` is known to hold an up to date copy of
+    /// `entry`, without reading either file where possible.
+    pub(crate) fn is_fresh(
+        &mut self,
+        entry: &Entry,
+        dest_metadata: Option<&Metadata>,
+        options: &Options,
+    ) -> Result<bool> {
+        let Some(cached) = self.previous.get(&entry.relative_path) else {
+            return Ok(false);
+        };
+        let Some(dest_metadata) = dest_metadata else {
+            return Ok(false);
+        };
+        if !dest_metadata.is_file()
+            || dest_metadata.len() != cached.size
+            || FileTime::from_last_modification_time(dest_metadata) != cached.dest_mtime
+            || entry.metadata.len() != cached.size
+        {
+            return Ok(false);
+        }
+
+        let mut fresh = FileTime::from_last_modification_time(&entry.metadata) == cached.mtime;
+        // The source was touched; its content may still be the same
+        if !fresh && options.compare == Compare::Hash {
+            if let Some(cached_hash) = &cached.hash {
+                fresh = hash_entry(entry, options)? == *cached_hash;
+            }
+        }
+        if fresh {
+            let mut cached = cached.clone();
+            cached.mtime = FileTime::from_last_modification_time(&entry.metadata);
+            self.current.insert(entry.relative_path.clone(), cached);
+        }
+        Ok(fresh)
+    }
+
+    /// Record that `dest_path` now holds an up to date copy of `entry`.
+    pub(crate) fn record(
+        &mut self,
+        entry: &Entry,
+        dest_metadata: &Metadata,
+        options: &Options,
+    ) -> Result<()> {
+        let hash = match options.compare {
+            Compare::Hash => Some(hash_entry(entry, options)?),
+            Compare::Metadata | Compare::Newer => None,
+        };
+        self.current.insert(
+            entry.relative_path.clone(),
+            CacheEntry {
+                size: entry.metadata.len(),
+                mtime: FileTime::from_last_modification_time(&entry.metadata),
+                dest_mtime: FileTime::from_last_modification_time(dest_metadata),
+                hash,
+            },
+        );
+        Ok(())
+    }
+
+    /// Write the state gathered during this run back to disk.
+    pub(crate) fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut out = BufWriter::new(std::fs::File::create(&tmp)?);
+        writeln!(out, "{HEADER}")?;
+        for (path, entry) in &self.current {
+            // Paths that cannot be stored on one line are just not cached
+            let Some(path) = path.to_str().filter(|p| !p.contains('\n')) else {
+                continue;
+            };
+            let hash = entry.hash.as_deref().unwrap_or("-");
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{hash}\t{path}",
+                entry.size,
+                entry.mtime.unix_seconds(),
+                entry.mtime.nanoseconds(),
+                entry.dest_mtime.unix_seconds(),
+                entry.dest_mtime.nanoseconds(),
+            )?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clone_entries;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_state_cache_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::create_dir_all(&dest)?;
+        fs::write(src.join("a.txt"), "content")?;
+        fs::write(dest.join("a.txt"), "content")?;
+        let entry = clone_entries(&src, &Options::new()).next().unwrap()?;
+
+        let options = Options::new().compare(Compare::Hash);
+        let mut cache = StateCache::load(&dest);
+        let dest_metadata = || fs::symlink_metadata(dest.join("a.txt")).ok();
+        assert!(!cache.is_fresh(&entry, dest_metadata().as_ref(), &options)?);
+        cache.record(&entry, &dest_metadata().unwrap(), &options)?;
+        cache.save()?;
+        assert!(cache_path(&dest).exists());
+
+        let mut cache = StateCache::load(&dest);
+        assert!(cache.is_fresh(&entry, dest_metadata().as_ref(), &options)?);
+
+        // A change to the destination invalidates the entry
+        fs::write(dest.join("a.txt"), "changed")?;
+        filetime::set_file_mtime(dest.join("a.txt"), FileTime::from_unix_time(1, 0))?;
+        assert!(!cache.is_fresh(&entry, dest_metadata().as_ref(), &options)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Stopping a clone from another thread.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle for cancelling clones, registered with
+/// [`Options::cancel_token`](crate::Options::cancel_token).
+///
+/// Clones check the token before each file, so after
+/// [`cancel`](Self::cancel) the files already being copied finish and the
+/// clone then fails with [`Error::Cancelled`](crate::Error::Cancelled).
+/// Clones of the token share its state, so one can be handed to a signal
+/// handler while another is set on the options.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every clone using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clone_tree, Error, Options};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cancel() -> crate::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), name)?;
+        }
+
+        // Cancel from the progress callback after the first file
+        let token = CancelToken::new();
+        let options = Options::new()
+            .keep_going(true)
+            .cancel_token(token.clone())
+            .on_progress({
+                let token = token.clone();
+                move |_| token.cancel()
+            });
+        let dest = temp_dir.path().join("dest");
+        let result = clone_tree(&src, &dest, &options);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(token.is_cancelled());
+        assert_eq!(fs::read_dir(&dest)?.count(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Options checked and compiled ahead of cloning.
+
+use crate::filter::Globs;
+use crate::{build_globs, Error, Junctions, Options, Overwrite, Result, Submodules, WalkerBackend};
+
+/// [`Options`] that have been checked and compiled, ready for any number of
+/// clones. Created by [`Options::build`].
+///
+/// The plain functions such as [`clone_tree`](crate::clone_tree) build a
+/// config on every call. A program running many clones with the same options
+/// can build one up front instead, so a bad glob or a contradictory setting
+/// is reported before anything is touched, and the globs are compiled once.
+#[derive(Debug, Clone)]
+pub struct CloneConfig {
+    pub(crate) options: Options,
+    /// The compiled globs, matched against paths relative to the tree root
+    pub(crate) globs: Option<Globs>,
+}
+
+impl Options {
+    /// Check these options and compile their globs.
+    ///
+    /// Fails with [`Error::InvalidGlob`] for a bad pattern, and with
+    /// [`Error::InvalidOptions`] for settings that contradict each other: a
+    /// [`min_size`](Self::min_size) above [`max_size`](Self::max_size), a
+    /// [`newer_than`](Self::newer_than) no earlier than
+    /// [`older_than`](Self::older_than), or [`update`](Self::update) with
+    /// [`Overwrite::Replace`], which empties the destination it would compare
+    /// against, or [`delete`](Self::delete) with [`Overwrite::NoClobber`],
+    /// which promises to leave existing files alone. The git options need
+    /// [`gitignore`](Self::gitignore), and they and
+    /// [`submodules`](Self::submodules) need a walker that reads ignore
+    /// files, as does following junctions with [`Junctions::Follow`].
+    pub fn build(&self) -> Result<CloneConfig> {
+        let invalid = |message: &str| {
+            Err(Error::InvalidOptions {
+                message: message.into(),
+            })
+        };
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            if min > max {
+                return invalid("min_size is larger than max_size");
+            }
+        }
+        if let (Some(newer), Some(older)) = (self.newer_than, self.older_than) {
+            if newer >= older {
+                return invalid("newer_than is not earlier than older_than");
+            }
+        }
+        if self.update && self.overwrite == Overwrite::Replace {
+            return invalid("update has nothing to compare against with Overwrite::Replace");
+        }
+        if self.delete && self.overwrite == Overwrite::NoClobber {
+            return invalid("delete would remove existing files with Overwrite::NoClobber");
+        }
+        if (self.git_global || self.git_exclude) && !self.gitignore {
+            return invalid("git_global and git_exclude need gitignore");
+        }
+        let git = self.gitignore || self.submodules != Submodules::Include;
+        if git && (!cfg!(feature = "filter") || self.walker != WalkerBackend::Standard) {
+            return invalid(
+                "gitignore and submodules need the filter feature and WalkerBackend::Standard",
+            );
+        }
+        let follow = self.junctions == Junctions::Follow;
+        if follow && (!cfg!(feature = "filter") || self.walker != WalkerBackend::Standard) {
+            return invalid(
+                "Junctions::Follow needs the filter feature and WalkerBackend::Standard",
+            );
+        }
+
+        let mut options = self.clone();
+        options.threads = options.threads.max(1);
+        Ok(CloneConfig {
+            globs: build_globs(&options)?,
+            options,
+        })
+    }
+}
+
+impl CloneConfig {
+    /// The options this config was built from, with defaults resolved.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+}
+
+#[cfg(all(test, feature = "filter"))]
+mod tests {
+    use super::*;
+    use crate::clone_tree;
+    use std::fs;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_build() -> Result<()> {
+        let result = Options::new().glob("a/{b").build();
+        assert!(matches!(result, Err(Error::InvalidGlob { .. })));
+        let result = Options::new().min_size(10).max_size(5).build();
+        assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+        let now = SystemTime::now();
+        let result = Options::new().newer_than(now).older_than(now).build();
+        assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+        let result = Options::new()
+            .update(true)
+            .overwrite(Overwrite::Replace)
+            .build();
+        assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+        let result = Options::new()
+            .delete(true)
+            .overwrite(Overwrite::NoClobber)
+            .build();
+        assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+        #[cfg(feature = "walkdir")]
+        {
+            let result = Options::new()
+                .junctions(Junctions::Follow)
+                .walker(WalkerBackend::Walkdir)
+                .build();
+            assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+        }
+
+        // A config reports errors before touching the filesystem
+        let temp_dir = TempDir::new()?;
+        let dest = temp_dir.path().join("dest");
+        let result = clone_tree(temp_dir.path(), &dest, &Options::new().glob("a/{b"));
+        assert!(matches!(result, Err(Error::InvalidGlob { .. })));
+        assert!(!dest.exists());
+
+        // One config serves several clones
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.rs"), "a")?;
+        fs::write(src.join("b.txt"), "b")?;
+        let config = Options::new().glob("*.rs").build()?;
+        for name in ["one", "two"] {
+            let report = config.clone_tree(&src, temp_dir.path().join(name))?;
+            assert_eq!(report.files(), 1);
+        }
+        assert_eq!(config.options().threads, 1);
+
+        Ok(())
+    }
+}
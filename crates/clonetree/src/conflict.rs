@@ -0,0 +1,38 @@
+//! Deciding what to do with destination files that already exist.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A file that is about to be replaced, passed to the callback registered
+/// with [`Options::on_conflict`](crate::Options::on_conflict).
+#[derive(Debug, Clone, Copy)]
+pub struct Conflict<'a> {
+    /// The file's path relative to the tree roots.
+    pub path: &'a Path,
+    /// The source file.
+    pub src: &'a Path,
+    /// The existing destination file.
+    pub dest: &'a Path,
+}
+
+/// What to do about a [`Conflict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Replace the destination file with the source.
+    Overwrite,
+    /// Leave the destination file alone and move on.
+    Skip,
+    /// Stop the clone, failing with [`Error::Cancelled`](crate::Error::Cancelled).
+    Abort,
+}
+
+/// A shareable conflict callback.
+#[derive(Clone)]
+pub(crate) struct ConflictFn(pub(crate) Arc<dyn Fn(&Conflict) -> Resolution + Send + Sync>);
+
+impl fmt::Debug for ConflictFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConflictFn")
+    }
+}
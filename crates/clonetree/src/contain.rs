@@ -0,0 +1,78 @@
+//! Keeping every write inside the destination root.
+//!
+//! Destination paths are the source's relative paths, after renaming, joined
+//! to the root. A relative path from an archive or a custom
+//! [`TreeSource`](crate::TreeSource) can hold `..` or be absolute, and a
+//! directory already in the destination can be a symlink leading elsewhere.
+//! Either would send a write outside the root, so both are refused with
+//! [`Error::OutsideDestination`] before anything is touched.
+
+use crate::{CreatedDirs, Error, Result};
+use std::path::{Component, Path};
+
+/// Fail unless `relative_path` stays inside any root it is joined to: it may
+/// only hold plain names and `.`.
+pub(crate) fn check_relative(relative_path: &Path) -> Result<()> {
+    let escapes = relative_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(Error::OutsideDestination {
+            path: relative_path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Fail if a directory between `root` and `dir`, including `dir` but not
+/// `root`, is a symlink. Directories that don't exist yet are fine, and the
+/// search stops at the first one in `known`, which was created or checked
+/// already.
+pub(crate) fn check_parents(root: &Path, dir: &Path, known: &CreatedDirs) -> Result<()> {
+    for ancestor in dir.ancestors() {
+        if ancestor == root || !ancestor.starts_with(root) || known.contains(ancestor) {
+            break;
+        }
+        let is_symlink = std::fs::symlink_metadata(ancestor).is_ok_and(|m| m.is_symlink());
+        if is_symlink {
+            return Err(Error::OutsideDestination {
+                path: ancestor.to_path_buf(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_containment() -> Result<()> {
+        assert!(check_relative(Path::new("a/./b.txt")).is_ok());
+        for path in ["../a", "a/../../b", "/etc/passwd"] {
+            assert!(matches!(
+                check_relative(Path::new(path)),
+                Err(Error::OutsideDestination { .. })
+            ));
+        }
+
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(root.join("real"))?;
+        std::os::unix::fs::symlink(temp_dir.path(), root.join("link"))?;
+        let known = CreatedDirs::default();
+        assert!(check_parents(&root, &root.join("real/missing"), &known).is_ok());
+        assert!(matches!(
+            check_parents(&root, &root.join("link/sub"), &known),
+            Err(Error::OutsideDestination { path }) if path == root.join("link")
+        ));
+        // Directories already known are trusted
+        let known = CreatedDirs::new(&root.join("link"));
+        assert!(check_parents(&root, &root.join("link/sub"), &known).is_ok());
+
+        Ok(())
+    }
+}
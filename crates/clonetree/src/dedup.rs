@@ -0,0 +1,486 @@
+//! Finding identical files within a tree and making them share extents.
+
+use crate::hash::{self, Blake3, Digest};
+use crate::{clone_entries, Entry, Error, Options, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Options for [`dedup_tree`].
+#[derive(Debug, Default)]
+pub struct DedupOptions {
+    filter: Options,
+    min_size: u64,
+}
+
+impl DedupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a glob pattern restricting which files are considered, with the
+    /// same semantics as [`Options::glob`].
+    pub fn glob<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.filter = self.filter.glob(pattern);
+        self
+    }
+
+    /// Ignore files smaller than `min_size` bytes. Empty files are always
+    /// ignored, since they have no extents to share.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+}
+
+/// The outcome of a [`dedup_tree`] run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Number of files examined.
+    pub files_scanned: u64,
+    /// Number of files replaced by a reflink to an identical file.
+    pub files_deduped: u64,
+    /// Total size of the replaced files, which no longer occupy space of their
+    /// own.
+    pub bytes_reclaimed: u64,
+}
+
+/// A set of files with identical contents, as returned by [`find_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Size of each file in bytes.
+    pub size: u64,
+    /// Paths of the identical files, sorted.
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// Bytes that deduplicating this group would reclaim: the size of every
+    /// copy but one.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+/// Find sets of files with identical contents under `root`, considering only
+/// files that pass the filters in `options`. Empty files are not reported.
+/// Groups are sorted by their first path.
+pub fn find_duplicates<P: AsRef<Path>>(root: P, options: &Options) -> Result<Vec<DuplicateGroup>> {
+    let (_, groups) = duplicate_groups(root.as_ref(), options, 0)?;
+    Ok(groups
+        .into_iter()
+        .map(|group| DuplicateGroup {
+            size: group[0].metadata.len(),
+            paths: group.into_iter().map(|e| e.path).collect(),
+        })
+        .collect())
+}
+
+/// Group the files under `root` by identical content. Candidates are bucketed
+/// by size first, so only files that share a size with another file are
+/// hashed. Hardlinks to a file already seen are skipped, since they share its
+/// storage anyway. Only groups with at least two files are returned, each
+/// sorted by path.
+pub(crate) fn duplicate_groups(
+    root: &Path,
+    options: &Options,
+    min_size: u64,
+) -> Result<(u64, Vec<Vec<Entry>>)> {
+    let mut scanned = 0;
+    let mut by_size: HashMap<u64, Vec<Entry>> = HashMap::new();
+    let mut inodes = HashSet::new();
+    for entry in clone_entries(root, options) {
+        let entry = entry?;
+        scanned += 1;
+        let len = entry.metadata.len();
+        if len > 0 && len >= min_size && !linked_earlier(&mut inodes, &entry) {
+            by_size.entry(len).or_default().push(entry);
+        }
+    }
+
+    let mut groups = Vec::new();
+    for (_, candidates) in by_size {
+        if candidates.len() < 2 {
+            continue;
+        }
+        let mut by_hash: HashMap<Digest, Vec<Entry>> = HashMap::new();
+        for entry in candidates {
+            by_hash
+                .entry(hash::hash_file(&entry.path, &Blake3)?)
+                .or_default()
+                .push(entry);
+        }
+        for (_, mut group) in by_hash {
+            if group.len() > 1 {
+                group.sort_by(|a, b| a.path.cmp(&b.path));
+                groups.push(group);
+            }
+        }
+    }
+    groups.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+
+    Ok((scanned, groups))
+}
+
+/// Whether `entry` is a hardlink to a file already recorded in `inodes`. The
+/// first link seen is recorded and returns false.
+#[cfg(unix)]
+fn linked_earlier(inodes: &mut HashSet<(u64, u64)>, entry: &Entry) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = &entry.metadata;
+    metadata.nlink() > 1 && !inodes.insert((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn linked_earlier(_inodes: &mut HashSet<(u64, u64)>, _entry: &Entry) -> bool {
+    false
+}
+
+/// Whether `entry` has hardlinks elsewhere, which replacing it would break.
+#[cfg(unix)]
+fn has_links(entry: &Entry) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    entry.metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_links(_entry: &Entry) -> bool {
+    false
+}
+
+/// A file already written during a clone, available as a reflink source for
+/// later files with the same contents.
+struct Copied {
+    src: PathBuf,
+    dest: PathBuf,
+    hash: Option<Digest>,
+}
+
+/// Tracks the files written during a clone so that later files with identical
+/// contents can be reflinked from the destination copy instead of being copied
+/// from the source again. Files are only hashed once a second file of the same
+/// size turns up.
+#[derive(Default)]
+pub(crate) struct CopiedFiles {
+    by_size: HashMap<u64, Vec<Copied>>,
+}
+
+impl CopiedFiles {
+    /// Find an already written destination file with the same contents as
+    /// `entry`.
+    pub(crate) fn find(&mut self, entry: &Entry) -> Result<Option<PathBuf>> {
+        let len = entry.metadata.len();
+        if len == 0 {
+            return Ok(None);
+        }
+        let Some(candidates) = self.by_size.get_mut(&len) else {
+            return Ok(None);
+        };
+        let hash = hash::hash_file(&entry.path, &Blake3)?;
+        for candidate in candidates {
+            let candidate_hash = match &candidate.hash {
+                Some(h) => h,
+                None => candidate
+                    .hash
+                    .insert(hash::hash_file(&candidate.src, &Blake3)?),
+            };
+            if *candidate_hash == hash {
+                return Ok(Some(candidate.dest.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record that `entry` was written to `dest`.
+    pub(crate) fn record(&mut self, entry: &Entry, dest: &Path) {
+        let len = entry.metadata.len();
+        if len > 0 {
+            self.by_size.entry(len).or_default().push(Copied {
+                src: entry.path.clone(),
+                dest: dest.to_path_buf(),
+                hash: None,
+            });
+        }
+    }
+}
+
+/// Replace `dup` with a reflink of `original`, keeping the permissions and
+/// modification time of `dup`.
+fn replace_with_reflink(original: &Path, dup: &Entry) -> Result<()> {
+    let file_name = dup.path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dup
+        .path
+        .with_file_name(format!(".{file_name}.clonetree-dedup"));
+    let copy_err = |source| Error::Copy {
+        src: original.to_path_buf(),
+        dest: dup.path.clone(),
+        source,
+    };
+
+    reflink_copy::reflink(original, &tmp).map_err(copy_err)?;
+    let finish = || -> std::io::Result<()> {
+        std::fs::set_permissions(&tmp, dup.metadata.permissions())?;
+        filetime::set_file_mtime(
+            &tmp,
+            filetime::FileTime::from_last_modification_time(&dup.metadata),
+        )?;
+        std::fs::rename(&tmp, &dup.path)
+    };
+    finish().map_err(|source| {
+        let _ = std::fs::remove_file(&tmp);
+        copy_err(source)
+    })
+}
+
+/// Share the extents of `original` with `dup` using the kernel's dedupe ioctl.
+/// Returns `Some(false)` if the contents differ, or `dup` can't be opened for
+/// writing or deduped, and `None` if the filesystem does not support it, in
+/// which case the caller falls back to [`replace_with_reflink`].
+#[cfg(target_os = "linux")]
+fn dedupe_in_place(original: &Path, dup: &Entry) -> Result<Option<bool>> {
+    use crate::dedupe_range::{dedupe, Dedupe};
+    use std::fs::{File, OpenOptions};
+
+    let src = File::open(original).map_err(|source| Error::Read {
+        path: original.to_path_buf(),
+        source,
+    })?;
+    // Without the kernel comparing under its lock, a file changed since it
+    // was hashed could be overwritten, so one we can't open is left alone
+    let Ok(dest) = OpenOptions::new().write(true).open(&dup.path) else {
+        return Ok(Some(false));
+    };
+    match dedupe(&src, &dest, dup.metadata.len()) {
+        Ok(Dedupe::Shared) => Ok(Some(true)),
+        Ok(Dedupe::Differs) => Ok(Some(false)),
+        Err(e) if is_unsupported(&e) => Ok(None),
+        // Ranges the kernel won't dedupe, or an immutable or append-only
+        // file: replacing it would skip the kernel's check, so leave it
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EPERM)) => Ok(Some(false)),
+        Err(source) => Err(Error::Copy {
+            src: original.to_path_buf(),
+            dest: dup.path.clone(),
+            source,
+        }),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn dedupe_in_place(_original: &Path, _dup: &Entry) -> Result<Option<bool>> {
+    Ok(None)
+}
+
+/// Errors indicating that the filesystem cannot dedupe these files at all.
+#[cfg(target_os = "linux")]
+fn is_unsupported(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV)
+    )
+}
+
+/// Find files with identical contents under `root` and replace all but one
+/// copy of each with a reflink, so they share storage on disk.
+///
+/// On Linux, the `FIDEDUPERANGE` ioctl is used where the filesystem supports
+/// it: the kernel verifies that the contents still match before sharing
+/// extents, so files modified after hashing are skipped rather than
+/// overwritten, as are files that can't be opened for writing. Elsewhere,
+/// duplicates are replaced by a fresh reflink of the original. This requires a
+/// filesystem with reflink support; on other filesystems the first
+/// replacement fails with [`Error::Copy`].
+///
+/// Hardlinks are never deduplicated, and files with links outside the
+/// scanned set are not replaced, since that would break the link. On Linux,
+/// files already sharing all their extents with the original are skipped, so
+/// a second run reports nothing; elsewhere they are replaced and counted
+/// again.
+pub fn dedup_tree<P: AsRef<Path>>(root: P, options: &DedupOptions) -> Result<DedupStats> {
+    let (files_scanned, groups) =
+        duplicate_groups(root.as_ref(), &options.filter, options.min_size)?;
+    let mut stats = DedupStats {
+        files_scanned,
+        ..DedupStats::default()
+    };
+
+    for group in groups {
+        let original = &group[0].path;
+        for dup in &group[1..] {
+            let len = dup.metadata.len();
+            if crate::is_reflink_of(original, len, &dup.path, &dup.metadata) {
+                continue;
+            }
+            match dedupe_in_place(original, dup)? {
+                Some(true) => {}
+                // Changed since it was hashed, or not writable
+                Some(false) => continue,
+                None if has_links(dup) => continue,
+                None => replace_with_reflink(original, dup)?,
+            }
+            stats.files_deduped += 1;
+            stats.bytes_reclaimed += dup.metadata.len();
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_duplicate_groups() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("a.txt"), "duplicate")?;
+        fs::write(root.join("sub/b.txt"), "duplicate")?;
+        fs::write(root.join("c.txt"), "different")?;
+        fs::write(root.join("d.txt"), "unique")?;
+        fs::write(root.join("empty1"), "")?;
+        fs::write(root.join("empty2"), "")?;
+
+        let (scanned, groups) = duplicate_groups(root, &Options::new(), 0)?;
+        assert_eq!(scanned, 6);
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<_> = groups[0].iter().map(|e| e.relative_path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]
+        );
+
+        let (_, groups) = duplicate_groups(root, &Options::new(), 100)?;
+        assert!(groups.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_duplicate_groups_hardlinks() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a.txt"), "linked")?;
+        fs::hard_link(root.join("a.txt"), root.join("b.txt"))?;
+        fs::write(root.join("c.txt"), "linked")?;
+
+        let options = Options::new().sorted(true);
+        let (scanned, groups) = duplicate_groups(root, &options, 0)?;
+        assert_eq!(scanned, 3);
+        let paths: Vec<_> = groups[0].iter().map(|e| e.relative_path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("a.txt"), PathBuf::from("c.txt")]);
+
+        fs::remove_file(root.join("c.txt"))?;
+        let (_, groups) = duplicate_groups(root, &options, 0)?;
+        assert!(groups.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_find_duplicates() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a.txt"), "twice")?;
+        fs::write(root.join("b.txt"), "twice")?;
+        fs::write(root.join("c.txt"), "three")?;
+        fs::write(root.join("d.txt"), "three")?;
+        fs::write(root.join("e.log"), "three")?;
+
+        let groups = find_duplicates(root, &Options::new())?;
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[0].paths,
+            vec![root.join("a.txt"), root.join("b.txt")]
+        );
+        assert_eq!(groups[0].wasted_bytes(), 5);
+        assert_eq!(groups[1].paths.len(), 3);
+        assert_eq!(groups[1].wasted_bytes(), 10);
+
+        let groups = find_duplicates(root, &Options::new().glob("!*.log"))?;
+        assert_eq!(
+            groups[1].paths,
+            vec![root.join("c.txt"), root.join("d.txt")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copied_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a"), "same")?;
+        fs::write(root.join("b"), "diff")?;
+        fs::write(root.join("c"), "same")?;
+        let entries =
+            clone_entries(root, &Options::new().sorted(true)).collect::<Result<Vec<_>>>()?;
+
+        let mut copied = CopiedFiles::default();
+        assert_eq!(copied.find(&entries[0])?, None);
+        copied.record(&entries[0], Path::new("/dest/a"));
+        assert_eq!(copied.find(&entries[1])?, None);
+        copied.record(&entries[1], Path::new("/dest/b"));
+        assert_eq!(copied.find(&entries[2])?, Some(PathBuf::from("/dest/a")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_tree() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a.txt"), "duplicate")?;
+        fs::write(root.join("b.txt"), "duplicate")?;
+        fs::write(root.join("c.txt"), "unique")?;
+
+        // Reflinks are not available on every filesystem the tests run on
+        let stats = match dedup_tree(root, &DedupOptions::new()) {
+            Ok(stats) => stats,
+            Err(Error::Copy { .. }) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        assert_eq!(
+            stats,
+            DedupStats {
+                files_scanned: 3,
+                files_deduped: 1,
+                bytes_reclaimed: 9,
+            }
+        );
+        assert_eq!(fs::read_to_string(root.join("b.txt"))?, "duplicate");
+        assert_eq!(fs::read_dir(root)?.count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_dedup_tree_twice() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a.txt"), vec![7; 16384])?;
+        fs::write(root.join("b.txt"), vec![7; 16384])?;
+
+        // Reflinks are not available on every filesystem the tests run on
+        match dedup_tree(root, &DedupOptions::new()) {
+            Ok(stats) => assert_eq!(stats.files_deduped, 1),
+            Err(Error::Copy { .. }) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let stats = dedup_tree(root, &DedupOptions::new())?;
+        assert_eq!(stats.files_deduped, 0);
+        assert_eq!(stats.bytes_reclaimed, 0);
+
+        Ok(())
+    }
+}
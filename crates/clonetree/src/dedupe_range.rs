@@ -0,0 +1,83 @@
+//! Linux `FIDEDUPERANGE` support.
+//!
+//! Unlike a plain reflink, the dedupe ioctl has the kernel compare the two
+//! ranges under lock and only share extents if they are byte-for-byte
+//! identical, so a file modified after it was hashed is never clobbered.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; 1],
+}
+
+/// Result of a dedupe request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Dedupe {
+    /// The destination now shares all of its extents with the source.
+    Shared,
+    /// The contents differ, and nothing was changed.
+    Differs,
+}
+
+/// Ask the kernel to share the extents of `src` with `dest`, which must be
+/// open for writing. Both files are expected to be `len` bytes long.
+pub(crate) fn dedupe(src: &File, dest: &File, len: u64) -> io::Result<Dedupe> {
+    let mut offset = 0;
+    while offset < len {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: len - offset,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dest.as_raw_fd().into(),
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        // SAFETY: `range` is a correctly laid out `file_dedupe_range` with room
+        // for the single `file_dedupe_range_info` named by `dest_count`, and
+        // both descriptors stay open for the duration of the call.
+        let ret = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let info = &range.info[0];
+        if info.status < 0 {
+            return Err(io::Error::from_raw_os_error(-info.status));
+        }
+        if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return Ok(Dedupe::Differs);
+        }
+        // Filesystems may cap the length of a single request
+        if info.bytes_deduped == 0 {
+            return Err(io::Error::other("dedupe made no progress"));
+        }
+        offset += info.bytes_deduped;
+    }
+    Ok(Dedupe::Shared)
+}
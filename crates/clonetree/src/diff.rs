@@ -0,0 +1,144 @@
+//! Comparing two directory trees.
+
+use crate::{clone_entries, Entry, Options, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// How files present in both trees are compared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    /// Files differ if their size or modification time differ. This is cheap,
+    /// but only meaningful when timestamps are preserved between the trees.
+    #[default]
+    Metadata,
+    /// Files differ if their size or content hash differ.
+    Hash,
+    /// Files differ only if the first is newer than the second, as with
+    /// `cp -u`. Size is ignored, so a destination file that is as new as or
+    /// newer than its source is always left alone.
+    Newer,
+}
+
+/// The differences between two trees, as returned by [`diff_trees`]. All paths
+/// are relative to the tree roots and sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// Files present only in the second tree.
+    pub added: Vec<PathBuf>,
+    /// Files present only in the first tree.
+    pub removed: Vec<PathBuf>,
+    /// Files present in both trees whose contents differ.
+    pub modified: Vec<PathBuf>,
+}
+
+impl TreeDiff {
+    /// True if the trees contain the same files.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Collect the filtered entries of `root`, keyed by relative path.
+fn collect(root: &Path, options: &Options) -> Result<BTreeMap<PathBuf, Entry>> {
+    clone_entries(root, options)
+        .map(|entry| entry.map(|e| (e.relative_path.clone(), e)))
+        .collect()
+}
+
+/// Check whether two files differ under the comparison mode and hasher in
+/// `options`.
+pub(crate) fn differs(a: &Entry, b: &Entry, options: &Options) -> Result<bool> {
+    let compare = options.compare;
+    if compare == Compare::Newer {
+        return Ok(match (a.metadata.modified(), b.metadata.modified()) {
+            (Ok(a), Ok(b)) => a > b,
+            _ => true,
+        });
+    }
+    if a.metadata.len() != b.metadata.len() {
+        return Ok(true);
+    }
+    match compare {
+        Compare::Metadata => Ok(a.metadata.modified().ok() != b.metadata.modified().ok()),
+        Compare::Hash => Ok(options.hash_file(&a.path)? != options.hash_file(&b.path)?),
+        Compare::Newer => unreachable!("handled above"),
+    }
+}
+
+/// Compare the files under `a` and `b`, applying the filters from `options` to
+/// both trees. Files present in both trees are compared as configured by
+/// [`Options::compare`].
+pub fn diff_trees<P: AsRef<Path>, Q: AsRef<Path>>(
+    a: P,
+    b: Q,
+    options: &Options,
+) -> Result<TreeDiff> {
+    let a = collect(a.as_ref(), options)?;
+    let mut b = collect(b.as_ref(), options)?;
+    let mut diff = TreeDiff::default();
+
+    for (path, a_entry) in a {
+        match b.remove(&path) {
+            Some(b_entry) => {
+                if differs(&a_entry, &b_entry, options)? {
+                    diff.modified.push(path);
+                }
+            }
+            None => diff.removed.push(path),
+        }
+    }
+    diff.added = b.into_keys().collect();
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clone_tree;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_diff_trees() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+
+        fs::create_dir_all(a.join("sub"))?;
+        fs::write(a.join("same.txt"), "same")?;
+        fs::write(a.join("gone.txt"), "gone")?;
+        fs::write(a.join("sub/changed.txt"), "before")?;
+        clone_tree(&a, &b, &Options::new())?;
+
+        fs::remove_file(b.join("gone.txt"))?;
+        fs::write(b.join("new.txt"), "new")?;
+        fs::write(b.join("sub/changed.txt"), "after!")?;
+
+        let diff = diff_trees(&a, &b, &Options::new().compare(Compare::Hash))?;
+        assert_eq!(diff.added, vec![PathBuf::from("new.txt")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("gone.txt")]);
+        assert_eq!(diff.modified, vec![PathBuf::from("sub/changed.txt")]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_diff_trees_identical() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+
+        fs::create_dir_all(&a)?;
+        fs::write(a.join("file.txt"), "content")?;
+        fs::write(a.join("skip.log"), "log")?;
+        clone_tree(&a, &b, &Options::new())?;
+        fs::write(b.join("skip.log"), "different")?;
+
+        let opts = Options::new().glob("!*.log").compare(Compare::Hash);
+        assert!(diff_trees(&a, &b, &opts)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! Reporting how much space a tree occupies on disk.
+
+use crate::{clone_entries, Options, Result};
+use std::path::Path;
+
+/// Space used by the files of a tree, as returned by [`disk_usage`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Number of files counted.
+    pub files: u64,
+    /// Sum of the file sizes, as reported by `ls`.
+    pub apparent_bytes: u64,
+    /// Space allocated to the files on disk, as reported by `du`. Extents
+    /// shared between files are counted once per file. On platforms without
+    /// block counts this equals `apparent_bytes`.
+    pub allocated_bytes: u64,
+    /// Bytes in extents that the filesystem reports as shared with other
+    /// files, such as reflinked clones. Only available on Linux filesystems
+    /// that support `FIEMAP`; `None` elsewhere.
+    pub shared_bytes: Option<u64>,
+}
+
+impl DiskUsage {
+    /// Allocated bytes not shared with any other file, if known. For a fresh
+    /// reflink clone this is close to zero.
+    pub fn exclusive_bytes(&self) -> Option<u64> {
+        self.shared_bytes
+            .map(|shared| self.allocated_bytes.saturating_sub(shared))
+    }
+}
+
+#[cfg(unix)]
+fn allocated(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+#[cfg(target_os = "linux")]
+fn shared(path: &Path) -> Option<u64> {
+    let file = std::fs::File::open(path).ok()?;
+    crate::fiemap::shared_bytes(&file).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Measure the apparent and allocated size of the files under `root` that pass
+/// the filters in `options`. Shared extent information is reported only if it
+/// is available for every file.
+pub fn disk_usage<P: AsRef<Path>>(root: P, options: &Options) -> Result<DiskUsage> {
+    let mut usage = DiskUsage {
+        shared_bytes: Some(0),
+        ..DiskUsage::default()
+    };
+
+    for entry in clone_entries(root, options) {
+        let entry = entry?;
+        usage.files += 1;
+        usage.apparent_bytes += entry.metadata.len();
+        usage.allocated_bytes += allocated(&entry.metadata);
+        usage.shared_bytes = match (usage.shared_bytes, shared(&entry.path)) {
+            (Some(total), Some(bytes)) => Some(total + bytes),
+            _ => None,
+        };
+    }
+
+    Ok(usage)
+}
+
+#[cfg(all(test, feature = "filter"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_disk_usage() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+
+        fs::write(root.join("a.txt"), vec![b'a'; 10_000])?;
+        fs::write(root.join("b.txt"), "small")?;
+        fs::write(root.join("c.log"), "skipped")?;
+
+        let usage = disk_usage(root, &Options::new().glob("*.txt"))?;
+        assert_eq!(usage.files, 2);
+        assert_eq!(usage.apparent_bytes, 10_005);
+        assert!(usage.allocated_bytes >= 10_000);
+        if let Some(exclusive) = usage.exclusive_bytes() {
+            assert!(exclusive <= usage.allocated_bytes);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Clone events delivered over channels.
+
+use crate::{FileOutcome, RecordedWarning};
+use std::fs::Metadata;
+use std::path::PathBuf;
+
+/// Something that happened during a clone, received from
+/// [`CloneTree::events`](crate::CloneTree::events) or the channel given to
+/// [`Options::event_sender`](crate::Options::event_sender).
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A destination directory was created.
+    DirCreated {
+        /// The directory relative to the destination root, empty for the
+        /// root itself.
+        path: PathBuf,
+        /// The metadata of the source directory it stands for, or of the
+        /// new directory when the source has none, as with an archive.
+        metadata: Metadata,
+    },
+    /// A file was processed.
+    File(FileOutcome),
+    /// The clone worked around a problem with a file.
+    Warning(RecordedWarning),
+    /// A file couldn't be cloned. With
+    /// [`Options::keep_going`](crate::Options::keep_going) the clone carries
+    /// on; otherwise it stops with this error.
+    Error {
+        /// The file the error is about, from
+        /// [`Error::path`](crate::Error::path).
+        path: Option<PathBuf>,
+        /// The error message.
+        message: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clone_between, clone_tree, DirSink, DirSource, Options, Overwrite};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_event_sender() -> crate::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("b.txt"), "b")?;
+        fs::create_dir_all(dest.join("a.txt/sub"))?;
+
+        let (sender, receiver) = mpsc::channel();
+        let consumer = std::thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+        let options = Options::new()
+            .overwrite(true)
+            .keep_going(true)
+            .sorted(true)
+            .threads(1)
+            .event_sender(sender);
+        assert!(clone_tree(&src, &dest, &options).is_err());
+        // Dropping the options closes the channel
+        drop(options);
+
+        let events = consumer.join().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Event::Error { .. }));
+        assert!(matches!(&events[1], Event::File(f) if f.path == Path::new("b.txt")));
+
+        // Errors carry the file they are about
+        let (sender, receiver) = mpsc::channel();
+        fs::write(dest.join("b.txt"), "kept")?;
+        let options = Options::new()
+            .overwrite(Overwrite::NoClobber)
+            .keep_going(true)
+            .event_sender(sender);
+        assert!(clone_tree(&src, &dest, &options).is_err());
+        assert!(receiver.try_iter().any(|event| matches!(
+            event,
+            Event::Error { path: Some(path), message }
+                if path == dest.join("b.txt") && message.contains("b.txt")
+        )));
+
+        // Each directory made in the destination is reported, the root too
+        fs::create_dir_all(src.join("sub/deep"))?;
+        fs::write(src.join("sub/deep/c.txt"), "c")?;
+        let created = |receiver: mpsc::Receiver<Event>| -> Vec<(PathBuf, bool)> {
+            receiver
+                .try_iter()
+                .filter_map(|event| match event {
+                    Event::DirCreated { path, metadata } => Some((path, metadata.is_dir())),
+                    _ => None,
+                })
+                .collect()
+        };
+        let expected = ["", "sub", "sub/deep"].map(|path| (PathBuf::from(path), true));
+        let (sender, receiver) = mpsc::channel();
+        let dest = temp_dir.path().join("fresh");
+        clone_tree(&src, &dest, &Options::new().event_sender(sender))?;
+        assert_eq!(created(receiver), expected);
+
+        // As they are when writing through a DirSink
+        let (sender, receiver) = mpsc::channel();
+        let dest = temp_dir.path().join("sink");
+        clone_between(
+            &mut DirSource::new(&src),
+            &mut DirSink::new(&dest),
+            &Options::new().sorted(true).event_sender(sender),
+        )?;
+        assert_eq!(created(receiver), expected);
+        Ok(())
+    }
+}
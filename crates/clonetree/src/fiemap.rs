@@ -0,0 +1,129 @@
+//! Linux `FS_IOC_FIEMAP` support, used to find extents shared with other files.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// Number of extents fetched per ioctl call.
+const EXTENT_BATCH: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; EXTENT_BATCH],
+}
+
+/// Total length of the extents of `file` that the filesystem reports as shared
+/// with another file, for example through a reflink.
+pub(crate) fn shared_bytes(file: &File) -> io::Result<u64> {
+    let mut shared = 0;
+    for_each_extent(file, |extent| {
+        if extent.fe_flags & FIEMAP_EXTENT_SHARED != 0 {
+            shared += extent.fe_length;
+        }
+    })?;
+    Ok(shared)
+}
+
+/// Whether `a` and `b` are made of the same shared extents, in the same
+/// order, so that their contents are identical without reading them. Extents
+/// without a known location, such as data not yet written out or stored
+/// inline in the inode, never count as shared.
+pub(crate) fn same_extents(a: &File, b: &File) -> io::Result<bool> {
+    let layout = |file: &File| -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+        let mut extents = Vec::new();
+        let mut usable = true;
+        for_each_extent(file, |extent| {
+            let unknown =
+                FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_DATA_INLINE;
+            usable &= extent.fe_flags & FIEMAP_EXTENT_SHARED != 0 && extent.fe_flags & unknown == 0;
+            extents.push((extent.fe_logical, extent.fe_physical, extent.fe_length));
+        })?;
+        Ok((usable && !extents.is_empty()).then_some(extents))
+    };
+    match (layout(a)?, layout(b)?) {
+        (Some(a), Some(b)) => Ok(a == b),
+        _ => Ok(false),
+    }
+}
+
+/// Call `visit` with each extent of `file`, in order.
+fn for_each_extent(file: &File, mut visit: impl FnMut(&FiemapExtent)) -> io::Result<()> {
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENT_BATCH as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); EXTENT_BATCH],
+        };
+        // SAFETY: `map` is a correctly laid out `struct fiemap` followed by
+        // room for `fm_extent_count` extents, and `file` stays open for the
+        // duration of the call.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let extents = &map.fm_extents[..map.fm_mapped_extents as usize];
+        let Some(last) = extents.last() else {
+            return Ok(());
+        };
+        extents.iter().for_each(&mut visit);
+        if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+            return Ok(());
+        }
+        start = last.fe_logical + last.fe_length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_same_extents() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        fs::write(&a, vec![1; 8192])?;
+        fs::write(&b, vec![1; 8192])?;
+
+        // Equal contents in separate extents are not shared
+        let (a, b) = (File::open(&a)?, File::open(&b)?);
+        if let Ok(same) = same_extents(&a, &b) {
+            assert!(!same);
+        }
+
+        Ok(())
+    }
+}
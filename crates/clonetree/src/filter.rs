@@ -0,0 +1,97 @@
+//! Compiled glob filters.
+//!
+//! Globs are matched with the `ignore` crate, so they need the `filter`
+//! feature. Without it any glob is refused with [`Error::InvalidGlob`], which
+//! keeps the dependency tree small for embedders that clone whole trees.
+
+use crate::{Error, Result};
+use std::path::Path;
+
+/// A set of glob patterns, compiled to match paths relative to a tree root.
+#[derive(Debug, Clone)]
+pub(crate) struct Globs {
+    #[cfg(feature = "filter")]
+    overrides: ignore::overrides::Override,
+}
+
+impl Globs {
+    /// Compile `patterns`, or `None` if there are none.
+    #[cfg(feature = "filter")]
+    pub(crate) fn build(patterns: &[String]) -> Result<Option<Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = ignore::overrides::OverrideBuilder::new("");
+        for pattern in patterns {
+            builder.add(pattern).map_err(|e| invalid(pattern, e))?;
+        }
+        let overrides = builder
+            .build()
+            .map_err(|e| Error::Other(format!("Failed to build glob overrides: {e}")))?;
+        Ok(Some(Self { overrides }))
+    }
+
+    #[cfg(not(feature = "filter"))]
+    pub(crate) fn build(patterns: &[String]) -> Result<Option<Self>> {
+        match patterns.first() {
+            Some(pattern) => check(pattern).map(|()| None),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the globs leave out `path`, which is a directory if `is_dir`.
+    /// Only the path itself is matched, not its parents.
+    pub(crate) fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        #[cfg(feature = "filter")]
+        return self.overrides.matched(path, is_dir).is_ignore();
+        #[cfg(not(feature = "filter"))]
+        {
+            let _ = (path, is_dir);
+            false
+        }
+    }
+}
+
+/// Fail with [`Error::InvalidGlob`] if `pattern` isn't a valid glob.
+pub(crate) fn check(pattern: &str) -> Result<()> {
+    #[cfg(feature = "filter")]
+    return ignore::overrides::OverrideBuilder::new("")
+        .add(pattern)
+        .map(|_| ())
+        .map_err(|e| invalid(pattern, e));
+    #[cfg(not(feature = "filter"))]
+    Err(invalid(pattern, "globs need the `filter` feature"))
+}
+
+fn invalid<E>(pattern: &str, source: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::InvalidGlob {
+        pattern: pattern.to_string(),
+        source: source.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_globs() -> Result<()> {
+        assert!(Globs::build(&[])?.is_none());
+        let patterns = ["!*.log".to_string()];
+        #[cfg(feature = "filter")]
+        {
+            let globs = Globs::build(&patterns)?.expect("globs");
+            assert!(globs.excludes(Path::new("a.log"), false));
+            assert!(!globs.excludes(Path::new("a.txt"), false));
+        }
+        #[cfg(not(feature = "filter"))]
+        assert!(matches!(
+            Globs::build(&patterns),
+            Err(Error::InvalidGlob { pattern, .. }) if pattern == "!*.log"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! A clone set up ahead of time, then run, watched and stopped through one
+//! handle.
+
+use crate::{CancelToken, CloneReport, Event, Options, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+
+/// A configured clone of one tree, owning its source, destination and
+/// options.
+///
+/// The handle can be shared between threads: one calls [`run`](Self::run),
+/// while others [`abort`](Self::abort) it or drain its
+/// [`events`](Self::events).
+///
+/// ```no_run
+/// use clonetree::{CloneTree, Event, Options};
+///
+/// let op = CloneTree::new("src", "dest", Options::new());
+/// let events = op.events();
+/// let report = std::thread::scope(|scope| {
+///     scope.spawn(|| {
+///         for event in events {
+///             if let Event::File(file) = event {
+///                 println!("{}", file.path.display());
+///             }
+///         }
+///     });
+///     op.run()
+/// })?;
+/// println!("{} files", report.files());
+/// # Ok::<(), clonetree::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct CloneTree {
+    src: PathBuf,
+    dest: PathBuf,
+    options: Options,
+    cancel: CancelToken,
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+}
+
+impl CloneTree {
+    /// Set up a clone of `src` to `dest` with `options`. Nothing is read or
+    /// written until [`run`](Self::run). A [`CancelToken`] already set on
+    /// `options` is the one [`abort`](Self::abort) cancels.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dest: Q, options: Options) -> Self {
+        let cancel = options.cancel.clone().unwrap_or_default();
+        Self {
+            src: src.as_ref().to_path_buf(),
+            dest: dest.as_ref().to_path_buf(),
+            options: options.cancel_token(cancel.clone()),
+            cancel,
+            subscribers: Mutex::default(),
+        }
+    }
+
+    /// The directory to clone.
+    pub fn src(&self) -> &Path {
+        &self.src
+    }
+
+    /// Where the clone goes.
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    /// The options the clone runs with, including the handle's cancel token.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Receive the events of the next [`run`](Self::run). The channel
+    /// closes when it returns, so a receiver on another thread can simply
+    /// be iterated until then.
+    pub fn events(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("subscribers lock")
+            .push(sender);
+        receiver
+    }
+
+    /// Stop the clone: files already being copied finish, and
+    /// [`run`](Self::run) then fails with
+    /// [`Error::Cancelled`](crate::Error::Cancelled). A clone aborted before
+    /// it runs stops before its first file.
+    pub fn abort(&self) {
+        self.cancel.cancel();
+    }
+
+    /// The token [`abort`](Self::abort) cancels, to hand to code that
+    /// doesn't have the handle, such as a Ctrl-C handler.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Clone the tree, as [`clone_tree`](crate::clone_tree) does, sending events to each
+    /// receiver returned by [`events`](Self::events) since the last run.
+    pub fn run(&self) -> Result<CloneReport> {
+        let subscribers = std::mem::take(&mut *self.subscribers.lock().expect("subscribers lock"));
+        // The senders are dropped with these options when the clone returns,
+        // closing the channels
+        let options = subscribers
+            .into_iter()
+            .fold(self.options.clone(), Options::event_sender);
+        options.build()?.clone_tree(&self.src, &self.dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_clone_tree_handle() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), name)?;
+        }
+
+        let op = CloneTree::new(&src, temp_dir.path().join("dest"), Options::new());
+        let events = op.events();
+        let report = op.run()?;
+        assert_eq!(report.files(), 3);
+        // The channel is closed once the run returns
+        let mut paths: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::File(file) => Some(file.path),
+                _ => None,
+            })
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["a", "b", "c"].map(PathBuf::from));
+
+        let op = CloneTree::new(&src, temp_dir.path().join("aborted"), Options::new());
+        op.abort();
+        assert!(op.cancel_token().is_cancelled());
+        assert!(matches!(op.run(), Err(Error::Cancelled)));
+        Ok(())
+    }
+}
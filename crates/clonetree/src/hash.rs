@@ -0,0 +1,200 @@
+//! Content hashing shared by the diff, dedup, cache and manifest code.
+
+use crate::{Error, Options, Result};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A content hash algorithm, chosen with [`Options::hasher`].
+///
+/// Hashes decide whether files differ with [`Compare::Hash`](crate::Compare),
+/// and are recorded in the state cache and in manifests. [`Blake3`], the
+/// default, is fast and cryptographically strong; [`Sha256`] is slower but
+/// what other tools usually expect; [`XxHash64`] and [`Crc32c`] are faster
+/// still, and fine for catching corruption but not for content an adversary
+/// controls.
+pub trait Hasher: Send + Sync {
+    /// A short name for the algorithm, such as `"blake3"`, recorded alongside
+    /// its hashes so that hashes from different algorithms are never compared.
+    fn name(&self) -> &'static str;
+
+    /// Hash everything `reader` yields.
+    fn hash(&self, reader: &mut dyn Read) -> io::Result<Digest>;
+}
+
+/// The output of a [`Hasher`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest(Vec<u8>);
+
+impl Digest {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The digest as lowercase hex.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// BLAKE3, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Blake3;
+
+impl Hasher for Blake3 {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(reader, &mut hasher)?;
+        Ok(Digest::new(hasher.finalize().as_bytes().as_slice()))
+    }
+}
+
+/// SHA-256.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        use sha2::Digest as _;
+        let mut hasher = sha2::Sha256::new();
+        read_chunks(reader, |chunk| hasher.update(chunk))?;
+        Ok(Digest::new(hasher.finalize().as_slice()))
+    }
+}
+
+/// xxHash64, which is not cryptographic.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct XxHash64;
+
+impl Hasher for XxHash64 {
+    fn name(&self) -> &'static str {
+        "xxh64"
+    }
+
+    fn hash(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        let mut hasher = xxhash_rust::xxh64::Xxh64::new(0);
+        read_chunks(reader, |chunk| hasher.update(chunk))?;
+        Ok(Digest::new(hasher.digest().to_be_bytes()))
+    }
+}
+
+/// CRC-32C, which is not cryptographic, and is computed in hardware on most
+/// CPUs. At 32 bits it is meant for catching corruption one file at a time,
+/// not for telling apart many files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Crc32c;
+
+impl Hasher for Crc32c {
+    fn name(&self) -> &'static str {
+        "crc32c"
+    }
+
+    fn hash(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        let mut crc = 0;
+        read_chunks(reader, |chunk| crc = crc32c::crc32c_append(crc, chunk))?;
+        Ok(Digest::new(crc.to_be_bytes()))
+    }
+}
+
+/// Pass everything `reader` yields to `update`, a buffer at a time.
+fn read_chunks(reader: &mut dyn Read, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The built-in hasher called `name`.
+#[cfg(any(feature = "manifest", feature = "profile"))]
+pub(crate) fn by_name(name: &str) -> Option<Arc<dyn Hasher>> {
+    match name {
+        "blake3" => Some(Arc::new(Blake3)),
+        "sha256" => Some(Arc::new(Sha256)),
+        "xxh64" => Some(Arc::new(XxHash64)),
+        "crc32c" => Some(Arc::new(Crc32c)),
+        _ => None,
+    }
+}
+
+/// A shareable hasher.
+#[derive(Clone)]
+pub(crate) struct HasherRef(pub(crate) Arc<dyn Hasher>);
+
+impl fmt::Debug for HasherRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HasherRef({})", self.0.name())
+    }
+}
+
+impl Options {
+    /// The hasher chosen with [`Options::hasher`], or [`Blake3`].
+    pub(crate) fn content_hasher(&self) -> &dyn Hasher {
+        match &self.hasher {
+            Some(HasherRef(hasher)) => hasher.as_ref(),
+            None => &Blake3,
+        }
+    }
+
+    /// Hash the file at `path` with the chosen hasher.
+    pub(crate) fn hash_file(&self, path: &Path) -> Result<Digest> {
+        hash_file(path, self.content_hasher())
+    }
+}
+
+/// Hash the contents of the file at `path` with `hasher`.
+pub(crate) fn hash_file(path: &Path, hasher: &dyn Hasher) -> Result<Digest> {
+    let read_err = |source| Error::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut file = File::open(path).map_err(read_err)?;
+    hasher.hash(&mut file).map_err(read_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashers() -> io::Result<()> {
+        let hex = |hasher: &dyn Hasher| hasher.hash(&mut &b"abc"[..]).map(|d| d.to_hex());
+        assert_eq!(hex(&Blake3)?, blake3::hash(b"abc").to_hex().as_str());
+        assert_eq!(
+            hex(&Sha256)?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hex(&XxHash64)?, "44bc2cf5ad770999");
+        let check = Crc32c.hash(&mut &b"123456789"[..])?;
+        assert_eq!(check.to_hex(), "e3069283");
+        #[cfg(any(feature = "manifest", feature = "profile"))]
+        for name in ["blake3", "sha256", "xxh64", "crc32c"] {
+            assert_eq!(by_name(name).map(|h| h.name()), Some(name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Linux inode flags, as shown by `lsattr` and set by `chattr`.
+//!
+//! Only flags that describe how a file is stored or may be changed are
+//! copied. `nocow` is the one that matters most for clones: btrfs only honors
+//! it on an empty file, so it has to be set before any data is written.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// The kernel declares these with a `long` argument but reads an `int`.
+const FS_IOC_GETFLAGS: libc::c_ulong =
+    0x8000_6601 | (std::mem::size_of::<libc::c_long>() as libc::c_ulong) << 16;
+const FS_IOC_SETFLAGS: libc::c_ulong =
+    0x4000_6602 | (std::mem::size_of::<libc::c_long>() as libc::c_ulong) << 16;
+
+const FS_COMPR_FL: u32 = 0x0000_0004;
+const FS_SYNC_FL: u32 = 0x0000_0008;
+const FS_IMMUTABLE_FL: u32 = 0x0000_0010;
+const FS_APPEND_FL: u32 = 0x0000_0020;
+const FS_NODUMP_FL: u32 = 0x0000_0040;
+const FS_NOATIME_FL: u32 = 0x0000_0080;
+const FS_NOCOMP_FL: u32 = 0x0000_0400;
+pub(crate) const FS_NOCOW_FL: u32 = 0x0080_0000;
+
+/// The flags copied from source files.
+pub(crate) const COPIED: u32 = FS_COMPR_FL
+    | FS_SYNC_FL
+    | FS_IMMUTABLE_FL
+    | FS_APPEND_FL
+    | FS_NODUMP_FL
+    | FS_NOATIME_FL
+    | FS_NOCOMP_FL
+    | FS_NOCOW_FL;
+
+/// Open `path` for the flag ioctls, which work on a read-only descriptor
+/// even for immutable files. Symlinks are refused.
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// The inode flags of `path`.
+pub(crate) fn get(path: &Path) -> io::Result<u32> {
+    get_file(&open(path)?)
+}
+
+fn get_file(file: &File) -> io::Result<u32> {
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the ioctl writes a single int through the pointer, and `file`
+    // stays open for the duration of the call.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+/// Set the flags of `path` in `mask` to those in `flags`, leaving the rest
+/// alone.
+pub(crate) fn set(path: &Path, flags: u32, mask: u32) -> io::Result<()> {
+    let file = open(path)?;
+    let current = get_file(&file)?;
+    let wanted = (current & !mask) | (flags & mask);
+    if wanted == current {
+        return Ok(());
+    }
+    let wanted = wanted as libc::c_int;
+    // SAFETY: the ioctl reads a single int through the pointer, and `file`
+    // stays open for the duration of the call.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &wanted) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `e` means the filesystem or the process can't have the flags
+/// asked for, rather than a real failure.
+pub(crate) fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM | libc::EACCES)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_flags() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, "data")?;
+        let Ok(before) = get(&path) else {
+            // The filesystem has no inode flags
+            return Ok(());
+        };
+        if let Err(e) = set(&path, FS_NODUMP_FL | FS_NOATIME_FL, COPIED) {
+            assert!(unsupported(&e));
+            return Ok(());
+        }
+        let before = before & !COPIED;
+        assert_eq!(get(&path)?, before | FS_NODUMP_FL | FS_NOATIME_FL);
+
+        // Flags outside the mask are untouched
+        set(&path, 0, FS_NOATIME_FL)?;
+        assert_eq!(get(&path)?, before | FS_NODUMP_FL);
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! NTFS junctions.
+//!
+//! A junction is a directory reparse point much like a directory symlink,
+//! and the standard library reports it as a symlink. Unlike a symlink it
+//! needs no privilege to create, always holds an absolute target, and only
+//! exists on Windows, so elsewhere nothing is ever a junction.
+
+use std::io;
+use std::path::Path;
+
+/// How NTFS junctions in the source are cloned, set with
+/// [`Options::junctions`](crate::Options::junctions). Other reparse points,
+/// such as symlinks, follow [`Options::symlinks`](crate::Options::symlinks).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Junctions {
+    /// Treat them like directory symlinks: recreated as one with
+    /// [`Options::symlinks`](crate::Options::symlinks), and skipped without.
+    #[default]
+    Symlink,
+    /// Recreate them as junctions. A target inside the source tree is moved
+    /// to the same place in the destination, so the clone doesn't point back
+    /// into the source.
+    Junction,
+    /// Clone the directory each one points to as if it were in the tree,
+    /// after the rest of the source. A junction leading to one of its own
+    /// parents is skipped rather than followed forever. Needs the `filter`
+    /// feature and [`WalkerBackend::Standard`](crate::WalkerBackend::Standard).
+    Follow,
+    /// Leave them out, listing each as
+    /// [`SkipReason::NotRegular`](crate::SkipReason::NotRegular).
+    Skip,
+}
+
+/// How links to directories are recreated on Windows, set with
+/// [`Options::dir_links`](crate::Options::dir_links).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DirLinks {
+    /// A directory symlink, which needs administrator rights or Developer
+    /// Mode, failing without them.
+    #[default]
+    Symlink,
+    /// A junction, which anyone can create. Relative targets become
+    /// absolute, since junctions can't hold anything else.
+    Junction,
+    /// A directory symlink where allowed, otherwise a junction.
+    Auto,
+}
+
+/// Whether `path` is a junction, without following it.
+#[cfg(windows)]
+pub(crate) fn is_junction(path: &Path) -> bool {
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FindClose, FindFirstFileW, FILE_ATTRIBUTE_REPARSE_POINT, WIN32_FIND_DATAW,
+    };
+    use windows_sys::Win32::System::SystemServices::IO_REPARSE_TAG_MOUNT_POINT;
+
+    let path = crate::preserve::wide(path);
+    let mut data = WIN32_FIND_DATAW::default();
+    // SAFETY: `path` is NUL-terminated and `data` is a valid find struct.
+    let handle = unsafe { FindFirstFileW(path.as_ptr(), &mut data) };
+    if handle == INVALID_HANDLE_VALUE {
+        return false;
+    }
+    // SAFETY: `handle` is the valid find handle opened above.
+    unsafe { FindClose(handle) };
+    // The reparse tag is reported in the reserved field
+    data.dwFileAttributes & FILE_ATTRIBUTE_REPARSE_POINT != 0
+        && data.dwReserved0 == IO_REPARSE_TAG_MOUNT_POINT
+}
+
+#[cfg(not(windows))]
+pub(crate) fn is_junction(_path: &Path) -> bool {
+    false
+}
+
+/// Whether the link at `path` leads to one of its own parents, so following
+/// it would never end.
+#[cfg(feature = "filter")]
+pub(crate) fn loops(path: &Path) -> bool {
+    let target = std::fs::canonicalize(path);
+    let parent = path.parent().map(std::fs::canonicalize);
+    match (target, parent) {
+        (Ok(target), Some(Ok(parent))) => parent.starts_with(target),
+        _ => true,
+    }
+}
+
+/// Create a junction at `link` pointing to the absolute path `target`.
+#[cfg(windows)]
+pub(crate) fn create(target: &Path, link: &Path) -> io::Result<()> {
+    std::fs::create_dir(link)?;
+    let result = mount_point(target, link);
+    if result.is_err() {
+        let _ = std::fs::remove_dir(link);
+    }
+    result
+}
+
+#[cfg(not(windows))]
+pub(crate) fn create(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "junctions only exist on Windows",
+    ))
+}
+
+/// Turn the empty directory `link` into a junction to `target`.
+#[cfg(windows)]
+fn mount_point(target: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT,
+    };
+    use windows_sys::Win32::System::Ioctl::FSCTL_SET_REPARSE_POINT;
+    use windows_sys::Win32::System::SystemServices::IO_REPARSE_TAG_MOUNT_POINT;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    // The target is stored as an NT path, `\??\C:\dir`, along with the
+    // plain `C:\dir` shown to users
+    let target = std::path::absolute(target)?;
+    let target: Vec<u16> = target.as_os_str().encode_wide().collect();
+    let verbatim: Vec<u16> = r"\\?\".encode_utf16().collect();
+    let unc: Vec<u16> = r"\\?\UNC\".encode_utf16().collect();
+    let print = target.strip_prefix(verbatim.as_slice()).unwrap_or(&target);
+    if target.starts_with(&unc) || print.starts_with(&[b'\\' as u16; 2]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "junctions can't point to network shares",
+        ));
+    }
+    let substitute: Vec<u16> = r"\??\"
+        .encode_utf16()
+        .chain(print.iter().copied())
+        .collect();
+
+    // A REPARSE_DATA_BUFFER holding a MountPointReparseBuffer: the tag and
+    // data length, then the name offsets and lengths in bytes, then both
+    // names, each NUL-terminated
+    let names: Vec<u16> = substitute
+        .iter()
+        .chain([&0])
+        .chain(print.iter())
+        .chain([&0])
+        .copied()
+        .collect();
+    let bytes = |len: usize| u16::try_from(len * 2).map_err(|_| io::ErrorKind::InvalidInput);
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes());
+    buffer.extend_from_slice(&bytes(names.len() + 4)?.to_le_bytes());
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    for field in [0, substitute.len(), substitute.len() + 1, print.len()] {
+        buffer.extend_from_slice(&bytes(field)?.to_le_bytes());
+    }
+    for unit in names {
+        buffer.extend_from_slice(&unit.to_le_bytes());
+    }
+
+    let dir = std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+        .open(link)?;
+    let mut returned = 0;
+    // SAFETY: `buffer` is a complete reparse data buffer of the given length,
+    // and `dir` stays open for the duration of the call.
+    let ok = unsafe {
+        DeviceIoControl(
+            dir.as_raw_handle(),
+            FSCTL_SET_REPARSE_POINT,
+            buffer.as_ptr().cast(),
+            buffer.len() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(windows)]
+    #[test]
+    fn test_junction() -> io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let target = temp_dir.path().join("target");
+        let link = temp_dir.path().join("target/link");
+        std::fs::create_dir(&target)?;
+        std::fs::write(target.join("a.txt"), "a")?;
+
+        create(&target, &link)?;
+        assert!(is_junction(&link));
+        assert!(!is_junction(&target));
+        assert_eq!(std::fs::read_to_string(link.join("a.txt"))?, "a");
+        // It points at its own parent
+        #[cfg(feature = "filter")]
+        assert!(loops(&link));
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_no_junctions() -> io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        assert!(!is_junction(temp_dir.path()));
+        assert!(create(temp_dir.path(), &temp_dir.path().join("link")).is_err());
+
+        // A symlink to a parent loops, one to a sibling doesn't
+        #[cfg(all(unix, feature = "filter"))]
+        {
+            std::fs::create_dir(temp_dir.path().join("dir"))?;
+            std::os::unix::fs::symlink("..", temp_dir.path().join("dir/up"))?;
+            std::os::unix::fs::symlink("dir", temp_dir.path().join("side"))?;
+            assert!(loops(&temp_dir.path().join("dir/up")));
+            assert!(!loops(&temp_dir.path().join("side")));
+        }
+        Ok(())
+    }
+}
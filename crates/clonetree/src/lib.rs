@@ -9,9 +9,35 @@
 //!
 //! - **Copy-on-Write Support**: Automatically uses reflinks when available on
 //!   supported filesystems (Btrfs, XFS, APFS, etc.)
-//! - **Glob Filtering**: Include or exclude files using glob patterns
-//! - **Efficient Traversal**: Built on the `ignore` crate for fast directory walking
+//! - **Glob Filtering**: Include or exclude files using glob patterns, with
+//!   the default `filter` feature
+//! - **Efficient Traversal**: Built on the `ignore` crate for fast directory
+//!   walking. Without the `filter` feature a plain recursive walker is used
+//!   instead, for a much smaller dependency tree. The `walkdir` and `jwalk`
+//!   features add alternative backends, chosen with `Options::walker`
 //! - **Type-Safe Errors**: Comprehensive error handling with descriptive error types
+//! - **Watch Mode**: With the `watch` feature, `watch_tree` keeps a clone up to
+//!   date as the source changes
+//! - **Profiles**: With the `profile` feature, `Options::from_file` loads
+//!   options from a TOML or JSON file
+//! - **Instrumentation**: With the `tracing` feature, clones emit a span per
+//!   directory and an event per file copied, reflinked, skipped, or failed.
+//!   The `log` feature emits the same events as `log` records
+//! - **Backends**: `clone_between` copies from any `TreeSource` to any
+//!   `TreeSink`, such as a directory, an archive or an in-memory tree
+//! - **Archives**: With the `archive` feature, `clone_to_archive` writes the
+//!   selected files into a `.tar`, `.tar.gz` or `.tar.zst` instead of a
+//!   directory, and `clone_from_archive` extracts one through the same filters.
+//!   The `zip` feature adds `clone_to_zip` for `.zip` archives
+//! - **Object stores**: With the `object-store` feature,
+//!   `clone_to_object_store` uploads a tree to S3, GCS or any other
+//!   [`object_store`](https://docs.rs/object_store) backend
+//! - **Manifests**: With the `manifest` feature, `snapshot` lists the files a
+//!   clone would copy, with sizes, modes, times and optional hashes, as JSON,
+//!   `Manifest::diff` compares two listings, and `clone_from_manifest` clones
+//!   exactly the files in one
+//! - **Trash**: With the `trash` feature, `Options::trash` sends files that a
+//!   clone deletes or replaces to the system trash, where they can be recovered
 //!
 //! # Example
 //!
@@ -45,10 +71,106 @@
 //!
 //! These constraints are validated before any filesystem operations begin.
 
-use ignore::{overrides::OverrideBuilder, WalkBuilder};
-use reflink_copy::reflink_or_copy;
+mod append;
+#[cfg(feature = "archive")]
+mod archive;
+mod backend;
+mod backup;
+mod cache;
+mod cancel;
+mod config;
+mod conflict;
+mod contain;
+mod dedup;
+#[cfg(target_os = "linux")]
+mod dedupe_range;
+mod diff;
+mod du;
+mod event;
+#[cfg(target_os = "linux")]
+mod fiemap;
+mod filter;
+mod handle;
+mod hash;
+#[cfg(target_os = "linux")]
+mod inode_flags;
+mod junction;
+mod longpath;
+#[cfg(feature = "manifest")]
+mod manifest;
+mod multi;
+#[cfg(feature = "object-store")]
+mod objstore;
+mod observer;
+mod parallel;
+mod pattern;
+mod plan;
+mod preserve;
+#[cfg(feature = "profile")]
+mod profile;
+mod progress;
+mod remove;
+mod report;
+mod sanitize;
+mod stream;
+mod trace;
+mod unicode;
+mod walk;
+mod warning;
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "zip")]
+mod zipfile;
+
+#[cfg(feature = "archive")]
+pub use archive::{clone_from_archive, clone_to_archive, Compression, TarSink, TarSource};
+pub use backend::{
+    clone_between, DirSink, DirSource, FileInfo, FileKind, MemoryTree, TreeSink, TreeSource,
+    Visitor,
+};
+pub use backup::Backup;
+pub use cancel::CancelToken;
+pub use config::CloneConfig;
+pub use conflict::{Conflict, Resolution};
+pub use dedup::{dedup_tree, find_duplicates, DedupOptions, DedupStats, DuplicateGroup};
+pub use diff::{diff_trees, Compare, TreeDiff};
+pub use du::{disk_usage, DiskUsage};
+pub use event::Event;
+pub use handle::CloneTree;
+pub use hash::{Blake3, Crc32c, Digest, Hasher, Sha256, XxHash64};
+pub use junction::{DirLinks, Junctions};
+pub use longpath::LongPaths;
+#[cfg(feature = "manifest")]
+pub use manifest::{clone_from_manifest, snapshot, Manifest, ManifestDiff, ManifestEntry};
+pub use multi::{clone_targets, clone_trees};
+#[cfg(feature = "object-store")]
+pub use objstore::{clone_to_object_store, ObjectStoreSink};
+pub use observer::CloneObserver;
+pub use pattern::Pattern;
+pub use plan::{plan_clone, Operation};
+pub use preserve::Preserve;
+pub use progress::{Outcome, Progress};
+pub use remove::{remove_tree, RemoveOptions, RemoveStats};
+pub use report::{
+    CloneReport, DetailedReport, FileOutcome, LargeFile, RecordedWarning, SkipReason, SkippedEntry,
+    Tally,
+};
+pub use sanitize::{Sanitize, Sanitized};
+pub use unicode::Normalization;
+pub use walk::{Submodules, WalkerBackend};
+pub use warning::{Warning, WarningKind};
+#[cfg(feature = "watch")]
+pub use watch::{watch_tree, TreeWatcher};
+#[cfg(feature = "zip")]
+pub use zipfile::{clone_to_zip, ZipSink};
+
+use filter::Globs;
+use std::borrow::Cow;
 use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use stream::{Copied, CopySettings};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -71,32 +193,249 @@ pub enum Error {
         source: std::io::Error,
     },
 
+    #[error("Failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to lock {path}: {source}")]
+    Lock {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error("Invalid glob pattern '{pattern}': {source}")]
     InvalidGlob {
         pattern: String,
         #[source]
-        source: ignore::Error,
+        source: Box<dyn std::error::Error + Send + Sync>,
     },
 
     #[error("Destination already exists: {path}")]
     DestinationExists { path: PathBuf },
 
+    #[error("File already exists: {path}")]
+    FileExists { path: PathBuf },
+
     #[error("Source is not a directory: {path}")]
     SourceNotDirectory { path: PathBuf },
 
     #[error("Source does not exist: {path}")]
     SourceNotFound { path: PathBuf },
 
+    #[error("Source {src} is inside the destination {dest}, which replacing would delete")]
+    SourceInDestination { src: PathBuf, dest: PathBuf },
+
+    #[error("Two sources are named {name}, so their clones would collide")]
+    SourceCollision { name: PathBuf },
+
+    #[error("Refusing to write through {path}, which leads outside the destination")]
+    OutsideDestination { path: PathBuf },
+
+    #[error("Two source files map to {path} once their names are normalized or sanitized")]
+    NameCollision { path: PathBuf },
+
+    #[error("Destination path for {path} is longer than the platform allows")]
+    PathTooLong { path: PathBuf },
+
+    #[error("Destination is locked by another clone: {path}")]
+    DestinationLocked { path: PathBuf },
+
+    #[error("Insufficient space at {path}: {required} bytes required, {available} available")]
+    InsufficientSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+
+    #[error("Refusing to remove {path}: {reason}")]
+    RemoveRefused { path: PathBuf, reason: String },
+
+    #[error("Copy of {src} at {dest} does not match the source")]
+    VerificationFailed { src: PathBuf, dest: PathBuf },
+
+    #[error("Invalid options: {message}")]
+    InvalidOptions { message: String },
+
+    #[error("Invalid manifest: {message}")]
+    InvalidManifest { message: String },
+
+    #[error("Source file {path} does not match the manifest")]
+    ManifestMismatch { path: PathBuf },
+
+    #[error("Invalid profile {path}: {message}")]
+    Profile { path: PathBuf, message: String },
+
+    #[error("Clone incomplete: {} files failed", .errors.len())]
+    Incomplete { errors: Vec<Error> },
+
+    #[error("Clone cancelled")]
+    Cancelled,
+
+    #[error("Clone did not finish within {timeout:?}")]
+    Timeout { timeout: Duration },
+
+    #[error("Clone stopped at its limit of {limit} {quota}")]
+    QuotaExceeded { quota: Quota, limit: u64 },
+
     #[error("Operation error: {0}")]
     Other(String),
 }
 
+impl Error {
+    /// The file or directory the error is about, if it names one: for a
+    /// failed copy, the source file.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::CreateDirectory { path, .. }
+            | Error::Read { path, .. }
+            | Error::Lock { path, .. }
+            | Error::DestinationExists { path }
+            | Error::FileExists { path }
+            | Error::SourceNotDirectory { path }
+            | Error::SourceNotFound { path }
+            | Error::OutsideDestination { path }
+            | Error::NameCollision { path }
+            | Error::PathTooLong { path }
+            | Error::DestinationLocked { path }
+            | Error::InsufficientSpace { path, .. }
+            | Error::RemoveRefused { path, .. }
+            | Error::ManifestMismatch { path }
+            | Error::Profile { path, .. } => Some(path),
+            Error::Copy { src, .. }
+            | Error::SourceInDestination { src, .. }
+            | Error::VerificationFailed { src, .. } => Some(src),
+            Error::SourceCollision { name } => Some(name),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Default)]
+/// Which limit an [`Error::QuotaExceeded`] clone ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// [`Options::max_files`].
+    Files,
+    /// [`Options::max_total_bytes`].
+    Bytes,
+}
+
+impl std::fmt::Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Quota::Files => "files",
+            Quota::Bytes => "bytes",
+        })
+    }
+}
+
+/// How file data is copied.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Reflink {
+    /// Reflink when the filesystem supports it, and fall back to a regular
+    /// copy otherwise.
+    #[default]
+    Auto,
+    /// Always reflink, and fail if the filesystem does not support it.
+    Always,
+    /// Never reflink; always copy the data.
+    Never,
+}
+
+/// What to do when the destination already exists, set with
+/// [`Options::overwrite`]. A `bool` converts to [`Never`](Self::Never) or
+/// [`Merge`](Self::Merge).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Fail with [`Error::DestinationExists`].
+    #[default]
+    Never,
+    /// Clone into the existing destination, replacing files that are in the
+    /// way and leaving everything else alone. On filesystems that report
+    /// extents, a file that is still a reflink of its source is skipped.
+    Merge,
+    /// Delete everything in the destination first, so it ends up an exact
+    /// copy of the source. The destination directory itself is kept, and a
+    /// source inside it is refused with [`Error::SourceInDestination`].
+    Replace,
+    /// Clone into the existing destination, but never touch a file that is
+    /// already there: each one fails with [`Error::FileExists`], or is
+    /// collected into [`Error::Incomplete`] with
+    /// [`keep_going`](Options::keep_going).
+    NoClobber,
+}
+
+impl From<bool> for Overwrite {
+    fn from(overwrite: bool) -> Self {
+        if overwrite {
+            Overwrite::Merge
+        } else {
+            Overwrite::Never
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Options {
     globs: Vec<String>,
-    overwrite: bool,
+    overwrite: Overwrite,
+    backup: Backup,
+    #[cfg(feature = "trash")]
+    trash: bool,
+    sorted: bool,
+    walker: WalkerBackend,
+    gitignore: bool,
+    git_global: bool,
+    git_exclude: bool,
+    submodules: Submodules,
+    share_git_objects: bool,
+    check_space: bool,
+    compare: Compare,
+    update: bool,
+    delete: bool,
+    prune_empty_dirs: bool,
+    delete_excluded: bool,
+    preserve: Preserve,
+    symlinks: bool,
+    junctions: Junctions,
+    dir_links: DirLinks,
+    dedup_source: bool,
+    state_cache: bool,
+    lock_source: bool,
+    reflink: Reflink,
+    threads: usize,
+    keep_going: bool,
+    verify: bool,
+    append: bool,
+    chunk_size: Option<usize>,
+    record_skipped: bool,
+    record_breakdown: bool,
+    record_largest: usize,
+    count_first: bool,
+    files: Option<Vec<PathBuf>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    max_files: Option<u64>,
+    max_total_bytes: Option<u64>,
+    timeout: Option<Duration>,
+    file_timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+    normalization: Normalization,
+    sanitize: Sanitize,
+    sanitized: Option<sanitize::SanitizeFn>,
+    long_paths: LongPaths,
+    warning: Option<warning::WarningFn>,
+    observers: observer::Observers,
+    hasher: Option<hash::HasherRef>,
+    progress: Option<progress::ProgressFn>,
+    conflict: Option<conflict::ConflictFn>,
 }
 
 impl Options {
@@ -104,300 +443,3870 @@ impl Options {
         Self::default()
     }
 
+    /// Add a glob pattern selecting which files are cloned; prefix it with
+    /// `!` to exclude instead. A bad pattern fails the clone with
+    /// [`Error::InvalidGlob`]; pass a [`Pattern`] to check it up front.
     pub fn glob<S: Into<String>>(mut self, pattern: S) -> Self {
         self.globs.push(pattern.into());
         self
     }
 
-    pub fn overwrite(mut self, overwrite: bool) -> Self {
-        self.overwrite = overwrite;
+    /// Allow cloning into a destination that already exists, merging into
+    /// it with `true` or [`Overwrite::Merge`], emptying it first with
+    /// [`Overwrite::Replace`], or adding to it without replacing anything
+    /// with [`Overwrite::NoClobber`].
+    pub fn overwrite<O: Into<Overwrite>>(mut self, overwrite: O) -> Self {
+        self.overwrite = overwrite.into();
         self
     }
-}
-
-pub fn clone_tree<P: AsRef<Path>, Q: AsRef<Path>>(
-    src: P,
-    dest: Q,
-    options: &Options,
-) -> Result<()> {
-    let src = src.as_ref();
-    let dest = dest.as_ref();
 
-    // Validate source exists
-    if !src.exists() {
-        return Err(Error::SourceNotFound {
-            path: src.to_path_buf(),
-        });
+    /// Keep the destination files a clone replaces, as described on
+    /// [`Backup`], instead of removing them. Backups are ordinary files in
+    /// the destination, so [`delete`](Self::delete) and
+    /// [`Overwrite::Replace`] remove them like any other.
+    pub fn backup(mut self, backup: Backup) -> Self {
+        self.backup = backup;
+        self
     }
 
-    // Validate source is a directory
-    if !src.is_dir() {
-        return Err(Error::SourceNotDirectory {
-            path: src.to_path_buf(),
-        });
+    /// Send the files a clone deletes or replaces to the system trash instead
+    /// of removing them, so they can be recovered: extraneous files with
+    /// [`delete`](Self::delete), the old contents with
+    /// [`Overwrite::Replace`], and overwritten files unless
+    /// [`backup`](Self::backup) keeps them.
+    #[cfg(feature = "trash")]
+    pub fn trash(mut self, trash: bool) -> Self {
+        self.trash = trash;
+        self
     }
 
-    // Validate destination
-    if dest.exists() && !options.overwrite {
-        return Err(Error::DestinationExists {
-            path: dest.to_path_buf(),
-        });
+    /// When overwriting a file whose source has only grown since it was
+    /// copied, as log files do, append the new bytes instead of copying the
+    /// whole file again. The existing bytes are read back to check that they
+    /// match the start of the source; a file that changed in any other way is
+    /// replaced as usual. Not used with [`backup`](Self::backup), which needs
+    /// the old file kept intact.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
     }
 
-    // Create destination directory if it doesn't exist
-    if !dest.exists() {
-        std::fs::create_dir_all(dest).map_err(|source| Error::CreateDirectory {
-            path: dest.to_path_buf(),
-            source,
-        })?;
+    /// Read each copied file back and compare its hash with the source's,
+    /// failing with [`Error::VerificationFailed`] on a mismatch. This reads
+    /// every file twice more, so for large clones pick a fast
+    /// [`hasher`](Self::hasher) such as [`XxHash64`] or [`Crc32c`].
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
     }
 
-    // Track created directories to avoid redundant create_dir_all calls
-    let mut created_dirs = HashSet::new();
-    created_dirs.insert(dest.to_path_buf());
-
-    // Build walker with standard filters disabled
-    let mut builder = WalkBuilder::new(src);
-    builder.standard_filters(false);
+    /// Copy file data that isn't reflinked in chunks of `bytes`, 1 MiB by
+    /// default. Larger chunks mean fewer system calls, at the cost of that
+    /// much memory per thread.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = Some(bytes);
+        self
+    }
 
-    // Add glob patterns using overrides
-    if !options.globs.is_empty() {
-        let mut overrides = OverrideBuilder::new(src);
-        for pattern in &options.globs {
-            overrides
-                .add(pattern)
-                .map_err(|source| Error::InvalidGlob {
-                    pattern: pattern.clone(),
-                    source,
-                })?;
-        }
-        builder.overrides(
-            overrides
-                .build()
-                .map_err(|e| Error::Other(format!("Failed to build glob overrides: {e}")))?,
-        );
+    /// Hash file contents with `hasher` wherever a clone compares or records
+    /// hashes, instead of [`Blake3`]. See [`Hasher`] for the trade-offs.
+    pub fn hasher<H: Hasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Some(hash::HasherRef(std::sync::Arc::new(hasher)));
+        self
     }
 
-    // Walk the source directory
-    for entry in builder.build() {
-        let entry = entry.map_err(|source| Error::Other(format!("Walk error: {source}")))?;
-        let path = entry.path();
-
-        // Skip the root directory itself
-        if path == src {
-            continue;
-        }
-
-        // Calculate relative path and destination path
-        let relative_path = path
-            .strip_prefix(src)
-            .map_err(|e| Error::Other(format!("Failed to strip prefix from path: {e}")))?;
-        let dest_path = dest.join(relative_path);
-
-        // Only process files
-        if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-            // Create parent directories if needed
-            if let Some(parent) = dest_path.parent() {
-                // Only create directory if we haven't created it before
-                if !created_dirs.contains(parent) {
-                    std::fs::create_dir_all(parent).map_err(|source| Error::CreateDirectory {
-                        path: parent.to_path_buf(),
-                        source,
-                    })?;
-                    created_dirs.insert(parent.to_path_buf());
-                }
-            }
+    /// Process entries in lexicographic order by file name, so that repeated
+    /// runs over the same tree visit files in the same order.
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
 
-            // If overwrite is enabled and the destination exists, remove it first
-            if options.overwrite && dest_path.exists() {
-                std::fs::remove_file(&dest_path).map_err(Error::Io)?;
-            }
+    /// Choose how the source tree is walked. The default,
+    /// [`WalkerBackend::Standard`], suits almost every tree.
+    pub fn walker(mut self, walker: WalkerBackend) -> Self {
+        self.walker = walker;
+        self
+    }
 
-            // Copy file using reflink when available
-            reflink_or_copy(path, &dest_path).map_err(|source| Error::Copy {
-                src: path.to_path_buf(),
-                dest: dest_path.clone(),
-                source,
-            })?;
-        }
+    /// Leave out files that the `.gitignore` files of a git repository
+    /// ignore, including those in the source's parent directories. They are
+    /// left out silently, not listed in
+    /// [`CloneReport::skipped_entries`]. Files named with
+    /// [`files`](Self::files) are not checked. Needs the `filter` feature
+    /// and [`WalkerBackend::Standard`].
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
     }
 
-    Ok(())
-}
+    /// With [`gitignore`](Self::gitignore), also honor the user's global
+    /// gitignore: git's `core.excludesFile`, or `$XDG_CONFIG_HOME/git/ignore`
+    /// if that isn't set.
+    pub fn git_global(mut self, git_global: bool) -> Self {
+        self.git_global = git_global;
+        self
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::TempDir;
+    /// With [`gitignore`](Self::gitignore), also honor the repository's
+    /// `$GIT_DIR/info/exclude`, so the files left out match what
+    /// `git status` shows as ignored.
+    pub fn git_exclude(mut self, git_exclude: bool) -> Self {
+        self.git_exclude = git_exclude;
+        self
+    }
 
-    #[test]
-    fn test_clone_tree_basic() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let src = temp_dir.path().join("src");
-        let dest = temp_dir.path().join("dest");
+    /// Choose how git submodules in the source are cloned: walked like any
+    /// other directory (the default), skipped, or cloned separately with
+    /// their own ignore files. Needs the `filter` feature and
+    /// [`WalkerBackend::Standard`].
+    pub fn submodules(mut self, submodules: Submodules) -> Self {
+        self.submodules = submodules;
+        self
+    }
 
-        // Create source structure
-        fs::create_dir_all(&src)?;
-        fs::write(src.join("file1.txt"), "content1")?;
-        fs::create_dir(src.join("subdir"))?;
-        fs::write(src.join("subdir/file2.txt"), "content2")?;
+    /// When the source is a git repository, clone every file in
+    /// `.git/objects` whatever the globs and other filters say, hardlinking
+    /// each one, as `git clone --local` does. Objects are never modified in
+    /// place, so sharing them is safe, and snapshots of a repository cost
+    /// almost nothing. Objects that can't be hardlinked, such as across
+    /// filesystems, are reflinked or copied as usual. Hardlinked objects are
+    /// left exactly as they are, so the preserve options and
+    /// [`verify`](Self::verify) don't apply to them.
+    pub fn share_git_objects(mut self, share_git_objects: bool) -> Self {
+        self.share_git_objects = share_git_objects;
+        self
+    }
 
-        // Clone the tree
-        let opts = Options::new();
-        clone_tree(&src, &dest, &opts)?;
+    /// Before copying, check that the destination filesystem has room for the
+    /// filtered tree. When reflinks work between source and destination the
+    /// clone needs no extra data space, and the check always passes.
+    pub fn check_space(mut self, check_space: bool) -> Self {
+        self.check_space = check_space;
+        self
+    }
 
-        // Verify structure
-        assert!(dest.join("file1.txt").exists());
-        assert!(dest.join("subdir/file2.txt").exists());
-        assert_eq!(fs::read_to_string(dest.join("file1.txt"))?, "content1");
-        assert_eq!(
-            fs::read_to_string(dest.join("subdir/file2.txt"))?,
-            "content2"
-        );
+    /// Set how files present on both sides are compared when looking for
+    /// changes, for example by [`diff_trees`].
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.compare = compare;
+        self
+    }
 
-        Ok(())
+    /// When overwriting, skip files whose destination copy is unchanged
+    /// according to the [`Compare`] mode, copying only new or changed files.
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
     }
 
-    #[test]
-    fn test_clone_tree_with_excludes() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let src = temp_dir.path().join("src");
-        let dest = temp_dir.path().join("dest");
+    /// When overwriting, delete files in the destination that are not in the
+    /// source, so the destination mirrors the source. Only files that pass the
+    /// glob filters are considered, so excluded files in the destination are
+    /// left alone unless [`delete_excluded`](Self::delete_excluded) is set.
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
 
-        // Create source structure
-        fs::create_dir_all(&src)?;
-        fs::write(src.join("file.txt"), "keep")?;
-        fs::create_dir(src.join("target"))?;
-        fs::write(src.join("target/build.out"), "exclude")?;
-        fs::create_dir(src.join(".git"))?;
-        fs::write(src.join(".git/config"), "exclude")?;
+    /// With [`delete`](Self::delete), also remove destination directories
+    /// left empty by the deletions, even where the source still has the
+    /// directory but the filters exclude everything in it. Directories are
+    /// only ever created to hold cloned files, so a fresh clone never
+    /// contains filtered-out directories either way.
+    pub fn prune_empty_dirs(mut self, prune: bool) -> Self {
+        self.prune_empty_dirs = prune;
+        self
+    }
 
-        // Clone with exclude globs (! prefix excludes)
-        let opts = Options::new().glob("!target/**").glob("!.git/**");
-        clone_tree(&src, &dest, &opts)?;
+    /// With [`delete`](Self::delete), also delete destination files that the
+    /// filters exclude, like rsync's `--delete-excluded`. Globs, size and time
+    /// filters and [`files`](Self::files) then only pick what is copied, and
+    /// the destination ends up holding nothing else.
+    pub fn delete_excluded(mut self, delete_excluded: bool) -> Self {
+        self.delete_excluded = delete_excluded;
+        self
+    }
 
-        // Verify excludes worked
-        assert!(dest.join("file.txt").exists());
-        assert!(!dest.join("target").exists());
-        assert!(!dest.join(".git").exists());
+    /// Choose all the metadata clones keep from their sources at once,
+    /// replacing what was chosen before. The `preserve_*` methods each turn
+    /// one of these on or off.
+    pub fn preserve(mut self, preserve: Preserve) -> Self {
+        self.preserve = preserve;
+        self
+    }
 
-        Ok(())
+    /// Set the modification time of each copied file to match its source,
+    /// to the nanosecond, or as finely as the destination filesystem allows
+    /// (100ns on NTFS). Update mode compares times exactly, so it relies on
+    /// this to recognize unchanged files.
+    pub fn preserve_times(mut self, preserve_times: bool) -> Self {
+        self.preserve.set(Preserve::TIMES, preserve_times);
+        self
     }
 
-    #[test]
-    fn test_clone_tree_with_positive_globs() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let src = temp_dir.path().join("src");
-        let dest = temp_dir.path().join("dest");
+    /// Set the creation time of each copied file to match its source. Only
+    /// macOS and Windows let it be set; Linux reports birth times through
+    /// `statx` but has no way to change them, so there this does nothing.
+    pub fn preserve_birth_time(mut self, preserve_birth_time: bool) -> Self {
+        self.preserve.set(Preserve::BIRTH_TIME, preserve_birth_time);
+        self
+    }
 
-        // Create source structure
-        fs::create_dir_all(&src)?;
-        fs::write(src.join("include1.txt"), "include")?;
-        fs::write(src.join("include2.txt"), "include")?;
-        fs::write(src.join("exclude.log"), "exclude")?;
-        fs::create_dir(src.join("data"))?;
-        fs::write(src.join("data/file.txt"), "include")?;
-        fs::write(src.join("data/debug.log"), "exclude")?;
+    /// Set the permission bits of each copied file to match its source,
+    /// apart from the setuid, setgid and sticky bits, which need
+    /// [`preserve_special_bits`](Self::preserve_special_bits). On Windows
+    /// this copies the read-only, hidden, system and archive attributes
+    /// instead.
+    pub fn preserve_permissions(mut self, preserve_permissions: bool) -> Self {
+        self.preserve.set(Preserve::MODE, preserve_permissions);
+        self
+    }
 
-        // Clone with positive globs (only include .txt files)
-        let opts = Options::new().glob("**/*.txt");
-        clone_tree(&src, &dest, &opts)?;
+    /// With [`preserve_permissions`](Self::preserve_permissions), also keep
+    /// the setuid, setgid and sticky bits. A cloned setuid binary runs with
+    /// its owner's privileges, so this is left to the caller to decide;
+    /// without it, copies never carry these bits. Has no effect on Windows.
+    pub fn preserve_special_bits(mut self, preserve_special_bits: bool) -> Self {
+        self.preserve
+            .set(Preserve::SPECIAL_BITS, preserve_special_bits);
+        self
+    }
 
-        // Verify only .txt files were included
-        assert!(dest.join("include1.txt").exists());
-        assert!(dest.join("include2.txt").exists());
-        assert!(dest.join("data/file.txt").exists());
-        assert!(!dest.join("exclude.log").exists());
-        assert!(!dest.join("data/debug.log").exists());
+    /// Set the owner and group of each copied file to match its source. Only
+    /// root can give files to other users, so for everyone else this is a
+    /// no-op. Has no effect on Windows.
+    pub fn preserve_ownership(mut self, preserve_ownership: bool) -> Self {
+        self.preserve.set(Preserve::OWNER, preserve_ownership);
+        self
+    }
 
-        Ok(())
+    /// Copy the extended attributes of each file. Attributes the destination
+    /// filesystem or the current user can't set are skipped. Has no effect on
+    /// Windows.
+    pub fn preserve_xattrs(mut self, preserve_xattrs: bool) -> Self {
+        self.preserve.set(Preserve::XATTR, preserve_xattrs);
+        self
     }
 
-    #[test]
-    fn test_source_not_found() {
-        let temp_dir = TempDir::new().unwrap();
-        let src = temp_dir.path().join("nonexistent");
-        let dest = temp_dir.path().join("dest");
+    /// Copy the NTFS access control list of each file, so clones keep who
+    /// may read and change them. Entries the source inherits from its
+    /// parent directory are inherited from the destination's parent instead.
+    /// Lists the current user can't read or set are skipped with a warning.
+    /// Has no effect outside Windows; POSIX ACLs are extended attributes, so
+    /// [`preserve_xattrs`](Self::preserve_xattrs) copies those.
+    pub fn preserve_acls(mut self, preserve_acls: bool) -> Self {
+        self.preserve.set(Preserve::ACL, preserve_acls);
+        self
+    }
 
-        let opts = Options::new();
-        let result = clone_tree(&src, &dest, &opts);
+    /// Copy the named NTFS data streams of each file, such as the
+    /// `Zone.Identifier` stream that marks downloads. Reflinks and streamed
+    /// copies only carry the main contents, so without this the streams are
+    /// dropped. A destination that can't hold them gets a warning. Has no
+    /// effect outside Windows.
+    pub fn preserve_streams(mut self, preserve_streams: bool) -> Self {
+        self.preserve.set(Preserve::STREAMS, preserve_streams);
+        self
+    }
 
-        assert!(matches!(result, Err(Error::SourceNotFound { .. })));
+    /// Copy Linux file capabilities, the `security.capability` attribute,
+    /// so that binaries such as `ping` keep working in the clone. The kernel
+    /// drops capabilities when a file's owner changes, so they are set after
+    /// everything else, whether or not [`preserve_xattrs`](Self::preserve_xattrs)
+    /// is on. Setting them needs `CAP_SETFCAP`; without it they are skipped
+    /// with a warning.
+    pub fn preserve_capabilities(mut self, preserve_capabilities: bool) -> Self {
+        self.preserve
+            .set(Preserve::CAPABILITIES, preserve_capabilities);
+        self
     }
 
-    #[test]
-    fn test_source_not_directory() {
-        let temp_dir = TempDir::new().unwrap();
-        let src = temp_dir.path().join("file.txt");
-        let dest = temp_dir.path().join("dest");
+    /// Copy the Linux inode flags of each file that `chattr` sets, such as
+    /// immutable, append-only, `nodump` and `nocow`. A `nocow` file is
+    /// created with the flag before its data is written, since btrfs ignores
+    /// it on a file that isn't empty, so it is copied rather than reflinked
+    /// unless [`Reflink::Always`] is set. Immutable and append-only files
+    /// can't be overwritten by a later clone until the flags are cleared.
+    /// Flags the destination or the current user can't set are skipped with
+    /// a warning. Has no effect outside Linux.
+    pub fn preserve_inode_flags(mut self, preserve_inode_flags: bool) -> Self {
+        self.preserve
+            .set(Preserve::INODE_FLAGS, preserve_inode_flags);
+        self
+    }
 
-        // Create source as a file, not a directory
-        fs::write(&src, "content").unwrap();
+    /// Recreate hardlinks between source files: the first path of each
+    /// linked file is copied, and the others are hardlinked to that copy
+    /// rather than copied again. Files linked to something outside the
+    /// source are copied as usual. Has no effect on Windows.
+    pub fn preserve_hardlinks(mut self, preserve_hardlinks: bool) -> Self {
+        self.preserve.set(Preserve::HARDLINKS, preserve_hardlinks);
+        self
+    }
 
-        let opts = Options::new();
-        let result = clone_tree(&src, &dest, &opts);
+    /// Recreate symbolic links in the destination, pointing at the same
+    /// target. By default symlinks are skipped. Links are never followed.
+    pub fn symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
 
-        assert!(matches!(result, Err(Error::SourceNotDirectory { .. })));
+    /// Choose how NTFS junctions are cloned: like directory symlinks (the
+    /// default), recreated as junctions, followed, or skipped. Only Windows
+    /// has junctions, so elsewhere this changes nothing.
+    pub fn junctions(mut self, junctions: Junctions) -> Self {
+        self.junctions = junctions;
+        self
     }
 
-    #[test]
-    fn test_destination_exists() {
-        let temp_dir = TempDir::new().unwrap();
-        let src = temp_dir.path().join("src");
-        let dest = temp_dir.path().join("dest");
+    /// Choose how symlinks to directories are recreated on Windows: as
+    /// directory symlinks (the default), which need administrator rights or
+    /// Developer Mode, as junctions, or as symlinks with a fallback to
+    /// junctions when the privilege is missing. Elsewhere links are always
+    /// symlinks.
+    pub fn dir_links(mut self, dir_links: DirLinks) -> Self {
+        self.dir_links = dir_links;
+        self
+    }
 
-        // Create both source and destination directories
-        fs::create_dir_all(&src).unwrap();
-        fs::create_dir_all(&dest).unwrap();
+    /// Copy the data of identical source files only once. Later copies are
+    /// reflinked from the first destination file, which saves space even when
+    /// the source is on a different filesystem. Candidate files are hashed, so
+    /// this costs extra reads of the source.
+    pub fn dedup_source(mut self, dedup_source: bool) -> Self {
+        self.dedup_source = dedup_source;
+        self
+    }
 
-        let opts = Options::new();
-        let result = clone_tree(&src, &dest, &opts);
+    /// In update mode, keep a cache of file sizes, modification times and
+    /// hashes in a hidden file beside the destination. Repeated runs then skip
+    /// files whose source and destination are unchanged since the last run
+    /// without reading them, which makes frequent syncs of large trees cheap
+    /// even with [`Compare::Hash`].
+    pub fn state_cache(mut self, state_cache: bool) -> Self {
+        self.state_cache = state_cache;
+        self
+    }
 
-        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+    /// Hold a shared advisory lock (`flock` on Unix, `LockFileEx` on Windows)
+    /// on each source file while it is copied. Writers that take an exclusive
+    /// lock before modifying files will never be copied mid-write. The lock is
+    /// advisory, so writers that don't lock are not held off.
+    pub fn lock_source(mut self, lock_source: bool) -> Self {
+        self.lock_source = lock_source;
+        self
+    }
+
+    /// Set whether file data is reflinked or copied.
+    pub fn reflink(mut self, reflink: Reflink) -> Self {
+        self.reflink = reflink;
+        self
+    }
+
+    /// Clone files on `threads` worker threads while the calling thread walks
+    /// the source. With 0 or 1, the default, files are cloned one at a time on
+    /// the calling thread. With several threads, files finish in no particular
+    /// order, even when [`sorted`](Self::sorted) is set.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Select only the listed files, given relative to the source root,
+    /// instead of walking the whole tree. Glob filters still apply on top.
+    /// Listed paths that don't exist, or aren't files, are skipped; paths
+    /// that would escape the source root are reported as errors.
+    pub fn files<I, P>(mut self, files: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.files = Some(files.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Select only files of at least `bytes` bytes.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    /// Select only files of at most `bytes` bytes.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Select only files modified after `time`.
+    pub fn newer_than(mut self, time: SystemTime) -> Self {
+        self.newer_than = Some(time);
+        self
+    }
+
+    /// Select only files last modified before `time`.
+    pub fn older_than(mut self, time: SystemTime) -> Self {
+        self.older_than = Some(time);
+        self
+    }
+
+    /// Stop with [`Error::QuotaExceeded`] rather than process more than
+    /// `count` files. Files cloned before the limit was hit stay in place and
+    /// have been reported to [`on_progress`](Self::on_progress), so callers
+    /// can show what was done. [`keep_going`](Self::keep_going) does not
+    /// continue past a quota.
+    pub fn max_files(mut self, count: u64) -> Self {
+        self.max_files = Some(count);
+        self
+    }
+
+    /// Stop with [`Error::QuotaExceeded`] rather than process a file that
+    /// would take the total size of the clone past `bytes`, as for
+    /// [`max_files`](Self::max_files).
+    pub fn max_total_bytes(mut self, bytes: u64) -> Self {
+        self.max_total_bytes = Some(bytes);
+        self
+    }
+
+    /// Stop with [`Error::Timeout`] once the clone has run for `timeout`.
+    /// The deadline is checked before each file, so a file already being
+    /// copied is allowed to finish; pair this with
+    /// [`file_timeout`](Self::file_timeout) to bound a single stuck file.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop with [`Error::Cancelled`] once `token` is cancelled, for example
+    /// from a Ctrl-C handler. See [`CancelToken`].
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Give up on a file whose data takes longer than `timeout` to copy, such
+    /// as one stuck on an unresponsive network filesystem. The file fails
+    /// with a timed-out [`Error::Copy`], which [`keep_going`](Self::keep_going)
+    /// can carry on past. A copy blocked in the kernel can't be interrupted,
+    /// so it is left running in the background and may still complete later.
+    /// Only directory clones honour this; other backends stream file data
+    /// themselves.
+    pub fn file_timeout(mut self, timeout: Duration) -> Self {
+        self.file_timeout = Some(timeout);
+        self
+    }
+
+    /// Normalize the Unicode in destination file names to `form`, so trees
+    /// moving between macOS and Linux don't end up with two encodings of the
+    /// same name. Source files whose names collide once normalized fail with
+    /// [`Error::NameCollision`].
+    pub fn normalize_unicode(mut self, form: Normalization) -> Self {
+        self.normalization = form;
+        self
+    }
+
+    /// Rewrite or skip destination names that Windows can't store, such as
+    /// `a:b` or `aux.txt`, as described on [`Sanitize`]. Like normalization,
+    /// two source names that sanitize to the same name fail with
+    /// [`Error::NameCollision`].
+    pub fn sanitize_names(mut self, sanitize: Sanitize) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// Choose what happens to files whose destination path is longer than
+    /// the platform allows, as described on [`LongPaths`]. They fail with
+    /// [`Error::PathTooLong`] by default, before anything is written.
+    pub fn long_paths(mut self, long_paths: LongPaths) -> Self {
+        self.long_paths = long_paths;
+        self
+    }
+
+    /// Keep going when a file can't be read or copied, instead of stopping at
+    /// the first failure. The clone then fails at the end with
+    /// [`Error::Incomplete`], which holds every error encountered.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// List every entry left out of the clone, with the reason, in
+    /// [`CloneReport::skipped_entries`]. Off by default, since a filtered
+    /// clone of a large tree can skip far more than it copies.
+    pub fn record_skipped(mut self, record_skipped: bool) -> Self {
+        self.record_skipped = record_skipped;
+        self
+    }
+
+    /// Break down the files processed by top-level directory and by
+    /// extension, in [`CloneReport::by_directory`] and
+    /// [`CloneReport::by_extension`], to see what dominates a tree.
+    pub fn record_breakdown(mut self, record_breakdown: bool) -> Self {
+        self.record_breakdown = record_breakdown;
+        self
+    }
+
+    /// List the `count` largest files processed in
+    /// [`CloneReport::largest_files`], as candidates for exclude globs.
+    pub fn record_largest(mut self, count: usize) -> Self {
+        self.record_largest = count;
+        self
+    }
+
+    /// Call `callback` after each file is processed, with running totals.
+    /// Combine with [`count_first`](Self::count_first) to show progress
+    /// against the full tree.
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(progress::ProgressFn(std::sync::Arc::new(callback)));
+        self
+    }
+
+    /// Walk the source once to count the selected files and bytes before
+    /// cloning anything, so that [`Progress`] carries the totals and can be
+    /// shown as a percentage. Off by default, since the extra walk makes the
+    /// clone slower.
+    pub fn count_first(mut self, count_first: bool) -> Self {
+        self.count_first = count_first;
+        self
+    }
+
+    /// When overwriting, call `callback` before replacing each existing
+    /// destination file, and overwrite, skip or abort as it decides. Without
+    /// a callback every conflicting file is overwritten. With several
+    /// [`threads`](Self::threads) the callback may be called concurrently.
+    pub fn on_conflict<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Conflict) -> Resolution + Send + Sync + 'static,
+    {
+        self.conflict = Some(conflict::ConflictFn(std::sync::Arc::new(callback)));
+        self
+    }
+
+    /// Call `callback` for each file that [`sanitize_names`](Self::sanitize_names)
+    /// renames or skips, so the changes can be reported.
+    pub fn on_sanitize<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Sanitized) + Send + Sync + 'static,
+    {
+        self.sanitized = Some(sanitize::SanitizeFn(std::sync::Arc::new(callback)));
+        self
+    }
+
+    /// Call `callback` for each problem the clone works around rather than
+    /// failing on: extended attributes, ownership or times that couldn't be
+    /// set, and special files left out. Without a callback these are only
+    /// logged. With several [`threads`](Self::threads) the callback may be
+    /// called concurrently.
+    pub fn on_warning<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Warning) + Send + Sync + 'static,
+    {
+        self.warning = Some(warning::WarningFn(std::sync::Arc::new(callback)));
+        self
+    }
+
+    /// Report directories, files, errors and warnings to `observer`, as
+    /// described on [`CloneObserver`]. This is called alongside any
+    /// callbacks, and replaces an observer set before.
+    pub fn observer(mut self, observer: std::sync::Arc<dyn CloneObserver>) -> Self {
+        self.observers.observer = Some(observer);
+        self
+    }
+
+    /// Send each file processed, warning and error to `sender` as an
+    /// [`Event`], to be handled on another thread rather than in a callback
+    /// on the copying threads. Sending never blocks, and stops quietly if the
+    /// receiver is dropped. Call this again to send to several channels.
+    pub fn event_sender(mut self, sender: std::sync::mpsc::Sender<Event>) -> Self {
+        self.observers.senders.push(sender);
+        self
+    }
+}
+
+/// Compile the glob patterns from `options`, to match paths relative to the
+/// tree root.
+fn build_globs(options: &Options) -> Result<Option<Globs>> {
+    Globs::build(&options.globs)
+}
+
+/// Check whether the file at `relative_path` is excluded by `globs`, either
+/// directly or because one of its parent directories is. This mirrors the
+/// decisions the walker makes, for paths that are not reached by a walk.
+fn is_excluded(globs: Option<&Globs>, relative_path: &Path) -> bool {
+    let Some(globs) = globs else {
+        return false;
+    };
+    globs.excludes(relative_path, false)
+        || relative_path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| globs.excludes(dir, true))
+}
+
+/// A file that [`clone_tree`] would copy, as yielded by [`clone_entries`].
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Full path to the file in the source tree.
+    pub path: PathBuf,
+    /// Path relative to the source root; this is also the path the file will
+    /// have relative to the destination root. With
+    /// [`Options::normalize_unicode`] it is normalized, so it may differ from
+    /// the path below the source root.
+    pub relative_path: PathBuf,
+    /// Metadata for the file, as reported by the walker.
+    pub metadata: std::fs::Metadata,
+}
+
+/// Where a walk records the entries it leaves out, if anywhere, with the
+/// prefix to give their paths, and where it warns about special files.
+#[derive(Debug, Clone, Default)]
+struct Skipped {
+    log: Option<report::SkipLog>,
+    prefix: PathBuf,
+    warning: Option<warning::WarningFn>,
+    observers: observer::Observers,
+}
+
+impl Skipped {
+    fn record(&self, relative_path: &Path, reason: SkipReason) {
+        if let Some(log) = &self.log {
+            log.record(self.prefix.join(relative_path), reason);
+        }
+    }
+
+    /// Record a file left out for its type: a symlink without
+    /// [`Options::symlinks`], or a special file, which is also warned about.
+    fn file_type(&self, file_type: std::fs::FileType, relative_path: &Path) {
+        self.record(relative_path, SkipReason::NotRegular);
+        if file_type.is_symlink() {
+            return;
+        }
+        let warning = Warning {
+            path: relative_path,
+            kind: WarningKind::SpecialFile,
+            error: None,
+        };
+        warning::report(self.warning.as_ref(), &self.observers, &warning);
+    }
+}
+
+/// Iterator over the files selected by an [`Options`] filter set. Created by
+/// [`clone_entries`].
+pub struct Entries {
+    src: PathBuf,
+    select: Select,
+    walk: Option<walk::Walk>,
+    /// Paths from [`Options::files`], with the glob filters to check them against
+    list: Option<(std::vec::IntoIter<PathBuf>, Option<Globs>)>,
+    /// The source's git objects with [`Options::share_git_objects`], listed
+    /// after everything else whatever the filters
+    objects: Option<walk::PlainWalk>,
+    rename: sanitize::Rename,
+    /// Rewritten paths seen so far, to catch names that collide
+    renamed: HashSet<PathBuf>,
+    skipped: Skipped,
+    error: Option<Error>,
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        loop {
+            let entry = if self.list.is_some() {
+                self.next_listed()
+            } else {
+                self.next_walked()
+            };
+            let entry = match entry {
+                // Objects are listed on their own at the end
+                Some(Ok(entry))
+                    if self.objects.is_some() && entry.relative_path.starts_with(GIT_OBJECTS) =>
+                {
+                    continue
+                }
+                Some(entry) => entry,
+                None => self.next_object()?,
+            };
+            match entry {
+                Ok(entry) if self.rename.is_identity() => return Some(Ok(entry)),
+                Ok(entry) => match self.rewrite(entry) {
+                    Ok(Some(entry)) => return Some(Ok(entry)),
+                    Ok(None) => continue,
+                    Err(error) => return Some(Err(error)),
+                },
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+impl Entries {
+    /// Give `entry` its destination name, or `None` if sanitizing skips it.
+    fn rewrite(&mut self, mut entry: Entry) -> Result<Option<Entry>> {
+        match self.rename.apply(&entry.relative_path) {
+            None => {
+                self.skipped
+                    .record(&entry.relative_path, SkipReason::InvalidName);
+                return Ok(None);
+            }
+            Some(Cow::Owned(path)) => entry.relative_path = path,
+            Some(Cow::Borrowed(_)) => {}
+        }
+        if !self.renamed.insert(entry.relative_path.clone()) {
+            return Err(Error::NameCollision {
+                path: entry.relative_path,
+            });
+        }
+        Ok(Some(entry))
+    }
+
+    /// The next file from the walk that passes the filters.
+    fn next_walked(&mut self) -> Option<Result<Entry>> {
+        let walk = self.walk.as_mut()?;
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+
+            // Directories are created as needed, so are never selected
+            let file_type = entry.file_type;
+            if file_type.is_dir() {
+                continue;
+            }
+
+            let relative_path = match entry.path.strip_prefix(&self.src) {
+                Ok(relative_path) => relative_path.to_path_buf(),
+                Err(e) => {
+                    return Some(Err(Error::Other(format!(
+                        "Failed to strip prefix from path: {e}"
+                    ))))
+                }
+            };
+            if !self.select.file_type(file_type, &entry.path) {
+                self.skipped.file_type(file_type, &relative_path);
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(source) => return Some(Err(Error::Other(format!("Walk error: {source}")))),
+            };
+            if !self.select.metadata(&metadata) {
+                self.skipped.record(&relative_path, SkipReason::Filtered);
+                continue;
+            }
+            return Some(Ok(Entry {
+                path: entry.path,
+                relative_path,
+                metadata,
+            }));
+        }
+        None
+    }
+}
+
+/// The per-file filters from [`Options`], applied to each walked or listed
+/// path.
+#[derive(Debug, Clone, Copy)]
+struct Select {
+    symlinks: bool,
+    junctions: Junctions,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+}
+
+impl Select {
+    fn new(options: &Options) -> Self {
+        Self {
+            symlinks: options.symlinks,
+            junctions: options.junctions,
+            min_size: options.min_size,
+            max_size: options.max_size,
+            newer_than: options.newer_than,
+            older_than: options.older_than,
+        }
+    }
+
+    /// Only files and, if enabled, symlinks are cloned. Junctions are
+    /// symlinks too, unless [`Options::junctions`] says otherwise.
+    fn file_type(&self, file_type: std::fs::FileType, path: &Path) -> bool {
+        if file_type.is_symlink()
+            && self.junctions != Junctions::Symlink
+            && junction::is_junction(path)
+        {
+            return self.junctions == Junctions::Junction;
+        }
+        file_type.is_file() || (self.symlinks && file_type.is_symlink())
+    }
+
+    fn metadata(&self, metadata: &std::fs::Metadata) -> bool {
+        self.size_and_time(metadata.len(), || metadata.modified().ok())
+    }
+
+    /// Check a file's size and, only if a time filter needs it, its mtime.
+    fn size_and_time(&self, len: u64, mtime: impl FnOnce() -> Option<SystemTime>) -> bool {
+        if !(self.min_size.is_none_or(|min| len >= min)
+            && self.max_size.is_none_or(|max| len <= max))
+        {
+            return false;
+        }
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+        let Some(mtime) = mtime() else {
+            return false;
+        };
+        self.newer_than.is_none_or(|t| mtime > t) && self.older_than.is_none_or(|t| mtime < t)
+    }
+}
+
+impl Entries {
+    /// The next file from an explicit list that exists and passes the filters.
+    fn next_listed(&mut self) -> Option<Result<Entry>> {
+        let (paths, globs) = self.list.as_mut()?;
+        for listed in paths {
+            // Drop `./` prefixes, and refuse anything that leaves the root
+            let mut relative_path = PathBuf::new();
+            for component in listed.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::Normal(part) => relative_path.push(part),
+                    _ => {
+                        return Some(Err(Error::Other(format!(
+                            "Listed path is outside the source: {}",
+                            listed.display()
+                        ))))
+                    }
+                }
+            }
+            if relative_path.as_os_str().is_empty() {
+                continue;
+            }
+            if is_excluded(globs.as_ref(), &relative_path) {
+                self.skipped.record(&relative_path, SkipReason::Excluded);
+                continue;
+            }
+
+            let path = self.src.join(&relative_path);
+            let metadata = match std::fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(source) => return Some(Err(Error::Read { path, source })),
+            };
+            if !self.select.file_type(metadata.file_type(), &path) {
+                self.skipped.file_type(metadata.file_type(), &relative_path);
+                continue;
+            }
+            if !self.select.metadata(&metadata) {
+                self.skipped.record(&relative_path, SkipReason::Filtered);
+                continue;
+            }
+            return Some(Ok(Entry {
+                path,
+                relative_path,
+                metadata,
+            }));
+        }
+        None
+    }
+
+    /// The next regular file in the source's git objects.
+    fn next_object(&mut self) -> Option<Result<Entry>> {
+        for entry in self.objects.as_mut()? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+            if !entry.file_type.is_file() {
+                continue;
+            }
+            let Ok(relative_path) = entry.path.strip_prefix(&self.src) else {
+                continue;
+            };
+            let relative_path = relative_path.to_path_buf();
+            return Some(match entry.metadata() {
+                Ok(metadata) => Ok(Entry {
+                    path: entry.path,
+                    relative_path,
+                    metadata,
+                }),
+                Err(source) => Err(Error::Read {
+                    path: entry.path,
+                    source,
+                }),
+            });
+        }
+        None
+    }
+}
+
+/// List the files under `src` that [`clone_tree`] would copy with `options`,
+/// without copying anything. Invalid glob patterns are reported as the first
+/// item of the iterator.
+pub fn clone_entries<P: AsRef<Path>>(src: P, options: &Options) -> Entries {
+    match options.build() {
+        Ok(config) => config.entries(src),
+        Err(error) => Entries {
+            error: Some(error),
+            ..Entries::new(src.as_ref(), options, Skipped::default())
+        },
+    }
+}
+
+impl Entries {
+    /// Entries under `src` with nothing to walk or list yet.
+    fn new(src: &Path, options: &Options, skipped: Skipped) -> Self {
+        Self {
+            src: src.to_path_buf(),
+            select: Select::new(options),
+            walk: None,
+            list: None,
+            objects: None,
+            rename: sanitize::Rename::new(options),
+            renamed: HashSet::new(),
+            skipped,
+            error: None,
+        }
+    }
+}
+
+/// Where a git repository keeps its objects, relative to the work tree.
+const GIT_OBJECTS: &str = ".git/objects";
+
+/// [`CloneConfig::entries`], recording the entries left out in `skipped`.
+fn recorded_entries(src: &Path, config: &CloneConfig, skipped: Skipped) -> Entries {
+    let options = &config.options;
+    let mut entries = Entries::new(src, options, skipped);
+    match &options.files {
+        Some(files) => {
+            let mut files = files.clone();
+            if options.sorted {
+                files.sort();
+            }
+            entries.list = Some((files.into_iter(), config.globs.clone()));
+        }
+        None => entries.walk = Some(walk::Walk::new(src, config, &entries.skipped)),
+    }
+    let objects = src.join(GIT_OBJECTS);
+    if options.share_git_objects && objects.is_dir() {
+        entries.objects = Some(walk::PlainWalk::new(&objects, options.sorted));
+    }
+    entries
+}
+
+/// Totals for the portion of a tree that [`clone_tree`] would copy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeEstimate {
+    /// Number of files that pass the filters.
+    pub files: u64,
+    /// Number of directories that would be created to hold those files, not
+    /// counting the destination root.
+    pub dirs: u64,
+    /// Total size of the selected files in bytes.
+    pub bytes: u64,
+}
+
+/// Walk `src` with the filters from `options` applied and total up what a
+/// clone would copy, without touching the filesystem otherwise.
+pub fn estimate<P: AsRef<Path>>(src: P, options: &Options) -> Result<TreeEstimate> {
+    let src = src.as_ref();
+    validate_source(src)?;
+    let mut estimate = TreeEstimate::default();
+    let mut dirs = HashSet::new();
+
+    for entry in clone_entries(src, options) {
+        let entry = entry?;
+        estimate.files += 1;
+        estimate.bytes += entry.metadata.len();
+
+        // Count every ancestor directory between the file and the root
+        let mut parent = entry.relative_path.parent();
+        while let Some(dir) = parent {
+            if dir.as_os_str().is_empty() || !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+
+    estimate.dirs = dirs.len() as u64;
+    Ok(estimate)
+}
+
+/// The closest ancestor of `path` (including `path` itself) that exists.
+fn existing_ancestor(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|p| p.exists())
+}
+
+/// Find a regular file under `src` to use as a reflink probe.
+fn probe_file(src: &Path, options: &Options) -> Result<Option<PathBuf>> {
+    clone_entries(src, options)
+        .next()
+        .transpose()
+        .map(|entry| entry.map(|e| e.path))
+}
+
+/// Check whether a reflink from `file` into the directory `dir` succeeds.
+fn reflink_works(file: &Path, dir: &Path) -> bool {
+    let probe = dir.join(format!(".clonetree-probe-{}", std::process::id()));
+    let ok = reflink_copy::reflink(file, &probe).is_ok();
+    let _ = std::fs::remove_file(&probe);
+    ok
+}
+
+/// Fail with [`Error::InsufficientSpace`] if `dest` cannot hold the filtered
+/// contents of `src`.
+fn check_free_space(src: &Path, dest: &Path, options: &Options) -> Result<()> {
+    let Some(target) = existing_ancestor(dest) else {
+        return Ok(());
+    };
+
+    // Reflinked files share extents with the source, so they need no space.
+    if let Some(file) = probe_file(src, options)? {
+        if target.is_dir() && reflink_works(&file, target) {
+            return Ok(());
+        }
+    }
+
+    let required = estimate(src, options)?.bytes;
+    let available = fs4::available_space(target)?;
+    if required > available {
+        return Err(Error::InsufficientSpace {
+            path: target.to_path_buf(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Check the source and destination constraints described in the crate docs.
+fn validate(src: &Path, dest: &Path, options: &Options) -> Result<()> {
+    validate_source(src)?;
+
+    // Validate destination
+    if dest.exists() && options.overwrite == Overwrite::Never {
+        return Err(Error::DestinationExists {
+            path: dest.to_path_buf(),
+        });
+    }
+
+    // Emptying the destination must not take the source with it
+    if options.overwrite == Overwrite::Replace {
+        if let (Ok(src), Ok(dest)) = (src.canonicalize(), dest.canonicalize()) {
+            if src.starts_with(&dest) {
+                return Err(Error::SourceInDestination { src, dest });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete everything inside the directory `dest`, for
+/// [`Overwrite::Replace`]. The directory itself stays, so a lock held on it
+/// stays valid.
+fn empty_destination(dest: &Path, options: &Options) -> Result<()> {
+    let read_err = |source| Error::Read {
+        path: dest.to_path_buf(),
+        source,
+    };
+    for entry in std::fs::read_dir(dest).map_err(read_err)? {
+        let path = entry.map_err(read_err)?.path();
+        trace::event!(debug, "deleted", path = path.display());
+        options.discard(&path)?;
+    }
+    Ok(())
+}
+
+/// Check that `src` exists and is a directory.
+fn validate_source(src: &Path) -> Result<()> {
+    // Validate source exists
+    if !src.exists() {
+        return Err(Error::SourceNotFound {
+            path: src.to_path_buf(),
+        });
+    }
+
+    // Validate source is a directory
+    if !src.is_dir() {
+        return Err(Error::SourceNotDirectory {
+            path: src.to_path_buf(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Clone `src` to `dest` with `options`. For the counts of what was done,
+/// use [`CloneConfig::clone_tree`] or [`clone_tree_with_report`].
+pub fn clone_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<()> {
+    options.build()?.clone_tree(src, dest).map(drop)
+}
+
+/// Clone `src` to `dest` like [`clone_tree`], but return everything that
+/// happened: the counts, each file's outcome, the warnings, and any errors,
+/// which [`clone_tree`] reports instead of the counts. Callbacks set with
+/// [`Options::on_progress`] and [`Options::on_warning`] are still called.
+pub fn clone_tree_with_report<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> DetailedReport {
+    let files = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let warnings = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let options = options
+        .clone()
+        .also_on_progress({
+            let files = files.clone();
+            move |p| files.lock().expect("files lock").push(FileOutcome::from(p))
+        })
+        .also_on_warning({
+            let warnings = warnings.clone();
+            move |w| {
+                warnings
+                    .lock()
+                    .expect("warnings lock")
+                    .push(RecordedWarning::from(w))
+            }
+        });
+
+    let (summary, result) = match options.build() {
+        Ok(config) => config.run(src.as_ref(), dest.as_ref()),
+        Err(error) => (CloneReport::default(), Err(error)),
+    };
+    let errors = match result {
+        Ok(()) => Vec::new(),
+        Err(Error::Incomplete { errors }) => errors,
+        Err(error) => vec![error],
+    };
+    let files = std::mem::take(&mut *files.lock().expect("files lock"));
+    let warnings = std::mem::take(&mut *warnings.lock().expect("warnings lock"));
+    DetailedReport {
+        summary,
+        files,
+        warnings,
+        errors,
+    }
+}
+
+impl CloneConfig {
+    /// [`clone_tree`] with this config, returning what was done.
+    pub fn clone_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        src: P,
+        dest: Q,
+    ) -> Result<CloneReport> {
+        let (report, result) = self.run(src.as_ref(), dest.as_ref());
+        result.map(|()| report)
+    }
+
+    /// Clone `src` to `dest`, returning what was done even if it failed.
+    fn run(&self, src: &Path, dest: &Path) -> (CloneReport, Result<()>) {
+        let mut tracker = progress::Tracker::new(&self.options);
+        if self.options.count_first {
+            match estimate(src, &self.options) {
+                Ok(estimate) => tracker.count(estimate),
+                Err(error) => return (CloneReport::default(), Err(error)),
+            }
+        }
+        let progress = Mutex::new(tracker);
+        let result = clone_tree_with(src, dest, self, &progress, Path::new(""));
+        (
+            progress.into_inner().expect("progress lock").report(),
+            result,
+        )
+    }
+
+    /// [`clone_entries`] with this config.
+    pub fn entries<P: AsRef<Path>>(&self, src: P) -> Entries {
+        recorded_entries(src.as_ref(), self, Skipped::default())
+    }
+}
+
+/// Clone `src` to `dest`, reporting progress to `progress` with paths under
+/// `prefix`, so several clones can share one running total.
+fn clone_tree_with(
+    src: &Path,
+    dest: &Path,
+    config: &CloneConfig,
+    progress: &Mutex<progress::Tracker>,
+    prefix: &Path,
+) -> Result<()> {
+    let options = &config.options;
+    let _span = trace::clone_span(src, dest);
+    trace::event!(info, "cloning", src = src.display(), dest = dest.display());
+
+    validate(src, dest, options)?;
+
+    // Nested trees can run past MAX_PATH on Windows
+    let src = longpath::extended(src);
+    let dest = longpath::extended(dest);
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+
+    if options.check_space {
+        check_free_space(src, dest, options)?;
+    }
+
+    // A file where the destination directory should be is replaced too
+    if options.overwrite == Overwrite::Replace && dest.symlink_metadata().is_ok_and(|m| !m.is_dir())
+    {
+        options.discard(dest)?;
+    }
+
+    // Create destination directory if it doesn't exist
+    if !dest.exists() {
+        std::fs::create_dir_all(dest).map_err(|source| Error::CreateDirectory {
+            path: dest.to_path_buf(),
+            source,
+        })?;
+        root_created(dest, prefix, Some(src), options);
+    }
+
+    // Keep other clones out of the destination until we are done
+    let _dest_lock = lock_destination(dest)?;
+    if options.overwrite == Overwrite::Replace {
+        empty_destination(dest, options)?;
+    }
+
+    let use_cache = options.update && options.state_cache;
+    let cloner = Cloner {
+        src,
+        dest,
+        prefix,
+        options,
+        created_dirs: Mutex::new(CreatedDirs::new(dest)),
+        copied: Mutex::new(dedup::CopiedFiles::default()),
+        #[cfg(unix)]
+        links: Mutex::new(std::collections::HashMap::new()),
+        cache: Mutex::new(if use_cache {
+            cache::StateCache::load(dest)
+        } else {
+            cache::StateCache::default()
+        }),
+        use_cache,
+    };
+
+    // Relative paths of all source files, used to find files to delete
+    let mut seen = HashSet::new();
+
+    // Walk the source directory
+    let mut dir_span = trace::DirSpan::default();
+    let skipped = Skipped {
+        log: progress.lock().expect("progress lock").skip_log(),
+        prefix: prefix.to_path_buf(),
+        warning: options.warning.clone(),
+        observers: options.observers.clone(),
+    };
+    let mut last_dir = None;
+    let entries = recorded_entries(src, config, skipped).inspect(|entry| {
+        if let Ok(entry) = entry {
+            if options.delete {
+                seen.insert(entry.relative_path.clone());
+            }
+            if let Some(dir) = entry.relative_path.parent() {
+                dir_span.enter(dir);
+                if last_dir.as_deref() != Some(dir) {
+                    options.notify(|observer| observer.on_dir(&prefix.join(dir)));
+                    last_dir = Some(dir.to_path_buf());
+                }
+            }
+        }
+    });
+    let failures = Mutex::new(Vec::new());
+    let fail = |error: Error| -> Result<()> {
+        if !matches!(error, Error::Cancelled) {
+            options.notify(|observer| observer.on_error(&error));
+        }
+        if !options.keep_going || matches!(error, Error::Cancelled) {
+            return Err(error);
+        }
+        trace::event!(warn, "continuing after error", error = error);
+        failures.lock().expect("failures lock").push(error);
+        Ok(())
+    };
+    let entries = entries.filter_map(|entry| match entry {
+        // Bad globs make the whole walk meaningless
+        Err(error @ Error::InvalidGlob { .. }) => Some(Err(error)),
+        Err(error) => fail(error).err().map(Err),
+        Ok(entry) => Some(Ok(entry)),
+    });
+    let notifying = Mutex::new(());
+    parallel::try_for_each(options.threads, entries, |entry| {
+        progress
+            .lock()
+            .expect("progress lock")
+            .start_file(entry.metadata.len())?;
+        match cloner.clone_file(&entry) {
+            Ok(outcome) => {
+                let path = prefix.join(&entry.relative_path);
+                // The callbacks run outside the tracker lock, but one at a
+                // time and in the order files were counted, so the running
+                // totals they see never go backwards
+                let _notifying = notifying.lock().expect("notify lock");
+                let done = progress.lock().expect("progress lock").file_done(
+                    &path,
+                    entry.metadata.len(),
+                    outcome,
+                );
+                done.notify();
+            }
+            Err(error) => fail(error)?,
+        }
+        Ok(())
+    })?;
+
+    if options.delete {
+        delete_extraneous(src, dest, &seen, options)?;
+    }
+
+    if use_cache {
+        cloner.cache.lock().expect("cache lock").save()?;
+    }
+
+    let errors = failures.into_inner().expect("failures lock");
+    if !errors.is_empty() {
+        return Err(Error::Incomplete { errors });
+    }
+
+    trace::event!(info, "clone finished", dest = dest.display());
+    Ok(())
+}
+
+/// Tell the observers that the destination root `dest` was just created, as
+/// `path`, with the metadata of the source directory `src`, or of `dest`
+/// itself when the source isn't a directory.
+fn root_created(dest: &Path, path: &Path, src: Option<&Path>, options: &Options) {
+    if options.observers.is_empty() {
+        return;
+    }
+    if let Ok(metadata) = std::fs::metadata(src.unwrap_or(dest)) {
+        options.notify(|observer| observer.on_dir_created(path, &metadata));
+    }
+}
+
+/// Create `dir` and any missing parents, as [`std::fs::create_dir_all`]
+/// does, returning the directories created, outermost first.
+fn create_dirs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+        .collect();
+    let mut created = Vec::new();
+    for dir in missing.into_iter().rev() {
+        match std::fs::create_dir(dir) {
+            Ok(()) => created.push(dir.to_path_buf()),
+            // Made by another worker in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(created)
+}
+
+/// The directory most recently created or found in a destination. It and
+/// all its ancestors are known to exist as real directories, not symlinks
+/// leading out of the destination, which in a depth-first walk covers the
+/// parents of nearly every file that follows, without remembering each
+/// directory of the tree.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CreatedDirs {
+    last: Option<PathBuf>,
+}
+
+impl CreatedDirs {
+    /// Start with `root`, which already exists.
+    pub(crate) fn new(root: &Path) -> Self {
+        Self {
+            last: Some(root.to_path_buf()),
+        }
+    }
+
+    /// Whether `dir` is known to exist.
+    pub(crate) fn contains(&self, dir: &Path) -> bool {
+        self.last
+            .as_deref()
+            .is_some_and(|last| last.starts_with(dir))
+    }
+
+    /// Record that `dir` was just created or checked.
+    pub(crate) fn insert(&mut self, dir: &Path) {
+        if !self.contains(dir) {
+            self.last = Some(dir.to_path_buf());
+        }
+    }
+}
+
+/// State carried from file to file during a clone, shared between worker
+/// threads.
+struct Cloner<'a> {
+    src: &'a Path,
+    dest: &'a Path,
+    /// Where `dest` is under the root paths are reported relative to
+    prefix: &'a Path,
+    options: &'a Options,
+    /// Directories already created, to avoid redundant create_dir_all calls
+    created_dirs: Mutex<CreatedDirs>,
+    /// Files written so far, used to reflink duplicates within the source
+    copied: Mutex<dedup::CopiedFiles>,
+    /// The first copy of each linked source file, by device and inode, to
+    /// hardlink the others to
+    #[cfg(unix)]
+    links: Mutex<std::collections::HashMap<(u64, u64), PathBuf>>,
+    cache: Mutex<cache::StateCache>,
+    use_cache: bool,
+}
+
+impl Cloner<'_> {
+    /// Bring the destination copy of `entry` up to date.
+    fn clone_file(&self, entry: &Entry) -> Result<Outcome> {
+        let options = self.options;
+        let path = &entry.path;
+        contain::check_relative(&entry.relative_path)?;
+        let dest_path = self.dest.join(&entry.relative_path);
+        if longpath::too_long(&dest_path) {
+            return match options.long_paths {
+                LongPaths::Error => Err(Error::PathTooLong {
+                    path: entry.relative_path.clone(),
+                }),
+                LongPaths::Skip => {
+                    options.warn(&entry.relative_path, WarningKind::PathTooLong, None);
+                    Ok(Outcome::Skipped(SkipReason::PathTooLong))
+                }
+            };
+        }
+        // The one lookup of the destination; the source metadata comes from
+        // the walk
+        let existing = std::fs::symlink_metadata(&dest_path).ok();
+
+        if self.use_cache
+            && self
+                .cache
+                .lock()
+                .expect("cache lock")
+                .is_fresh(entry, existing.as_ref(), options)?
+        {
+            trace::event!(
+                debug,
+                "skipped",
+                path = entry.relative_path.display(),
+                reason = "cached"
+            );
+            return Ok(Outcome::Skipped(SkipReason::Unchanged));
+        }
+        if options.update && is_unchanged(entry, &dest_path, existing.as_ref(), options)? {
+            trace::event!(
+                debug,
+                "skipped",
+                path = entry.relative_path.display(),
+                reason = "unchanged"
+            );
+            if let (true, Some(existing)) = (self.use_cache, &existing) {
+                self.record(entry, existing)?;
+            }
+            return Ok(Outcome::Skipped(SkipReason::Unchanged));
+        }
+        // A destination that is already a reflink of the source holds the
+        // same data, so there is nothing to write
+        if let Some(existing) = existing
+            .as_ref()
+            .filter(|_| options.overwrite == Overwrite::Merge && entry.metadata.is_file())
+        {
+            if is_reflink_of(path, entry.metadata.len(), &dest_path, existing) {
+                trace::event!(
+                    debug,
+                    "skipped",
+                    path = entry.relative_path.display(),
+                    reason = "shared"
+                );
+                if self.use_cache {
+                    self.record(entry, existing)?;
+                }
+                return Ok(Outcome::Skipped(SkipReason::Unchanged));
+            }
+        }
+
+        // Check the parent directories don't lead out of the destination,
+        // and create them if needed; an existing destination file means its
+        // parent is already there
+        if let Some(parent) = dest_path.parent() {
+            let known = {
+                let created_dirs = self.created_dirs.lock().expect("dirs lock");
+                (!created_dirs.contains(parent)).then(|| created_dirs.clone())
+            };
+            if let Some(known) = known {
+                contain::check_parents(self.dest, parent, &known)?;
+                if existing.is_none() {
+                    let created = create_dirs(parent).map_err(|source| Error::CreateDirectory {
+                        path: parent.to_path_buf(),
+                        source,
+                    })?;
+                    self.dirs_created(entry, &created);
+                }
+                self.created_dirs.lock().expect("dirs lock").insert(parent);
+            }
+        }
+
+        // If overwrite is enabled and the destination exists, remove it first
+        let mut appended = false;
+        if let Some(existing) = existing
+            .as_ref()
+            .filter(|_| options.overwrite != Overwrite::Never)
+        {
+            if options.overwrite == Overwrite::NoClobber {
+                return Err(Error::FileExists { path: dest_path });
+            }
+            if let Some(conflict::ConflictFn(resolve)) = &options.conflict {
+                let conflict = Conflict {
+                    path: &entry.relative_path,
+                    src: path,
+                    dest: &dest_path,
+                };
+                match resolve(&conflict) {
+                    Resolution::Overwrite => {}
+                    Resolution::Skip => {
+                        trace::event!(
+                            debug,
+                            "skipped",
+                            path = entry.relative_path.display(),
+                            reason = "conflict"
+                        );
+                        return Ok(Outcome::Skipped(SkipReason::Conflict));
+                    }
+                    Resolution::Abort => return Err(Error::Cancelled),
+                }
+            }
+            appended = options.append
+                && options.backup == Backup::Off
+                && entry.metadata.is_file()
+                && append::append_tail(
+                    path,
+                    entry.metadata.len(),
+                    &dest_path,
+                    existing,
+                    options.copy_settings().chunk_size,
+                )
+                .map_err(|source| Error::Copy {
+                    src: path.clone(),
+                    dest: dest_path.clone(),
+                    source,
+                })?;
+            if !appended {
+                options
+                    .replace_file(&dest_path, existing)
+                    .map_err(Error::Io)?;
+            }
+        }
+
+        let mut digest = None;
+        let outcome = if appended {
+            trace::event!(debug, "appended", path = entry.relative_path.display());
+            Outcome::Copied
+        } else if (self.is_shared_object(entry) && std::fs::hard_link(path, &dest_path).is_ok())
+            || self.link_earlier(entry, &dest_path)
+        {
+            trace::event!(debug, "hardlinked", path = entry.relative_path.display());
+            Outcome::Hardlinked
+        } else if entry.metadata.is_symlink() {
+            let junction = options.junctions == Junctions::Junction && junction::is_junction(path);
+            let linked = if junction {
+                self.junction(path, &dest_path)
+            } else {
+                preserve::symlink(path, &dest_path, options.dir_links)
+            };
+            linked.map_err(|source| Error::Copy {
+                src: path.clone(),
+                dest: dest_path.clone(),
+                source,
+            })?;
+            trace::event!(debug, "symlinked", path = entry.relative_path.display());
+            Outcome::Copied
+        } else {
+            let (outcome, source_digest) = self.copy_file(entry, &dest_path)?;
+            digest = source_digest;
+            outcome
+        };
+        // A hardlink is the source inode itself, so is left as it is
+        if outcome != Outcome::Hardlinked {
+            if options.verify && !entry.metadata.is_symlink() {
+                verify_copy(path, &dest_path, digest, options)?;
+            }
+            self.preserve_metadata(entry, &dest_path)?;
+        }
+
+        if self.use_cache {
+            self.record(entry, &std::fs::symlink_metadata(&dest_path)?)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Tell the observers about the destination directories just created
+    /// for `entry`, with the metadata of the source directories at the same
+    /// depth.
+    fn dirs_created(&self, entry: &Entry, created: &[PathBuf]) {
+        if self.options.observers.is_empty() {
+            return;
+        }
+        let depth = entry.relative_path.components().count();
+        for dir in created {
+            let Ok(relative) = dir.strip_prefix(self.dest) else {
+                continue;
+            };
+            let up = depth - relative.components().count();
+            let metadata = entry
+                .path
+                .ancestors()
+                .nth(up)
+                .and_then(|src_dir| std::fs::metadata(src_dir).ok());
+            if let Some(metadata) = metadata {
+                let path = self.prefix.join(relative);
+                self.options
+                    .notify(|observer| observer.on_dir_created(&path, &metadata));
+            }
+        }
+    }
+
+    /// Recreate the junction at `path` at `dest_path`, moving a target inside
+    /// the source tree to the same place in the destination.
+    fn junction(&self, path: &Path, dest_path: &Path) -> std::io::Result<()> {
+        let target = std::fs::read_link(path)?;
+        let target = match target.strip_prefix(self.src) {
+            Ok(inside) => self.dest.join(inside),
+            Err(_) => target,
+        };
+        junction::create(&target, dest_path)
+    }
+
+    /// With [`Preserve::HARDLINKS`], hardlink `dest_path` to the copy of an
+    /// earlier path of the same source file, returning whether it did. The
+    /// first path seen is remembered and copied as usual.
+    #[cfg(unix)]
+    fn link_earlier(&self, entry: &Entry, dest_path: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = &entry.metadata;
+        if !self.options.preserve.contains(Preserve::HARDLINKS)
+            || !metadata.is_file()
+            || metadata.nlink() < 2
+        {
+            return false;
+        }
+        let earlier = {
+            let mut links = self.links.lock().expect("links lock");
+            let key = (metadata.dev(), metadata.ino());
+            match links.get(&key) {
+                Some(earlier) => earlier.clone(),
+                None => {
+                    links.insert(key, dest_path.to_path_buf());
+                    return false;
+                }
+            }
+        };
+        // The earlier copy may not be written yet by another worker, in
+        // which case this one is copied too
+        std::fs::hard_link(earlier, dest_path).is_ok()
+    }
+
+    #[cfg(not(unix))]
+    fn link_earlier(&self, _entry: &Entry, _dest_path: &Path) -> bool {
+        false
+    }
+
+    /// Whether `entry` is a git object to share with the source.
+    fn is_shared_object(&self, entry: &Entry) -> bool {
+        self.options.share_git_objects
+            && entry.metadata.is_file()
+            && entry.relative_path.starts_with(GIT_OBJECTS)
+    }
+
+    /// Record `entry` in the state cache as up to date at a destination with
+    /// metadata `dest_metadata`.
+    fn record(&self, entry: &Entry, dest_metadata: &std::fs::Metadata) -> Result<()> {
+        self.cache
+            .lock()
+            .expect("cache lock")
+            .record(entry, dest_metadata, self.options)
+    }
+
+    /// Copy or reflink the data of the regular file `entry` to `dest_path`.
+    /// When verifying a streamed copy, also returns the digest of the data as
+    /// it was read.
+    fn copy_file(&self, entry: &Entry, dest_path: &Path) -> Result<(Outcome, Option<Digest>)> {
+        let options = self.options;
+        let path = &entry.path;
+
+        // Held until the copy is done
+        let _lock = if options.lock_source {
+            Some(lock_shared(path)?)
+        } else {
+            None
+        };
+
+        // Reflink from an identical file already in the destination, falling
+        // back to a regular copy if that fails
+        let mut outcome = None;
+        let mut record = false;
+        let mut digest = None;
+        let mut settings = options.copy_settings();
+        // A nocow file is created empty with the flag, then streamed into
+        if options.preserve.contains(Preserve::INODE_FLAGS)
+            && settings.reflink != Reflink::Always
+            && preserve::create_nocow(path, dest_path)?
+        {
+            settings.reflink = Reflink::Never;
+        } else if options.dedup_source {
+            let existing = self.copied.lock().expect("copied lock").find(entry)?;
+            if let Some(existing) = existing {
+                if reflink_copy::reflink(&existing, dest_path).is_ok() {
+                    outcome = Some(Outcome::Reflinked);
+                    trace::event!(
+                        debug,
+                        "reflinked duplicate",
+                        path = entry.relative_path.display(),
+                        from = existing.display()
+                    );
+                } else {
+                    trace::event!(
+                        debug,
+                        "duplicate reflink failed, copying",
+                        path = entry.relative_path.display()
+                    );
+                }
+            } else {
+                record = true;
+            }
+        }
+
+        // Copy file using reflink when available
+        if outcome.is_none() {
+            let copied = match options.file_timeout {
+                Some(timeout) => copy_data_within(path, dest_path, &settings, timeout),
+                None => copy_data(path, dest_path, &settings),
+            };
+            let copied = copied.map_err(|source| {
+                trace::event!(
+                    error,
+                    "copy failed",
+                    path = entry.relative_path.display(),
+                    error = source
+                );
+                Error::Copy {
+                    src: path.clone(),
+                    dest: dest_path.to_path_buf(),
+                    source,
+                }
+            })?;
+            outcome = Some(match copied {
+                Copied::Reflinked => {
+                    trace::event!(debug, "reflinked", path = entry.relative_path.display());
+                    Outcome::Reflinked
+                }
+                // Reflinks were unavailable, so the data was streamed
+                Copied::Streamed {
+                    bytes: _bytes,
+                    digest: source_digest,
+                } => {
+                    trace::event!(
+                        debug,
+                        "copied",
+                        path = entry.relative_path.display(),
+                        bytes = _bytes
+                    );
+                    digest = source_digest;
+                    Outcome::Copied
+                }
+            });
+        }
+
+        // Only offer the file for reuse once its data is complete, so other
+        // threads never reflink a partial copy
+        if record {
+            self.copied
+                .lock()
+                .expect("copied lock")
+                .record(entry, dest_path);
+        }
+        Ok((outcome.unwrap_or(Outcome::Copied), digest))
+    }
+
+    /// Apply the metadata preservation options to the newly written `dest_path`.
+    fn preserve_metadata(&self, entry: &Entry, dest_path: &Path) -> Result<()> {
+        let options = self.options;
+        let is_symlink = entry.metadata.is_symlink();
+        let warn = |kind, error: &std::io::Error| {
+            options.warn(&entry.relative_path, kind, Some(error));
+        };
+        if options.preserve.contains(Preserve::XATTR) {
+            if let Some(error) = preserve::xattrs(&entry.path, dest_path)? {
+                warn(WarningKind::Xattrs, &error);
+            }
+        }
+        // Written before permissions can make the file read-only
+        if options.preserve.contains(Preserve::STREAMS) && !is_symlink {
+            if let Some(error) = preserve::streams(&entry.path, dest_path)? {
+                warn(WarningKind::Streams, &error);
+            }
+        }
+        // Changing the owner can clear setuid bits, so set permissions after
+        if options.preserve.contains(Preserve::OWNER) {
+            if let Some(error) = preserve::ownership(&entry.metadata, dest_path)? {
+                warn(WarningKind::Ownership, &error);
+            }
+        }
+        if options.preserve.contains(Preserve::MODE) && !is_symlink {
+            preserve::permissions(
+                &entry.metadata,
+                dest_path,
+                options.preserve.contains(Preserve::SPECIAL_BITS),
+            )?;
+        }
+        // Likewise for capabilities, which a chown clears
+        if options.preserve.contains(Preserve::CAPABILITIES) && !is_symlink {
+            if let Some(error) = preserve::capabilities(&entry.path, dest_path)? {
+                warn(WarningKind::Capabilities, &error);
+            }
+        }
+        if options.preserve.contains(Preserve::TIMES) {
+            let mtime = filetime::FileTime::from_last_modification_time(&entry.metadata);
+            let set = if is_symlink {
+                let atime = filetime::FileTime::from_last_access_time(&entry.metadata);
+                filetime::set_symlink_file_times(dest_path, atime, mtime)
+            } else {
+                filetime::set_file_mtime(dest_path, mtime)
+            };
+            if let Err(error) = set {
+                warn(WarningKind::Times, &error);
+            }
+        }
+        if options.preserve.contains(Preserve::BIRTH_TIME) && !is_symlink {
+            if let Err(error) = preserve::birth_time(&entry.metadata, dest_path) {
+                warn(WarningKind::BirthTime, &error);
+            }
+        }
+        // A copied list may deny the changes above, so it comes after them
+        if options.preserve.contains(Preserve::ACL) && !is_symlink {
+            if let Some(error) = preserve::acl(&entry.path, dest_path)? {
+                warn(WarningKind::Acl, &error);
+            }
+        }
+        // Last, as immutable and append-only files refuse further changes
+        if options.preserve.contains(Preserve::INODE_FLAGS) && !is_symlink {
+            if let Some(error) = preserve::inode_flags(&entry.path, dest_path)? {
+                warn(WarningKind::InodeFlags, &error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy the contents of `src` to `dest`, reflinking or streaming the data as
+/// `settings` allow.
+fn copy_data(src: &Path, dest: &Path, settings: &CopySettings) -> std::io::Result<Copied> {
+    let stream = || {
+        let hasher = settings.hasher.as_deref();
+        stream::copy_file(src, dest, settings.chunk_size, hasher)
+    };
+    match settings.reflink {
+        Reflink::Auto => match reflink_copy::reflink(src, dest) {
+            Ok(()) => Ok(Copied::Reflinked),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound
+                        | std::io::ErrorKind::PermissionDenied
+                        | std::io::ErrorKind::AlreadyExists
+                ) =>
+            {
+                Err(e)
+            }
+            Err(_) => stream(),
+        },
+        Reflink::Always => reflink_copy::reflink(src, dest).map(|()| Copied::Reflinked),
+        Reflink::Never => stream(),
+    }
+}
+
+/// [`copy_data`] on a helper thread, giving up after `timeout`. The thread
+/// can't be stopped, so after a timeout it is left to finish on its own.
+fn copy_data_within(
+    src: &Path,
+    dest: &Path,
+    settings: &CopySettings,
+    timeout: Duration,
+) -> std::io::Result<Copied> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (src, dest, settings) = (src.to_path_buf(), dest.to_path_buf(), settings.clone());
+    std::thread::spawn(move || {
+        let _ = tx.send(copy_data(&src, &dest, &settings));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no result after {timeout:?}"),
+        )),
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            Err(std::io::Error::other("copy thread panicked"))
+        }
+    }
+}
+
+/// Open `path` and take a shared advisory lock on it, blocking until any
+/// exclusive lock is released. The lock is held until the file is dropped.
+fn lock_shared(path: &Path) -> Result<std::fs::File> {
+    let lock_err = |source| Error::Lock {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(lock_err)?;
+    file.lock_shared().map_err(lock_err)?;
+    Ok(file)
+}
+
+/// Take an exclusive advisory lock on the destination directory, failing with
+/// [`Error::DestinationLocked`] if another clone holds it. On Unix the
+/// directory itself is locked; elsewhere a lock file beside it is used.
+fn lock_destination(dest: &Path) -> Result<std::fs::File> {
+    #[cfg(unix)]
+    let lock_path = dest.to_path_buf();
+    #[cfg(not(unix))]
+    let lock_path = {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        dest.with_file_name(format!(".{name}.clonetree-lock"))
+    };
+
+    let lock_err = |source| Error::Lock {
+        path: lock_path.clone(),
+        source,
+    };
+    #[cfg(unix)]
+    let file = std::fs::File::open(&lock_path).map_err(lock_err)?;
+    #[cfg(not(unix))]
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(lock_err)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(Error::DestinationLocked {
+            path: dest.to_path_buf(),
+        }),
+        Err(std::fs::TryLockError::Error(source)) => Err(lock_err(source)),
+    }
+}
+
+/// Whether `dest`, with metadata `dest_metadata`, is a file of `len` bytes
+/// made of exactly the extents of `src`, as it is right after a reflink, so the
+/// two are known to be identical.
+#[cfg(target_os = "linux")]
+fn is_reflink_of(src: &Path, len: u64, dest: &Path, dest_metadata: &std::fs::Metadata) -> bool {
+    if !dest_metadata.is_file() || dest_metadata.len() != len {
+        return false;
+    }
+    let (Ok(src), Ok(dest)) = (std::fs::File::open(src), std::fs::File::open(dest)) else {
+        return false;
+    };
+    fiemap::same_extents(&src, &dest).unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_reflink_of(_src: &Path, _len: u64, _dest: &Path, _dest_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Check that `dest` holds the same contents as `src`, whose digest may
+/// already be known from copying it.
+fn verify_copy(src: &Path, dest: &Path, digest: Option<Digest>, options: &Options) -> Result<()> {
+    let digest = match digest {
+        Some(digest) => digest,
+        None => options.hash_file(src)?,
+    };
+    if digest != options.hash_file(dest)? {
+        return Err(Error::VerificationFailed {
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+        });
+    }
+    trace::event!(debug, "verified", path = dest.display());
+    Ok(())
+}
+
+/// Check whether `dest_path`, with metadata `dest_metadata` if it exists,
+/// already holds an unchanged copy of `entry`.
+fn is_unchanged(
+    entry: &Entry,
+    dest_path: &Path,
+    dest_metadata: Option<&std::fs::Metadata>,
+    options: &Options,
+) -> Result<bool> {
+    let Some(metadata) = dest_metadata.filter(|m| m.is_file()) else {
+        return Ok(false);
+    };
+    let existing = Entry {
+        path: dest_path.to_path_buf(),
+        relative_path: entry.relative_path.clone(),
+        metadata: metadata.clone(),
+    };
+    Ok(!diff::differs(entry, &existing, options)?)
+}
+
+/// Files under `dest` that pass the filters but are not in `seen`.
+fn find_extraneous(dest: &Path, seen: &HashSet<PathBuf>, options: &Options) -> Result<Vec<Entry>> {
+    // Match the names actually on disk, so that a file left in the other
+    // normalization form by an earlier clone counts as extraneous
+    let mut options = options
+        .clone()
+        .normalize_unicode(Normalization::None)
+        .sanitize_names(Sanitize::Off);
+    if options.delete_excluded {
+        options.globs.clear();
+        options.files = None;
+        options.min_size = None;
+        options.max_size = None;
+        options.newer_than = None;
+        options.older_than = None;
+    }
+    clone_entries(dest, &options)
+        .filter(|entry| match entry {
+            Ok(entry) => !seen.contains(&entry.relative_path),
+            Err(_) => true,
+        })
+        .collect()
+}
+
+/// Remove files under `dest` that pass the filters but are not in `seen`, then
+/// remove any directories emptied as a result that do not exist in `src`.
+fn delete_extraneous(
+    src: &Path,
+    dest: &Path,
+    seen: &HashSet<PathBuf>,
+    options: &Options,
+) -> Result<()> {
+    for entry in find_extraneous(dest, seen, options)? {
+        options.discard(&entry.path)?;
+        trace::event!(debug, "deleted", path = entry.relative_path.display());
+
+        let mut parent = entry.relative_path.parent();
+        while let Some(dir) = parent {
+            if dir.as_os_str().is_empty() || (!options.prune_empty_dirs && src.join(dir).is_dir()) {
+                break;
+            }
+            // Stops at the first directory that still has contents
+            if std::fs::remove_dir(dest.join(dir)).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_clone_tree_basic() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        // Create source structure
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file1.txt"), "content1")?;
+        fs::create_dir(src.join("subdir"))?;
+        fs::write(src.join("subdir/file2.txt"), "content2")?;
+
+        // Clone the tree
+        let opts = Options::new();
+        clone_tree(&src, &dest, &opts)?;
+
+        // Verify structure
+        assert!(dest.join("file1.txt").exists());
+        assert!(dest.join("subdir/file2.txt").exists());
+        assert_eq!(fs::read_to_string(dest.join("file1.txt"))?, "content1");
+        assert_eq!(
+            fs::read_to_string(dest.join("subdir/file2.txt"))?,
+            "content2"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_clone_tree_with_excludes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        // Create source structure
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "keep")?;
+        fs::create_dir(src.join("target"))?;
+        fs::write(src.join("target/build.out"), "exclude")?;
+        fs::create_dir(src.join(".git"))?;
+        fs::write(src.join(".git/config"), "exclude")?;
+
+        // Clone with exclude globs (! prefix excludes)
+        let opts = Options::new().glob("!target/**").glob("!.git/**");
+        clone_tree(&src, &dest, &opts)?;
+
+        // Verify excludes worked
+        assert!(dest.join("file.txt").exists());
+        assert!(!dest.join("target").exists());
+        assert!(!dest.join(".git").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_clone_tree_with_positive_globs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        // Create source structure
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("include1.txt"), "include")?;
+        fs::write(src.join("include2.txt"), "include")?;
+        fs::write(src.join("exclude.log"), "exclude")?;
+        fs::create_dir(src.join("data"))?;
+        fs::write(src.join("data/file.txt"), "include")?;
+        fs::write(src.join("data/debug.log"), "exclude")?;
+
+        // Clone with positive globs (only include .txt files)
+        let opts = Options::new().glob("**/*.txt");
+        clone_tree(&src, &dest, &opts)?;
+
+        // Verify only .txt files were included
+        assert!(dest.join("include1.txt").exists());
+        assert!(dest.join("include2.txt").exists());
+        assert!(dest.join("data/file.txt").exists());
+        assert!(!dest.join("exclude.log").exists());
+        assert!(!dest.join("data/debug.log").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_not_found() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("nonexistent");
+        let dest = temp_dir.path().join("dest");
+
+        let opts = Options::new();
+        let result = clone_tree(&src, &dest, &opts);
+
+        assert!(matches!(result, Err(Error::SourceNotFound { .. })));
+    }
+
+    #[test]
+    fn test_source_not_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("file.txt");
+        let dest = temp_dir.path().join("dest");
+
+        // Create source as a file, not a directory
+        fs::write(&src, "content").unwrap();
+
+        let opts = Options::new();
+        let result = clone_tree(&src, &dest, &opts);
+
+        assert!(matches!(result, Err(Error::SourceNotDirectory { .. })));
+    }
+
+    #[test]
+    fn test_destination_exists() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        // Create both source and destination directories
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+
+        let opts = Options::new();
+        let result = clone_tree(&src, &dest, &opts);
+
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+    }
+
+    #[test]
+    fn test_overwrite_existing_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        // Create source structure
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file1.txt"), "new_content1")?;
+        fs::write(src.join("file2.txt"), "new_content2")?;
+        fs::create_dir(src.join("subdir"))?;
+        fs::write(src.join("subdir/file3.txt"), "new_content3")?;
+
+        // Create destination with some existing files
+        fs::create_dir_all(&dest)?;
+        fs::write(dest.join("file1.txt"), "old_content1")?;
+        fs::write(dest.join("existing_file.txt"), "should_remain")?;
+        fs::create_dir(dest.join("subdir"))?;
+        fs::write(dest.join("subdir/file3.txt"), "old_content3")?;
+        fs::write(dest.join("subdir/existing_file.txt"), "should_remain")?;
+
+        // Clone with overwrite enabled
+        let opts = Options::new().overwrite(true);
+        clone_tree(&src, &dest, &opts)?;
+
+        // Verify overwrites happened
+        assert_eq!(fs::read_to_string(dest.join("file1.txt"))?, "new_content1");
+        assert_eq!(fs::read_to_string(dest.join("file2.txt"))?, "new_content2");
+        assert_eq!(
+            fs::read_to_string(dest.join("subdir/file3.txt"))?,
+            "new_content3"
+        );
+
+        // Verify existing files that weren't in source remain untouched
+        assert_eq!(
+            fs::read_to_string(dest.join("existing_file.txt"))?,
+            "should_remain"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("subdir/existing_file.txt"))?,
+            "should_remain"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_skips_unchanged() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("same.txt"), "same")?;
+        fs::write(src.join("changed.txt"), "new")?;
+
+        let opts = Options::new().preserve_times(true);
+        clone_tree(&src, &dest, &opts)?;
+
+        // Same size and mtime as the source, but different content: a
+        // metadata comparison treats this as unchanged
+        fs::write(dest.join("same.txt"), "SAME")?;
+        let mtime = fs::metadata(src.join("same.txt"))?.modified()?;
+        filetime::set_file_mtime(
+            dest.join("same.txt"),
+            filetime::FileTime::from_system_time(mtime),
+        )?;
+        fs::write(dest.join("changed.txt"), "older")?;
+
+        let opts = Options::new().overwrite(true).update(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("same.txt"))?, "SAME");
+        assert_eq!(fs::read_to_string(dest.join("changed.txt"))?, "new");
+
+        // A hash comparison catches the content change
+        let opts = opts.compare(Compare::Hash);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("same.txt"))?, "same");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_archive_options() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("run.sh"), "#!/bin/sh")?;
+        fs::set_permissions(src.join("run.sh"), fs::Permissions::from_mode(0o4700))?;
+        std::os::unix::fs::symlink("run.sh", src.join("link"))?;
+        let mode = || -> Result<u32> {
+            Ok(fs::metadata(dest.join("run.sh"))?.permissions().mode() & 0o7777)
+        };
+
+        // Symlinks are skipped by default, and copies never carry setuid
+        clone_tree(&src, &dest, &Options::new())?;
+        assert!(dest.join("link").symlink_metadata().is_err());
+        assert_eq!(mode()?, 0o700);
+
+        let opts = Options::new()
+            .overwrite(true)
+            .symlinks(true)
+            .preserve_permissions(true)
+            .preserve_ownership(true)
+            .preserve_xattrs(true)
+            .preserve_times(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_link(dest.join("link"))?, Path::new("run.sh"));
+        assert_eq!(mode()?, 0o700);
+
+        // Existing symlinks are replaced rather than followed
+        let opts = opts.preserve_special_bits(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_link(dest.join("link"))?, Path::new("run.sh"));
+        assert_eq!(mode()?, 0o4700);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserve_capabilities() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("ping"), "binary")?;
+
+        // A version 2 capability set granting an effective CAP_NET_RAW
+        let mut cap = Vec::new();
+        for word in [0x0200_0001u32, 1 << 13, 0, 0, 0] {
+            cap.extend_from_slice(&word.to_le_bytes());
+        }
+        if xattr::set(src.join("ping"), "security.capability", &cap).is_err() {
+            // Needs root and a filesystem with security xattrs
+            return Ok(());
+        }
+        let caps = || xattr::get(dest.join("ping"), "security.capability");
+
+        clone_tree(&src, &dest, &Options::new())?;
+        assert_eq!(caps()?, None);
+
+        // Kept even though the owner is set after the xattrs are copied
+        let opts = Options::new()
+            .overwrite(true)
+            .preserve_xattrs(true)
+            .preserve_ownership(true)
+            .preserve_capabilities(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(caps()?, Some(cap));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nanosecond_times() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        // A multiple of 100ns, which is as fine as NTFS goes
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 123_456_700);
+        filetime::set_file_mtime(src.join("a.txt"), mtime)?;
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("a.txt", src.join("link"))?;
+            filetime::set_symlink_file_times(src.join("link"), mtime, mtime)?;
+        }
+        let dest_mtime = |name: &str| -> Result<filetime::FileTime> {
+            let metadata = fs::symlink_metadata(dest.join(name))?;
+            Ok(filetime::FileTime::from_last_modification_time(&metadata))
+        };
+
+        let opts = Options::new().symlinks(true).preserve_times(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(dest_mtime("a.txt")?, mtime);
+        #[cfg(unix)]
+        assert_eq!(dest_mtime("link")?, mtime);
+
+        // Exact times let update mode skip the file
+        let report = opts
+            .overwrite(true)
+            .update(true)
+            .build()?
+            .clone_tree(&src, &dest)?;
+        assert_eq!(report.skipped, 1);
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_attributes() -> Result<()> {
+        use std::os::windows::fs::MetadataExt;
+        // Hidden and read-only
+        const ATTRIBUTES: u32 = 0x2 | 0x1;
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("desktop.ini"), "[.ShellClassInfo]")?;
+        std::process::Command::new("attrib")
+            .args(["+h", "+r"])
+            .arg(src.join("desktop.ini"))
+            .status()?;
+        let attributes = |root: &Path| -> Result<u32> {
+            Ok(fs::metadata(root.join("desktop.ini"))?.file_attributes() & ATTRIBUTES)
+        };
+        assert_eq!(attributes(&src)?, ATTRIBUTES);
+
+        clone_tree(&src, &dest, &Options::new().preserve_permissions(true))?;
+        assert_eq!(attributes(&dest)?, ATTRIBUTES);
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_preserve_acls() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("secret.txt"), "secret")?;
+        // Stop inheriting, keeping a copy of the inherited entries, and let
+        // everyone read
+        let icacls = |path: &Path, args: &[&str]| -> Result<String> {
+            let output = std::process::Command::new("icacls")
+                .arg(path)
+                .args(args)
+                .output()?;
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+        icacls(
+            &src.join("secret.txt"),
+            &["/inheritance:d", "/grant", "*S-1-1-0:R"],
+        )?;
+        // The listing starts with the path, so only the entries are compared
+        let entries = |root: &Path| -> Result<String> {
+            let listing = icacls(&root.join("secret.txt"), &[])?;
+            let path = root.join("secret.txt");
+            Ok(listing.replace(&*path.to_string_lossy(), ""))
+        };
+
+        clone_tree(&src, &dest, &Options::new().preserve_acls(true))?;
+        assert_eq!(entries(&dest)?, entries(&src)?);
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_preserve_streams() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("setup.exe"), "binary")?;
+        fs::write(src.join("setup.exe:Zone.Identifier"), "[ZoneTransfer]")?;
+
+        // Plain copies drop the stream
+        clone_tree(&src, &dest, &Options::new())?;
+        assert!(fs::read(dest.join("setup.exe:Zone.Identifier")).is_err());
+
+        fs::remove_dir_all(&dest)?;
+        clone_tree(&src, &dest, &Options::new().preserve_streams(true))?;
+        assert_eq!(fs::read_to_string(dest.join("setup.exe"))?, "binary");
+        assert_eq!(
+            fs::read_to_string(dest.join("setup.exe:Zone.Identifier"))?,
+            "[ZoneTransfer]"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_birth_time() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("old.txt"), "old")?;
+
+        // Created later than the source, so only a preserved time matches
+        std::thread::sleep(Duration::from_millis(20));
+        clone_tree(&src, &dest, &Options::new().preserve_birth_time(true))?;
+        if cfg!(any(target_os = "macos", windows)) {
+            assert_eq!(
+                fs::metadata(dest.join("old.txt"))?.created()?,
+                fs::metadata(src.join("old.txt"))?.created()?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserve_inode_flags() -> Result<()> {
+        const FS_APPEND_FL: u32 = 0x20;
+        const FS_NODUMP_FL: u32 = 0x40;
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("log"), "line")?;
+        let flags = FS_APPEND_FL | FS_NODUMP_FL;
+        if inode_flags::set(&src.join("log"), flags, flags).is_err() {
+            // Needs root and a filesystem with inode flags
+            return Ok(());
+        }
+        let dest_flags = || -> Result<u32> { Ok(inode_flags::get(&dest.join("log"))? & flags) };
+
+        clone_tree(&src, &dest, &Options::new())?;
+        assert_eq!(dest_flags()?, 0);
+
+        // Append-only is set after the times, which it would otherwise block
+        fs::remove_dir_all(&dest)?;
+        let opts = Options::new()
+            .preserve_times(true)
+            .preserve_inode_flags(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(dest_flags()?, flags);
+
+        // Cleared so the temporary directory can be removed
+        inode_flags::set(&src.join("log"), 0, flags)?;
+        inode_flags::set(&dest.join("log"), 0, flags)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_hardlinks() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "shared")?;
+        fs::hard_link(src.join("a.txt"), src.join("sub/b.txt"))?;
+        fs::write(src.join("c.txt"), "alone")?;
+        let inode = |path: PathBuf| -> Result<u64> { Ok(fs::metadata(path)?.ino()) };
+
+        let dest = temp_dir.path().join("copied");
+        clone_tree(&src, &dest, &Options::new())?;
+        assert_ne!(inode(dest.join("a.txt"))?, inode(dest.join("sub/b.txt"))?);
+
+        let dest = temp_dir.path().join("linked");
+        let opts = Options::new()
+            .preserve(Preserve::HARDLINKS | Preserve::TIMES)
+            .threads(1);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert_eq!((report.hardlinked, report.files()), (1, 3));
+        assert_eq!(inode(dest.join("a.txt"))?, inode(dest.join("sub/b.txt"))?);
+        assert_ne!(inode(dest.join("a.txt"))?, inode(src.join("a.txt"))?);
+        assert_eq!(fs::read_to_string(dest.join("sub/b.txt"))?, "shared");
+        Ok(())
+    }
+
+    #[test]
+    fn test_threads() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        for dir in 0..10 {
+            fs::create_dir_all(src.join(format!("d{dir}")))?;
+            for file in 0..10 {
+                fs::write(src.join(format!("d{dir}/f{file}")), format!("{dir}/{file}"))?;
+            }
+        }
+
+        let files = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let opts = Options::new().threads(4).dedup_source(true).on_progress({
+            let files = files.clone();
+            move |p| {
+                files.fetch_max(p.files_done, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(files.load(std::sync::atomic::Ordering::Relaxed), 100);
+        assert!(diff_trees(&src, &dest, &Options::new().compare(Compare::Hash))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("b.txt"), "b")?;
+        // A directory where a file should go can't be replaced
+        fs::create_dir_all(dest.join("a.txt/sub"))?;
+
+        let opts = Options::new().overwrite(true).sorted(true);
+        let result = clone_tree(&src, &dest, &opts);
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(!dest.join("b.txt").exists());
+
+        let result = clone_tree(&src, &dest, &opts.keep_going(true));
+        match result {
+            Err(Error::Incomplete { errors }) => assert_eq!(errors.len(), 1),
+            other => panic!("expected Incomplete, got {other:?}"),
+        }
+        assert_eq!(fs::read_to_string(dest.join("b.txt"))?, "b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_tree_with_report() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("b.txt"), "b")?;
+        fs::create_dir_all(dest.join("a.txt/sub"))?;
+
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let opts = Options::new()
+            .overwrite(true)
+            .sorted(true)
+            .threads(1)
+            .on_progress({
+                let calls = calls.clone();
+                move |_| {
+                    calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            });
+
+        // The counts survive the error that stops the clone
+        let report = clone_tree_with_report(&src, &dest, &opts);
+        assert!(!report.is_complete());
+        assert!(matches!(report.errors[..], [Error::Io(_)]));
+        assert_eq!(report.summary.files(), 0);
+
+        let report = clone_tree_with_report(&src, &dest, &opts.keep_going(true));
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.summary.files(), 1);
+        let paths: Vec<_> = report.files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("b.txt")]);
+        assert!(report.warnings.is_empty());
+        // The caller's own callback still sees each file
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        fs::remove_dir_all(dest.join("a.txt"))?;
+        let report = clone_tree_with_report(&src, &dest, &Options::new().overwrite(true));
+        assert!(report.is_complete());
+        assert_eq!(report.summary.files(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_conflict() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("keep.txt"), "new")?;
+        fs::write(src.join("replace.txt"), "new")?;
+        fs::create_dir_all(&dest)?;
+        fs::write(dest.join("keep.txt"), "old")?;
+        fs::write(dest.join("replace.txt"), "old")?;
+
+        let opts = Options::new().overwrite(true).on_conflict(|c| {
+            if c.path == Path::new("keep.txt") {
+                Resolution::Skip
+            } else {
+                Resolution::Overwrite
+            }
+        });
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("keep.txt"))?, "old");
+        assert_eq!(fs::read_to_string(dest.join("replace.txt"))?, "new");
+
+        // Aborting stops the clone even when keeping going after errors
+        let opts = opts.keep_going(true).on_conflict(|_| Resolution::Abort);
+        assert!(matches!(
+            clone_tree(&src, &dest, &opts),
+            Err(Error::Cancelled)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_files_list() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("sub/c.log"), "c")?;
+        fs::write(src.join("unlisted.txt"), "d")?;
+
+        let opts = Options::new()
+            .files(["./a.txt", "sub/b.txt", "sub/c.log", "sub", "missing.txt"])
+            .glob("!*.log");
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("a.txt").exists());
+        assert!(dest.join("sub/b.txt").exists());
+        assert!(!dest.join("sub/c.log").exists());
+        assert!(!dest.join("unlisted.txt").exists());
+
+        let opts = Options::new().files(["../escape.txt"]);
+        let mut entries = clone_entries(&src, &opts);
+        assert!(matches!(entries.next(), Some(Err(Error::Other(_)))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_filters() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("small"), "1")?;
+        fs::write(src.join("medium"), "12345")?;
+        fs::write(src.join("large"), "1234567890")?;
+
+        clone_tree(&src, &dest, &Options::new().min_size(2).max_size(5))?;
+        assert!(!dest.join("small").exists());
+        assert!(dest.join("medium").exists());
+        assert!(!dest.join("large").exists());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_record_skipped() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("logs"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("big.txt"), "1234567890")?;
+        fs::write(src.join("logs/1.log"), "log")?;
+        fs::write(src.join("logs/2.log"), "log")?;
+        std::os::unix::fs::symlink("a.txt", src.join("link"))?;
+
+        let opts = Options::new()
+            .glob("!logs")
+            .max_size(5)
+            .preserve_times(true)
+            .record_skipped(true);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert_eq!((report.copied + report.reflinked, report.skipped), (1, 0));
+        assert_eq!(report.bytes(), 1);
+        let skipped: Vec<_> = report
+            .skipped_entries
+            .iter()
+            .map(|s| (s.path.to_str().unwrap(), s.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                ("big.txt", SkipReason::Filtered),
+                ("link", SkipReason::NotRegular),
+                ("logs", SkipReason::Excluded),
+            ]
+        );
+
+        // Unchanged files count as skipped, and nothing is listed by default
+        let opts = opts.record_skipped(false).overwrite(true).update(true);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert_eq!(report.skipped, 1);
+        assert!(report.skipped_entries.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_breakdown() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("logs/old"))?;
+        fs::write(src.join("README"), "readme")?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("logs/1.log"), "12")?;
+        fs::write(src.join("logs/old/2.log"), "123")?;
+
+        let opts = Options::new().record_breakdown(true);
+        let report = opts
+            .build()?
+            .clone_tree(&src, temp_dir.path().join("dest"))?;
+        let tally = |files, bytes| Tally { files, bytes };
+        assert_eq!(
+            report.by_directory,
+            [
+                (PathBuf::new(), tally(2, 7)),
+                (PathBuf::from("logs"), tally(2, 5))
+            ]
+            .into()
+        );
+        assert_eq!(
+            report.by_extension,
+            [
+                (String::new(), tally(1, 6)),
+                ("log".to_string(), tally(2, 5)),
+                ("txt".to_string(), tally(1, 1))
+            ]
+            .into()
+        );
+
+        let report = Options::new()
+            .build()?
+            .clone_tree(&src, temp_dir.path().join("plain"))?;
+        assert!(report.by_directory.is_empty() && report.by_extension.is_empty());
+        assert!(report.largest_files.is_empty());
+
+        let opts = Options::new().record_largest(2);
+        let report = opts
+            .build()?
+            .clone_tree(&src, temp_dir.path().join("largest"))?;
+        let largest: Vec<_> = report
+            .largest_files
+            .iter()
+            .map(|f| (f.path.to_str().unwrap(), f.size))
+            .collect();
+        assert_eq!(largest, vec![("README", 6), ("logs/old/2.log", 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), "1234")?;
+        }
+
+        let dest = temp_dir.path().join("files");
+        let opts = Options::new().sorted(true).max_files(2).keep_going(true);
+        let result = clone_tree(&src, &dest, &opts);
+        assert!(matches!(
+            result,
+            Err(Error::QuotaExceeded {
+                quota: Quota::Files,
+                limit: 2
+            })
+        ));
+        assert!(dest.join("a").exists() && dest.join("b").exists());
+        assert!(!dest.join("c").exists());
+
+        let dest = temp_dir.path().join("bytes");
+        let result = clone_tree(&src, &dest, &Options::new().max_total_bytes(10));
+        assert!(matches!(result, Err(Error::QuotaExceeded { .. })));
+        assert_eq!(fs::read_dir(&dest)?.count(), 2);
+
+        clone_tree(&src, temp_dir.path().join("fits"), &opts.max_files(3))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_created_dirs() {
+        let mut dirs = CreatedDirs::new(Path::new("/dest"));
+        assert!(dirs.contains(Path::new("/dest")));
+        assert!(!dirs.contains(Path::new("/dest/a")));
+
+        // Ancestors of the last directory created exist too
+        dirs.insert(Path::new("/dest/a/b"));
+        assert!(dirs.contains(Path::new("/dest/a")));
+        assert!(!dirs.contains(Path::new("/dest/c")));
+        dirs.insert(Path::new("/dest/a"));
+        assert!(dirs.contains(Path::new("/dest/a/b")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_data_within() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let fifo = temp_dir.path().join("fifo");
+        let dest = temp_dir.path().join("dest");
+        // Reading a FIFO blocks until something opens it for writing
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status()?;
+        assert!(status.success());
+
+        let timeout = Duration::from_millis(50);
+        let settings = Options::new().reflink(Reflink::Never).copy_settings();
+        let result = copy_data_within(&fifo, &dest, &settings, timeout);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        // Let the abandoned copy finish
+        drop(fs::OpenOptions::new().write(true).open(&fifo)?);
+
+        fs::write(temp_dir.path().join("file"), "data")?;
+        let copied = copy_data_within(
+            &temp_dir.path().join("file"),
+            &dest.with_extension("copy"),
+            &settings,
+            Duration::from_secs(10),
+        )?;
+        assert_eq!(
+            copied,
+            Copied::Streamed {
+                bytes: 4,
+                digest: None
+            }
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_on_warning() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "a")?;
+        let status = std::process::Command::new("mkfifo")
+            .arg(src.join("pipe"))
+            .status()?;
+        assert!(status.success());
+
+        let warnings = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let opts = Options::new().on_warning({
+            let warnings = warnings.clone();
+            move |w| {
+                warnings
+                    .lock()
+                    .unwrap()
+                    .push((w.path.to_path_buf(), w.kind))
+            }
+        });
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("a.txt").exists());
+        assert!(!dest.join("pipe").exists());
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            vec![(PathBuf::from("pipe"), WarningKind::SpecialFile)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_filters() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("old"), "old")?;
+        fs::write(src.join("new"), "new")?;
+
+        let day = std::time::Duration::from_secs(86400);
+        let week_ago = SystemTime::now() - 7 * day;
+        filetime::set_file_mtime(
+            src.join("old"),
+            filetime::FileTime::from_system_time(week_ago - day),
+        )?;
+
+        let newer = temp_dir.path().join("newer");
+        clone_tree(&src, &newer, &Options::new().newer_than(week_ago))?;
+        assert!(newer.join("new").exists());
+        assert!(!newer.join("old").exists());
+
+        let older = temp_dir.path().join("older");
+        clone_tree(&src, &older, &Options::new().older_than(week_ago))?;
+        assert!(!older.join("new").exists());
+        assert!(older.join("old").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_unicode() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("cafe\u{301}"))?;
+        fs::write(src.join("cafe\u{301}/re\u{301}sume\u{301}.txt"), "cv")?;
+
+        let dest = temp_dir.path().join("dest");
+        let options = Options::new().normalize_unicode(Normalization::Nfc);
+        clone_tree(&src, &dest, &options)?;
+        assert_eq!(
+            fs::read_to_string(dest.join("caf\u{e9}/r\u{e9}sum\u{e9}.txt"))?,
+            "cv"
+        );
+        assert!(!dest.join("cafe\u{301}").exists());
+
+        // Both spellings in the source would land on the same destination name
+        fs::create_dir_all(src.join("caf\u{e9}"))?;
+        fs::write(src.join("caf\u{e9}/r\u{e9}sum\u{e9}.txt"), "other")?;
+        let result = clone_tree(&src, temp_dir.path().join("clash"), &options);
+        assert!(matches!(result, Err(Error::NameCollision { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_names() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("12:30"))?;
+        fs::write(src.join("12:30/notes?.txt"), "notes")?;
+        fs::write(src.join("aux.c"), "aux")?;
+        fs::write(src.join("plain.txt"), "plain")?;
+
+        let changes = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let record = {
+            let changes = changes.clone();
+            move |s: &Sanitized| {
+                let renamed = s.renamed.map(Path::to_path_buf);
+                changes
+                    .lock()
+                    .unwrap()
+                    .push((s.path.to_path_buf(), renamed));
+            }
+        };
+        let options = Options::new()
+            .sorted(true)
+            .sanitize_names(Sanitize::Replace)
+            .on_sanitize(record);
+        let dest = temp_dir.path().join("replaced");
+        clone_tree(&src, &dest, &options)?;
+        assert_eq!(fs::read_to_string(dest.join("12_30/notes_.txt"))?, "notes");
+        assert_eq!(fs::read_to_string(dest.join("aux_.c"))?, "aux");
+        assert!(dest.join("plain.txt").exists());
+        assert_eq!(changes.lock().unwrap().len(), 2);
+
+        let dest = temp_dir.path().join("skipped");
+        clone_tree(&src, &dest, &Options::new().sanitize_names(Sanitize::Skip))?;
+        assert!(dest.join("plain.txt").exists());
+        assert!(!dest.join("aux.c").exists());
+        assert!(!dest.join("12:30").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_long_paths() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("short.txt"), "short")?;
+        fs::write(src.join("n".repeat(250)), "long")?;
+        // Deep enough that only the long name goes past PATH_MAX
+        let mut parent = temp_dir.path().to_path_buf();
+        while parent.as_os_str().len() < 3700 {
+            parent.push("d".repeat(200));
+        }
+        parent.push("d".repeat(3900 - parent.as_os_str().len()));
+        fs::create_dir_all(&parent)?;
+
+        let dest = parent.join("error");
+        let result = clone_tree(&src, &dest, &Options::new().sorted(true));
+        assert!(matches!(result, Err(Error::PathTooLong { .. })));
+
+        let warnings = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let options = Options::new()
+            .long_paths(LongPaths::Skip)
+            .record_skipped(true)
+            .on_warning({
+                let warnings = warnings.clone();
+                move |w| warnings.lock().unwrap().push(w.kind)
+            });
+        let dest = parent.join("skip");
+        let report = options.build()?.clone_tree(&src, &dest)?;
+        assert_eq!(fs::read_to_string(dest.join("short.txt"))?, "short");
+        assert_eq!(report.skipped_entries.len(), 1);
+        assert_eq!(report.skipped_entries[0].reason, SkipReason::PathTooLong);
+        assert_eq!(*warnings.lock().unwrap(), vec![WarningKind::PathTooLong]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_gitignore() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join(".git/info"))?;
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join(".gitignore"), "*.log\n")?;
+        fs::write(src.join(".git/info/exclude"), "secret.txt\n")?;
+        fs::write(src.join("sub/a.txt"), "a")?;
+        fs::write(src.join("sub/a.log"), "log")?;
+        fs::write(src.join("secret.txt"), "secret")?;
+
+        let dest = temp_dir.path().join("plain");
+        clone_tree(&src, &dest, &Options::new())?;
+        assert!(dest.join("sub/a.log").exists());
+
+        let dest = temp_dir.path().join("gitignore");
+        clone_tree(&src, &dest, &Options::new().gitignore(true))?;
+        assert!(dest.join("sub/a.txt").exists());
+        assert!(!dest.join("sub/a.log").exists());
+        assert!(dest.join("secret.txt").exists());
+
+        let dest = temp_dir.path().join("exclude");
+        let opts = Options::new().gitignore(true).git_exclude(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert!(!dest.join("sub/a.log").exists());
+        assert!(!dest.join("secret.txt").exists());
+
+        let result = Options::new().git_exclude(true).build();
+        assert!(matches!(result, Err(Error::InvalidOptions { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(windows, feature = "filter"))]
+    fn test_junctions() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(src.join("real"))?;
+        fs::create_dir_all(&outside)?;
+        fs::write(src.join("real/a.txt"), "a")?;
+        fs::write(outside.join("b.txt"), "b")?;
+        junction::create(&src.join("real"), &src.join("inner"))?;
+        junction::create(&outside, &src.join("outer"))?;
+        // Leads back to the root, so is never followed
+        junction::create(&src, &src.join("real/up"))?;
+
+        let dest = temp_dir.path().join("skip");
+        let report = Options::new()
+            .junctions(Junctions::Skip)
+            .build()?
+            .clone_tree(&src, &dest)?;
+        assert_eq!(report.files(), 1);
+        assert!(dest.join("real/a.txt").exists());
+        assert!(dest.join("inner").symlink_metadata().is_err());
+
+        // A target inside the tree moves to the destination
+        let dest = temp_dir.path().join("junction");
+        clone_tree(&src, &dest, &Options::new().junctions(Junctions::Junction))?;
+        assert!(junction::is_junction(&dest.join("inner")));
+        let dest_root = longpath::extended(&dest);
+        assert_eq!(fs::read_link(dest.join("inner"))?, dest_root.join("real"));
+
+        let dest = temp_dir.path().join("follow");
+        clone_tree(&src, &dest, &Options::new().junctions(Junctions::Follow))?;
+        assert!(!junction::is_junction(&dest.join("inner")));
+        assert_eq!(fs::read_to_string(dest.join("inner/a.txt"))?, "a");
+        assert_eq!(fs::read_to_string(dest.join("outer/b.txt"))?, "b");
+        assert!(dest.join("real/up").symlink_metadata().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_dir_links() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("real"))?;
+        fs::write(src.join("real/a.txt"), "a")?;
+        if std::os::windows::fs::symlink_dir("real", src.join("link")).is_err() {
+            // Without the privilege there's no directory symlink to clone
+            return Ok(());
+        }
+        let options = Options::new().symlinks(true);
+
+        let dest = temp_dir.path().join("junction");
+        clone_tree(&src, &dest, &options.clone().dir_links(DirLinks::Junction))?;
+        assert!(junction::is_junction(&dest.join("link")));
+        assert_eq!(fs::read_to_string(dest.join("link/a.txt"))?, "a");
+
+        // The privilege is held here, so auto makes a symlink
+        let dest = temp_dir.path().join("auto");
+        clone_tree(&src, &dest, &options.dir_links(DirLinks::Auto))?;
+        assert!(!junction::is_junction(&dest.join("link")));
+        assert_eq!(fs::read_link(dest.join("link"))?, Path::new("real"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_submodules() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join(".git"))?;
+        fs::create_dir_all(src.join("lib/.git/info"))?;
+        fs::write(src.join(".gitignore"), "*.log\n")?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("a.log"), "log")?;
+        fs::write(src.join("lib/.gitignore"), "*.tmp\n")?;
+        fs::write(src.join("lib/.git/info/exclude"), "secret\n")?;
+        fs::write(src.join("lib/b.txt"), "b")?;
+        fs::write(src.join("lib/b.log"), "log")?;
+        fs::write(src.join("lib/b.tmp"), "tmp")?;
+        fs::write(src.join("lib/secret"), "secret")?;
+
+        let dest = temp_dir.path().join("skip");
+        let opts = Options::new()
+            .submodules(Submodules::Skip)
+            .record_skipped(true);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert!(dest.join("a.txt").exists());
+        assert!(!dest.join("lib").exists());
+        assert_eq!(
+            report.skipped_entries,
+            vec![SkippedEntry {
+                path: PathBuf::from("lib"),
+                reason: SkipReason::Submodule,
+            }]
+        );
+
+        // The superproject's ignore files stop at the submodule, which has
+        // its own
+        let dest = temp_dir.path().join("separate");
+        let opts = Options::new()
+            .gitignore(true)
+            .submodules(Submodules::Separate);
+        clone_tree(&src, &dest, &opts)?;
+        assert!(!dest.join("a.log").exists());
+        assert!(dest.join("lib/b.txt").exists());
+        assert!(dest.join("lib/b.log").exists());
+        assert!(!dest.join("lib/b.tmp").exists());
+        assert!(!dest.join("lib/secret").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "filter"))]
+    fn test_share_git_objects() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(src.join(".git/objects/ab"))?;
+        fs::write(src.join(".git/objects/ab/cdef"), "object")?;
+        fs::write(src.join(".git/HEAD"), "ref: refs/heads/main")?;
+        fs::write(src.join("a.txt"), "a")?;
+
+        // Objects are shared even though the globs leave out .git
+        let opts = Options::new()
+            .glob("!.git")
+            .share_git_objects(true)
+            .preserve_times(true);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert_eq!((report.hardlinked, report.files()), (1, 2));
+        assert!(!dest.join(".git/HEAD").exists());
+        assert_eq!(
+            fs::metadata(dest.join(".git/objects/ab/cdef"))?.ino(),
+            fs::metadata(src.join(".git/objects/ab/cdef"))?.ino()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_outside_destination() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::create_dir_all(&dest)?;
+        fs::create_dir_all(&outside)?;
+        fs::write(src.join("sub/a.txt"), "a")?;
+        fs::write(outside.join("a.txt"), "keep")?;
+        std::os::unix::fs::symlink(&outside, dest.join("sub"))?;
+
+        let result = clone_tree(&src, &dest, &Options::new().overwrite(true));
+        assert!(
+            matches!(result, Err(Error::OutsideDestination { path }) if path == dest.join("sub"))
+        );
+        assert_eq!(fs::read_to_string(outside.join("a.txt"))?, "keep");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_newer() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("stale.txt"), "new")?;
+        fs::write(src.join("fresh.txt"), "old")?;
+        fs::create_dir_all(&dest)?;
+        fs::write(dest.join("stale.txt"), "old")?;
+        fs::write(dest.join("fresh.txt"), "newer")?;
+
+        let hour_ago =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() - 3600, 0);
+        filetime::set_file_mtime(dest.join("stale.txt"), hour_ago)?;
+        filetime::set_file_mtime(src.join("fresh.txt"), hour_ago)?;
+
+        let opts = Options::new()
+            .overwrite(true)
+            .update(true)
+            .compare(Compare::Newer);
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("stale.txt"))?, "new");
+        assert_eq!(fs::read_to_string(dest.join("fresh.txt"))?, "newer");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_with_state_cache() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "first")?;
+
+        let opts = Options::new()
+            .overwrite(true)
+            .update(true)
+            .compare(Compare::Hash)
+            .state_cache(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert!(cache::cache_path(&dest).exists());
+        assert!(!dest.join(".dest.clonetree-cache").exists());
+
+        fs::write(src.join("a.txt"), "second")?;
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "second");
+
+        Ok(())
     }
 
     #[test]
-    fn test_overwrite_existing_files() -> Result<()> {
+    #[cfg(feature = "filter")]
+    fn test_delete_extraneous() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("kept"))?;
+        fs::write(src.join("kept/file.txt"), "file")?;
+
+        fs::create_dir_all(dest.join("kept"))?;
+        fs::create_dir_all(dest.join("stale/deep"))?;
+        fs::write(dest.join("kept/extra.txt"), "extra")?;
+        fs::write(dest.join("stale/deep/old.txt"), "old")?;
+        fs::write(dest.join("excluded.log"), "log")?;
+
+        let opts = Options::new().overwrite(true).delete(true).glob("!*.log");
+        clone_tree(&src, &dest, &opts)?;
+
+        assert!(dest.join("kept/file.txt").exists());
+        assert!(!dest.join("kept/extra.txt").exists());
+        assert!(!dest.join("stale").exists());
+        // Files excluded by the filters are not touched
+        assert!(dest.join("excluded.log").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_delete_excluded() -> Result<()> {
         let temp_dir = TempDir::new()?;
         let src = temp_dir.path().join("src");
         let dest = temp_dir.path().join("dest");
 
-        // Create source structure
         fs::create_dir_all(&src)?;
-        fs::write(src.join("file1.txt"), "new_content1")?;
-        fs::write(src.join("file2.txt"), "new_content2")?;
-        fs::create_dir(src.join("subdir"))?;
-        fs::write(src.join("subdir/file3.txt"), "new_content3")?;
+        fs::write(src.join("file.txt"), "file")?;
+        fs::write(src.join("build.log"), "log")?;
+        fs::create_dir_all(dest.join("logs"))?;
+        fs::write(dest.join("build.log"), "old log")?;
+        fs::write(dest.join("logs/old.log"), "old")?;
 
-        // Create destination with some existing files
+        let opts = Options::new()
+            .overwrite(true)
+            .delete(true)
+            .delete_excluded(true)
+            .glob("!*.log");
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("file.txt").exists());
+        assert!(!dest.join("build.log").exists());
+        assert!(!dest.join("logs").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_prune_empty_dirs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("logs"))?;
+        fs::write(src.join("logs/today.log"), "log")?;
+        fs::write(src.join("keep.txt"), "keep")?;
+        fs::create_dir_all(dest.join("logs"))?;
+        fs::write(dest.join("logs/old.txt"), "old")?;
+
+        // The source still has logs/, so without pruning it stays behind
+        let opts = Options::new().overwrite(true).delete(true).glob("!*.log");
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("logs").is_dir());
+        assert!(!dest.join("logs/old.txt").exists());
+
+        fs::write(dest.join("logs/old.txt"), "old")?;
+        clone_tree(&src, &dest, &opts.prune_empty_dirs(true))?;
+        assert!(!dest.join("logs").exists());
+        assert!(dest.join("keep.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_overwrite_replace() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "file")?;
+        fs::create_dir_all(dest.join("stale"))?;
+        fs::write(dest.join("stale/old.txt"), "old")?;
+        fs::write(dest.join("excluded.log"), "log")?;
+
+        // Unlike delete, replacing also clears files the filters exclude
+        let opts = Options::new().overwrite(Overwrite::Replace).glob("!*.log");
+        clone_tree(&src, &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("file.txt"))?, "file");
+        assert!(!dest.join("stale").exists());
+        assert!(!dest.join("excluded.log").exists());
+
+        // A source inside the destination would be deleted
+        let result = clone_tree(&src, temp_dir.path(), &opts);
+        assert!(matches!(result, Err(Error::SourceInDestination { .. })));
+        assert!(src.join("file.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_clobber() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), "new")?;
+        fs::write(src.join("b.txt"), "b")?;
         fs::create_dir_all(&dest)?;
-        fs::write(dest.join("file1.txt"), "old_content1")?;
-        fs::write(dest.join("existing_file.txt"), "should_remain")?;
-        fs::create_dir(dest.join("subdir"))?;
-        fs::write(dest.join("subdir/file3.txt"), "old_content3")?;
-        fs::write(dest.join("subdir/existing_file.txt"), "should_remain")?;
+        fs::write(dest.join("a.txt"), "old")?;
 
-        // Clone with overwrite enabled
+        let opts = Options::new().overwrite(Overwrite::NoClobber).sorted(true);
+        let result = clone_tree(&src, &dest, &opts);
+        assert!(matches!(result, Err(Error::FileExists { path }) if path == dest.join("a.txt")));
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "old");
+
+        let result = clone_tree(&src, &dest, &opts.keep_going(true));
+        match result {
+            Err(Error::Incomplete { errors }) => {
+                assert!(matches!(errors[..], [Error::FileExists { .. }]))
+            }
+            other => panic!("expected Incomplete, got {other:?}"),
+        }
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "old");
+        assert_eq!(fs::read_to_string(dest.join("b.txt"))?, "b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+
+        let opts = Options::new().overwrite(true).backup(Backup::Numbered);
+        for version in ["one", "two", "three"] {
+            fs::write(src.join("file.txt"), version)?;
+            clone_tree(&src, &dest, &opts)?;
+        }
+        assert_eq!(fs::read_to_string(dest.join("file.txt"))?, "three");
+        assert_eq!(fs::read_to_string(dest.join("file.txt.~1~"))?, "one");
+        assert_eq!(fs::read_to_string(dest.join("file.txt.~2~"))?, "two");
+        assert!(!dest.join("file.txt.~3~").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_append() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("app.log"), "start\n")?;
+        clone_tree(&src, &dest, &Options::new())?;
+
+        fs::write(src.join("app.log"), "start\nmore\n")?;
+        let inode = |path: &Path| -> Result<u64> {
+            use std::os::unix::fs::MetadataExt;
+            Ok(fs::metadata(path)?.ino())
+        };
+        let before = inode(&dest.join("app.log"))?;
+        clone_tree(&src, &dest, &Options::new().overwrite(true).append(true))?;
+        assert_eq!(fs::read_to_string(dest.join("app.log"))?, "start\nmore\n");
+        // Appended in place rather than replaced
+        assert_eq!(inode(&dest.join("app.log"))?, before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "contents")?;
+
+        let opts = Options::new().verify(true).hasher(Crc32c);
+        let report = opts.build()?.clone_tree(&src, &dest)?;
+        assert_eq!(report.files(), 1);
+
+        let copy = dest.join("file.txt");
+        fs::write(&copy, "corrupt!")?;
+        assert!(verify_copy(&src.join("file.txt"), &copy, None, &opts).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_source() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "same")?;
+        fs::write(src.join("sub/b.txt"), "same")?;
+        fs::write(src.join("c.txt"), "diff")?;
+
+        let opts = Options::new().dedup_source(true);
+        clone_tree(&src, &dest, &opts)?;
+
+        assert_eq!(fs::read_to_string(dest.join("a.txt"))?, "same");
+        assert_eq!(fs::read_to_string(dest.join("sub/b.txt"))?, "same");
+        assert_eq!(fs::read_to_string(dest.join("c.txt"))?, "diff");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_is_excluded() -> Result<()> {
+        let opts = Options::new().glob("**/*.rs").glob("!target/**");
+        let globs = build_globs(&opts)?;
+        let globs = globs.as_ref();
+
+        assert!(!is_excluded(globs, Path::new("main.rs")));
+        assert!(!is_excluded(globs, Path::new("src/lib.rs")));
+        assert!(is_excluded(globs, Path::new("README.md")));
+        assert!(is_excluded(globs, Path::new("target/debug/build.rs")));
+        assert!(!is_excluded(None, Path::new("README.md")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_source() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "content")?;
+
+        // Other shared lockers do not block the copy
+        let reader = fs::File::open(src.join("file.txt"))?;
+        reader.lock_shared()?;
+
+        clone_tree(&src, &dest, &Options::new().lock_source(true))?;
+        assert_eq!(fs::read_to_string(dest.join("file.txt"))?, "content");
+
+        // The clone released its lock, so an exclusive lock is available
+        reader.unlock()?;
+        assert!(fs::File::open(src.join("file.txt"))?.try_lock().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_destination_locked() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "content")?;
+        fs::create_dir_all(&dest)?;
+
+        let held = lock_destination(&dest)?;
         let opts = Options::new().overwrite(true);
+        let result = clone_tree(&src, &dest, &opts);
+        assert!(matches!(result, Err(Error::DestinationLocked { .. })));
+        assert!(!dest.join("file.txt").exists());
+
+        drop(held);
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("file.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflink_never() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "content")?;
+
+        clone_tree(&src, &dest, &Options::new().reflink(Reflink::Never))?;
+        assert_eq!(fs::read_to_string(dest.join("file.txt"))?, "content");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_progress() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "12345")?;
+        fs::write(src.join("sub/b.txt"), "123")?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let opts = Options::new().sorted(true).on_progress(move |p| {
+            recorder
+                .lock()
+                .unwrap()
+                .push((p.files_done, p.bytes_done, p.path.to_path_buf()));
+        });
         clone_tree(&src, &dest, &opts)?;
 
-        // Verify overwrites happened
-        assert_eq!(fs::read_to_string(dest.join("file1.txt"))?, "new_content1");
-        assert_eq!(fs::read_to_string(dest.join("file2.txt"))?, "new_content2");
         assert_eq!(
-            fs::read_to_string(dest.join("subdir/file3.txt"))?,
-            "new_content3"
+            *seen.lock().unwrap(),
+            vec![
+                (1, 5, PathBuf::from("a.txt")),
+                (2, 8, PathBuf::from("sub/b.txt"))
+            ]
         );
 
-        // Verify existing files that weren't in source remain untouched
+        // Totals are only known when counted first
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let recorder = totals.clone();
+        let opts = Options::new().overwrite(true).on_progress(move |p| {
+            recorder
+                .lock()
+                .unwrap()
+                .push((p.files_total, p.bytes_total));
+        });
+        clone_tree(&src, &dest, &opts)?;
+        clone_tree(&src, &dest, &opts.count_first(true))?;
         assert_eq!(
-            fs::read_to_string(dest.join("existing_file.txt"))?,
-            "should_remain"
+            *totals.lock().unwrap(),
+            vec![
+                (None, None),
+                (None, None),
+                (Some(2), Some(8)),
+                (Some(2), Some(8))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_progress_threads() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        for i in 0..200 {
+            fs::write(src.join(format!("{i}.txt")), "x".repeat(i % 17 + 1))?;
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let opts = Options::new().threads(8).on_progress(move |p| {
+            // Uneven delays let later files overtake earlier ones, unless
+            // the callbacks are kept in order
+            std::thread::sleep(Duration::from_micros(p.files_done % 5 * 200));
+            recorder
+                .lock()
+                .unwrap()
+                .push((p.files_done, p.bytes_done, p.bytes));
+        });
+        clone_tree(&src, temp_dir.path().join("dest"), &opts)?;
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 200);
+        assert!(seen.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        // Each file's own size adds up to the running total
+        assert_eq!(seen.iter().map(|p| p.2).sum::<u64>(), seen[199].1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_walk_order() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+
+        fs::create_dir_all(src.join("b"))?;
+        fs::create_dir_all(src.join("a"))?;
+        for name in ["c.txt", "a.txt", "b/z.txt", "b/y.txt", "a/x.txt"] {
+            fs::write(src.join(name), name)?;
+        }
+
+        let expected: Vec<PathBuf> = [
+            "", "a", "a/x.txt", "a.txt", "b", "b/y.txt", "b/z.txt", "c.txt",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        #[allow(unused_mut)]
+        let mut backends = vec![WalkerBackend::Standard];
+        #[cfg(feature = "walkdir")]
+        backends.push(WalkerBackend::Walkdir);
+        #[cfg(feature = "jwalk")]
+        backends.push(WalkerBackend::Jwalk);
+        for backend in backends {
+            let opts = Options::new().sorted(true).walker(backend);
+            let paths: Vec<PathBuf> = walk::Walk::new(&src, &opts.build()?, &Skipped::default())
+                .map(|e| e.unwrap().path.strip_prefix(&src).unwrap().to_path_buf())
+                .collect();
+            assert_eq!(paths, expected, "{backend:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_clone_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "12345")?;
+        fs::write(src.join("b.log"), "log")?;
+        fs::write(src.join("sub/c.txt"), "123")?;
+
+        let opts = Options::new().glob("**/*.txt").sorted(true);
+        let entries = clone_entries(&src, &opts).collect::<Result<Vec<_>>>()?;
+        let listed: Vec<(PathBuf, u64)> = entries
+            .iter()
+            .map(|e| (e.relative_path.clone(), e.metadata.len()))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![(PathBuf::from("a.txt"), 5), (PathBuf::from("sub/c.txt"), 3)]
+        );
+        assert_eq!(entries[1].path, src.join("sub/c.txt"));
+
+        // Nothing is written while listing
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_entries_invalid_glob() {
+        let temp_dir = TempDir::new().unwrap();
+        let opts = Options::new().glob("a[");
+        let mut entries = clone_entries(temp_dir.path(), &opts);
+
+        assert!(matches!(
+            entries.next(),
+            Some(Err(Error::InvalidGlob { .. }))
+        ));
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_check_space_passes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("file.txt"), "content")?;
+
+        let opts = Options::new().check_space(true);
+        clone_tree(&src, &dest, &opts)?;
+        assert!(dest.join("file.txt").exists());
+        // The reflink probe must not leave anything behind
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_estimate() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+
+        fs::create_dir_all(src.join("sub/deep"))?;
+        fs::create_dir_all(src.join("logs"))?;
+        fs::write(src.join("a.txt"), "12345")?;
+        fs::write(src.join("sub/deep/b.txt"), "123")?;
+        fs::write(src.join("logs/c.log"), "1234567")?;
+
+        assert_eq!(
+            estimate(&src, &Options::new())?,
+            TreeEstimate {
+                files: 3,
+                dirs: 3,
+                bytes: 15,
+            }
         );
         assert_eq!(
-            fs::read_to_string(dest.join("subdir/existing_file.txt"))?,
-            "should_remain"
+            estimate(&src, &Options::new().glob("**/*.txt"))?,
+            TreeEstimate {
+                files: 2,
+                dirs: 2,
+                bytes: 8,
+            }
         );
 
         Ok(())
@@ -0,0 +1,162 @@
+//! Paths past the Windows `MAX_PATH` limit.
+//!
+//! Win32 calls reject paths longer than 260 characters unless they use the
+//! `\\?\` extended-length form, which deeply nested trees such as
+//! `node_modules` easily exceed. The clone roots are converted once, and
+//! every path joined onto them inherits the prefix.
+//!
+//! Paths can still outgrow what the platform allows, even in that form, and
+//! [`LongPaths`] decides what happens to them.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+/// What happens to a file whose destination path is too long for the
+/// platform, set with [`Options::long_paths`](crate::Options::long_paths).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LongPaths {
+    /// Fail with [`Error::PathTooLong`](crate::Error::PathTooLong), which
+    /// [`Options::keep_going`](crate::Options::keep_going) can carry on past.
+    #[default]
+    Error,
+    /// Leave the file out with a
+    /// [`WarningKind::PathTooLong`](crate::WarningKind::PathTooLong) warning,
+    /// listing it as [`SkipReason::PathTooLong`](crate::SkipReason::PathTooLong).
+    Skip,
+}
+
+/// The longest name a path component can have, in bytes, or UTF-16 units on
+/// Windows.
+const NAME_MAX: usize = 255;
+
+/// The longest path the platform accepts. On Windows that depends on
+/// whether the path has the extended-length prefix.
+#[cfg(windows)]
+fn path_max(path: &Path) -> usize {
+    if path.as_os_str().as_encoded_bytes().starts_with(br"\\?\") {
+        32_767
+    } else {
+        260
+    }
+}
+
+#[cfg(all(unix, target_os = "macos"))]
+fn path_max(_path: &Path) -> usize {
+    1024
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn path_max(_path: &Path) -> usize {
+    4096
+}
+
+/// The length of `name` as the platform counts it.
+fn len(name: &std::ffi::OsStr) -> usize {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        name.encode_wide().count()
+    }
+    #[cfg(not(windows))]
+    {
+        name.len()
+    }
+}
+
+/// Whether `path`, or one of its names, is longer than the platform allows.
+/// The limit counts the terminating NUL.
+pub(crate) fn too_long(path: &Path) -> bool {
+    len(path.as_os_str()) >= path_max(path) || path.iter().any(|name| len(name) > NAME_MAX)
+}
+
+/// `path` in extended-length form on Windows: made absolute, since the
+/// prefix turns off `.` and `..` handling, and prefixed with `\\?\` (or
+/// `\\?\UNC\` for network shares). Elsewhere, and if `path` can't be made
+/// absolute, it is returned unchanged.
+#[cfg(windows)]
+pub(crate) fn extended(path: &Path) -> Cow<'_, Path> {
+    use std::ffi::OsString;
+    use std::path::{Component, PathBuf, Prefix};
+
+    let Ok(absolute) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    let Some(Component::Prefix(prefix)) = absolute.components().next() else {
+        return Cow::Borrowed(path);
+    };
+    let mut extended = match prefix.kind() {
+        Prefix::Disk(_) => {
+            let mut root = OsString::from(r"\\?\");
+            root.push(prefix.as_os_str());
+            PathBuf::from(root)
+        }
+        Prefix::UNC(server, share) => {
+            let mut root = OsString::from(r"\\?\UNC\");
+            root.push(server);
+            root.push(r"\");
+            root.push(share);
+            PathBuf::from(root)
+        }
+        // Already verbatim, or a device path that can't take the prefix
+        _ => return Cow::Owned(absolute),
+    };
+    extended.push(r"\");
+    for component in absolute.components().skip(1) {
+        if let Component::Normal(name) = component {
+            extended.push(name);
+        }
+    }
+    Cow::Owned(extended)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn extended(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_too_long() {
+        assert!(!too_long(Path::new("a/b/c.txt")));
+        assert!(too_long(&Path::new("a").join("n".repeat(256))));
+        let deep = Path::new("d").join(["dir"; 2000].join("/"));
+        assert!(too_long(&deep));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_extended_is_identity() {
+        let path = Path::new("some/relative/../path");
+        assert!(matches!(extended(path), Cow::Borrowed(p) if p == path));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_extended() {
+        assert_eq!(extended(Path::new(r"C:\a\..\b")), Path::new(r"\\?\C:\b"));
+        assert_eq!(
+            extended(Path::new(r"\\server\share\dir")),
+            Path::new(r"\\?\UNC\server\share\dir")
+        );
+        assert_eq!(extended(Path::new(r"\\?\C:\b")), Path::new(r"\\?\C:\b"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_clone_deep_tree() -> crate::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let mut deep = temp_dir.path().join("src");
+        for _ in 0..30 {
+            deep.push("node_modules_dependency");
+        }
+        std::fs::create_dir_all(extended(&deep))?;
+        std::fs::write(extended(&deep.join("index.js")), "x")?;
+
+        let dest = temp_dir.path().join("dest");
+        crate::clone_tree(temp_dir.path().join("src"), &dest, &crate::Options::new())?;
+        Ok(())
+    }
+}
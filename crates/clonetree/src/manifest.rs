@@ -0,0 +1,394 @@
+//! Machine-readable listings of a filtered tree.
+
+use crate::hash::{self, Hasher};
+use crate::{clone_entries, CloneReport, Compare, Error, Options, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A listing of the files in a tree, as taken by [`snapshot`]. It serializes
+/// to JSON with [`to_json`](Self::to_json), for other tools to read.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The [`Hasher::name`] of the algorithm behind the entries' hashes.
+    /// Manifests without one are taken to use [`Blake3`](crate::Blake3).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hasher: Option<String>,
+    /// One entry per file, sorted by path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// One file in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the tree root.
+    pub path: PathBuf,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unix permission bits, where the platform has them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Modification time, stored as nanoseconds since the Unix epoch.
+    #[serde(default, with = "unix_nanos", skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<SystemTime>,
+    /// Hash of the contents in hex, if the snapshot computed one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Where the entry points, if it is a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::InvalidManifest {
+            message: e.to_string(),
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| Error::InvalidManifest {
+            message: e.to_string(),
+        })
+    }
+}
+
+/// The differences between two manifests, as returned by [`Manifest::diff`].
+/// All paths are relative to the tree root and sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ManifestDiff {
+    /// Files present only in the second manifest.
+    pub added: Vec<PathBuf>,
+    /// Files present only in the first manifest.
+    pub removed: Vec<PathBuf>,
+    /// Files present in both whose contents differ.
+    pub modified: Vec<PathBuf>,
+    /// Files present in both with the same contents but different
+    /// permission bits.
+    pub mode_changed: Vec<PathBuf>,
+}
+
+impl ManifestDiff {
+    /// True if the manifests describe the same files.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.mode_changed.is_empty()
+    }
+}
+
+impl ManifestEntry {
+    /// Whether `other` has different contents. Hashes decide when both
+    /// entries have one from the same hasher; otherwise the sizes and
+    /// modification times are compared, as with [`Compare::Metadata`].
+    fn differs(&self, other: &ManifestEntry, same_hasher: bool) -> bool {
+        if self.size != other.size || self.target != other.target {
+            return true;
+        }
+        match (&self.hash, &other.hash) {
+            (Some(a), Some(b)) if same_hasher => a != b,
+            _ => self.mtime != other.mtime,
+        }
+    }
+}
+
+impl Manifest {
+    fn hasher_name(&self) -> &str {
+        self.hasher.as_deref().unwrap_or("blake3")
+    }
+
+    /// Compare this manifest with a later one of the same tree, without
+    /// touching the filesystem.
+    pub fn diff(&self, other: &Manifest) -> ManifestDiff {
+        let mut later: BTreeMap<&Path, &ManifestEntry> = other
+            .entries
+            .iter()
+            .map(|e| (e.path.as_path(), e))
+            .collect();
+        let same_hasher = self.hasher_name() == other.hasher_name();
+        let mut diff = ManifestDiff::default();
+        for entry in &self.entries {
+            match later.remove(entry.path.as_path()) {
+                Some(other) if entry.differs(other, same_hasher) => {
+                    diff.modified.push(entry.path.clone())
+                }
+                Some(other) if entry.mode != other.mode => {
+                    diff.mode_changed.push(entry.path.clone())
+                }
+                Some(_) => {}
+                None => diff.removed.push(entry.path.clone()),
+            }
+        }
+        diff.added = later.into_keys().map(Path::to_path_buf).collect();
+        diff.removed.sort();
+        diff.modified.sort();
+        diff.mode_changed.sort();
+        diff
+    }
+}
+
+/// List the files under `src` that [`clone_tree`](crate::clone_tree) would
+/// copy with `options`, with their sizes, modes and modification times.
+/// Contents are hashed too, with the [`Options::hasher`], when
+/// [`Options::compare`] is [`Compare::Hash`], and symlinks are listed with their targets when [`Options::symlinks`] is
+/// set.
+pub fn snapshot<P: AsRef<Path>>(src: P, options: &Options) -> Result<Manifest> {
+    let mut entries = Vec::new();
+    for entry in clone_entries(src, options) {
+        let entry = entry?;
+        let is_symlink = entry.metadata.is_symlink();
+        let target = if is_symlink {
+            Some(
+                std::fs::read_link(&entry.path).map_err(|source| Error::Read {
+                    path: entry.path.clone(),
+                    source,
+                })?,
+            )
+        } else {
+            None
+        };
+        let hash = if options.compare == Compare::Hash && !is_symlink {
+            Some(options.hash_file(&entry.path)?.to_hex())
+        } else {
+            None
+        };
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(entry.metadata.mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        entries.push(ManifestEntry {
+            path: entry.relative_path,
+            size: entry.metadata.len(),
+            mode,
+            mtime: entry.metadata.modified().ok(),
+            hash,
+            target,
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let hashed = options.compare == Compare::Hash;
+    Ok(Manifest {
+        hasher: hashed.then(|| options.content_hasher().name().to_string()),
+        entries,
+    })
+}
+
+/// Clone exactly the files listed in `manifest` from `src` to `dest`, as
+/// [`clone_tree`](crate::clone_tree) does with [`Options::files`]. Glob
+/// filters in `options` still apply on top, and symlinks in the manifest are
+/// recreated.
+///
+/// Every listed file is checked before anything is copied: a missing one
+/// fails with [`Error::SourceNotFound`], and one whose size, hash (if the
+/// manifest has one) or symlink target has changed fails with
+/// [`Error::ManifestMismatch`], so the clone is the tree the manifest
+/// describes.
+pub fn clone_from_manifest<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    manifest: &Manifest,
+    options: &Options,
+) -> Result<CloneReport> {
+    let src = src.as_ref();
+    // Hashes are checked with the hasher that made them
+    let name = manifest.hasher_name();
+    let builtin = hash::by_name(name);
+    let hasher = match &builtin {
+        _ if options.content_hasher().name() == name => options.content_hasher(),
+        Some(hasher) => hasher.as_ref(),
+        None if manifest.entries.iter().all(|e| e.hash.is_none()) => options.content_hasher(),
+        None => {
+            return Err(Error::InvalidManifest {
+                message: format!("unknown hasher {name}"),
+            })
+        }
+    };
+    for entry in &manifest.entries {
+        check(src, entry, hasher)?;
+    }
+    let has_symlinks = manifest.entries.iter().any(|e| e.target.is_some());
+    let options = options
+        .clone()
+        .files(manifest.entries.iter().map(|e| e.path.clone()))
+        .symlinks(options.symlinks || has_symlinks);
+    options.build()?.clone_tree(src, dest)
+}
+
+/// Check that the source file for `entry` still matches it.
+fn check(src: &Path, entry: &ManifestEntry, hasher: &dyn Hasher) -> Result<()> {
+    let path = src.join(&entry.path);
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|_| Error::SourceNotFound { path: path.clone() })?;
+    let mismatch = || Error::ManifestMismatch { path: path.clone() };
+    match &entry.target {
+        Some(target) => {
+            let current = std::fs::read_link(&path).map_err(|_| mismatch())?;
+            if &current != target {
+                return Err(mismatch());
+            }
+        }
+        None => {
+            if !metadata.is_file() || metadata.len() != entry.size {
+                return Err(mismatch());
+            }
+            if let Some(hash) = &entry.hash {
+                if hash::hash_file(&path, hasher)?.to_hex() != *hash {
+                    return Err(mismatch());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serde support for an optional time as signed nanoseconds since the Unix
+/// epoch, which JSON readers handle more easily than `SystemTime`'s own form.
+mod unix_nanos {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time.map(|time| match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_nanos() as i64,
+            Err(before) => -(before.duration().as_nanos() as i64),
+        })
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        Ok(Option::<i64>::deserialize(deserializer)?.map(|nanos| {
+            let offset = Duration::from_nanos(nanos.unsigned_abs());
+            if nanos >= 0 {
+                UNIX_EPOCH + offset
+            } else {
+                UNIX_EPOCH - offset
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path();
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("b.txt"), "bee")?;
+        fs::write(src.join("sub/a.rs"), "a")?;
+        fs::write(src.join("skip.log"), "log")?;
+
+        let options = Options::new().glob("!*.log").compare(Compare::Hash);
+        let manifest = snapshot(src, &options)?;
+        let paths: Vec<_> = manifest.entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("b.txt"), PathBuf::from("sub/a.rs")]
+        );
+        let entry = &manifest.entries[0];
+        assert_eq!(entry.size, 3);
+        assert_eq!(
+            entry.hash.as_deref(),
+            Some(blake3::hash(b"bee").to_hex().as_str())
+        );
+        assert!(entry.mtime.is_some());
+
+        assert_eq!(manifest.hasher.as_deref(), Some("blake3"));
+
+        // Times survive the round trip to the nanosecond
+        let json = manifest.to_json()?;
+        assert_eq!(Manifest::from_json(&json)?, manifest);
+        assert!(matches!(
+            Manifest::from_json("{\"entries\": 1}"),
+            Err(Error::InvalidManifest { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_diff() -> Result<()> {
+        let before = Manifest::from_json(
+            r#"{"entries": [
+                {"path": "gone.txt", "size": 1},
+                {"path": "same.txt", "size": 1, "mtime": 10, "mode": 420},
+                {"path": "touched.txt", "size": 1, "mtime": 10, "hash": "aa"},
+                {"path": "chmod.txt", "size": 1, "mtime": 10, "mode": 420}
+            ]}"#,
+        )?;
+        let after = Manifest::from_json(
+            r#"{"entries": [
+                {"path": "new.txt", "size": 1},
+                {"path": "same.txt", "size": 1, "mtime": 10, "mode": 420},
+                {"path": "touched.txt", "size": 1, "mtime": 20, "hash": "aa"},
+                {"path": "chmod.txt", "size": 1, "mtime": 10, "mode": 493}
+            ]}"#,
+        )?;
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added, vec![PathBuf::from("new.txt")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("gone.txt")]);
+        // Matching hashes outweigh a new modification time
+        assert!(diff.modified.is_empty());
+        assert_eq!(diff.mode_changed, vec![PathBuf::from("chmod.txt")]);
+        assert!(after.diff(&after).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_from_manifest() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("c.txt"), "c")?;
+        let manifest = Manifest::from_json(
+            r#"{"entries": [{"path": "a.txt", "size": 1}, {"path": "sub/b.txt", "size": 1}]}"#,
+        )?;
+
+        let dest = temp_dir.path().join("dest");
+        let report = clone_from_manifest(&src, &dest, &manifest, &Options::new())?;
+        assert_eq!(report.files(), 2);
+        assert!(dest.join("sub/b.txt").exists());
+        assert!(!dest.join("c.txt").exists());
+
+        // Hashes are checked with the hasher that made them
+        let options = Options::new()
+            .compare(Compare::Hash)
+            .hasher(crate::XxHash64);
+        let hashed = snapshot(&src, &options.glob("a.txt"))?;
+        assert_eq!(hashed.hasher.as_deref(), Some("xxh64"));
+        let dest = temp_dir.path().join("hashed");
+        clone_from_manifest(&src, &dest, &hashed, &Options::new())?;
+        assert!(dest.join("a.txt").exists());
+
+        // A source that has drifted from the manifest is refused
+        fs::write(src.join("a.txt"), "changed")?;
+        let dest = temp_dir.path().join("dest2");
+        let result = clone_from_manifest(&src, &dest, &manifest, &Options::new());
+        assert!(matches!(result, Err(Error::ManifestMismatch { .. })));
+        fs::remove_file(src.join("a.txt"))?;
+        let result = clone_from_manifest(&src, &dest, &manifest, &Options::new());
+        assert!(matches!(result, Err(Error::SourceNotFound { .. })));
+        assert!(!dest.exists());
+
+        Ok(())
+    }
+}
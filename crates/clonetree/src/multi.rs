@@ -0,0 +1,145 @@
+//! Cloning several source trees into one destination.
+
+use crate::{
+    clone_tree_with, estimate, progress, root_created, validate, CloneConfig, CloneReport, Error,
+    Options, Result,
+};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The directories [`clone_trees`] clones each of `srcs` to: a subdirectory of
+/// `dest` named after the source, as `cp -r a b dest` would. Relative sources
+/// like `.` are resolved first so they have a name. Two sources with the same
+/// name would collide, so that fails with [`Error::SourceCollision`].
+pub fn clone_targets<P: AsRef<Path>, Q: AsRef<Path>>(srcs: &[P], dest: Q) -> Result<Vec<PathBuf>> {
+    let dest = dest.as_ref();
+    let mut names = HashSet::new();
+    srcs.iter()
+        .map(|src| {
+            let src = src.as_ref();
+            let resolved = std::fs::canonicalize(src).map_err(|_| Error::SourceNotFound {
+                path: src.to_path_buf(),
+            })?;
+            let name = resolved
+                .file_name()
+                .ok_or_else(|| Error::SourceNotDirectory {
+                    path: src.to_path_buf(),
+                })?;
+            if !names.insert(name.to_os_string()) {
+                return Err(Error::SourceCollision {
+                    name: PathBuf::from(name),
+                });
+            }
+            Ok(dest.join(name))
+        })
+        .collect()
+}
+
+/// Clone each of `srcs` into its own subdirectory of `dest`, which is created
+/// if needed. Every source and target is validated before anything is
+/// copied, so a missing source or an existing target (without
+/// [`Options::overwrite`]) leaves `dest` untouched. Progress is reported as
+/// one running total, with paths relative to `dest`.
+pub fn clone_trees<P: AsRef<Path>, Q: AsRef<Path>>(
+    srcs: &[P],
+    dest: Q,
+    options: &Options,
+) -> Result<CloneReport> {
+    options.build()?.clone_trees(srcs, dest)
+}
+
+impl CloneConfig {
+    /// [`clone_trees`] with this config.
+    pub fn clone_trees<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        srcs: &[P],
+        dest: Q,
+    ) -> Result<CloneReport> {
+        let options = &self.options;
+        let dest = dest.as_ref();
+        let targets = clone_targets(srcs, dest)?;
+        for (src, target) in srcs.iter().zip(&targets) {
+            validate(src.as_ref(), target, options)?;
+        }
+
+        let created = !dest.exists();
+        std::fs::create_dir_all(dest).map_err(|source| Error::CreateDirectory {
+            path: dest.to_path_buf(),
+            source,
+        })?;
+        if created {
+            root_created(dest, Path::new(""), None, options);
+        }
+        let mut tracker = progress::Tracker::new(options);
+        if options.count_first {
+            for src in srcs {
+                tracker.count(estimate(src.as_ref(), options)?);
+            }
+        }
+        let progress = Mutex::new(tracker);
+        for (src, target) in srcs.iter().zip(&targets) {
+            let prefix = target.strip_prefix(dest).unwrap_or(target);
+            clone_tree_with(src.as_ref(), target, self, &progress, prefix)?;
+        }
+        Ok(progress.into_inner().expect("progress lock").report())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_clone_trees() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("one"))?;
+        fs::create_dir_all(root.join("two/sub"))?;
+        fs::write(root.join("one/a.txt"), "a")?;
+        fs::write(root.join("two/sub/b.txt"), "b")?;
+
+        let dest = root.join("dest");
+        let done = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let opts = Options::new().on_progress({
+            let done = done.clone();
+            move |p| {
+                done.lock()
+                    .unwrap()
+                    .push((p.files_done, p.path.to_path_buf()))
+            }
+        });
+        clone_trees(&[root.join("one"), root.join("two")], &dest, &opts)?;
+        assert_eq!(fs::read_to_string(dest.join("one/a.txt"))?, "a");
+        assert_eq!(fs::read_to_string(dest.join("two/sub/b.txt"))?, "b");
+        assert_eq!(
+            *done.lock().unwrap(),
+            vec![
+                (1, PathBuf::from("one/a.txt")),
+                (2, PathBuf::from("two/sub/b.txt"))
+            ]
+        );
+
+        // Targets now exist
+        let result = clone_trees(&[root.join("one")], &dest, &Options::new());
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_targets_collision() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/src"))?;
+        fs::create_dir_all(root.join("b/src"))?;
+
+        let result = clone_targets(&[root.join("a/src"), root.join("b/src")], root.join("dest"));
+        assert!(matches!(result, Err(Error::SourceCollision { name }) if name == Path::new("src")));
+        assert!(!root.join("dest").exists());
+
+        Ok(())
+    }
+}
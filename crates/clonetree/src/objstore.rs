@@ -0,0 +1,276 @@
+//! Uploading a tree to an object store such as S3 or GCS.
+
+use crate::backend::{FileInfo, FileKind, TreeSink};
+use crate::{
+    clone_between, trace, validate_source, CloneReport, Conflict, DirSource, Error, Options,
+    Outcome, Overwrite, Resolution, Result, SkipReason,
+};
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, ObjectStoreExt};
+use std::future::poll_fn;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncWrite;
+
+/// Upload the selected files of `src` to `store`, under `prefix`.
+///
+/// This is [`clone_between`] from a [`DirSource`] to an [`ObjectStoreSink`],
+/// so the same globs, filters and progress reporting apply as for
+/// [`clone_tree`](crate::clone_tree). Unless [`Options::overwrite`] is set,
+/// the clone fails with [`Error::DestinationExists`] if anything is already
+/// stored under `prefix`; with [`Overwrite::Replace`] everything under
+/// `prefix` is deleted first.
+///
+/// The store is built by the caller, with whichever `object_store` features
+/// its backend needs:
+///
+/// ```no_run
+/// use clonetree::{clone_to_object_store, Options};
+/// use object_store::memory::InMemory;
+/// use std::sync::Arc;
+///
+/// # fn main() -> clonetree::Result<()> {
+/// let store = Arc::new(InMemory::new());
+/// clone_to_object_store("./site", store, "releases/v1".into(), &Options::new())?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn clone_to_object_store<P: AsRef<Path>>(
+    src: P,
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    options: &Options,
+) -> Result<CloneReport> {
+    let src = src.as_ref();
+    let _span = trace::clone_span(src, Path::new(prefix.as_ref()));
+    validate_source(src)?;
+
+    let mut sink = ObjectStoreSink::new(store, prefix)?;
+    match options.overwrite {
+        Overwrite::Never => {
+            let existing = sink
+                .runtime
+                .block_on(sink.store.list_with_delimiter(Some(&sink.prefix)))
+                .map_err(|e| sink.error(&sink.prefix, e.into()))?;
+            if !existing.objects.is_empty() || !existing.common_prefixes.is_empty() {
+                return Err(Error::DestinationExists {
+                    path: PathBuf::from(sink.prefix.as_ref()),
+                });
+            }
+        }
+        Overwrite::Merge | Overwrite::NoClobber => {}
+        Overwrite::Replace => sink.clear()?,
+    }
+    clone_between(&mut DirSource::new(src), &mut sink, options)
+}
+
+/// An object store written as a [`TreeSink`], with each file uploaded to
+/// `prefix/relative/path`. Large files are uploaded in parts.
+///
+/// Object stores have no symlinks, so links are skipped, and no permissions
+/// or settable times, so the preserve options have no effect.
+/// [`Options::update`] skips files whose object has the same size and is at
+/// least as new, and [`Options::on_conflict`] is asked before an existing
+/// object is replaced.
+pub struct ObjectStoreSink {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl ObjectStoreSink {
+    /// Upload into `store` under `prefix`. Uploads run on a private
+    /// single-threaded runtime, so this must not be called from within an
+    /// async context.
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: ObjectPath) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            store,
+            prefix,
+            runtime,
+        })
+    }
+
+    /// The object a file at the relative `path` is uploaded to.
+    fn location(&self, path: &Path) -> ObjectPath {
+        path.iter().fold(self.prefix.clone(), |location, part| {
+            location.join(part.to_string_lossy().as_ref())
+        })
+    }
+
+    /// Delete every object under the prefix.
+    fn clear(&self) -> Result<()> {
+        let mut prefixes = vec![self.prefix.clone()];
+        while let Some(prefix) = prefixes.pop() {
+            let listing = self
+                .runtime
+                .block_on(self.store.list_with_delimiter(Some(&prefix)))
+                .map_err(|e| self.error(&prefix, e.into()))?;
+            for object in listing.objects {
+                self.runtime
+                    .block_on(self.store.delete(&object.location))
+                    .map_err(|e| self.error(&object.location, e.into()))?;
+                trace::event!(debug, "deleted", path = object.location);
+            }
+            prefixes.extend(listing.common_prefixes);
+        }
+        Ok(())
+    }
+
+    fn error(&self, location: &ObjectPath, source: io::Error) -> Error {
+        Error::Copy {
+            src: PathBuf::from(location.as_ref()),
+            dest: PathBuf::from(location.as_ref()),
+            source,
+        }
+    }
+}
+
+impl TreeSink for ObjectStoreSink {
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        options: &Options,
+    ) -> Result<Outcome> {
+        if let FileKind::Symlink(_) = file.kind {
+            return Ok(Outcome::Skipped(SkipReason::Unsupported));
+        }
+        let location = self.location(&file.path);
+
+        let no_clobber = options.overwrite == Overwrite::NoClobber;
+        if options.update || options.conflict.is_some() || no_clobber {
+            if let Ok(existing) = self.runtime.block_on(self.store.head(&location)) {
+                let secs = |time: SystemTime| {
+                    time.duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs() as i64)
+                };
+                let up_to_date = existing.size == file.len
+                    && file
+                        .mtime
+                        .is_some_and(|mtime| secs(mtime) <= existing.last_modified.timestamp());
+                if options.update && up_to_date {
+                    return Ok(Outcome::Skipped(SkipReason::Unchanged));
+                }
+                if no_clobber {
+                    return Err(Error::FileExists {
+                        path: PathBuf::from(location.as_ref()),
+                    });
+                }
+                if let Some(conflict) = &options.conflict {
+                    let dest = PathBuf::from(location.as_ref());
+                    let resolution = (conflict.0)(&Conflict {
+                        path: &file.path,
+                        src: &file.path,
+                        dest: &dest,
+                    });
+                    match resolution {
+                        Resolution::Overwrite => {}
+                        Resolution::Skip => return Ok(Outcome::Skipped(SkipReason::Conflict)),
+                        Resolution::Abort => return Err(Error::Cancelled),
+                    }
+                }
+            }
+        }
+
+        let upload = upload(Arc::clone(&self.store), location.clone(), contents);
+        self.runtime.block_on(upload).map_err(|e| Error::Copy {
+            src: file.path.clone(),
+            dest: PathBuf::from(location.as_ref()),
+            source: e,
+        })?;
+        trace::event!(debug, "uploaded", path = location);
+        Ok(Outcome::Copied)
+    }
+}
+
+/// Stream `contents` to `location`, aborting the upload if reading fails.
+async fn upload(
+    store: Arc<dyn ObjectStore>,
+    location: ObjectPath,
+    contents: &mut dyn Read,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(store, location);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = match contents.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(e);
+            }
+        };
+        let mut chunk = &buf[..read];
+        while !chunk.is_empty() {
+            let written = poll_fn(|cx| Pin::new(&mut writer).poll_write(cx, chunk)).await?;
+            chunk = &chunk[written..];
+        }
+    }
+    poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn read(store: &InMemory, location: &str) -> Result<Vec<u8>> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let result = store.get(&location.into()).await.map_err(io::Error::from)?;
+            let bytes = result.bytes().await.map_err(io::Error::from)?;
+            Ok(bytes.to_vec())
+        })
+    }
+
+    #[test]
+    fn test_clone_to_object_store() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("skip.log"), "log")?;
+
+        let store = Arc::new(InMemory::new());
+        let options = Options::new().glob("!*.log");
+        clone_to_object_store(&src, store.clone(), "snap".into(), &options)?;
+        assert_eq!(read(&store, "snap/a.txt")?, b"a");
+        assert_eq!(read(&store, "snap/sub/b.txt")?, b"b");
+        assert!(read(&store, "snap/skip.log").is_err());
+
+        let result = clone_to_object_store(&src, store.clone(), "snap".into(), &options);
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+
+        // Objects are newer than the files, so an update uploads nothing
+        let done = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = options.overwrite(true).update(true).on_progress({
+            let done = done.clone();
+            move |p| done.lock().unwrap().push(p.outcome)
+        });
+        clone_to_object_store(&src, store.clone(), "snap".into(), &options)?;
+        assert_eq!(
+            *done.lock().unwrap(),
+            vec![Outcome::Skipped(SkipReason::Unchanged); 2]
+        );
+
+        // Replacing drops objects whose files are gone
+        fs::remove_file(src.join("sub/b.txt"))?;
+        let options = Options::new().overwrite(Overwrite::Replace);
+        clone_to_object_store(&src, store.clone(), "snap".into(), &options)?;
+        assert_eq!(read(&store, "snap/a.txt")?, b"a");
+        assert!(read(&store, "snap/sub/b.txt").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Watching a clone through one object rather than a closure per concern.
+
+use crate::{Error, Event, FileOutcome, Options, Outcome, RecordedWarning, Warning};
+use std::fmt;
+use std::fs::Metadata;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+
+/// Hooks called as a clone goes, registered with [`Options::observer`].
+///
+/// Every method does nothing by default, so an observer only implements the
+/// ones it needs. Paths are relative to the source root. With several
+/// [`threads`](Options::threads) the methods may be called concurrently.
+pub trait CloneObserver: Send + Sync {
+    /// The walk reached the files of the directory at `path`. Directories
+    /// with nothing selected in them are passed over.
+    fn on_dir(&self, _path: &Path) {}
+
+    /// The destination directory at `path` was created, for the source
+    /// directory with `metadata`: a chance to index it or fix up its
+    /// permissions. Here `path` is relative to the destination root, and
+    /// empty for the root itself, which differs from the source path if
+    /// names are normalized or sanitized. Sources without directories of
+    /// their own, such as archives, pass the new directory's metadata.
+    fn on_dir_created(&self, _path: &Path, _metadata: &Metadata) {}
+
+    /// The file at `path` was processed, with what was done with it.
+    fn on_file(&self, _path: &Path, _outcome: Outcome) {}
+
+    /// A file couldn't be cloned. With [`Options::keep_going`] the clone
+    /// carries on; otherwise it stops with this error.
+    fn on_error(&self, _error: &Error) {}
+
+    /// The clone worked around a problem, as reported to
+    /// [`Options::on_warning`].
+    fn on_warning(&self, _warning: &Warning) {}
+}
+
+/// Everything watching a clone: the observer from [`Options::observer`] and
+/// the channels from [`Options::event_sender`].
+#[derive(Clone, Default)]
+pub(crate) struct Observers {
+    pub(crate) observer: Option<Arc<dyn CloneObserver>>,
+    pub(crate) senders: Vec<mpsc::Sender<Event>>,
+}
+
+impl Observers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.observer.is_none() && self.senders.is_empty()
+    }
+
+    /// Call `hook` with each observer, channels included.
+    pub(crate) fn each(&self, hook: impl Fn(&dyn CloneObserver)) {
+        if let Some(observer) = &self.observer {
+            hook(observer.as_ref());
+        }
+        for sender in &self.senders {
+            hook(&Sending(sender));
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("observer", &self.observer.is_some())
+            .field("senders", &self.senders.len())
+            .finish()
+    }
+}
+
+/// Turns the hooks into [`Event`]s sent down a channel.
+struct Sending<'a>(&'a mpsc::Sender<Event>);
+
+impl Sending<'_> {
+    fn send(&self, event: Event) {
+        // A receiver that has gone away just misses out
+        let _ = self.0.send(event);
+    }
+}
+
+impl CloneObserver for Sending<'_> {
+    fn on_dir_created(&self, path: &Path, metadata: &Metadata) {
+        self.send(Event::DirCreated {
+            path: path.to_path_buf(),
+            metadata: metadata.clone(),
+        });
+    }
+
+    fn on_file(&self, path: &Path, outcome: Outcome) {
+        self.send(Event::File(FileOutcome {
+            path: path.to_path_buf(),
+            outcome,
+        }));
+    }
+
+    fn on_error(&self, error: &Error) {
+        self.send(Event::Error {
+            path: error.path().map(Path::to_path_buf),
+            message: error.to_string(),
+        });
+    }
+
+    fn on_warning(&self, warning: &Warning) {
+        self.send(Event::Warning(RecordedWarning::from(warning)));
+    }
+}
+
+impl Options {
+    /// Call `hook` with each observer, if there are any.
+    pub(crate) fn notify(&self, hook: impl Fn(&dyn CloneObserver)) {
+        self.observers.each(hook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clone_tree;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct Recorder {
+        dirs: Mutex<Vec<PathBuf>>,
+        files: Mutex<Vec<PathBuf>>,
+        errors: Mutex<usize>,
+    }
+
+    impl CloneObserver for Recorder {
+        fn on_dir(&self, path: &Path) {
+            self.dirs.lock().unwrap().push(path.to_path_buf());
+        }
+
+        fn on_file(&self, path: &Path, _outcome: Outcome) {
+            self.files.lock().unwrap().push(path.to_path_buf());
+        }
+
+        fn on_error(&self, _error: &Error) {
+            *self.errors.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn test_observer() -> crate::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        // A directory where a file should go can't be replaced
+        fs::create_dir_all(dest.join("a.txt/sub"))?;
+
+        let recorder = Arc::new(Recorder::default());
+        let options = Options::new()
+            .overwrite(true)
+            .keep_going(true)
+            .sorted(true)
+            .threads(1)
+            .observer(recorder.clone());
+        assert!(clone_tree(&src, &dest, &options).is_err());
+        assert_eq!(
+            *recorder.dirs.lock().unwrap(),
+            [PathBuf::new(), PathBuf::from("sub")]
+        );
+        assert_eq!(
+            *recorder.files.lock().unwrap(),
+            [PathBuf::from("sub/b.txt")]
+        );
+        assert_eq!(*recorder.errors.lock().unwrap(), 1);
+        Ok(())
+    }
+}
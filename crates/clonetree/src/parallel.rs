@@ -0,0 +1,120 @@
+//! Spreading per-file work across threads.
+
+use crate::{Error, Result};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+/// Call `f` on each item from `items`, stopping at the first error.
+///
+/// With more than one thread, items are pulled from the iterator on the
+/// calling thread and handed to `threads` scoped workers through a bounded
+/// channel, so they may finish in any order. Otherwise everything runs on the
+/// calling thread, in iteration order.
+pub(crate) fn try_for_each<T, I, F>(threads: usize, items: I, f: F) -> Result<()>
+where
+    I: Iterator<Item = Result<T>>,
+    T: Send,
+    F: Fn(T) -> Result<()> + Sync,
+{
+    if threads <= 1 {
+        for item in items {
+            f(item?)?;
+        }
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::sync_channel::<T>(threads * 4);
+    let rx = Mutex::new(rx);
+    let failed: Mutex<Option<Error>> = Mutex::new(None);
+    let has_failed = || failed.lock().expect("error lock").is_some();
+    let fail = |e: Error| {
+        failed.lock().expect("error lock").get_or_insert(e);
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                // Once something has failed, keep draining the channel so the
+                // producer never blocks on a full buffer
+                loop {
+                    // Release the receiver before working on the item, so
+                    // the other workers can take the next ones meanwhile
+                    let Ok(item) = rx.lock().expect("receiver lock").recv() else {
+                        break;
+                    };
+                    if !has_failed() {
+                        if let Err(e) = f(item) {
+                            fail(e);
+                        }
+                    }
+                }
+            });
+        }
+
+        for item in items {
+            if has_failed() {
+                break;
+            }
+            match item {
+                Ok(item) => {
+                    if tx.send(item).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    fail(e);
+                    break;
+                }
+            }
+        }
+        drop(tx);
+    });
+
+    match failed.into_inner().expect("error lock") {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_try_for_each() {
+        for threads in [1, 4] {
+            let sum = AtomicU64::new(0);
+            try_for_each(threads, (1..=100).map(Ok), |n| {
+                sum.fetch_add(n, Ordering::Relaxed);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(sum.into_inner(), 5050);
+
+            let result = try_for_each(threads, (1..=100).map(Ok), |n| {
+                if n == 50 {
+                    Err(Error::Other("fifty".into()))
+                } else {
+                    Ok(())
+                }
+            });
+            assert!(matches!(result, Err(Error::Other(msg)) if msg == "fifty"));
+        }
+    }
+
+    #[test]
+    fn test_workers_overlap() {
+        let active = AtomicU64::new(0);
+        let most = AtomicU64::new(0);
+        try_for_each(4, (0..8).map(Ok), |_| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(20));
+            active.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+        assert!(most.into_inner() > 1);
+    }
+}
@@ -0,0 +1,109 @@
+//! Glob patterns checked when they are created.
+
+use crate::filter::check;
+use crate::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A glob pattern with the syntax of [`Options::glob`](crate::Options::glob),
+/// known to be valid.
+///
+/// Strings passed to `glob` are only checked when the options are
+/// [built](crate::Options::build) or used, deep inside a clone. Parsing a
+/// `Pattern` reports a bad pattern where it is written, and `glob` accepts a
+/// `Pattern` wherever it accepts a string.
+///
+/// ```
+/// use clonetree::{Options, Pattern};
+///
+/// # fn main() -> clonetree::Result<()> {
+/// # #[cfg(feature = "filter")] {
+/// let exclude = Pattern::new("!target/**")?;
+/// assert!(exclude.is_exclude());
+/// let config = Options::new().glob(exclude).build()?;
+/// assert!(Pattern::new("src/{a,b").is_err());
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pattern(String);
+
+impl Pattern {
+    /// Check `pattern`, failing with [`Error::InvalidGlob`] if it isn't a
+    /// valid glob.
+    pub fn new<S: Into<String>>(pattern: S) -> Result<Self> {
+        let pattern = pattern.into();
+        check(&pattern)?;
+        Ok(Self(pattern))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether this pattern excludes the paths it matches, rather than
+    /// including them: it starts with `!`.
+    pub fn is_exclude(&self) -> bool {
+        self.0.starts_with('!')
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        Self::new(pattern)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = Error;
+
+    fn try_from(pattern: String) -> Result<Self> {
+        Self::new(pattern)
+    }
+}
+
+impl TryFrom<&str> for Pattern {
+    type Error = Error;
+
+    fn try_from(pattern: &str) -> Result<Self> {
+        Self::new(pattern)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.0
+    }
+}
+
+impl AsRef<str> for Pattern {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(all(test, feature = "filter"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let pattern: Pattern = "**/*.rs".parse().unwrap();
+        assert_eq!(pattern.as_str(), "**/*.rs");
+        assert!(!pattern.is_exclude());
+        assert!(Pattern::new("!target/").unwrap().is_exclude());
+        assert!(matches!(
+            Pattern::try_from("a/{b"),
+            Err(Error::InvalidGlob { pattern, .. }) if pattern == "a/{b"
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+//! Planning a clone without performing it.
+
+use crate::{
+    clone_entries, find_extraneous, is_unchanged, validate, Error, Options, Overwrite, Result,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A single filesystem change that [`clone_tree`](crate::clone_tree) would
+/// make, as listed by [`plan_clone`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Create a directory in the destination.
+    CreateDir { path: PathBuf },
+    /// Copy a file to a destination path that does not exist yet.
+    Copy { src: PathBuf, dest: PathBuf },
+    /// Copy a file over an existing destination file.
+    Replace { src: PathBuf, dest: PathBuf },
+    /// Delete a destination file that is not in the source.
+    Delete { path: PathBuf },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::CreateDir { path } => write!(f, "mkdir   {}", path.display()),
+            Operation::Copy { src, dest } => {
+                write!(f, "copy    {} -> {}", src.display(), dest.display())
+            }
+            Operation::Replace { src, dest } => {
+                write!(f, "replace {} -> {}", src.display(), dest.display())
+            }
+            Operation::Delete { path } => write!(f, "delete  {}", path.display()),
+        }
+    }
+}
+
+/// List the operations that [`clone_tree`](crate::clone_tree) would perform
+/// with the same arguments, in order, without writing anything. The same
+/// validation is applied, so a plan that succeeds describes a clone that can
+/// start. Files that update mode would skip are left out. With
+/// [`Overwrite::Replace`] the plan starts by deleting every file in the
+/// destination, and with [`Overwrite::NoClobber`] a file already in the way
+/// fails the plan with [`Error::FileExists`].
+pub fn plan_clone<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<Vec<Operation>> {
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    validate(src, dest, options)?;
+
+    let mut operations = Vec::new();
+    let mut dirs = HashSet::new();
+    let mut seen = HashSet::new();
+
+    if !dest.exists() {
+        operations.push(Operation::CreateDir {
+            path: dest.to_path_buf(),
+        });
+        dirs.insert(dest.to_path_buf());
+    }
+
+    // After replacing, nothing below the destination exists any more
+    let replacing = options.overwrite == Overwrite::Replace && dest.is_dir();
+    if replacing {
+        for entry in clone_entries(dest, &Options::new().sorted(options.sorted).symlinks(true)) {
+            operations.push(Operation::Delete { path: entry?.path });
+        }
+        dirs.insert(dest.to_path_buf());
+    }
+    let exists = |path: &Path| !replacing && path.exists();
+
+    for entry in clone_entries(src, options) {
+        let entry = entry?;
+        let dest_path = dest.join(&entry.relative_path);
+        if options.delete {
+            seen.insert(entry.relative_path.clone());
+        }
+        if options.update
+            && !replacing
+            && is_unchanged(
+                &entry,
+                &dest_path,
+                dest_path.symlink_metadata().ok().as_ref(),
+                options,
+            )?
+        {
+            continue;
+        }
+
+        // Parents are created outermost first
+        let missing: Vec<&Path> = dest_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| !dirs.contains(*dir) && !exists(dir))
+            .collect();
+        for dir in missing.into_iter().rev() {
+            dirs.insert(dir.to_path_buf());
+            operations.push(Operation::CreateDir {
+                path: dir.to_path_buf(),
+            });
+        }
+
+        if options.overwrite == Overwrite::NoClobber && exists(&dest_path) {
+            return Err(Error::FileExists { path: dest_path });
+        }
+        operations.push(if exists(&dest_path) {
+            Operation::Replace {
+                src: entry.path,
+                dest: dest_path,
+            }
+        } else {
+            Operation::Copy {
+                src: entry.path,
+                dest: dest_path,
+            }
+        });
+    }
+
+    if options.delete && !replacing && dest.exists() {
+        for entry in find_extraneous(dest, &seen, options)? {
+            operations.push(Operation::Delete { path: entry.path });
+        }
+    }
+
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_plan_new_destination() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(src.join("a/b"))?;
+        fs::write(src.join("a/b/file.txt"), "content")?;
+
+        let plan = plan_clone(&src, &dest, &Options::new())?;
+        assert_eq!(
+            plan,
+            vec![
+                Operation::CreateDir { path: dest.clone() },
+                Operation::CreateDir {
+                    path: dest.join("a")
+                },
+                Operation::CreateDir {
+                    path: dest.join("a/b")
+                },
+                Operation::Copy {
+                    src: src.join("a/b/file.txt"),
+                    dest: dest.join("a/b/file.txt"),
+                },
+            ]
+        );
+        assert!(!dest.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_sync() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::create_dir_all(&dest)?;
+        fs::write(src.join("same.txt"), "same")?;
+        fs::write(src.join("changed.txt"), "new")?;
+        fs::write(dest.join("same.txt"), "same")?;
+        fs::write(dest.join("changed.txt"), "old!")?;
+        fs::write(dest.join("extra.txt"), "extra")?;
+
+        let opts = Options::new()
+            .overwrite(true)
+            .update(true)
+            .delete(true)
+            .compare(crate::Compare::Hash);
+        let plan = plan_clone(&src, &dest, &opts)?;
+        assert_eq!(
+            plan,
+            vec![
+                Operation::Replace {
+                    src: src.join("changed.txt"),
+                    dest: dest.join("changed.txt"),
+                },
+                Operation::Delete {
+                    path: dest.join("extra.txt")
+                },
+            ]
+        );
+        assert_eq!(fs::read_to_string(dest.join("changed.txt"))?, "old!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_replace() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::create_dir_all(dest.join("sub"))?;
+        fs::write(src.join("sub/file.txt"), "new")?;
+        fs::write(dest.join("sub/file.txt"), "old")?;
+
+        let opts = Options::new().overwrite(Overwrite::Replace);
+        let plan = plan_clone(&src, &dest, &opts)?;
+        assert_eq!(
+            plan,
+            vec![
+                Operation::Delete {
+                    path: dest.join("sub/file.txt")
+                },
+                Operation::CreateDir {
+                    path: dest.join("sub")
+                },
+                Operation::Copy {
+                    src: src.join("sub/file.txt"),
+                    dest: dest.join("sub/file.txt"),
+                },
+            ]
+        );
+
+        let opts = Options::new().overwrite(Overwrite::NoClobber);
+        let result = plan_clone(&src, &dest, &opts);
+        assert!(
+            matches!(result, Err(Error::FileExists { path }) if path == dest.join("sub/file.txt"))
+        );
+
+        Ok(())
+    }
+}
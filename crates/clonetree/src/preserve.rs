@@ -0,0 +1,557 @@
+//! Copying file metadata: permissions, ownership, creation times, extended
+//! attributes and symbolic links.
+
+use crate::{DirLinks, Error, Result};
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+
+bitflags::bitflags! {
+    /// The metadata clones keep from their sources, set with
+    /// [`Options::preserve`](crate::Options::preserve). Each flag is
+    /// described on the `Options` method that sets it alone.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Preserve: u32 {
+        /// Permission bits, or Windows file attributes.
+        const MODE = 1 << 0;
+        /// With `MODE`, the setuid, setgid and sticky bits.
+        const SPECIAL_BITS = 1 << 1;
+        /// Modification times.
+        const TIMES = 1 << 2;
+        /// Creation times, where they can be set.
+        const BIRTH_TIME = 1 << 3;
+        /// Owner and group.
+        const OWNER = 1 << 4;
+        /// Extended attributes.
+        const XATTR = 1 << 5;
+        /// NTFS access control lists.
+        const ACL = 1 << 6;
+        /// Named NTFS data streams.
+        const STREAMS = 1 << 7;
+        /// Linux file capabilities.
+        const CAPABILITIES = 1 << 8;
+        /// Linux inode flags such as immutable and `nocow`.
+        const INODE_FLAGS = 1 << 9;
+        /// Hardlinks between files in the source.
+        const HARDLINKS = 1 << 10;
+    }
+}
+
+/// The setuid, setgid and sticky bits, kept only with
+/// [`Options::preserve_special_bits`](crate::Options::preserve_special_bits).
+#[cfg(unix)]
+pub(crate) const SPECIAL_BITS: u32 = 0o7000;
+
+/// Set the permissions of `dest` to match `metadata`, leaving out the
+/// [special bits](SPECIAL_BITS) unless `special`.
+#[cfg(unix)]
+pub(crate) fn permissions(metadata: &Metadata, dest: &Path, special: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    mode(metadata.permissions().mode(), dest, special)
+}
+
+/// On Windows the attributes shown in Explorer stand in for permissions, so
+/// the [copied attributes](ATTRIBUTES) of `dest` are set to match `metadata`.
+#[cfg(windows)]
+pub(crate) fn permissions(metadata: &Metadata, dest: &Path, _special: bool) -> Result<()> {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::{SetFileAttributesW, FILE_ATTRIBUTE_NORMAL};
+
+    let current = std::fs::symlink_metadata(dest)
+        .map_err(Error::Io)?
+        .file_attributes();
+    let mut attributes = (current & !ATTRIBUTES) | (metadata.file_attributes() & ATTRIBUTES);
+    if attributes == current {
+        return Ok(());
+    }
+    if attributes == 0 {
+        attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    let wide = wide(dest);
+    // SAFETY: `wide` is a NUL-terminated UTF-16 path that outlives the call.
+    if unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } == 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// The Windows attributes copied with permissions: read-only, hidden,
+/// system and archive.
+#[cfg(windows)]
+const ATTRIBUTES: u32 = {
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY,
+        FILE_ATTRIBUTE_SYSTEM,
+    };
+    FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM | FILE_ATTRIBUTE_ARCHIVE
+};
+
+/// `path` as a NUL-terminated UTF-16 string, for Win32 calls.
+#[cfg(windows)]
+pub(crate) fn wide(path: &Path) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().chain([0]).collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn permissions(metadata: &Metadata, dest: &Path, _special: bool) -> Result<()> {
+    std::fs::set_permissions(dest, metadata.permissions()).map_err(Error::Io)
+}
+
+/// Set the owner and group of `dest` to match `metadata`, without following
+/// symlinks. Only root may give files away, so a permission error is
+/// returned as `Ok(Some(_))` for the caller to warn about.
+#[cfg(unix)]
+pub(crate) fn ownership(metadata: &Metadata, dest: &Path) -> Result<Option<io::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    owner(Some(metadata.uid()), Some(metadata.gid()), dest)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn ownership(_metadata: &Metadata, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// Set the owner and group of `dest` by id, as [`ownership`] does. `None`
+/// leaves that id unchanged.
+#[cfg(unix)]
+pub(crate) fn owner(uid: Option<u32>, gid: Option<u32>, dest: &Path) -> Result<Option<io::Error>> {
+    match std::os::unix::fs::lchown(dest, uid, gid) {
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(Some(e)),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn owner(
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+    _dest: &Path,
+) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// Set the permission bits of `dest` to `mode`, leaving out the
+/// [special bits](SPECIAL_BITS) unless `special`. Only unix has modes, so
+/// elsewhere this does nothing.
+#[cfg(unix)]
+pub(crate) fn mode(mode: u32, dest: &Path, special: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if special { mode } else { mode & !SPECIAL_BITS };
+    std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode)).map_err(Error::Io)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn mode(_mode: u32, _dest: &Path, _special: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Set the creation time of `dest` to that of the file `metadata` describes,
+/// where the platform allows it. Permissions may already have made `dest`
+/// read-only, so it is opened only with the access needed to change times.
+#[cfg(any(target_os = "macos", windows))]
+pub(crate) fn birth_time(metadata: &Metadata, dest: &Path) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::{FileTimesExt, OpenOptionsExt};
+
+    let created = metadata.created()?;
+    let mut open = std::fs::OpenOptions::new();
+    #[cfg(target_os = "macos")]
+    open.read(true);
+    // FILE_WRITE_ATTRIBUTES
+    #[cfg(windows)]
+    open.access_mode(0x100);
+    let file = open.open(dest)?;
+    file.set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+pub(crate) fn birth_time(_metadata: &Metadata, _dest: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copy the access control list (DACL) of `src` to `dest`. Whether the list
+/// is protected from inheritance is copied too, so entries inherited by the
+/// source are inherited afresh from the destination's parent. Lists the
+/// process may not read or write are returned as `Ok(Some(_))` for the caller
+/// to warn about.
+#[cfg(windows)]
+pub(crate) fn acl(src: &Path, dest: &Path) -> Result<Option<io::Error>> {
+    use windows_sys::Win32::Foundation::{LocalFree, ERROR_ACCESS_DENIED, ERROR_SUCCESS};
+    use windows_sys::Win32::Security::Authorization::{
+        GetNamedSecurityInfoW, SetNamedSecurityInfoW, SE_FILE_OBJECT,
+    };
+    use windows_sys::Win32::Security::{
+        GetSecurityDescriptorControl, ACL, DACL_SECURITY_INFORMATION,
+        PROTECTED_DACL_SECURITY_INFORMATION, SE_DACL_PROTECTED,
+        UNPROTECTED_DACL_SECURITY_INFORMATION,
+    };
+
+    let check = |code: u32| match code {
+        ERROR_SUCCESS => Ok(None),
+        ERROR_ACCESS_DENIED => Ok(Some(io::Error::from_raw_os_error(code as i32))),
+        _ => Err(Error::Io(io::Error::from_raw_os_error(code as i32))),
+    };
+    let (src, dest) = (wide(src), wide(dest));
+    let mut dacl: *mut ACL = std::ptr::null_mut();
+    let mut descriptor = std::ptr::null_mut();
+    // SAFETY: `src` is NUL-terminated, and on success `descriptor` is
+    // allocated for us and holds `dacl`, so is freed only after it is used.
+    let code = unsafe {
+        GetNamedSecurityInfoW(
+            src.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut dacl,
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if code != ERROR_SUCCESS {
+        return check(code);
+    }
+    let (mut control, mut revision) = (0, 0);
+    // SAFETY: `descriptor` is the valid descriptor returned above.
+    unsafe { GetSecurityDescriptorControl(descriptor, &mut control, &mut revision) };
+    let inheritance = if control & SE_DACL_PROTECTED != 0 {
+        PROTECTED_DACL_SECURITY_INFORMATION
+    } else {
+        UNPROTECTED_DACL_SECURITY_INFORMATION
+    };
+    // SAFETY: `dest` is NUL-terminated and `dacl` points into `descriptor`,
+    // which is still alive.
+    let code = unsafe {
+        SetNamedSecurityInfoW(
+            dest.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION | inheritance,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            dacl,
+            std::ptr::null(),
+        )
+    };
+    // SAFETY: `descriptor` came from GetNamedSecurityInfoW and isn't used
+    // again.
+    unsafe { LocalFree(descriptor) };
+    check(code)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn acl(_src: &Path, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// Copy the named NTFS streams of `src`, such as `Zone.Identifier`, to
+/// `dest`. A source filesystem without streams has none to copy, and a
+/// destination that can't hold them gives `Ok(Some(_))` for the caller to
+/// warn about.
+#[cfg(windows)]
+pub(crate) fn streams(src: &Path, dest: &Path) -> Result<Option<io::Error>> {
+    use windows_sys::Win32::Foundation::ERROR_INVALID_PARAMETER;
+
+    let names = match stream_names(src) {
+        Ok(names) => names,
+        // Not NTFS, or a name the stream API won't take
+        Err(e) if e.raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32) => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let stream_path = |path: &Path, name: &std::ffi::OsStr| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(":");
+        path.push(name);
+        std::path::PathBuf::from(path)
+    };
+    let mut skipped = None;
+    for name in names {
+        let mut reader = std::fs::File::open(stream_path(src, &name)).map_err(Error::Io)?;
+        let copied = std::fs::File::create(stream_path(dest, &name))
+            .and_then(|mut writer| io::copy(&mut reader, &mut writer));
+        if let Err(e) = copied {
+            skipped.get_or_insert(e);
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn streams(_src: &Path, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// The names of the named data streams of `path`, without the default
+/// unnamed stream.
+#[cfg(windows)]
+fn stream_names(path: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+    use std::os::windows::ffi::OsStringExt;
+    use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+        WIN32_FIND_STREAM_DATA,
+    };
+
+    let path = wide(path);
+    let mut data = WIN32_FIND_STREAM_DATA::default();
+    // SAFETY: `path` is NUL-terminated and `data` is the struct the standard
+    // info level fills in.
+    let handle = unsafe {
+        FindFirstStreamW(
+            path.as_ptr(),
+            FindStreamInfoStandard,
+            (&mut data as *mut WIN32_FIND_STREAM_DATA).cast(),
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(code) if code == ERROR_HANDLE_EOF as i32 => Ok(Vec::new()),
+            _ => Err(e),
+        };
+    }
+    let mut names = Vec::new();
+    let result = loop {
+        // Names look like `:Zone.Identifier:$DATA`, and `::$DATA` for the
+        // unnamed stream
+        let len = data.cStreamName.iter().position(|&c| c == 0).unwrap_or(0);
+        let name = std::ffi::OsString::from_wide(&data.cStreamName[..len]);
+        let name = name.to_string_lossy();
+        if let Some(name) = name
+            .strip_prefix(':')
+            .and_then(|n| n.strip_suffix(":$DATA"))
+        {
+            if !name.is_empty() {
+                names.push(std::ffi::OsString::from(name));
+            }
+        }
+        // SAFETY: `handle` is a valid find handle and `data` is as above.
+        let more =
+            unsafe { FindNextStreamW(handle, (&mut data as *mut WIN32_FIND_STREAM_DATA).cast()) };
+        if more == 0 {
+            let e = io::Error::last_os_error();
+            break match e.raw_os_error() {
+                Some(code) if code == ERROR_HANDLE_EOF as i32 => Ok(names),
+                _ => Err(e),
+            };
+        }
+    };
+    // SAFETY: `handle` is a valid find handle, closed only here.
+    unsafe { FindClose(handle) };
+    result
+}
+
+/// Copy the extended attributes of `src` to `dest`, without following
+/// symlinks. A source filesystem without xattr support has none to copy.
+/// Attributes the destination can't store, or the process may not set, such
+/// as `trusted.*` for non-root users, are skipped, and the first such error
+/// is returned as `Ok(Some(_))` for the caller to warn about.
+#[cfg(unix)]
+pub(crate) fn xattrs(src: &Path, dest: &Path) -> Result<Option<io::Error>> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(e) if unsupported(&e) => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let mut skipped = None;
+    for name in names {
+        let Some(value) = xattr::get(src, &name).map_err(Error::Io)? else {
+            continue;
+        };
+        match xattr::set(dest, &name, &value) {
+            Ok(()) => {}
+            Err(e) if unsupported(&e) || e.kind() == io::ErrorKind::PermissionDenied => {
+                skipped.get_or_insert(e);
+            }
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn xattrs(_src: &Path, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// The xattr that holds a file's Linux capabilities.
+#[cfg(unix)]
+const CAPABILITY: &str = "security.capability";
+
+/// Copy the capabilities of `src` to `dest`. Changing a file's owner or
+/// contents drops its capabilities, so this comes after both. Like
+/// [`xattrs`], a destination that can't store them, or a process without
+/// `CAP_SETFCAP`, gives `Ok(Some(_))` for the caller to warn about.
+#[cfg(unix)]
+pub(crate) fn capabilities(src: &Path, dest: &Path) -> Result<Option<io::Error>> {
+    let value = match xattr::get(src, CAPABILITY) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Ok(None),
+        Err(e) if unsupported(&e) => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    match xattr::set(dest, CAPABILITY, &value) {
+        Ok(()) => Ok(None),
+        Err(e) if unsupported(&e) || e.kind() == io::ErrorKind::PermissionDenied => Ok(Some(e)),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn capabilities(_src: &Path, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// Copy the `chattr` flags of `src` to `dest`. As with [`xattrs`], flags the
+/// destination or the process can't set give `Ok(Some(_))` for the caller to
+/// warn about, and a source filesystem without flags has none to copy.
+#[cfg(target_os = "linux")]
+pub(crate) fn inode_flags(src: &Path, dest: &Path) -> Result<Option<io::Error>> {
+    use crate::inode_flags::{self, COPIED};
+    let flags = match inode_flags::get(src) {
+        Ok(flags) => flags & COPIED,
+        Err(e) if inode_flags::unsupported(&e) => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    match inode_flags::set(dest, flags, COPIED) {
+        Ok(()) => Ok(None),
+        Err(e) if inode_flags::unsupported(&e) => Ok(Some(e)),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn inode_flags(_src: &Path, _dest: &Path) -> Result<Option<io::Error>> {
+    Ok(None)
+}
+
+/// If `src` is a `nocow` file, create `dest` as an empty file with the flag
+/// set, ready for its data, and return true. Failing to set the flag leaves
+/// it for [`inode_flags`] to report.
+#[cfg(target_os = "linux")]
+pub(crate) fn create_nocow(src: &Path, dest: &Path) -> Result<bool> {
+    use crate::inode_flags::{self, FS_NOCOW_FL};
+    let nocow = inode_flags::get(src).is_ok_and(|flags| flags & FS_NOCOW_FL != 0);
+    if !nocow {
+        return Ok(false);
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .map_err(Error::Io)?;
+    match inode_flags::set(dest, FS_NOCOW_FL, FS_NOCOW_FL) {
+        Err(e) if !inode_flags::unsupported(&e) => Err(Error::Io(e)),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn create_nocow(_src: &Path, _dest: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+/// Create a symlink at `dest` pointing wherever the symlink at `src` points.
+/// On Windows a link to a directory is made as `dir_links` says.
+pub(crate) fn symlink(src: &Path, dest: &Path, dir_links: DirLinks) -> io::Result<()> {
+    let target = std::fs::read_link(src)?;
+    #[cfg(unix)]
+    {
+        let _ = dir_links;
+        std::os::unix::fs::symlink(target, dest)
+    }
+    #[cfg(windows)]
+    {
+        if std::fs::metadata(src).is_ok_and(|m| m.is_dir()) {
+            dir_link(&target, dest, dir_links)
+        } else {
+            std::os::windows::fs::symlink_file(target, dest)
+        }
+    }
+}
+
+/// Create a symlink at `dest` pointing to `target`. On Windows the link is
+/// a directory link, made as `dir_links` says, if `target` resolves to a
+/// directory from `dest`.
+pub(crate) fn symlink_to(target: &Path, dest: &Path, dir_links: DirLinks) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let _ = dir_links;
+        std::os::unix::fs::symlink(target, dest)
+    }
+    #[cfg(windows)]
+    {
+        let resolved = dest.parent().unwrap_or(Path::new("")).join(target);
+        if resolved.is_dir() {
+            dir_link(target, dest, dir_links)
+        } else {
+            std::os::windows::fs::symlink_file(target, dest)
+        }
+    }
+}
+
+/// Link `dest` to the directory `target` with a symlink or a junction. A
+/// junction can't hold a relative target, so it gets `target` resolved from
+/// `dest`, which still points inside the clone.
+#[cfg(windows)]
+fn dir_link(target: &Path, dest: &Path, dir_links: DirLinks) -> io::Result<()> {
+    use windows_sys::Win32::Foundation::ERROR_PRIVILEGE_NOT_HELD;
+
+    let junction = || {
+        let resolved = dest.parent().unwrap_or(Path::new("")).join(target);
+        crate::junction::create(&resolved, dest)
+    };
+    match dir_links {
+        DirLinks::Symlink => std::os::windows::fs::symlink_dir(target, dest),
+        DirLinks::Junction => junction(),
+        DirLinks::Auto => match std::os::windows::fs::symlink_dir(target, dest) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD as i32) => junction(),
+            linked => linked,
+        },
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_permissions_and_symlink() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src.sh");
+        let dest = temp_dir.path().join("dest.sh");
+        fs::write(&src, "#!/bin/sh")?;
+        fs::write(&dest, "#!/bin/sh")?;
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o4750))?;
+
+        // The setuid bit is only kept when asked for
+        permissions(&fs::metadata(&src)?, &dest, false)?;
+        assert_eq!(fs::metadata(&dest)?.permissions().mode() & 0o7777, 0o750);
+        permissions(&fs::metadata(&src)?, &dest, true)?;
+        assert_eq!(fs::metadata(&dest)?.permissions().mode() & 0o7777, 0o4750);
+
+        let link = temp_dir.path().join("link");
+        let copy = temp_dir.path().join("copy");
+        std::os::unix::fs::symlink("src.sh", &link)?;
+        symlink(&link, &copy, DirLinks::default())?;
+        assert_eq!(fs::read_link(&copy)?, Path::new("src.sh"));
+
+        // Not root, so giving the file away fails quietly; as root it is a no-op
+        ownership(&fs::symlink_metadata(&link)?, &copy)?;
+        xattrs(&src, &dest)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Declarative clone profiles, loaded with [`Options::from_file`].
+
+use crate::{
+    hash, Backup, Compare, DirLinks, Error, Junctions, LongPaths, Normalization, Options,
+    Overwrite, Pattern, Reflink, Result, Sanitize, Submodules,
+};
+use serde::Deserialize;
+use std::path::Path;
+
+/// The on-disk form of a profile. Every field is optional and falls back to
+/// the [`Options`] default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Profile {
+    globs: Vec<String>,
+    overwrite: OverwriteSetting,
+    backup: BackupMode,
+    #[cfg(feature = "trash")]
+    trash: bool,
+    sorted: bool,
+    gitignore: bool,
+    git_global: bool,
+    git_exclude: bool,
+    submodules: SubmoduleMode,
+    share_git_objects: bool,
+    check_space: bool,
+    compare: CompareMode,
+    hasher: Option<String>,
+    update: bool,
+    delete: bool,
+    prune_empty_dirs: bool,
+    delete_excluded: bool,
+    dedup_source: bool,
+    state_cache: bool,
+    lock_source: bool,
+    symlinks: bool,
+    junctions: JunctionMode,
+    dir_links: DirLinkMode,
+    threads: usize,
+    keep_going: bool,
+    verify: bool,
+    append: bool,
+    chunk_size: Option<usize>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    max_files: Option<u64>,
+    max_total_bytes: Option<u64>,
+    strategy: Strategy,
+    normalize: NormalizeMode,
+    sanitize: SanitizeMode,
+    long_paths: LongPathMode,
+    preserve: PreserveTable,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PreserveTable {
+    times: bool,
+    birth_time: bool,
+    permissions: bool,
+    special_bits: bool,
+    ownership: bool,
+    xattrs: bool,
+    acls: bool,
+    streams: bool,
+    capabilities: bool,
+    inode_flags: bool,
+    hardlinks: bool,
+}
+
+/// `overwrite = true`, or a mode such as `overwrite = "replace"`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OverwriteSetting {
+    Enabled(bool),
+    Mode(OverwriteMode),
+}
+
+impl Default for OverwriteSetting {
+    fn default() -> Self {
+        OverwriteSetting::Enabled(false)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OverwriteMode {
+    Never,
+    Merge,
+    Replace,
+    NoClobber,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BackupMode {
+    #[default]
+    Off,
+    Numbered,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CompareMode {
+    #[default]
+    Metadata,
+    Hash,
+    Newer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NormalizeMode {
+    #[default]
+    None,
+    Nfc,
+    Nfd,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SanitizeMode {
+    #[default]
+    Off,
+    Replace,
+    Skip,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SubmoduleMode {
+    #[default]
+    Include,
+    Skip,
+    Separate,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JunctionMode {
+    #[default]
+    Symlink,
+    Junction,
+    Follow,
+    Skip,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LongPathMode {
+    #[default]
+    Error,
+    Skip,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DirLinkMode {
+    #[default]
+    Symlink,
+    Junction,
+    Auto,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Strategy {
+    #[default]
+    Auto,
+    Reflink,
+    Copy,
+}
+
+impl From<Profile> for Options {
+    fn from(profile: Profile) -> Self {
+        let mut options = Options::new();
+        for glob in profile.globs {
+            options = options.glob(glob);
+        }
+        if let Some(bytes) = profile.min_size {
+            options = options.min_size(bytes);
+        }
+        if let Some(bytes) = profile.max_size {
+            options = options.max_size(bytes);
+        }
+        if let Some(bytes) = profile.chunk_size {
+            options = options.chunk_size(bytes);
+        }
+        if let Some(count) = profile.max_files {
+            options = options.max_files(count);
+        }
+        if let Some(bytes) = profile.max_total_bytes {
+            options = options.max_total_bytes(bytes);
+        }
+        #[cfg(feature = "trash")]
+        {
+            options = options.trash(profile.trash);
+        }
+        if let Some(hasher) = profile.hasher.as_deref().and_then(hash::by_name) {
+            options.hasher = Some(hash::HasherRef(hasher));
+        }
+        options
+            .overwrite(match profile.overwrite {
+                OverwriteSetting::Enabled(enabled) => Overwrite::from(enabled),
+                OverwriteSetting::Mode(OverwriteMode::Never) => Overwrite::Never,
+                OverwriteSetting::Mode(OverwriteMode::Merge) => Overwrite::Merge,
+                OverwriteSetting::Mode(OverwriteMode::Replace) => Overwrite::Replace,
+                OverwriteSetting::Mode(OverwriteMode::NoClobber) => Overwrite::NoClobber,
+            })
+            .backup(match profile.backup {
+                BackupMode::Off => Backup::Off,
+                BackupMode::Numbered => Backup::Numbered,
+            })
+            .sorted(profile.sorted)
+            .gitignore(profile.gitignore)
+            .git_global(profile.git_global)
+            .git_exclude(profile.git_exclude)
+            .submodules(match profile.submodules {
+                SubmoduleMode::Include => Submodules::Include,
+                SubmoduleMode::Skip => Submodules::Skip,
+                SubmoduleMode::Separate => Submodules::Separate,
+            })
+            .share_git_objects(profile.share_git_objects)
+            .check_space(profile.check_space)
+            .compare(match profile.compare {
+                CompareMode::Metadata => Compare::Metadata,
+                CompareMode::Hash => Compare::Hash,
+                CompareMode::Newer => Compare::Newer,
+            })
+            .update(profile.update)
+            .delete(profile.delete)
+            .prune_empty_dirs(profile.prune_empty_dirs)
+            .delete_excluded(profile.delete_excluded)
+            .dedup_source(profile.dedup_source)
+            .state_cache(profile.state_cache)
+            .lock_source(profile.lock_source)
+            .symlinks(profile.symlinks)
+            .junctions(match profile.junctions {
+                JunctionMode::Symlink => Junctions::Symlink,
+                JunctionMode::Junction => Junctions::Junction,
+                JunctionMode::Follow => Junctions::Follow,
+                JunctionMode::Skip => Junctions::Skip,
+            })
+            .dir_links(match profile.dir_links {
+                DirLinkMode::Symlink => DirLinks::Symlink,
+                DirLinkMode::Junction => DirLinks::Junction,
+                DirLinkMode::Auto => DirLinks::Auto,
+            })
+            .threads(profile.threads)
+            .keep_going(profile.keep_going)
+            .verify(profile.verify)
+            .append(profile.append)
+            .reflink(match profile.strategy {
+                Strategy::Auto => Reflink::Auto,
+                Strategy::Reflink => Reflink::Always,
+                Strategy::Copy => Reflink::Never,
+            })
+            .normalize_unicode(match profile.normalize {
+                NormalizeMode::None => Normalization::None,
+                NormalizeMode::Nfc => Normalization::Nfc,
+                NormalizeMode::Nfd => Normalization::Nfd,
+            })
+            .sanitize_names(match profile.sanitize {
+                SanitizeMode::Off => Sanitize::Off,
+                SanitizeMode::Replace => Sanitize::Replace,
+                SanitizeMode::Skip => Sanitize::Skip,
+            })
+            .long_paths(match profile.long_paths {
+                LongPathMode::Error => LongPaths::Error,
+                LongPathMode::Skip => LongPaths::Skip,
+            })
+            .preserve_times(profile.preserve.times)
+            .preserve_birth_time(profile.preserve.birth_time)
+            .preserve_permissions(profile.preserve.permissions)
+            .preserve_special_bits(profile.preserve.special_bits)
+            .preserve_ownership(profile.preserve.ownership)
+            .preserve_xattrs(profile.preserve.xattrs)
+            .preserve_acls(profile.preserve.acls)
+            .preserve_streams(profile.preserve.streams)
+            .preserve_capabilities(profile.preserve.capabilities)
+            .preserve_inode_flags(profile.preserve.inode_flags)
+            .preserve_hardlinks(profile.preserve.hardlinks)
+    }
+}
+
+impl Options {
+    /// Load options from a TOML or JSON profile, chosen by the file extension.
+    /// Field names match the builder methods, with `overwrite` either a bool
+    /// or `"never" | "merge" | "replace" | "no-clobber"`, backups as
+    /// `backup = "off" | "numbered"`, the content hash as
+    /// `hasher = "blake3" | "sha256" | "xxh64" | "crc32c"`, the reflink strategy given
+    /// as `strategy = "auto" | "reflink" | "copy"`, Unicode normalization as
+    /// `normalize = "none" | "nfc" | "nfd"`, Windows name handling as
+    /// `sanitize = "off" | "replace" | "skip"`, over-long destination paths
+    /// as `long_paths = "error" | "skip"`, git submodules as
+    /// `submodules = "include" | "skip" | "separate"`, Windows junctions as
+    /// `junctions = "symlink" | "junction" | "follow" | "skip"`, links to
+    /// directories on Windows as `dir_links = "symlink" | "junction" | "auto"`,
+    /// and
+    /// preservation flags in a `preserve` table:
+    ///
+    /// ```toml
+    /// globs = ["src/**", "!src/tests/**"]
+    /// overwrite = true
+    /// compare = "hash"
+    /// strategy = "reflink"
+    ///
+    /// [preserve]
+    /// times = true
+    /// permissions = true
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|source| Error::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let profile_err = |message: String| Error::Profile {
+            path: path.to_path_buf(),
+            message,
+        };
+        let profile: Profile = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| profile_err(e.to_string()))?,
+            Some("json") => {
+                serde_json::from_str(&contents).map_err(|e| profile_err(e.to_string()))?
+            }
+            _ => return Err(profile_err("expected a .toml or .json file".into())),
+        };
+        for glob in &profile.globs {
+            Pattern::new(glob.as_str()).map_err(|e| profile_err(e.to_string()))?;
+        }
+        if let Some(hasher) = &profile.hasher {
+            if hash::by_name(hasher).is_none() {
+                return Err(profile_err(format!("unknown hasher {hasher}")));
+            }
+        }
+        Ok(profile.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Preserve;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_from_toml() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("profile.toml");
+        fs::write(
+            &path,
+            r#"
+globs = ["**/*.rs", "!target/**"]
+overwrite = true
+backup = "numbered"
+compare = "hash"
+strategy = "copy"
+normalize = "nfc"
+
+[preserve]
+times = true
+xattrs = true
+"#,
+        )?;
+
+        let options = Options::from_file(&path)?;
+        assert_eq!(options.globs, vec!["**/*.rs", "!target/**"]);
+        assert_eq!(options.overwrite, Overwrite::Merge);
+        assert_eq!(options.backup, Backup::Numbered);
+        assert_eq!(options.compare, Compare::Hash);
+        assert_eq!(options.reflink, Reflink::Never);
+        assert_eq!(options.normalization, Normalization::Nfc);
+        assert_eq!(options.sanitize, Sanitize::Off);
+        assert_eq!(options.preserve, Preserve::TIMES | Preserve::XATTR);
+        assert!(!options.update);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_json() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("profile.json");
+        fs::write(
+            &path,
+            r#"{"globs": ["*.txt"], "sorted": true, "threads": 4, "overwrite": "replace", "hasher": "sha256"}"#,
+        )?;
+
+        let options = Options::from_file(&path)?;
+        assert_eq!(options.globs, vec!["*.txt"]);
+        assert!(options.sorted);
+        assert_eq!(options.threads, 4);
+        assert_eq!(options.overwrite, Overwrite::Replace);
+        assert_eq!(options.content_hasher().name(), "sha256");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_profile() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("profile.toml");
+        fs::write(&path, "unknown_field = 1")?;
+        assert!(matches!(
+            Options::from_file(&path),
+            Err(Error::Profile { .. })
+        ));
+
+        fs::write(&path, r#"globs = ["src/{a,b"]"#)?;
+        assert!(matches!(
+            Options::from_file(&path),
+            Err(Error::Profile { .. })
+        ));
+
+        let path = temp_dir.path().join("profile.yaml");
+        fs::write(&path, "globs: []")?;
+        assert!(matches!(
+            Options::from_file(&path),
+            Err(Error::Profile { .. })
+        ));
+
+        Ok(())
+    }
+}
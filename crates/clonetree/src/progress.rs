@@ -0,0 +1,389 @@
+//! Progress reporting for long-running clones.
+
+use crate::observer::Observers;
+use crate::report::{CloneReport, LargeFile, SkipLog, SkipReason};
+use crate::{CancelToken, Error, Options, Quota, Result, TreeEstimate};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What happened to a single file during a clone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The file was reflinked, sharing extents with the source or with an
+    /// identical file already in the destination.
+    Reflinked,
+    /// The file's data was copied.
+    Copied,
+    /// The file was hardlinked to the source, as git objects are with
+    /// [`Options::share_git_objects`](crate::Options::share_git_objects), or
+    /// to an earlier copy with
+    /// [`Options::preserve_hardlinks`](crate::Options::preserve_hardlinks).
+    Hardlinked,
+    /// Nothing was written, for the given reason.
+    Skipped(SkipReason),
+}
+
+/// A snapshot of clone progress, passed to the callback registered with
+/// [`Options::on_progress`](crate::Options::on_progress) after each file.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    /// Files processed so far, whether copied or skipped as unchanged.
+    pub files_done: u64,
+    /// Bytes processed so far: the total size of the processed files.
+    pub bytes_done: u64,
+    /// How many files the clone processes in all, counted before it started
+    /// with [`Options::count_first`](crate::Options::count_first).
+    pub files_total: Option<u64>,
+    /// The total size of those files.
+    pub bytes_total: Option<u64>,
+    /// Time since the clone started.
+    pub elapsed: Duration,
+    /// Recent throughput in bytes per second, smoothed over several seconds
+    /// so that a run of small or large files doesn't swing it.
+    pub bytes_per_sec: f64,
+    /// Estimated time until the remaining bytes are done at the current
+    /// rate, when the totals are known.
+    pub eta: Option<Duration>,
+    /// The file just processed, relative to the source root.
+    pub path: &'a Path,
+    /// The size of the file just processed.
+    pub bytes: u64,
+    /// What was done with the file just processed.
+    pub outcome: Outcome,
+}
+
+/// A shareable progress callback.
+#[derive(Clone)]
+pub(crate) struct ProgressFn(pub(crate) Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl fmt::Debug for ProgressFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressFn")
+    }
+}
+
+impl Options {
+    /// Call `callback` after each file, as well as any progress callback
+    /// already set.
+    pub(crate) fn also_on_progress<F>(self, callback: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        let existing = self.progress.clone();
+        self.on_progress(move |progress| {
+            callback(progress);
+            if let Some(ProgressFn(existing)) = &existing {
+                existing(progress);
+            }
+        })
+    }
+}
+
+/// Counts progress through a clone and reports it to the callback, if any.
+/// Also enforces cancellation, the quotas and the overall timeout, counting
+/// each file as it starts so parallel workers can't overshoot them.
+#[derive(Default)]
+pub(crate) struct Tracker {
+    callback: Option<ProgressFn>,
+    observers: Observers,
+    files_done: u64,
+    bytes_done: u64,
+    files_total: Option<u64>,
+    bytes_total: Option<u64>,
+    throughput: Throughput,
+    max_files: Option<u64>,
+    max_bytes: Option<u64>,
+    files_started: u64,
+    bytes_started: u64,
+    deadline: Option<(Instant, Duration)>,
+    cancel: Option<CancelToken>,
+    report: CloneReport,
+    skipped: Option<SkipLog>,
+    breakdown: bool,
+    /// The largest files so far, smallest on top so it can be dropped.
+    largest: BinaryHeap<Reverse<LargeFile>>,
+    largest_count: usize,
+}
+
+impl Tracker {
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            callback: options.progress.clone(),
+            observers: options.observers.clone(),
+            max_files: options.max_files,
+            max_bytes: options.max_total_bytes,
+            deadline: options
+                .timeout
+                .map(|timeout| (Instant::now() + timeout, timeout)),
+            cancel: options.cancel.clone(),
+            skipped: options.record_skipped.then(SkipLog::default),
+            breakdown: options.record_breakdown,
+            largest_count: options.record_largest,
+            ..Self::default()
+        }
+    }
+
+    /// Add a tree counted up front to the totals reported with progress.
+    pub(crate) fn count(&mut self, estimate: TreeEstimate) {
+        self.files_total = Some(self.files_total.unwrap_or(0) + estimate.files);
+        self.bytes_total = Some(self.bytes_total.unwrap_or(0) + estimate.bytes);
+    }
+
+    /// Count a file of `bytes` bytes against the quotas before processing
+    /// it, failing with [`Error::QuotaExceeded`] if it doesn't fit, or with
+    /// [`Error::Timeout`] once the clone is out of time, or with
+    /// [`Error::Cancelled`] once it has been cancelled.
+    pub(crate) fn start_file(&mut self, bytes: u64) -> Result<()> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        if let Some((deadline, timeout)) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout { timeout });
+            }
+        }
+        if let Some(limit) = self.max_files.filter(|&max| self.files_started >= max) {
+            return Err(Error::QuotaExceeded {
+                quota: Quota::Files,
+                limit,
+            });
+        }
+        let total = self.bytes_started.saturating_add(bytes);
+        if let Some(limit) = self.max_bytes.filter(|&max| total > max) {
+            return Err(Error::QuotaExceeded {
+                quota: Quota::Bytes,
+                limit,
+            });
+        }
+        self.files_started += 1;
+        self.bytes_started = total;
+        Ok(())
+    }
+
+    /// Record that a file of `bytes` bytes at `path` has been processed,
+    /// returning what to tell the callbacks about it.
+    pub(crate) fn file_done<'a>(
+        &mut self,
+        path: &'a Path,
+        bytes: u64,
+        outcome: Outcome,
+    ) -> FileDone<'a> {
+        self.files_done += 1;
+        self.bytes_done += bytes;
+        if self.breakdown {
+            self.report.tally(path, bytes);
+        }
+        if self.largest_count > 0 {
+            self.largest.push(Reverse(LargeFile {
+                size: bytes,
+                path: path.to_path_buf(),
+            }));
+            if self.largest.len() > self.largest_count {
+                self.largest.pop();
+            }
+        }
+        match outcome {
+            Outcome::Reflinked => {
+                self.report.reflinked += 1;
+                self.report.reflinked_bytes += bytes;
+            }
+            Outcome::Copied => {
+                self.report.copied += 1;
+                self.report.copied_bytes += bytes;
+            }
+            Outcome::Hardlinked => {
+                self.report.hardlinked += 1;
+                self.report.hardlinked_bytes += bytes;
+            }
+            Outcome::Skipped(reason) => {
+                self.report.skipped += 1;
+                self.skip(path, reason);
+            }
+        }
+        let progress = self.callback.clone().map(|callback| {
+            let now = Instant::now();
+            let bytes_per_sec = self.throughput.update(now, self.bytes_done);
+            let eta = self
+                .bytes_total
+                .filter(|_| bytes_per_sec > 0.0)
+                .map(|total| total.saturating_sub(self.bytes_done) as f64 / bytes_per_sec)
+                .map(Duration::from_secs_f64);
+            let progress = Progress {
+                files_done: self.files_done,
+                bytes_done: self.bytes_done,
+                files_total: self.files_total,
+                bytes_total: self.bytes_total,
+                elapsed: now - self.throughput.started,
+                bytes_per_sec,
+                eta,
+                path,
+                bytes,
+                outcome,
+            };
+            (callback, progress)
+        });
+        FileDone {
+            path,
+            outcome,
+            observers: self.observers.clone(),
+            progress,
+        }
+    }
+
+    /// Record that the entry at `path` was left out of the clone, if
+    /// [`Options::record_skipped`] is set.
+    pub(crate) fn skip(&self, path: &Path, reason: SkipReason) {
+        if let Some(log) = &self.skipped {
+            log.record(path.to_path_buf(), reason);
+        }
+    }
+
+    /// Where entries left out by the walk are recorded, if anywhere.
+    pub(crate) fn skip_log(&self) -> Option<SkipLog> {
+        self.skipped.clone()
+    }
+
+    /// What the clone has done so far.
+    pub(crate) fn report(&self) -> CloneReport {
+        CloneReport {
+            skipped_entries: self
+                .skipped
+                .as_ref()
+                .map(SkipLog::entries)
+                .unwrap_or_default(),
+            largest_files: {
+                let mut largest: Vec<_> = self.largest.iter().map(|f| f.0.clone()).collect();
+                largest.sort_by(|a, b| b.cmp(a));
+                largest
+            },
+            ..self.report.clone()
+        }
+    }
+}
+
+/// A processed file, with the progress at that point, for the observers and
+/// progress callback.
+#[must_use = "the callbacks are only called by `notify`"]
+pub(crate) struct FileDone<'a> {
+    path: &'a Path,
+    outcome: Outcome,
+    observers: Observers,
+    progress: Option<(ProgressFn, Progress<'a>)>,
+}
+
+impl FileDone<'_> {
+    /// Call the callbacks. This is done once the tracker is unlocked, so a
+    /// slow callback doesn't hold up workers still copying, though callers
+    /// with several workers must keep notifications in order.
+    pub(crate) fn notify(self) {
+        self.observers
+            .each(|observer| observer.on_file(self.path, self.outcome));
+        if let Some((ProgressFn(callback), progress)) = &self.progress {
+            callback(progress);
+        }
+    }
+}
+
+/// How far back, roughly, the throughput estimate looks.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Samples shorter than this are folded into the next one, since a burst of
+/// tiny files finishing together says little about the rate.
+const MIN_SAMPLE: Duration = Duration::from_millis(100);
+
+/// An exponentially weighted moving average of bytes per second.
+#[derive(Debug)]
+struct Throughput {
+    started: Instant,
+    sampled: Instant,
+    sampled_bytes: u64,
+    rate: Option<f64>,
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            sampled: now,
+            sampled_bytes: 0,
+            rate: None,
+        }
+    }
+}
+
+impl Throughput {
+    /// Take in `bytes_done` at `now` and return the current rate. Until the
+    /// first full sample, that is the plain average since the start.
+    fn update(&mut self, now: Instant, bytes_done: u64) -> f64 {
+        let span = now - self.sampled;
+        if span >= MIN_SAMPLE {
+            let sample = (bytes_done - self.sampled_bytes) as f64 / span.as_secs_f64();
+            // Weight the sample by how much of the window it covers
+            let weight = 1.0 - (-span.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp();
+            self.rate = Some(match self.rate {
+                Some(rate) => rate + weight * (sample - rate),
+                None => sample,
+            });
+            self.sampled = now;
+            self.sampled_bytes = bytes_done;
+        }
+        self.rate.unwrap_or_else(|| {
+            let elapsed = (now - self.started).as_secs_f64();
+            if elapsed > 0.0 {
+                bytes_done as f64 / elapsed
+            } else {
+                0.0
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let mut tracker = Tracker::new(&Options::new().max_files(2).max_total_bytes(100));
+        assert!(tracker.start_file(60).is_ok());
+        assert!(matches!(
+            tracker.start_file(50),
+            Err(Error::QuotaExceeded {
+                quota: Quota::Bytes,
+                limit: 100
+            })
+        ));
+        assert!(tracker.start_file(40).is_ok());
+        assert!(matches!(
+            tracker.start_file(0),
+            Err(Error::QuotaExceeded {
+                quota: Quota::Files,
+                limit: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = Throughput::default();
+        let start = throughput.started;
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(throughput.update(at(1000), 100), 100.0);
+        // A faster second moves the estimate only part of the way
+        let rate = throughput.update(at(2000), 1100);
+        assert!(rate > 100.0 && rate < 1000.0);
+        // Too short to count as a sample
+        assert_eq!(throughput.update(at(2010), 5000), rate);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut tracker = Tracker::new(&Options::new().timeout(Duration::ZERO));
+        assert!(matches!(tracker.start_file(0), Err(Error::Timeout { .. })));
+    }
+}
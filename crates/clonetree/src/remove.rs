@@ -0,0 +1,188 @@
+//! Deleting trees, with guards against deleting the wrong one.
+
+use crate::{build_globs, clone_entries, trace, Error, Options, Result};
+use std::path::{Path, PathBuf};
+
+/// Options for [`remove_tree`].
+#[derive(Debug, Default)]
+pub struct RemoveOptions {
+    filter: Options,
+    root: Option<PathBuf>,
+}
+
+impl RemoveOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a glob pattern restricting which files are removed, with the same
+    /// semantics as [`Options::glob`]. With any globs set, only matching files
+    /// are removed and the directories are left in place.
+    pub fn glob<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.filter = self.filter.glob(pattern);
+        self
+    }
+
+    /// Refuse to remove anything that isn't inside `root`, once symlinks in
+    /// both paths are resolved.
+    pub fn within<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+}
+
+/// The outcome of a [`remove_tree`] call.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RemoveStats {
+    /// Number of files and symlinks removed.
+    pub files_removed: u64,
+    /// Total size of the removed files.
+    pub bytes_removed: u64,
+}
+
+/// Remove the file or directory tree at `path`.
+///
+/// Fails with [`Error::RemoveRefused`] before touching anything if `path` is
+/// a filesystem root, or lies outside the root given to
+/// [`RemoveOptions::within`]. A symlink at `path` is removed itself; the
+/// tree it points to is left alone. Without globs the whole tree goes,
+/// directories included.
+pub fn remove_tree<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<RemoveStats> {
+    let path = path.as_ref();
+    let resolved = resolve(path)?;
+    let refuse = |reason: String| {
+        Err(Error::RemoveRefused {
+            path: path.to_path_buf(),
+            reason,
+        })
+    };
+    if resolved.parent().is_none() {
+        return refuse("it is a filesystem root".into());
+    }
+    if let Some(root) = &options.root {
+        let root = root.canonicalize().map_err(|source| Error::Read {
+            path: root.clone(),
+            source,
+        })?;
+        if !resolved.starts_with(&root) {
+            return refuse(format!("it is outside {}", root.display()));
+        }
+    }
+
+    let mut stats = RemoveStats::default();
+    let metadata = resolved.symlink_metadata()?;
+    if !metadata.is_dir() {
+        if is_selected(&resolved, &options.filter)? {
+            std::fs::remove_file(&resolved)?;
+            stats.files_removed = 1;
+            stats.bytes_removed = metadata.len();
+        }
+        return Ok(stats);
+    }
+
+    for entry in clone_entries(&resolved, &options.filter.clone().symlinks(true)) {
+        let entry = entry?;
+        trace::event!(debug, "deleted", path = entry.path.display());
+        std::fs::remove_file(&entry.path)?;
+        stats.files_removed += 1;
+        stats.bytes_removed += entry.metadata.len();
+    }
+    if options.filter.globs.is_empty() {
+        std::fs::remove_dir_all(&resolved)?;
+    }
+    Ok(stats)
+}
+
+/// `path` made absolute with symlinks resolved, except for a symlink at
+/// `path` itself.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let canonicalize = |path: &Path| {
+        path.canonicalize().map_err(|source| Error::Read {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            let resolved = canonicalize(parent)?.join(name);
+            resolved.symlink_metadata().map_err(|source| Error::Read {
+                path: path.to_path_buf(),
+                source,
+            })?;
+            Ok(resolved)
+        }
+        // `/`, `.` or `..`
+        _ => canonicalize(path),
+    }
+}
+
+/// Whether the single file at `path` passes the globs in `filter`, matched
+/// against its name.
+fn is_selected(path: &Path, filter: &Options) -> Result<bool> {
+    let (Some(globs), Some(name)) = (build_globs(filter)?, path.file_name()) else {
+        return Ok(true);
+    };
+    Ok(!globs.excludes(Path::new(name), false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn test_remove_tree() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("root");
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("sub"))?;
+        fs::write(tree.join("a.log"), "log")?;
+        fs::write(tree.join("sub/b.txt"), "text")?;
+
+        let options = RemoveOptions::new().within(&root).glob("*.log");
+        let stats = remove_tree(&tree, &options)?;
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.bytes_removed, 3);
+        assert!(!tree.join("a.log").exists());
+        assert!(tree.join("sub/b.txt").exists());
+
+        let stats = remove_tree(&tree, &RemoveOptions::new().within(&root))?;
+        assert_eq!(stats.files_removed, 1);
+        assert!(!tree.exists());
+        assert!(root.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_remove_refused() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("root");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(&root)?;
+        fs::create_dir_all(&outside)?;
+        fs::write(outside.join("file.txt"), "keep")?;
+
+        let options = RemoveOptions::new().within(&root);
+        let result = remove_tree(root.join("../outside"), &options);
+        assert!(matches!(result, Err(Error::RemoveRefused { .. })));
+        let result = remove_tree("/", &RemoveOptions::new());
+        assert!(matches!(result, Err(Error::RemoveRefused { .. })));
+
+        // A symlink is removed without following it
+        std::os::unix::fs::symlink(&outside, root.join("link"))?;
+        remove_tree(root.join("link"), &options)?;
+        assert!(!root.join("link").exists());
+        assert!(outside.join("file.txt").exists());
+
+        Ok(())
+    }
+}
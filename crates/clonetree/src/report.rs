@@ -0,0 +1,227 @@
+//! What a finished clone did.
+
+use crate::{Error, Outcome, Progress, Warning, WarningKind};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The result of a successful clone, returned by [`clone_tree`](crate::clone_tree)
+/// and the other clone functions.
+///
+/// The counts cover every file the clone processed. Files left out along the
+/// way are listed in [`skipped_entries`](Self::skipped_entries) when
+/// [`Options::record_skipped`](crate::Options::record_skipped) is set, so
+/// callers can audit exactly why something isn't in the destination.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloneReport {
+    /// Files reflinked, sharing data with the source.
+    pub reflinked: u64,
+    /// Total size of the reflinked files.
+    pub reflinked_bytes: u64,
+    /// Files whose data was copied.
+    pub copied: u64,
+    /// Total size of the copied files.
+    pub copied_bytes: u64,
+    /// Files hardlinked to the source, with
+    /// [`Options::share_git_objects`](crate::Options::share_git_objects), or
+    /// to an earlier copy, with
+    /// [`Options::preserve_hardlinks`](crate::Options::preserve_hardlinks).
+    pub hardlinked: u64,
+    /// Total size of the hardlinked files.
+    pub hardlinked_bytes: u64,
+    /// Files that were selected but not written, such as unchanged files in
+    /// update mode.
+    pub skipped: u64,
+    /// Everything left out of the clone and why, sorted by path. Empty unless
+    /// [`Options::record_skipped`](crate::Options::record_skipped) is set.
+    pub skipped_entries: Vec<SkippedEntry>,
+    /// The files processed, grouped by their top-level directory, with files
+    /// at the root under the empty path. Empty unless
+    /// [`Options::record_breakdown`](crate::Options::record_breakdown) is set.
+    pub by_directory: BTreeMap<PathBuf, Tally>,
+    /// The files processed, grouped by extension, with files that have none
+    /// under the empty string. Empty unless
+    /// [`Options::record_breakdown`](crate::Options::record_breakdown) is set.
+    pub by_extension: BTreeMap<String, Tally>,
+    /// The largest files processed, biggest first, as many as
+    /// [`Options::record_largest`](crate::Options::record_largest) asks for.
+    pub largest_files: Vec<LargeFile>,
+}
+
+impl CloneReport {
+    /// Files processed, whether written or skipped.
+    pub fn files(&self) -> u64 {
+        self.reflinked + self.copied + self.hardlinked + self.skipped
+    }
+
+    /// Bytes written to the destination, reflinked, copied or hardlinked.
+    pub fn bytes(&self) -> u64 {
+        self.reflinked_bytes + self.copied_bytes + self.hardlinked_bytes
+    }
+
+    /// Add a processed file of `bytes` bytes at `path` to the breakdowns.
+    pub(crate) fn tally(&mut self, path: &Path, bytes: u64) {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => path
+                .components()
+                .next()
+                .map(|c| PathBuf::from(c.as_os_str())),
+            _ => None,
+        };
+        let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+        self.by_directory
+            .entry(dir.unwrap_or_default())
+            .or_default()
+            .add(bytes);
+        self.by_extension
+            .entry(extension.unwrap_or_default())
+            .or_default()
+            .add(bytes);
+    }
+}
+
+/// Everything a clone did, returned by
+/// [`clone_tree_with_report`](crate::clone_tree_with_report) whether or not
+/// it finished.
+#[derive(Debug, Default)]
+pub struct DetailedReport {
+    /// The counts [`clone_tree`](crate::clone_tree) returns, covering the
+    /// files processed before any error that stopped the clone.
+    pub summary: CloneReport,
+    /// Each file processed and what was done with it, in the order they
+    /// finished.
+    pub files: Vec<FileOutcome>,
+    /// The warnings raised, in the order they were raised.
+    pub warnings: Vec<RecordedWarning>,
+    /// The error that stopped the clone, or with
+    /// [`Options::keep_going`](crate::Options::keep_going) each one it
+    /// carried on past. Empty if the clone completed.
+    pub errors: Vec<Error>,
+}
+
+impl DetailedReport {
+    /// Whether the clone completed without errors.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A file listed in [`DetailedReport::files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOutcome {
+    /// The path relative to the source root.
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+impl From<&Progress<'_>> for FileOutcome {
+    fn from(progress: &Progress) -> Self {
+        Self {
+            path: progress.path.to_path_buf(),
+            outcome: progress.outcome,
+        }
+    }
+}
+
+/// A [`Warning`] kept in [`DetailedReport::warnings`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedWarning {
+    /// The path relative to the source root.
+    pub path: PathBuf,
+    pub kind: WarningKind,
+    /// The message of the error behind the warning, if there was one.
+    pub error: Option<String>,
+}
+
+impl From<&Warning<'_>> for RecordedWarning {
+    fn from(warning: &Warning) -> Self {
+        Self {
+            path: warning.path.to_path_buf(),
+            kind: warning.kind,
+            error: warning.error.map(ToString::to_string),
+        }
+    }
+}
+
+/// A file count and total size, for one group of a breakdown in a
+/// [`CloneReport`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// One of the files listed in [`CloneReport::largest_files`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LargeFile {
+    pub size: u64,
+    /// The path relative to the source root.
+    pub path: PathBuf,
+}
+
+/// An entry left out of a clone, listed in
+/// [`CloneReport::skipped_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedEntry {
+    /// The path relative to the source root. For [`SkipReason::Excluded`]
+    /// this may be a directory, standing for everything inside it.
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+/// Why an entry was left out of a clone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    /// An exclude glob matched it, or include globs were given and none
+    /// matched.
+    Excluded,
+    /// It is outside the size or modification time limits.
+    Filtered,
+    /// It is not a regular file: a symlink without
+    /// [`Options::symlinks`](crate::Options::symlinks), or a special file such
+    /// as a FIFO or socket.
+    NotRegular,
+    /// Its name isn't valid on Windows and
+    /// [`Sanitize::Skip`](crate::Sanitize::Skip) is set.
+    InvalidName,
+    /// The destination copy was already up to date.
+    Unchanged,
+    /// The conflict callback chose [`Resolution::Skip`](crate::Resolution::Skip).
+    Conflict,
+    /// The destination can't store it, such as a symlink in an object store.
+    Unsupported,
+    /// It is a git submodule and
+    /// [`Submodules::Skip`](crate::Submodules::Skip) is set.
+    Submodule,
+    /// Its destination path is too long for the platform and
+    /// [`LongPaths::Skip`](crate::LongPaths::Skip) is set.
+    PathTooLong,
+}
+
+/// Skipped entries collected during a clone, shared between the walk and
+/// the workers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SkipLog(Arc<Mutex<Vec<SkippedEntry>>>);
+
+impl SkipLog {
+    pub(crate) fn record(&self, path: PathBuf, reason: SkipReason) {
+        self.0
+            .lock()
+            .expect("skip log lock")
+            .push(SkippedEntry { path, reason });
+    }
+
+    /// Everything recorded so far, sorted by path.
+    pub(crate) fn entries(&self) -> Vec<SkippedEntry> {
+        let mut entries = self.0.lock().expect("skip log lock").clone();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+}
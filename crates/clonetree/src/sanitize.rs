@@ -0,0 +1,177 @@
+//! Rewriting destination names that Windows can't store.
+
+use crate::{Normalization, Options};
+use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What to do with file names that aren't valid on Windows, set with
+/// [`Options::sanitize_names`](crate::Options::sanitize_names).
+///
+/// Windows (and FAT or exFAT volumes mounted anywhere) refuse names
+/// containing `<>:"\|?*` or control characters, names ending in a dot or a
+/// space, and device names such as `CON` or `lpt1.txt`. Unix filesystems
+/// allow all of these, so a tree cloned from Linux onto a Windows share or a
+/// USB stick can fail part way through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitize {
+    /// Keep names exactly as they are in the source.
+    #[default]
+    Off,
+    /// Replace each invalid character, and each trailing dot or space, with
+    /// `_`, and add `_` after a device name.
+    Replace,
+    /// Leave files with invalid names out of the clone.
+    Skip,
+}
+
+/// A destination name changed by sanitizing, passed to the callback
+/// registered with [`Options::on_sanitize`](crate::Options::on_sanitize).
+#[derive(Debug, Clone, Copy)]
+pub struct Sanitized<'a> {
+    /// The file's path relative to the source root.
+    pub path: &'a Path,
+    /// The path it is written to instead, or `None` if it was skipped.
+    pub renamed: Option<&'a Path>,
+}
+
+/// A shareable sanitize callback.
+#[derive(Clone)]
+pub(crate) struct SanitizeFn(pub(crate) Arc<dyn Fn(&Sanitized) + Send + Sync>);
+
+impl fmt::Debug for SanitizeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SanitizeFn")
+    }
+}
+
+impl Sanitize {
+    /// `path` with each component made valid, or `None` if the file is
+    /// skipped. Components that are not valid UTF-8 are left alone.
+    pub(crate) fn apply(self, path: &Path) -> Option<Cow<'_, Path>> {
+        if self == Sanitize::Off || path.iter().all(|part| part.to_str().is_none_or(is_valid)) {
+            return Some(Cow::Borrowed(path));
+        }
+        if self == Sanitize::Skip {
+            return None;
+        }
+        let path: PathBuf = path
+            .iter()
+            .map(|part| match part.to_str() {
+                Some(name) => replace(name).into(),
+                None => part.to_os_string(),
+            })
+            .collect();
+        Some(Cow::Owned(path))
+    }
+}
+
+/// Names Windows reserves for devices, with or without an extension.
+const DEVICE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_invalid_char(c: char) -> bool {
+    c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*')
+}
+
+/// The part of `name` before its first dot, which is what Windows checks
+/// against the device names.
+fn stem(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+fn is_device_name(name: &str) -> bool {
+    let stem = stem(name).trim_end_matches(' ');
+    DEVICE_NAMES.iter().any(|d| d.eq_ignore_ascii_case(stem))
+}
+
+fn is_valid(name: &str) -> bool {
+    !name.contains(is_invalid_char) && !name.ends_with(['.', ' ']) && !is_device_name(name)
+}
+
+fn replace(name: &str) -> String {
+    let kept = name.trim_end_matches(['.', ' ']);
+    let mut replaced: String = kept
+        .chars()
+        .map(|c| if is_invalid_char(c) { '_' } else { c })
+        .collect();
+    replaced.extend(std::iter::repeat_n('_', name.len() - kept.len()));
+    if is_device_name(&replaced) {
+        replaced.insert(stem(&replaced).len(), '_');
+    }
+    replaced
+}
+
+/// How the relative path of a source file becomes its destination path:
+/// Unicode normalization, then sanitizing, reporting any sanitized name.
+#[derive(Debug, Clone)]
+pub(crate) struct Rename {
+    normalization: Normalization,
+    sanitize: Sanitize,
+    report: Option<SanitizeFn>,
+}
+
+impl Rename {
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            normalization: options.normalization,
+            sanitize: options.sanitize,
+            report: options.sanitized.clone(),
+        }
+    }
+
+    /// Whether every path is kept as it is.
+    pub(crate) fn is_identity(&self) -> bool {
+        self.normalization == Normalization::None && self.sanitize == Sanitize::Off
+    }
+
+    /// The destination path for `path`, or `None` if the file is skipped.
+    pub(crate) fn apply<'a>(&self, path: &'a Path) -> Option<Cow<'a, Path>> {
+        let normalized = self.normalization.apply(path);
+        let sanitized = match self.sanitize.apply(&normalized) {
+            None => None,
+            Some(Cow::Borrowed(_)) => return Some(normalized),
+            Some(Cow::Owned(sanitized)) => Some(sanitized),
+        };
+        if let Some(SanitizeFn(report)) = &self.report {
+            report(&Sanitized {
+                path: &normalized,
+                renamed: sanitized.as_deref(),
+            });
+        }
+        sanitized.map(Cow::Owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace() {
+        assert_eq!(replace("notes: draft?.txt"), "notes_ draft_.txt");
+        assert_eq!(replace("ends with. "), "ends with__");
+        assert_eq!(replace("con"), "con_");
+        assert_eq!(replace("Lpt1.tar.gz"), "Lpt1_.tar.gz");
+        assert_eq!(replace("console.log"), "console.log");
+        assert!(is_valid(&replace("aux .")));
+    }
+
+    #[test]
+    fn test_apply() {
+        let path = Path::new("a:b/ok/nul.txt");
+        assert_eq!(
+            Sanitize::Replace.apply(path).unwrap(),
+            Path::new("a_b/ok/nul_.txt")
+        );
+        assert!(Sanitize::Skip.apply(path).is_none());
+        assert!(matches!(
+            Sanitize::Skip.apply(Path::new("fine/name.txt")),
+            Some(Cow::Borrowed(_))
+        ));
+        assert_eq!(Sanitize::Off.apply(path).unwrap(), path);
+    }
+}
@@ -0,0 +1,236 @@
+//! The streaming copier used whenever file data is copied rather than
+//! reflinked.
+//!
+//! Data moves through a set of buffers totalling
+//! [`Options::chunk_size`](crate::Options::chunk_size) bytes, filled with
+//! vectored reads and drained with vectored writes. The copier is itself a
+//! [`Read`] over the bytes it has written, so a [`Hasher`] can check them in
+//! the same pass instead of reading the source a second time.
+
+use crate::hash::{self, Blake3, Digest, Hasher};
+use crate::Reflink;
+use std::fs::File;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The default for [`Options::chunk_size`](crate::Options::chunk_size).
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The largest single buffer. Bigger chunks are split across several, still
+/// moved with one vectored call each way.
+const SEGMENT_SIZE: usize = 128 * 1024;
+
+/// How [`copy_data`](crate::copy_data) moves the data of one file.
+#[derive(Clone)]
+pub(crate) struct CopySettings {
+    pub(crate) reflink: Reflink,
+    pub(crate) chunk_size: usize,
+    /// Hash the data as it is streamed, for verification.
+    pub(crate) hasher: Option<Arc<dyn Hasher>>,
+}
+
+/// How the data of a file reached the destination.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Copied {
+    /// The destination shares the source's extents.
+    Reflinked,
+    /// The data was streamed, with its digest if a hasher was given.
+    Streamed { bytes: u64, digest: Option<Digest> },
+}
+
+impl crate::Options {
+    /// How to copy file data under these options.
+    pub(crate) fn copy_settings(&self) -> CopySettings {
+        let hasher = self.verify.then(|| match &self.hasher {
+            Some(hash::HasherRef(hasher)) => hasher.clone(),
+            None => Arc::new(Blake3) as Arc<dyn Hasher>,
+        });
+        CopySettings {
+            reflink: self.reflink,
+            chunk_size: self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            hasher,
+        }
+    }
+}
+
+/// Copy the file `src` to `dest`, creating or truncating it and giving it the
+/// permissions of `src` as [`std::fs::copy`] does, apart from the
+/// [special bits](crate::preserve::SPECIAL_BITS). With a `hasher`, the data
+/// is hashed as it is copied.
+pub(crate) fn copy_file(
+    src: &Path,
+    dest: &Path,
+    chunk_size: usize,
+    hasher: Option<&dyn Hasher>,
+) -> io::Result<Copied> {
+    let input = File::open(src)?;
+    let permissions = input.metadata()?.permissions();
+    #[cfg(unix)]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::Permissions::from_mode(permissions.mode() & !crate::preserve::SPECIAL_BITS)
+    };
+    let output = File::create(dest)?;
+    output.set_permissions(permissions)?;
+
+    let mut stream = StreamCopy::new(input, output, chunk_size);
+    let digest = hasher.map(|hasher| hasher.hash(&mut stream)).transpose()?;
+    let bytes = stream.finish()?;
+    Ok(Copied::Streamed { bytes, digest })
+}
+
+/// Copies from `src` to `dest` a chunk at a time. Reading from it yields each
+/// chunk once it has been written.
+pub(crate) struct StreamCopy<R, W> {
+    src: R,
+    dest: W,
+    segments: Vec<Vec<u8>>,
+    /// Length of the current chunk, already written to `dest`.
+    filled: usize,
+    /// How much of the current chunk has been handed out by `read`.
+    pos: usize,
+    copied: u64,
+}
+
+impl<R: Read, W: Write> StreamCopy<R, W> {
+    pub(crate) fn new(src: R, dest: W, chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        let segments = (0..chunk_size)
+            .step_by(SEGMENT_SIZE)
+            .map(|start| vec![0; SEGMENT_SIZE.min(chunk_size - start)])
+            .collect();
+        Self {
+            src,
+            dest,
+            segments,
+            filled: 0,
+            pos: 0,
+            copied: 0,
+        }
+    }
+
+    /// Copy whatever is left, returning the total number of bytes copied.
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        while self.next_chunk()? > 0 {}
+        self.dest.flush()?;
+        Ok(self.copied)
+    }
+
+    /// Read the next chunk from the source and write it out, returning its
+    /// length, which is 0 once the source is exhausted.
+    fn next_chunk(&mut self) -> io::Result<usize> {
+        let capacity: usize = self.segments.iter().map(Vec::len).sum();
+        let mut filled = 0;
+        while filled < capacity {
+            let mut bufs = unfilled(&mut self.segments, filled);
+            match self.src.read_vectored(&mut bufs) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut slices = filled_slices(&self.segments, filled);
+        let mut bufs = &mut slices[..];
+        while !bufs.is_empty() {
+            match self.dest.write_vectored(bufs) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.filled = filled;
+        self.pos = 0;
+        self.copied += filled as u64;
+        Ok(filled)
+    }
+}
+
+impl<R: Read, W: Write> Read for StreamCopy<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled && self.next_chunk()? == 0 {
+            return Ok(0);
+        }
+        // Every segment but the last is SEGMENT_SIZE long
+        let (index, offset) = (self.pos / SEGMENT_SIZE, self.pos % SEGMENT_SIZE);
+        let end = (self.filled - index * SEGMENT_SIZE).min(SEGMENT_SIZE);
+        let available = &self.segments[index][offset..end];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The parts of `segments` after the first `skip` bytes.
+fn unfilled(segments: &mut [Vec<u8>], mut skip: usize) -> Vec<IoSliceMut<'_>> {
+    let mut bufs = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if skip >= segment.len() {
+            skip -= segment.len();
+        } else {
+            bufs.push(IoSliceMut::new(&mut segment[skip..]));
+            skip = 0;
+        }
+    }
+    bufs
+}
+
+/// The first `len` bytes of `segments`.
+fn filled_slices(segments: &[Vec<u8>], mut len: usize) -> Vec<IoSlice<'_>> {
+    let mut bufs = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if len == 0 {
+            break;
+        }
+        let n = len.min(segment.len());
+        bufs.push(IoSlice::new(&segment[..n]));
+        len -= n;
+    }
+    bufs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stream_copy() -> io::Result<()> {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        // Chunks smaller than, equal to and spanning several segments
+        for chunk_size in [1, 1000, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 7] {
+            let mut out = Vec::new();
+            let mut stream = StreamCopy::new(&data[..], &mut out, chunk_size);
+            let mut seen = Vec::new();
+            // Read part of the way, then let finish copy the rest
+            (&mut stream).take(5000).read_to_end(&mut seen)?;
+            assert_eq!(stream.finish()?, data.len() as u64);
+            assert_eq!(seen, data[..5000]);
+            assert_eq!(out, data);
+        }
+
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        fs::write(&src, &data)?;
+        fs::write(&dest, "longer stale contents to be truncated")?;
+        let copied = copy_file(&src, &dest, DEFAULT_CHUNK_SIZE, Some(&Blake3))?;
+        assert_eq!(
+            copied,
+            Copied::Streamed {
+                bytes: data.len() as u64,
+                digest: Some(Blake3.hash(&mut &data[..])?),
+            }
+        );
+        assert_eq!(fs::read(&dest)?, data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+//! Optional instrumentation. With the `tracing` feature enabled, clones emit a
+//! span per directory and an event per file. With the `log` feature, the same
+//! events are emitted as `log` records. With neither, everything here compiles
+//! away.
+
+use std::path::Path;
+
+/// Emit an event at the given level, as `event!(debug, "copied", path = p)`.
+/// Field values are formatted with `Display`. With the `tracing` feature they
+/// become structured fields; with `log` they are appended to the message as
+/// `key=value` pairs. Arguments are not evaluated when both are disabled.
+macro_rules! event {
+    ($level:ident, $message:literal $(, $field:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($field = %$value,)* $message);
+        #[cfg(feature = "log")]
+        log::$level!(
+            concat!($message $(, " ", stringify!($field), "={}")*)
+            $(, $value)*
+        );
+    }};
+}
+pub(crate) use event;
+
+/// Enter a span for the whole clone, held until the returned guard drops.
+#[cfg(feature = "tracing")]
+pub(crate) fn clone_span(src: &Path, dest: &Path) -> tracing::span::EnteredSpan {
+    tracing::info_span!("clone_tree", src = %src.display(), dest = %dest.display()).entered()
+}
+
+/// Stand-in for an entered span when tracing is disabled.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn clone_span(_src: &Path, _dest: &Path) -> NoSpan {
+    NoSpan
+}
+
+/// Keeps a span open for the directory currently being processed. Entries
+/// from the walker arrive grouped by directory, so the span is replaced each
+/// time the parent directory changes.
+#[derive(Default)]
+pub(crate) struct DirSpan {
+    #[cfg(feature = "tracing")]
+    current: Option<(std::path::PathBuf, tracing::span::EnteredSpan)>,
+}
+
+impl DirSpan {
+    /// Make sure the span for `dir`, relative to the source root, is entered.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn enter(&mut self, dir: &Path) {
+        if matches!(&self.current, Some((current, _)) if current == dir) {
+            return;
+        }
+        // Exit the previous span before entering its sibling
+        self.current = None;
+        let span = tracing::debug_span!("dir", path = %dir.display()).entered();
+        self.current = Some((dir.to_path_buf(), span));
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn enter(&mut self, _dir: &Path) {}
+}
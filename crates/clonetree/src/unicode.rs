@@ -0,0 +1,64 @@
+//! Normalizing the Unicode in destination file names.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+/// How file names are normalized on their way to the destination, set with
+/// [`Options::normalize_unicode`](crate::Options::normalize_unicode).
+///
+/// The same accented name can be encoded precomposed (NFC, as most Linux
+/// tools write it) or decomposed (NFD, as older macOS filesystems store it).
+/// Linux treats the two as different files, so a tree that round-trips
+/// through a Mac can end up with both, or with names that no longer match
+/// what other tools expect. Normalizing makes every destination name use one
+/// form.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Keep names exactly as they are in the source.
+    #[default]
+    None,
+    /// Precomposed characters, the usual form on Linux and Windows.
+    Nfc,
+    /// Decomposed characters, the form HFS+ stores.
+    Nfd,
+}
+
+impl Normalization {
+    /// `path` with each component normalized. Components that are not valid
+    /// UTF-8 are left alone.
+    pub(crate) fn apply(self, path: &Path) -> Cow<'_, Path> {
+        let normalized = |name: &str| match self {
+            Normalization::None => true,
+            Normalization::Nfc => is_nfc(name),
+            Normalization::Nfd => is_nfd(name),
+        };
+        if path.iter().all(|part| part.to_str().is_none_or(normalized)) {
+            return Cow::Borrowed(path);
+        }
+        let path: PathBuf = path
+            .iter()
+            .map(|part| match part.to_str() {
+                Some(name) if self == Normalization::Nfc => name.nfc().collect::<String>().into(),
+                Some(name) => name.nfd().collect::<String>().into(),
+                None => part.to_os_string(),
+            })
+            .collect();
+        Cow::Owned(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let nfc = Path::new("caf\u{e9}/r\u{e9}sum\u{e9}.txt");
+        let nfd = Path::new("cafe\u{301}/re\u{301}sume\u{301}.txt");
+        assert_eq!(Normalization::Nfc.apply(nfd), nfc);
+        assert_eq!(Normalization::Nfd.apply(nfc), nfd);
+        assert!(matches!(Normalization::Nfc.apply(nfc), Cow::Borrowed(_)));
+        assert_eq!(Normalization::None.apply(nfd), nfd);
+    }
+}
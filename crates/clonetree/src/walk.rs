@@ -0,0 +1,440 @@
+//! Walking a source tree: by default with the `ignore` crate when the
+//! `filter` feature is enabled, so that globs prune whole directories, and
+//! with a plain recursive walker without it. The `walkdir` and `jwalk`
+//! features add [`WalkerBackend::Walkdir`] and [`WalkerBackend::Jwalk`].
+
+use crate::{CloneConfig, Error, Result, Skipped};
+#[cfg(feature = "filter")]
+use std::collections::VecDeque;
+use std::fs::FileType;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "filter")]
+use std::sync::{Arc, Mutex};
+
+pub(crate) use plain::PlainWalk;
+
+/// A file or directory found by a [`Walk`], starting with the root itself.
+#[derive(Debug)]
+pub(crate) struct WalkEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) file_type: FileType,
+}
+
+impl WalkEntry {
+    /// The entry's own metadata, not following symlinks.
+    pub(crate) fn metadata(&self) -> std::io::Result<std::fs::Metadata> {
+        std::fs::symlink_metadata(&self.path)
+    }
+}
+
+/// How the source tree is walked, set with
+/// [`Options::walker`](crate::Options::walker). Every backend yields the same
+/// entries; they differ in how directories are read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WalkerBackend {
+    /// The `ignore` crate with the `filter` feature, so globs prune whole
+    /// directories, or a plain recursive walk without it.
+    #[default]
+    Standard,
+    /// The `walkdir` crate, for trees where the `ignore` crate's behavior
+    /// gets in the way.
+    #[cfg(feature = "walkdir")]
+    Walkdir,
+    /// The `jwalk` crate, which reads directories in parallel on the rayon
+    /// thread pool. Much faster on very wide trees, such as a directory
+    /// holding a million files.
+    #[cfg(feature = "jwalk")]
+    Jwalk,
+}
+
+/// How git submodules in the source are cloned, set with
+/// [`Options::submodules`](crate::Options::submodules). A submodule is any
+/// directory below the source root holding a `.git` file or directory, so
+/// nested repositories count too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Submodules {
+    /// Walk into submodules like any other directory.
+    #[default]
+    Include,
+    /// Leave submodules out, listing each as
+    /// [`SkipReason::Submodule`](crate::SkipReason::Submodule).
+    Skip,
+    /// Clone submodules as trees of their own, after the rest of the source.
+    /// Each one's `.gitignore` files and `info/exclude` apply inside it, and
+    /// the superproject's ignore files don't.
+    Separate,
+}
+
+/// The entries under a tree root, in depth-first order. Errors are yielded
+/// in place of the entries they hide, and the walk carries on after them.
+pub(crate) struct Walk {
+    inner: Inner,
+}
+
+enum Inner {
+    #[cfg(feature = "filter")]
+    Ignore(Box<IgnoreWalk>),
+    #[cfg(not(feature = "filter"))]
+    Plain(plain::PlainWalk),
+    #[cfg(feature = "walkdir")]
+    Walkdir(Box<dyn Iterator<Item = walkdir::Result<walkdir::DirEntry>> + Send>),
+    #[cfg(feature = "jwalk")]
+    Jwalk(jwalk::DirEntryIter<((), ())>),
+}
+
+impl Walk {
+    /// Walk `src` with the backend and filters from `config`, recording what
+    /// the globs exclude in `skipped`.
+    pub(crate) fn new(src: &Path, config: &CloneConfig, skipped: &Skipped) -> Self {
+        let inner = match config.options.walker {
+            WalkerBackend::Standard => standard(src, config, skipped),
+            #[cfg(feature = "walkdir")]
+            WalkerBackend::Walkdir => {
+                let mut walker = walkdir::WalkDir::new(src);
+                if config.options.sorted {
+                    walker = walker.sort_by_file_name();
+                }
+                let excluded = exclusion(src, config, skipped);
+                let walk = walker
+                    .into_iter()
+                    .filter_entry(move |entry| !excluded(entry.path(), entry.file_type().is_dir()));
+                Inner::Walkdir(Box::new(walk))
+            }
+            #[cfg(feature = "jwalk")]
+            WalkerBackend::Jwalk => {
+                let excluded = exclusion(src, config, skipped);
+                let walker = jwalk::WalkDir::new(src)
+                    .skip_hidden(false)
+                    .sort(config.options.sorted)
+                    .process_read_dir(move |_, _, _, children| {
+                        // Dropping an excluded directory skips reading it
+                        children.retain(|child| match child {
+                            Ok(entry) => !excluded(&entry.path(), entry.file_type.is_dir()),
+                            Err(_) => true,
+                        });
+                    });
+                Inner::Jwalk(walker.into_iter())
+            }
+        };
+        Self { inner }
+    }
+}
+
+/// Build the default walker.
+#[cfg(feature = "filter")]
+fn standard(src: &Path, config: &CloneConfig, skipped: &Skipped) -> Inner {
+    let mut walk = IgnoreWalk {
+        walk: None,
+        src: src.to_path_buf(),
+        config: config.clone(),
+        skipped: skipped.clone(),
+        pending: Arc::default(),
+        nested: false,
+    };
+    walk.walk = Some(walk.build(src, false));
+    Inner::Ignore(Box::new(walk))
+}
+
+/// A walk with the `ignore` crate, followed by a walk of each submodule it
+/// set aside with [`Submodules::Separate`] and each junction it set aside
+/// with [`Junctions::Follow`](crate::Junctions::Follow).
+#[cfg(feature = "filter")]
+struct IgnoreWalk {
+    walk: Option<ignore::Walk>,
+    src: PathBuf,
+    config: CloneConfig,
+    skipped: Skipped,
+    /// Submodules and junctions found and not yet walked, in the order they
+    /// were found, flagged if a submodule
+    pending: Arc<Mutex<VecDeque<(PathBuf, bool)>>>,
+    /// Whether the current walk is of one of the pending roots, which are
+    /// already accounted for
+    nested: bool,
+}
+
+#[cfg(feature = "filter")]
+impl IgnoreWalk {
+    /// Walk `root`, which is the source root, a submodule in it or a junction
+    /// to follow, with standard filters disabled.
+    fn build(&self, root: &Path, submodule: bool) -> ignore::Walk {
+        let (config, options) = (&self.config, &self.config.options);
+        let mut builder = ignore::WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .parents(options.gitignore && !submodule)
+            .git_ignore(options.gitignore || submodule)
+            .git_global(options.git_global)
+            .git_exclude(options.git_exclude || submodule);
+
+        if options.sorted {
+            builder.sort_by_file_name(|a, b| a.cmp(b));
+        }
+
+        let (submodules, junctions) = (options.submodules, options.junctions);
+        let follow = junctions == crate::Junctions::Follow;
+        if config.globs.is_some() || submodules != Submodules::Include || follow {
+            let excluded = exclusion(&self.src, config, &self.skipped);
+            let (src, skipped) = (self.src.clone(), self.skipped.clone());
+            let pending = self.pending.clone();
+            builder.filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+                if excluded(entry.path(), is_dir) {
+                    return false;
+                }
+                let is_followed = follow
+                    && entry.path_is_symlink()
+                    && crate::junction::is_junction(entry.path())
+                    && !crate::junction::loops(entry.path());
+                if is_followed {
+                    let path = entry.path().to_path_buf();
+                    pending
+                        .lock()
+                        .expect("pending lock")
+                        .push_back((path, false));
+                    return false;
+                }
+                let is_submodule = is_dir
+                    && submodules != Submodules::Include
+                    && entry.depth() > 0
+                    && entry.path().join(".git").exists();
+                if !is_submodule {
+                    return true;
+                }
+                if submodules == Submodules::Separate {
+                    let path = entry.path().to_path_buf();
+                    pending
+                        .lock()
+                        .expect("pending lock")
+                        .push_back((path, true));
+                } else if let Ok(relative_path) = entry.path().strip_prefix(&src) {
+                    skipped.record(relative_path, crate::SkipReason::Submodule);
+                }
+                false
+            });
+        }
+
+        builder.build()
+    }
+
+    fn next(&mut self) -> Option<Result<WalkEntry>> {
+        loop {
+            let Some(next) = self.walk.as_mut()?.next() else {
+                let root = self.pending.lock().expect("pending lock").pop_front();
+                self.walk = root.map(|(root, submodule)| self.build(&root, submodule));
+                self.nested = true;
+                continue;
+            };
+            let entry = match next {
+                Ok(entry) => entry,
+                Err(source) => return Some(Err(walk_error(source))),
+            };
+            if self.nested && entry.depth() == 0 {
+                continue;
+            }
+            // Only entries read from stdin lack a file type
+            if let Some(file_type) = entry.file_type() {
+                return Some(Ok(WalkEntry {
+                    file_type,
+                    path: entry.into_path(),
+                }));
+            }
+        }
+    }
+}
+
+/// Without the `filter` feature there are no globs, so nothing is excluded.
+#[cfg(not(feature = "filter"))]
+fn standard(src: &Path, config: &CloneConfig, _skipped: &Skipped) -> Inner {
+    Inner::Plain(plain::PlainWalk::new(src, config.options.sorted))
+}
+
+/// A predicate telling whether the entry at a path under `src`, which is a
+/// directory if the flag is set, is excluded by the globs in `config`. Each
+/// exclusion is recorded in `skipped`. The globs are compiled without a root,
+/// so they match paths relative to `src`.
+#[cfg(any(feature = "filter", feature = "walkdir", feature = "jwalk"))]
+fn exclusion(
+    src: &Path,
+    config: &CloneConfig,
+    skipped: &Skipped,
+) -> impl Fn(&Path, bool) -> bool + Send + Sync + 'static {
+    let (src, globs, skipped) = (src.to_path_buf(), config.globs.clone(), skipped.clone());
+    move |path, is_dir| {
+        let (Some(globs), Ok(relative_path)) = (&globs, path.strip_prefix(&src)) else {
+            return false;
+        };
+        if !globs.excludes(relative_path, is_dir) {
+            return false;
+        }
+        skipped.record(relative_path, crate::SkipReason::Excluded);
+        true
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            #[cfg(feature = "filter")]
+            Inner::Ignore(walk) => walk.next(),
+            #[cfg(not(feature = "filter"))]
+            Inner::Plain(walk) => walk.next(),
+            #[cfg(feature = "walkdir")]
+            Inner::Walkdir(walk) => Some(
+                walk.next()?
+                    .map(|entry| WalkEntry {
+                        file_type: entry.file_type(),
+                        path: entry.into_path(),
+                    })
+                    .map_err(walk_error),
+            ),
+            #[cfg(feature = "jwalk")]
+            Inner::Jwalk(walk) => Some(
+                walk.next()?
+                    .map(|entry| WalkEntry {
+                        file_type: entry.file_type,
+                        path: entry.path(),
+                    })
+                    .map_err(walk_error),
+            ),
+        }
+    }
+}
+
+fn walk_error(source: impl std::fmt::Display) -> Error {
+    Error::Other(format!("Walk error: {source}"))
+}
+
+mod plain {
+    use super::{walk_error, WalkEntry};
+    use crate::Result;
+    use std::fs::DirEntry;
+    use std::path::{Path, PathBuf};
+    use std::vec::IntoIter;
+
+    /// A depth-first walk using only the standard library.
+    pub(crate) struct PlainWalk {
+        root: Option<PathBuf>,
+        sorted: bool,
+        /// The unvisited entries of each directory being walked, innermost
+        /// last.
+        stack: Vec<IntoIter<DirEntry>>,
+    }
+
+    impl PlainWalk {
+        pub(crate) fn new(root: &Path, sorted: bool) -> Self {
+            Self {
+                root: Some(root.to_path_buf()),
+                sorted,
+                stack: Vec::new(),
+            }
+        }
+
+        /// Descend into the directory at `path`.
+        fn push(&mut self, path: &Path) -> Result<()> {
+            let read = |path: &Path| -> std::io::Result<Vec<DirEntry>> {
+                std::fs::read_dir(path)?.collect()
+            };
+            let mut entries =
+                read(path).map_err(|e| walk_error(format!("{}: {e}", path.display())))?;
+            if self.sorted {
+                entries.sort_by_key(DirEntry::file_name);
+            }
+            self.stack.push(entries.into_iter());
+            Ok(())
+        }
+    }
+
+    impl Iterator for PlainWalk {
+        type Item = Result<WalkEntry>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if let Some(root) = self.root.take() {
+                let file_type = match std::fs::symlink_metadata(&root) {
+                    Ok(metadata) => metadata.file_type(),
+                    Err(e) => return Some(Err(walk_error(format!("{}: {e}", root.display())))),
+                };
+                if file_type.is_dir() {
+                    if let Err(e) = self.push(&root) {
+                        return Some(Err(e));
+                    }
+                }
+                return Some(Ok(WalkEntry {
+                    path: root,
+                    file_type,
+                }));
+            }
+            loop {
+                let dir = self.stack.last_mut()?;
+                let Some(entry) = dir.next() else {
+                    self.stack.pop();
+                    continue;
+                };
+                let path = entry.path();
+                let file_type = match entry.file_type() {
+                    Ok(file_type) => file_type,
+                    Err(e) => return Some(Err(walk_error(format!("{}: {e}", path.display())))),
+                };
+                if file_type.is_dir() {
+                    if let Err(e) = self.push(&path) {
+                        return Some(Err(e));
+                    }
+                }
+                return Some(Ok(WalkEntry { path, file_type }));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "filter", any(feature = "walkdir", feature = "jwalk")))]
+mod tests {
+    use super::WalkerBackend;
+    use crate::{Options, SkipReason};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backend_globs() -> crate::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("logs"))?;
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join(".hidden"), "h")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("sub/c.log"), "c")?;
+        fs::write(src.join("logs/1.log"), "1")?;
+
+        let backends = [
+            #[cfg(feature = "walkdir")]
+            WalkerBackend::Walkdir,
+            #[cfg(feature = "jwalk")]
+            WalkerBackend::Jwalk,
+        ];
+        for backend in backends {
+            let opts = Options::new()
+                .walker(backend)
+                .glob("!logs")
+                .glob("!*.log")
+                .record_skipped(true);
+            let dest = temp_dir.path().join(format!("{backend:?}"));
+            let report = opts.build()?.clone_tree(&src, &dest)?;
+            assert_eq!(report.files(), 2, "{backend:?}");
+            let skipped: Vec<_> = report
+                .skipped_entries
+                .iter()
+                .map(|s| (s.path.to_str().unwrap(), s.reason))
+                .collect();
+            assert_eq!(
+                skipped,
+                vec![
+                    ("logs", SkipReason::Excluded),
+                    ("sub/c.log", SkipReason::Excluded),
+                ],
+                "{backend:?}"
+            );
+        }
+
+        Ok(())
+    }
+}
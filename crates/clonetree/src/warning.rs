@@ -0,0 +1,124 @@
+//! Problems that don't stop a clone.
+
+use crate::observer::Observers;
+use crate::{trace, Options};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Something a clone couldn't do for a file but carried on without, passed
+/// to the callback registered with [`Options::on_warning`].
+#[derive(Debug, Clone, Copy)]
+pub struct Warning<'a> {
+    /// The file's path relative to the source root.
+    pub path: &'a Path,
+    pub kind: WarningKind,
+    /// The error behind the warning, if there was one.
+    pub error: Option<&'a io::Error>,
+}
+
+/// What a [`Warning`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    /// Extended attributes weren't copied, because the destination doesn't
+    /// support them or the process may not set them.
+    Xattrs,
+    /// The access control list wasn't copied, because the process may not
+    /// read or set it.
+    Acl,
+    /// Named NTFS data streams weren't copied, because the destination
+    /// can't hold them.
+    Streams,
+    /// File capabilities weren't copied, because the process lacks
+    /// `CAP_SETFCAP` or the destination can't store them.
+    Capabilities,
+    /// Inode flags such as immutable or `nocow` weren't copied, because the
+    /// destination doesn't support them or the process may not set them.
+    InodeFlags,
+    /// The owner wasn't set, because only root may give files away.
+    Ownership,
+    /// The modification time couldn't be set.
+    Times,
+    /// The creation time couldn't be set.
+    BirthTime,
+    /// A FIFO, socket or device file was left out, since only regular files
+    /// and symlinks are cloned.
+    SpecialFile,
+    /// The file was left out because its destination path is too long, as
+    /// [`LongPaths::Skip`](crate::LongPaths::Skip) asks.
+    PathTooLong,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WarningKind::Xattrs => "extended attributes not copied",
+            WarningKind::Acl => "access control list not copied",
+            WarningKind::Streams => "alternate data streams not copied",
+            WarningKind::Capabilities => "file capabilities not copied",
+            WarningKind::InodeFlags => "inode flags not copied",
+            WarningKind::Ownership => "ownership not preserved",
+            WarningKind::Times => "modification time not preserved",
+            WarningKind::BirthTime => "creation time not preserved",
+            WarningKind::SpecialFile => "special file not copied",
+            WarningKind::PathTooLong => "destination path too long, not copied",
+        })
+    }
+}
+
+impl fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.kind)?;
+        if let Some(error) = self.error {
+            write!(f, " ({error})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A shareable warning callback.
+#[derive(Clone)]
+pub(crate) struct WarningFn(pub(crate) Arc<dyn Fn(&Warning) + Send + Sync>);
+
+impl fmt::Debug for WarningFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningFn")
+    }
+}
+
+impl Options {
+    /// Call `callback` for each warning, as well as any warning callback
+    /// already set.
+    pub(crate) fn also_on_warning<F>(self, callback: F) -> Self
+    where
+        F: Fn(&Warning) + Send + Sync + 'static,
+    {
+        let existing = self.warning.clone();
+        self.on_warning(move |warning| {
+            callback(warning);
+            if let Some(WarningFn(existing)) = &existing {
+                existing(warning);
+            }
+        })
+    }
+
+    /// Report a warning about the file at `path` to the callback, if any.
+    pub(crate) fn warn(&self, path: &Path, kind: WarningKind, error: Option<&io::Error>) {
+        report(
+            self.warning.as_ref(),
+            &self.observers,
+            &Warning { path, kind, error },
+        );
+    }
+}
+
+/// Log `warning` and pass it to `callback` and the observers, if there are
+/// any.
+pub(crate) fn report(callback: Option<&WarningFn>, observers: &Observers, warning: &Warning) {
+    trace::event!(warn, "warning", warning = warning);
+    if let Some(WarningFn(callback)) = callback {
+        callback(warning);
+    }
+    observers.each(|observer| observer.on_warning(warning));
+}
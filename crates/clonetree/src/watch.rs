@@ -0,0 +1,258 @@
+//! Continuous incremental cloning driven by filesystem events.
+
+use crate::filter::Globs;
+use crate::{
+    build_globs, clone_entries, clone_tree, copy_data, is_excluded, CopySettings, Error, Options,
+    Preserve, Result,
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long the source must be quiet before a batch of changes is applied.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+enum Message {
+    Event(notify::Result<notify::Event>),
+    Stop,
+}
+
+/// A running watch started by [`watch_tree`]. Changes keep being applied in
+/// the background until [`TreeWatcher::stop`] is called or the watcher is
+/// dropped.
+pub struct TreeWatcher {
+    // Held so that events keep flowing until the watcher is stopped
+    _watcher: RecommendedWatcher,
+    tx: Sender<Message>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl TreeWatcher {
+    /// Apply any pending changes, stop watching, and return the first error
+    /// encountered while applying changes, if any.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        let _ = self.tx.send(Message::Stop);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| Error::Other("Watch thread panicked".into()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TreeWatcher {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Clone `src` to `dest`, then keep `dest` up to date as files under `src`
+/// change. Events are debounced, and changed paths are filtered with the same
+/// glob rules as the initial clone. Files deleted from the source are deleted
+/// from the destination.
+pub fn watch_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<TreeWatcher> {
+    let src = src.as_ref();
+    let dest = dest.as_ref().to_path_buf();
+    clone_tree(src, &dest, options)?;
+
+    // Event paths are reported in canonical form
+    let src = src.canonicalize()?;
+    let (tx, rx) = mpsc::channel();
+    let event_tx = tx.clone();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = event_tx.send(Message::Event(event));
+    })
+    .map_err(|e| Error::Other(format!("Failed to start watcher: {e}")))?;
+    watcher
+        .watch(&src, RecursiveMode::Recursive)
+        .map_err(|e| Error::Other(format!("Failed to watch {}: {e}", src.display())))?;
+
+    let applier = Applier {
+        globs: build_globs(options)?,
+        options: options.clone().overwrite(true),
+        src,
+        dest,
+    };
+    let thread = std::thread::spawn(move || {
+        let mut pending = BTreeSet::new();
+        loop {
+            let message = if pending.is_empty() {
+                rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                rx.recv_timeout(DEBOUNCE)
+            };
+            match message {
+                Ok(Message::Event(Ok(event))) => pending.extend(event.paths),
+                Ok(Message::Event(Err(e))) => {
+                    return Err(Error::Other(format!("Watch error: {e}")));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    applier.apply(&pending)?;
+                    pending.clear();
+                }
+                Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                    return applier.apply(&pending);
+                }
+            }
+        }
+    });
+
+    Ok(TreeWatcher {
+        _watcher: watcher,
+        tx,
+        thread: Some(thread),
+    })
+}
+
+/// Applies batches of changed source paths to the destination.
+struct Applier {
+    src: PathBuf,
+    dest: PathBuf,
+    options: Options,
+    globs: Option<Globs>,
+}
+
+impl Applier {
+    fn apply(&self, paths: &BTreeSet<PathBuf>) -> Result<()> {
+        for path in paths {
+            let Ok(relative_path) = path.strip_prefix(&self.src) else {
+                continue;
+            };
+            if relative_path.as_os_str().is_empty() {
+                continue;
+            }
+            self.apply_one(relative_path)?;
+        }
+        Ok(())
+    }
+
+    fn apply_one(&self, relative_path: &Path) -> Result<()> {
+        let src_path = self.src.join(relative_path);
+        let dest_path = self.dest.join(relative_path);
+        let metadata = std::fs::symlink_metadata(&src_path).ok();
+
+        match metadata {
+            Some(metadata) if metadata.is_dir() => {
+                // A new or moved-in directory: copy everything under it
+                for entry in clone_entries(&src_path, &Options::new()) {
+                    let entry = entry?;
+                    self.copy_file(&relative_path.join(&entry.relative_path))?;
+                }
+                Ok(())
+            }
+            Some(metadata) if metadata.is_file() => self.copy_file(relative_path),
+            Some(_) => Ok(()),
+            None => {
+                if is_excluded(self.globs.as_ref(), relative_path) {
+                    return Ok(());
+                }
+                if std::fs::symlink_metadata(&dest_path).is_ok() {
+                    self.options.discard(&dest_path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn copy_file(&self, relative_path: &Path) -> Result<()> {
+        if is_excluded(self.globs.as_ref(), relative_path) {
+            return Ok(());
+        }
+        let src_path = self.src.join(relative_path);
+        let dest_path = self.dest.join(relative_path);
+        if let Some(parent) = dest_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|source| Error::CreateDirectory {
+                path: parent.to_path_buf(),
+                source,
+            })?;
+        }
+        if let Ok(metadata) = dest_path.symlink_metadata() {
+            self.options.replace_file(&dest_path, &metadata)?;
+        }
+        // Watching never verifies, so there is nothing to hash
+        let settings = CopySettings {
+            hasher: None,
+            ..self.options.copy_settings()
+        };
+        match copy_data(&src_path, &dest_path, &settings) {
+            Ok(_) => {}
+            // Removed again before the batch was applied
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(source) => {
+                return Err(Error::Copy {
+                    src: src_path,
+                    dest: dest_path,
+                    source,
+                })
+            }
+        }
+        if self.options.preserve.contains(Preserve::TIMES) {
+            if let Ok(metadata) = std::fs::metadata(&src_path) {
+                let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+                filetime::set_file_mtime(&dest_path, mtime)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    /// Poll `check` until it passes or a few seconds have gone by.
+    fn wait_for(check: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if check() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_watch_tree() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("initial.txt"), "initial")?;
+        fs::write(src.join("doomed.txt"), "doomed")?;
+
+        let opts = Options::new().glob("!*.log");
+        let watcher = watch_tree(&src, &dest, &opts)?;
+        assert!(dest.join("initial.txt").exists());
+
+        fs::create_dir(src.join("sub"))?;
+        fs::write(src.join("sub/new.txt"), "new")?;
+        fs::write(src.join("skipped.log"), "log")?;
+        fs::remove_file(src.join("doomed.txt"))?;
+
+        assert!(wait_for(
+            || dest.join("sub/new.txt").exists() && !dest.join("doomed.txt").exists()
+        ));
+        watcher.stop()?;
+        assert_eq!(fs::read_to_string(dest.join("sub/new.txt"))?, "new");
+        assert!(!dest.join("skipped.log").exists());
+
+        Ok(())
+    }
+}
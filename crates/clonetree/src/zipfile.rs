@@ -0,0 +1,174 @@
+//! Writing a tree into a zip archive instead of a directory.
+
+use crate::backend::{FileInfo, FileKind, TreeSink};
+use crate::{
+    clone_between, trace, validate, CloneReport, DirSource, Error, Options, Outcome, Result,
+};
+use std::fs::File;
+use std::io::{self, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Clone `src` into a deflate-compressed zip archive at `dest`.
+///
+/// This works like [`clone_to_archive`](crate::clone_to_archive), for
+/// workflows where zip is more convenient than tar: it is [`clone_between`]
+/// from a [`DirSource`] to a [`ZipSink`], so the same filters select the
+/// files, progress reports every file as [`Outcome::Copied`], an existing
+/// `dest` is replaced only with [`Options::overwrite`], and a failed clone
+/// removes the partial archive unless [`Options::keep_going`] let it finish.
+/// Symlinks are stored as links with [`Options::symlinks`].
+pub fn clone_to_zip<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &Options,
+) -> Result<CloneReport> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let _span = trace::clone_span(src, dest);
+    validate(src, dest, options)?;
+
+    let mut sink = ZipSink::create(dest)?;
+    let result = clone_between(&mut DirSource::new(src), &mut sink, options);
+    // With keep_going the archive holds everything that could be read
+    if result
+        .as_ref()
+        .is_err_and(|e| !matches!(e, Error::Incomplete { .. }))
+    {
+        drop(sink);
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+/// A zip archive written as a [`TreeSink`]. Modification times are stored in
+/// UTC, along with unix permissions when the source has them.
+pub struct ZipSink {
+    path: PathBuf,
+    zip: Option<ZipWriter<BufWriter<File>>>,
+}
+
+impl ZipSink {
+    /// Create an archive at `path`, replacing any file already there.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|source| Error::Copy {
+            src: path.clone(),
+            dest: path.clone(),
+            source,
+        })?;
+        Ok(Self {
+            zip: Some(ZipWriter::new(BufWriter::new(file))),
+            path,
+        })
+    }
+
+    fn write_error(&self, source: io::Error) -> Error {
+        Error::Copy {
+            src: self.path.clone(),
+            dest: self.path.clone(),
+            source,
+        }
+    }
+}
+
+impl TreeSink for ZipSink {
+    fn write(
+        &mut self,
+        file: &FileInfo,
+        contents: &mut dyn Read,
+        _options: &Options,
+    ) -> Result<Outcome> {
+        let Some(zip) = self.zip.as_mut() else {
+            return Err(self.write_error(io::ErrorKind::BrokenPipe.into()));
+        };
+        add_file(zip, file, contents).map_err(|source| Error::Copy {
+            src: file.path.clone(),
+            dest: self.path.join(&file.path),
+            source,
+        })?;
+        Ok(Outcome::Copied)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let Some(zip) = self.zip.take() else {
+            return Ok(());
+        };
+        zip.finish()
+            .map_err(io::Error::from)
+            .and_then(|file| file.into_inner().map(drop).map_err(|e| e.into_error()))
+            .map_err(|e| self.write_error(e))
+    }
+}
+
+/// Write one file or symlink into the archive.
+fn add_file(
+    zip: &mut ZipWriter<BufWriter<File>>,
+    file: &FileInfo,
+    contents: &mut dyn Read,
+) -> io::Result<()> {
+    let mut options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(file.len >= u32::MAX as u64);
+    if let Some(time) = file.mtime.and_then(zip_time) {
+        options = options.last_modified_time(time);
+    }
+    if let Some(mode) = file.mode {
+        options = options.unix_permissions(mode);
+    }
+    match &file.kind {
+        FileKind::File => {
+            zip.start_file_from_path(&file.path, options)?;
+            io::copy(contents, zip)?;
+        }
+        FileKind::Symlink(target) => zip.add_symlink_from_path(&file.path, target, options)?,
+    }
+    Ok(())
+}
+
+/// A zip timestamp for `time`, in UTC. Zip can't represent times before 1980.
+fn zip_time(time: std::time::SystemTime) -> Option<zip::DateTime> {
+    let time = time::OffsetDateTime::from(time);
+    zip::DateTime::try_from(time::PrimitiveDateTime::new(time.date(), time.time())).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_clone_to_zip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a.txt"), "a")?;
+        fs::write(src.join("sub/b.txt"), "b")?;
+        fs::write(src.join("skip.log"), "log")?;
+
+        let dest = temp_dir.path().join("out.zip");
+        clone_to_zip(&src, &dest, &Options::new().glob("!*.log"))?;
+
+        let mut archive = zip::ZipArchive::new(File::open(&dest)?).map_err(std::io::Error::from)?;
+        let names: Vec<_> = archive
+            .file_names()
+            .filter_map(|name| name.ok().map(|name| name.to_string()))
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"a.txt".to_string()));
+        assert!(names.contains(&"sub/b.txt".to_string()));
+        let mut contents = String::new();
+        archive
+            .by_name("sub/b.txt")
+            .map_err(std::io::Error::from)?
+            .read_to_string(&mut contents)?;
+        assert_eq!(contents, "b");
+
+        let result = clone_to_zip(&src, &dest, &Options::new());
+        assert!(matches!(result, Err(Error::DestinationExists { .. })));
+
+        Ok(())
+    }
+}
@@ -1,51 +1,1571 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use clonetree::{clone_tree, Options};
+mod units;
+
+use anyhow::{bail, Context, Result};
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use clonetree::{
+    clone_entries, clone_from_archive, clone_targets, clone_to_archive, clone_to_zip, clone_trees,
+    dedup_tree, disk_usage, plan_clone, Backup, CancelToken, CloneReport, Compare, Compression,
+    Conflict, Crc32c, DedupOptions, DirLinks, Junctions, LongPaths, Normalization, Options,
+    Outcome, Overwrite, Pattern, Preserve, Reflink, Resolution, Sanitize, Sha256, Submodules,
+    XxHash64,
+};
+use console::style;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Parser)]
 #[command(
     name = "ctree",
+    version,
     about = "Copy-on-write directory tree cloning",
-    long_about = "Copies a directory tree using filesystem reflinks when available, with glob-based filtering"
+    long_about = "Copies a directory tree using filesystem reflinks when available, with glob-based filtering",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
-struct Args {
-    /// Source directory to clone
-    src: String,
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    clone: CloneArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Clone a tree (the default when no command is given)
+    Clone(CloneArgs),
+    /// One-way sync: copy new and changed files, delete files missing from the source
+    Sync(SyncArgs),
+    /// Reflink identical files within a tree so they share storage
+    Dedup(DedupArgs),
+    /// Report apparent size and actual disk usage of a tree
+    Du(DuArgs),
+    /// Print a shell completion script
+    #[command(hide = true)]
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Print the man page, or write a page per command into a directory
+    #[command(hide = true)]
+    Man {
+        /// Write ctree.1 and one page per subcommand into this directory
+        #[arg(long = "out-dir", value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("replace").multiple(true)))]
+struct CloneArgs {
+    /// Source directory to clone. Given several, each is cloned into its own
+    /// subdirectory of DEST, named after the source
+    #[arg(required = true, value_name = "SRC")]
+    srcs: Vec<String>,
 
     /// Destination directory
+    #[arg(required = true)]
+    dest: Option<String>,
+
+    /// Clone into an existing destination, replacing files that are in the way
+    #[arg(long = "overwrite", group = "replace")]
+    overwrite: bool,
+
+    /// Only copy files whose source is newer than the destination copy (implies --overwrite)
+    #[arg(short = 'u', long = "update", group = "replace")]
+    update: bool,
+
+    /// Empty an existing destination first, so it ends up an exact copy of the source
+    #[arg(long = "replace", group = "replace", conflicts_with = "interactive")]
+    replace_dest: bool,
+
+    /// Clone into an existing destination, failing on any file that is already there
+    #[arg(
+        long = "no-clobber",
+        group = "replace",
+        conflicts_with_all = ["replace_dest", "delete", "interactive"]
+    )]
+    no_clobber: bool,
+
+    /// Keep each replaced file as a numbered backup, FILE.~1~, FILE.~2~ and so on
+    #[arg(long = "backup", conflicts_with_all = ["replace_dest", "no_clobber"])]
+    backup: bool,
+
+    /// Send deleted and replaced files to the trash instead of removing them
+    #[arg(long = "trash")]
+    trash: bool,
+
+    /// Delete destination files that are not in the source (requires --overwrite or --update)
+    #[arg(long = "delete", requires = "replace")]
+    delete: bool,
+
+    #[command(flatten)]
+    copy: CopyArgs,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    /// Source directory
+    src: String,
+
+    /// Destination directory, created if it does not exist
     dest: String,
 
+    /// Detect changes by content hash instead of size and modification time
+    #[arg(short = 'c', long = "checksum")]
+    checksum: bool,
+
+    /// Keep a state cache beside the destination so unchanged files are skipped without being read
+    #[arg(long = "cache", env = "CTREE_CACHE", value_parser = BoolishValueParser::new())]
+    cache: bool,
+
+    #[command(flatten)]
+    copy: CopyArgs,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Args)]
+struct DedupArgs {
+    /// Directory to deduplicate
+    dir: String,
+
+    /// Ignore files smaller than SIZE, e.g. 4K
+    #[arg(long = "min-size", value_name = "SIZE", default_value = "0", value_parser = units::parse_size)]
+    min_size: u64,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Args)]
+struct DuArgs {
+    /// Directory to measure
+    dir: String,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+/// Flags for commands that copy files
+#[derive(Args)]
+struct CopyArgs {
+    /// List the operations that would be performed without writing anything
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+
+    /// Disable reflink, perform a regular copy
+    #[arg(long = "no-reflink", env = "CTREE_NO_REFLINK", value_parser = BoolishValueParser::new())]
+    no_reflink: bool,
+
+    /// Clone only the files listed in FILE, one path per line relative to the
+    /// source; use - to read the list from stdin
+    #[arg(long = "files-from", value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// Skip files git ignores, as git status would: .gitignore files,
+    /// .git/info/exclude and the global excludes file
+    #[arg(long = "gitignore", env = "CTREE_GITIGNORE", value_parser = BoolishValueParser::new())]
+    gitignore: bool,
+
+    /// Hardlink the files in .git/objects, whatever the filters, so repository
+    /// snapshots share their objects with the source
+    #[arg(long = "share-git-objects")]
+    share_git_objects: bool,
+
+    /// Leave out git submodules (skip), or clone each one on its own with
+    /// its own ignore files (separate)
+    #[arg(long = "submodules", value_name = "MODE", value_enum)]
+    submodules: Option<SubmoduleMode>,
+
+    /// Recreate Windows junctions as junctions, follow them into the
+    /// directories they point to, or skip them, rather than treating them
+    /// as directory symlinks
+    #[arg(long = "junctions", value_name = "MODE", value_enum)]
+    junctions: Option<JunctionMode>,
+
+    /// Recreate symlinks to directories on Windows as junctions, or as
+    /// symlinks falling back to junctions without the privilege (auto)
+    #[arg(long = "dir-links", value_name = "MODE", value_enum)]
+    dir_links: Option<DirLinkMode>,
+
+    /// Skip files smaller than SIZE, e.g. 4K or 1.5M
+    #[arg(long = "min-size", value_name = "SIZE", value_parser = units::parse_size)]
+    min_size: Option<u64>,
+
+    /// Skip files larger than SIZE, e.g. 500M or 2G
+    #[arg(long = "max-size", value_name = "SIZE", value_parser = units::parse_size)]
+    max_size: Option<u64>,
+
+    /// Stop after cloning N files
+    #[arg(long = "max-files", value_name = "N")]
+    max_files: Option<u64>,
+
+    /// Stop before the cloned files add up to more than SIZE, e.g. 10G
+    #[arg(long = "max-total", value_name = "SIZE", value_parser = units::parse_size)]
+    max_total: Option<u64>,
+
+    /// Give up once the whole clone has run for DURATION, e.g. 30m (exit code 8)
+    #[arg(long = "timeout", value_name = "DURATION", value_parser = units::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Fail a file whose data takes longer than DURATION to copy, e.g. 2m
+    #[arg(long = "file-timeout", value_name = "DURATION", value_parser = units::parse_duration)]
+    file_timeout: Option<Duration>,
+
+    /// Only clone files modified after TIME: an age like 7d or 12h, or a UTC
+    /// date like 2024-05-01
+    #[arg(long = "newer-than", value_name = "TIME", value_parser = units::parse_time)]
+    newer_than: Option<SystemTime>,
+
+    /// Only clone files last modified before TIME (same forms as --newer-than)
+    #[arg(long = "older-than", value_name = "TIME", value_parser = units::parse_time)]
+    older_than: Option<SystemTime>,
+
+    /// Rewrite destination names into one Unicode normalization form, so
+    /// trees copied from macOS don't end up with lookalike duplicates
+    #[arg(long = "normalize", value_name = "FORM", value_enum)]
+    normalize: Option<NormalForm>,
+
+    /// Rename (replace) or leave out (skip) files whose names aren't valid on
+    /// Windows, such as a:b or aux.txt, listing each one changed
+    #[arg(long = "sanitize", value_name = "MODE", value_enum)]
+    sanitize: Option<SanitizeMode>,
+
+    /// Leave out files whose destination path is too long for the platform,
+    /// with a warning, instead of failing
+    #[arg(long = "skip-long-paths")]
+    skip_long_paths: bool,
+
+    /// When deleting, also remove destination directories left empty, even
+    /// if the source directory still exists
+    #[arg(short = 'm', long = "prune-empty-dirs")]
+    prune_empty_dirs: bool,
+
+    /// When deleting, also delete destination files that the filters exclude
+    #[arg(long = "delete-excluded")]
+    delete_excluded: bool,
+
+    /// Ask before overwriting each existing destination file
+    #[arg(short = 'i', long = "interactive")]
+    interactive: bool,
+
+    /// Keep cloning after a file fails, and report all failures at the end
+    #[arg(long = "keep-going")]
+    keep_going: bool,
+
+    /// When a file has only grown since it was copied, as logs do, append the new bytes
+    #[arg(long = "append")]
+    append: bool,
+
+    /// Read each copied file back and check it against the source
+    #[arg(long = "verify")]
+    verify: bool,
+
+    /// Hash for --checksum and --verify: blake3 is strong, xxh64 and crc32c are fastest
+    #[arg(long = "hash", value_name = "ALGORITHM", value_enum)]
+    hash: Option<HashAlgorithm>,
+
+    /// If interrupted with Ctrl-C, delete the destination when this run
+    /// created it, rather than leave a partial clone behind
+    #[arg(long = "remove-partial")]
+    remove_partial: bool,
+
+    /// Copy file data that can't be reflinked SIZE at a time, e.g. 4M
+    #[arg(long = "chunk-size", value_name = "SIZE", value_parser = units::parse_size)]
+    chunk_size: Option<u64>,
+
+    /// Number of files to clone in parallel; 1 clones sequentially
+    #[arg(short = 'j', long = "threads", value_name = "N", env = "CTREE_THREADS", default_value_t = default_threads())]
+    threads: usize,
+
+    /// Archive mode: recreate symlinks and hardlinks and preserve
+    /// permissions, times, ownership (when running as root), extended
+    /// attributes including file capabilities, and Windows ACLs and
+    /// alternate data streams
+    #[arg(short = 'a', long = "archive")]
+    archive: bool,
+
+    /// With --archive, also keep setuid, setgid and sticky bits
+    #[arg(long = "preserve-special", requires = "archive")]
+    preserve_special: bool,
+
+    /// With --archive, also copy chattr flags such as immutable and nocow
+    #[arg(long = "preserve-flags", requires = "archive")]
+    preserve_flags: bool,
+
+    /// With --archive, don't preserve these, like cp: mode (including ACLs),
+    /// ownership, timestamps, xattr (including capabilities and Windows
+    /// data streams), links or all, e.g. --no-preserve=mode,ownership
+    #[arg(
+        long = "no-preserve",
+        value_name = "ATTRS",
+        value_enum,
+        value_delimiter = ',',
+        requires = "archive"
+    )]
+    no_preserve: Vec<Attribute>,
+
+    /// Print a machine-readable JSON summary instead of progress output
+    #[arg(long = "json")]
+    json: bool,
+
+    /// Print the summary even with --quiet, followed by a breakdown by
+    /// directory and extension and the largest files, to stderr with --json
+    #[arg(long = "stats")]
+    stats: bool,
+
+    /// List each file as it is cloned; repeat (-vv) to show how it was cloned
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    verbose: u8,
+
+    /// How to report per-file events while cloning
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+/// A conflict callback that asks on the terminal whether to overwrite each
+/// existing file. End of input skips the file.
+fn prompt_conflict() -> impl Fn(&Conflict) -> Resolution + Send + Sync {
+    let overwrite_all = AtomicBool::new(false);
+    move |conflict| {
+        if overwrite_all.load(Ordering::Relaxed) {
+            return Resolution::Overwrite;
+        }
+        loop {
+            eprint!(
+                "overwrite '{}'? [y]es, [n]o, [a]ll, [q]uit: ",
+                conflict.dest.display()
+            );
+            let mut answer = String::new();
+            if matches!(std::io::stdin().read_line(&mut answer), Ok(0) | Err(_)) {
+                return Resolution::Skip;
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Resolution::Overwrite,
+                "" | "n" | "no" => return Resolution::Skip,
+                "a" | "all" => {
+                    overwrite_all.store(true, Ordering::Relaxed);
+                    return Resolution::Overwrite;
+                }
+                "q" | "quit" => return Resolution::Abort,
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// One thread per CPU, falling back to sequential if that can't be determined
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Unicode normalization form for --normalize
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum NormalForm {
+    /// Precomposed, as Linux and Windows tools write names
+    Nfc,
+    /// Decomposed, as HFS+ stores names
+    Nfd,
+}
+
+/// Content hash for --hash
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HashAlgorithm {
+    Blake3,
+    Sha256,
+    Xxh64,
+    Crc32c,
+}
+
+/// What --sanitize does with invalid names
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SanitizeMode {
+    /// Replace invalid characters with _
+    Replace,
+    /// Leave the file out
+    Skip,
+}
+
+/// What --submodules does with git submodules
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SubmoduleMode {
+    /// Leave submodules out
+    Skip,
+    /// Clone each submodule on its own, with its own ignore files
+    Separate,
+}
+
+/// What --junctions does with Windows junctions
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum JunctionMode {
+    /// Recreate them as junctions
+    Junction,
+    /// Clone the directories they point to
+    Follow,
+    /// Leave them out
+    Skip,
+}
+
+/// How --dir-links recreates symlinks to directories on Windows
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DirLinkMode {
+    /// Always as directory symlinks
+    Symlink,
+    /// Always as junctions
+    Junction,
+    /// As symlinks where allowed, otherwise junctions
+    Auto,
+}
+
+/// What --no-preserve leaves out of --archive
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Attribute {
+    /// Permissions, special bits and ACLs
+    Mode,
+    /// Owner and group
+    Ownership,
+    /// Modification and creation times
+    Timestamps,
+    /// Extended attributes, capabilities and alternate data streams
+    Xattr,
+    /// Hardlinks between source files
+    Links,
+    /// Everything above, and chattr flags
+    All,
+}
+
+impl Attribute {
+    /// The preserve flags this turns off
+    fn flags(self) -> Preserve {
+        match self {
+            Attribute::Mode => Preserve::MODE | Preserve::SPECIAL_BITS | Preserve::ACL,
+            Attribute::Ownership => Preserve::OWNER,
+            Attribute::Timestamps => Preserve::TIMES | Preserve::BIRTH_TIME,
+            Attribute::Xattr => Preserve::XATTR | Preserve::CAPABILITIES | Preserve::STREAMS,
+            Attribute::Links => Preserve::HARDLINKS,
+            Attribute::All => Preserve::all(),
+        }
+    }
+}
+
+/// Output format for per-file events
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable progress bar
+    Text,
+    /// One JSON object per event on stdout
+    Ndjson,
+}
+
+impl CopyArgs {
+    /// Apply the copy flags to `options`
+    fn apply(&self, mut options: Options) -> Result<Options> {
+        options = options
+            .threads(self.threads)
+            .keep_going(self.keep_going)
+            .prune_empty_dirs(self.prune_empty_dirs)
+            .delete_excluded(self.delete_excluded);
+        if let Some(bytes) = self.min_size {
+            options = options.min_size(bytes);
+        }
+        if let Some(bytes) = self.max_size {
+            options = options.max_size(bytes);
+        }
+        if let Some(count) = self.max_files {
+            options = options.max_files(count);
+        }
+        if let Some(bytes) = self.max_total {
+            options = options.max_total_bytes(bytes);
+        }
+        if let Some(timeout) = self.timeout {
+            options = options.timeout(timeout);
+        }
+        if let Some(timeout) = self.file_timeout {
+            options = options.file_timeout(timeout);
+        }
+        if let Some(time) = self.newer_than {
+            options = options.newer_than(time);
+        }
+        if let Some(time) = self.older_than {
+            options = options.older_than(time);
+        }
+        if let Some(form) = self.normalize {
+            options = options.normalize_unicode(match form {
+                NormalForm::Nfc => Normalization::Nfc,
+                NormalForm::Nfd => Normalization::Nfd,
+            });
+        }
+        if let Some(mode) = self.sanitize {
+            options = options.sanitize_names(match mode {
+                SanitizeMode::Replace => Sanitize::Replace,
+                SanitizeMode::Skip => Sanitize::Skip,
+            });
+        }
+        if self.skip_long_paths {
+            options = options.long_paths(LongPaths::Skip);
+        }
+        if let Some(list) = &self.files_from {
+            options = options.files(read_file_list(list, self.interactive)?);
+        }
+        if self.gitignore {
+            options = options.gitignore(true).git_global(true).git_exclude(true);
+        }
+        if self.share_git_objects {
+            options = options.share_git_objects(true);
+        }
+        if let Some(mode) = self.submodules {
+            options = options.submodules(match mode {
+                SubmoduleMode::Skip => Submodules::Skip,
+                SubmoduleMode::Separate => Submodules::Separate,
+            });
+        }
+        if let Some(mode) = self.junctions {
+            options = options.junctions(match mode {
+                JunctionMode::Junction => Junctions::Junction,
+                JunctionMode::Follow => Junctions::Follow,
+                JunctionMode::Skip => Junctions::Skip,
+            });
+        }
+        if let Some(mode) = self.dir_links {
+            options = options.dir_links(match mode {
+                DirLinkMode::Symlink => DirLinks::Symlink,
+                DirLinkMode::Junction => DirLinks::Junction,
+                DirLinkMode::Auto => DirLinks::Auto,
+            });
+        }
+        if self.no_reflink {
+            options = options.reflink(Reflink::Never);
+        }
+        if let Some(bytes) = self.chunk_size {
+            options = options.chunk_size(usize::try_from(bytes).unwrap_or(usize::MAX));
+        }
+        options = options.verify(self.verify).append(self.append);
+        if self.stats {
+            options = options.record_breakdown(true).record_largest(STATS_ROWS);
+        }
+        options = match self.hash {
+            Some(HashAlgorithm::Blake3) | None => options,
+            Some(HashAlgorithm::Sha256) => options.hasher(Sha256),
+            Some(HashAlgorithm::Xxh64) => options.hasher(XxHash64),
+            Some(HashAlgorithm::Crc32c) => options.hasher(Crc32c),
+        };
+        if self.interactive {
+            // One prompt at a time, in walk order
+            options = options
+                .overwrite(true)
+                .threads(1)
+                .on_conflict(prompt_conflict());
+        }
+        if self.archive {
+            let mut preserve = Preserve::all();
+            preserve.set(Preserve::SPECIAL_BITS, self.preserve_special);
+            preserve.set(Preserve::INODE_FLAGS, self.preserve_flags);
+            for attribute in &self.no_preserve {
+                preserve.remove(attribute.flags());
+            }
+            options = options.symlinks(true).preserve(preserve);
+        }
+        Ok(options)
+    }
+}
+
+/// Read a list of paths, one per line, from `list` or from stdin if it is `-`
+fn read_file_list(list: &Path, interactive: bool) -> Result<Vec<String>> {
+    let contents = if list == Path::new("-") {
+        if interactive {
+            bail!("--files-from - can't be combined with --interactive, which reads answers from stdin");
+        }
+        std::io::read_to_string(std::io::stdin()).context("Failed to read file list from stdin")?
+    } else {
+        std::fs::read_to_string(list)
+            .with_context(|| format!("Failed to read file list '{}'", list.display()))?
+    };
+    Ok(contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+#[derive(Args)]
+struct CommonArgs {
     /// Match or exclude glob patterns (repeatable)
-    /// Prefix with ! to exclude
+    /// Prefix with ! to exclude. If none are given, whitespace-separated
+    /// patterns are read from CTREE_GLOBS
     #[arg(short = 'g', long = "glob", value_name = "GLOB")]
-    globs: Vec<String>,
+    globs: Vec<Pattern>,
+
+    /// Read include patterns from FILE, one per line (repeatable)
+    #[arg(long = "include-from", value_name = "FILE")]
+    include_from: Vec<PathBuf>,
+
+    /// Read exclude patterns from FILE, one per line (repeatable)
+    #[arg(long = "exclude-from", value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
 
     /// Suppress progress output
-    #[arg(short = 'q', long = "quiet")]
+    #[arg(short = 'q', long = "quiet", env = "CTREE_QUIET", value_parser = BoolishValueParser::new())]
     quiet: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+impl CommonArgs {
+    /// Globs from the command line, falling back to CTREE_GLOBS, followed by
+    /// those from pattern files. Excludes come last, so they win over
+    /// includes.
+    fn globs(&self) -> Result<Vec<Pattern>> {
+        let mut globs = if !self.globs.is_empty() {
+            self.globs.clone()
+        } else {
+            env_globs(&std::env::var("CTREE_GLOBS").unwrap_or_default())?
+        };
+        for file in &self.include_from {
+            globs.extend(read_patterns(file, "")?);
+        }
+        for file in &self.exclude_from {
+            globs.extend(read_patterns(file, "!")?);
+        }
+        Ok(globs)
+    }
 
-    // Build options
-    let mut options = Options::new();
-    for glob in args.globs {
-        options = options.glob(glob);
+    /// Build options with the shared flags applied
+    fn options(&self) -> Result<Options> {
+        let mut options = Options::new();
+        for glob in self.globs()? {
+            options = options.glob(glob);
+        }
+        Ok(options)
     }
+}
+
+/// Parse the whitespace-separated globs of CTREE_GLOBS
+fn env_globs(value: &str) -> Result<Vec<Pattern>> {
+    value
+        .split_whitespace()
+        .map(|glob| Pattern::new(glob).context("Invalid glob in CTREE_GLOBS"))
+        .collect()
+}
+
+/// Read glob patterns from `file`, one per line and each given `prefix`,
+/// skipping blank lines and `#` comments as rsync and tar do
+fn read_patterns(file: &Path, prefix: &str) -> Result<Vec<Pattern>> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read patterns from '{}'", file.display()))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Pattern::new(format!("{prefix}{line}"))
+                .with_context(|| format!("Invalid pattern in '{}'", file.display()))
+        })
+        .collect()
+}
+
+/// What a clone reads from and writes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Directories into a directory
+    Tree,
+    /// A directory into a tar archive
+    Pack,
+    /// A directory into a zip archive
+    Zip,
+    /// A tar archive into a directory
+    Unpack,
+}
+
+/// A token that the first Ctrl-C cancels, letting files in progress finish
+/// so the summary can still be printed. A second Ctrl-C exits at once.
+fn cancel_on_interrupt(quiet: bool) -> CancelToken {
+    let token = CancelToken::new();
+    let handler = {
+        let token = token.clone();
+        move || {
+            if token.is_cancelled() {
+                std::process::exit(exit::CANCELLED.into());
+            }
+            token.cancel();
+            if !quiet {
+                eprintln!("Interrupted, finishing files in progress (Ctrl-C again to quit now)");
+            }
+        }
+    };
+    // Only fails if a handler is already installed, which leaves Ctrl-C
+    // killing the process as before
+    let _ = ctrlc::set_handler(handler);
+    token
+}
 
-    // Show progress message if not quiet
-    if !args.quiet {
-        println!("Cloning '{}' to '{}'...", args.src, args.dest);
+/// Run a clone of one or more sources, wrapped in the progress messages
+fn run(
+    action: &str,
+    srcs: &[String],
+    dest: &str,
+    options: &Options,
+    copy: &CopyArgs,
+    quiet: bool,
+    mode: Mode,
+) -> Result<()> {
+    let what = format!("'{}' to '{dest}'", srcs.join("', '"));
+
+    // A single source is cloned to DEST itself, several into subdirectories
+    let targets = match srcs {
+        [_] => vec![PathBuf::from(dest)],
+        _ => clone_targets(srcs, dest).with_context(|| format!("Cannot clone {what}"))?,
+    };
+
+    if copy.dry_run && matches!(mode, Mode::Pack | Mode::Zip) {
+        for entry in clone_entries(&srcs[0], options) {
+            let entry = entry.with_context(|| format!("Failed to plan clone of {what}"))?;
+            println!("archive {} -> {dest}", entry.path.display());
+        }
+        return Ok(());
+    }
+    if copy.dry_run {
+        for (src, target) in srcs.iter().zip(&targets) {
+            let plan = plan_clone(src, target, options)
+                .with_context(|| format!("Failed to plan clone of {what}"))?;
+            for operation in &plan {
+                println!("{operation}");
+            }
+        }
+        return Ok(());
+    }
+
+    let ndjson = copy.log_format == LogFormat::Ndjson;
+    if ndjson {
+        println!(
+            "{}",
+            json!({ "event": "start", "sources": srcs, "dest": dest })
+        );
     }
 
+    let bar = if quiet || copy.json || ndjson || copy.interactive || mode == Mode::Unpack {
+        None
+    } else {
+        Some(progress_bar(action, 0))
+    };
+
+    // Progress paths are relative to DEST, so for the listing find the
+    // source each one came from by the subdirectory it's under
+    let roots: Vec<(PathBuf, PathBuf)> = match srcs {
+        [src] => vec![(PathBuf::new(), PathBuf::from(src))],
+        _ => targets
+            .iter()
+            .zip(srcs)
+            .map(|(target, src)| {
+                (
+                    PathBuf::from(target.file_name().unwrap_or_default()),
+                    src.into(),
+                )
+            })
+            .collect(),
+    };
+
+    let cancel = cancel_on_interrupt(quiet);
+    let summary = Arc::new(Mutex::new(Summary::new(srcs, dest)));
+    // Size up the trees first so the bar can show totals and an ETA
+    let options = options
+        .clone()
+        .cancel_token(cancel)
+        .count_first(bar.is_some());
+    let options = options.on_progress({
+        let bar = bar.clone();
+        let summary = Arc::clone(&summary);
+        let (dest, verbose) = (PathBuf::from(dest), copy.verbose);
+        move |p| {
+            summary
+                .lock()
+                .expect("summary lock")
+                .record(p.outcome, p.bytes);
+            if ndjson {
+                println!(
+                    "{}",
+                    json!({
+                        "event": "file",
+                        "path": p.path,
+                        "outcome": outcome_name(p.outcome),
+                        "files_done": p.files_done,
+                        "bytes_done": p.bytes_done,
+                        "bytes_per_sec": p.bytes_per_sec.round() as u64,
+                    })
+                );
+            }
+            if verbose > 0 && !ndjson {
+                let src = roots.iter().find_map(|(prefix, src)| {
+                    p.path.strip_prefix(prefix).ok().map(|rest| src.join(rest))
+                });
+                let line =
+                    src.and_then(|src| listing(&src, &dest.join(p.path), p.outcome, verbose));
+                if let Some(line) = line {
+                    match &bar {
+                        Some(bar) => bar.suspend(|| println!("{line}")),
+                        None => println!("{line}"),
+                    }
+                }
+            }
+            if let Some(bar) = &bar {
+                bar.set_length(p.bytes_total.unwrap_or(0));
+                bar.set_position(p.bytes_done);
+                let files = p.files_total.unwrap_or(0);
+                bar.set_message(format!("{}/{files} files", p.files_done));
+            }
+        }
+    });
+    let options = options.on_sanitize({
+        let bar = bar.clone();
+        move |s| {
+            if ndjson {
+                println!(
+                    "{}",
+                    json!({ "event": "sanitize", "path": s.path, "renamed": s.renamed })
+                );
+                return;
+            }
+            if quiet {
+                return;
+            }
+            let line = match s.renamed {
+                Some(renamed) => format!("renamed {} -> {}", s.path.display(), renamed.display()),
+                None => format!("skipped {}: not a valid Windows name", s.path.display()),
+            };
+            match &bar {
+                Some(bar) => bar.suspend(|| eprintln!("{line}")),
+                None => eprintln!("{line}"),
+            }
+        }
+    });
+    let options = options.on_warning({
+        let bar = bar.clone();
+        move |w| {
+            if ndjson {
+                println!(
+                    "{}",
+                    json!({
+                        "event": "warning",
+                        "path": w.path,
+                        "kind": w.kind.to_string(),
+                        "error": w.error.map(|e| e.to_string()),
+                    })
+                );
+                return;
+            }
+            if quiet {
+                return;
+            }
+            let line = format!("{} {w}", style("Warning:").yellow());
+            match &bar {
+                Some(bar) => bar.suspend(|| eprintln!("{line}")),
+                None => eprintln!("{line}"),
+            }
+        }
+    });
+
     // Perform the clone
-    clone_tree(&args.src, &args.dest, &options)
-        .with_context(|| format!("Failed to clone '{}' to '{}'", args.src, args.dest))?;
+    let created = !Path::new(dest).exists();
+    let start = Instant::now();
+    let result = match srcs {
+        [src] if mode == Mode::Pack => clone_to_archive(src, dest, &options),
+        [src] if mode == Mode::Zip => clone_to_zip(src, dest, &options),
+        [src] if mode == Mode::Unpack => clone_from_archive(src, dest, &options),
+        [src] => options
+            .build()
+            .and_then(|config| config.clone_tree(src, dest)),
+        _ => clone_trees(srcs, dest, &options),
+    };
+    if let Some(bar) = &bar {
+        bar.finish_and_clear();
+    }
+    let mut summary = summary.lock().expect("summary lock").clone();
+    summary.elapsed_secs = start.elapsed().as_secs_f64();
+
+    let summarized = match &result {
+        Ok(_) => true,
+        Err(e) => summary.fail(e),
+    };
+    if ndjson {
+        let error = match &result {
+            Err(clonetree::Error::Incomplete { .. }) => summary.errors.first().cloned(),
+            Err(e) => Some(e.to_string()),
+            Ok(_) => None,
+        };
+        println!("{}", summary.finish_event(error.as_deref()));
+    }
+    if summarized {
+        if copy.json {
+            // Keep the stream line-delimited when combined with ndjson events
+            let out = if ndjson {
+                serde_json::to_string(&summary)?
+            } else {
+                serde_json::to_string_pretty(&summary)?
+            };
+            println!("{out}");
+        }
+        if copy.stats {
+            let mut out = summary.table();
+            if let Ok(report) = &result {
+                out += &breakdown(report);
+            }
+            // Keep stdout to JSON alone when it's being printed
+            if copy.json || ndjson {
+                eprint!("{out}");
+            } else {
+                print!("{out}");
+            }
+        } else if !(quiet || copy.json || ndjson) {
+            print!("{}", summary.table());
+        }
+    }
+    if copy.remove_partial && created && matches!(result, Err(clonetree::Error::Cancelled)) {
+        let dest = Path::new(dest);
+        let removed = if dest.is_dir() {
+            std::fs::remove_dir_all(dest)
+        } else {
+            std::fs::remove_file(dest)
+        };
+        match removed {
+            Ok(()) if !quiet => eprintln!("Removed partial destination '{}'", dest.display()),
+            Ok(()) => {}
+            Err(e) => eprintln!("Could not remove '{}': {e}", dest.display()),
+        }
+    }
+    result.with_context(|| format!("Failed to clone {what}"))?;
+
+    Ok(())
+}
+
+/// End-of-run statistics, printed as JSON by `--json`
+#[derive(Debug, Clone, Serialize)]
+struct Summary {
+    sources: Vec<String>,
+    dest: String,
+    success: bool,
+    files: u64,
+    bytes: u64,
+    reflinked: u64,
+    copied: u64,
+    hardlinked: u64,
+    skipped: u64,
+    reflinked_bytes: u64,
+    copied_bytes: u64,
+    hardlinked_bytes: u64,
+    /// Fraction of written bytes that were reflinked rather than copied
+    reflink_ratio: f64,
+    elapsed_secs: f64,
+    errors: Vec<String>,
+}
+
+impl Summary {
+    fn new(sources: &[String], dest: &str) -> Self {
+        Self {
+            sources: sources.to_vec(),
+            dest: dest.to_string(),
+            success: true,
+            files: 0,
+            bytes: 0,
+            reflinked: 0,
+            copied: 0,
+            hardlinked: 0,
+            skipped: 0,
+            reflinked_bytes: 0,
+            copied_bytes: 0,
+            hardlinked_bytes: 0,
+            reflink_ratio: 0.0,
+            elapsed_secs: 0.0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Count one processed file of `bytes` bytes
+    fn record(&mut self, outcome: Outcome, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        match outcome {
+            Outcome::Reflinked => {
+                self.reflinked += 1;
+                self.reflinked_bytes += bytes;
+            }
+            Outcome::Copied => {
+                self.copied += 1;
+                self.copied_bytes += bytes;
+            }
+            Outcome::Hardlinked => {
+                self.hardlinked += 1;
+                self.hardlinked_bytes += bytes;
+            }
+            Outcome::Skipped(_) => self.skipped += 1,
+        }
+        let written = self.reflinked_bytes + self.copied_bytes;
+        if written > 0 {
+            self.reflink_ratio = self.reflinked_bytes as f64 / written as f64;
+        }
+    }
+
+    /// Account for the error that ended the clone, returning whether the
+    /// summary is still worth showing. It isn't for an error before any file
+    /// was attempted, such as an existing destination, which speaks for
+    /// itself.
+    fn fail(&mut self, error: &clonetree::Error) -> bool {
+        use clonetree::Error;
+
+        self.success = false;
+        match error {
+            Error::Incomplete { errors } => {
+                self.errors.extend(errors.iter().map(|e| e.to_string()));
+                true
+            }
+            // Stopped part way, so there are partial statistics to show
+            Error::Cancelled | Error::Timeout { .. } | Error::QuotaExceeded { .. } => true,
+            // A file failed once the clone was under way
+            error if self.files > 0 => {
+                self.errors.push(error.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The ndjson event ending the stream, with the error that stopped the
+    /// clone or the first file that failed
+    fn finish_event(&self, error: Option<&str>) -> serde_json::Value {
+        json!({
+            "event": "finish",
+            "success": self.success,
+            "files": self.files,
+            "bytes": self.bytes,
+            "error": error,
+        })
+    }
+
+    /// The end-of-run summary shown unless `--quiet`, colored when stdout is
+    /// a terminal
+    fn table(&self) -> String {
+        let written = self.reflinked_bytes + self.copied_bytes;
+        let elapsed = Duration::from_secs_f64(self.elapsed_secs);
+        let rate = if self.elapsed_secs > 0.0 {
+            format!(
+                ", {}/s",
+                HumanBytes((written as f64 / self.elapsed_secs) as u64)
+            )
+        } else {
+            String::new()
+        };
+        let mut rows = vec![
+            (
+                style("Reflinked").green(),
+                self.reflinked,
+                Some(self.reflinked_bytes),
+            ),
+            (style("Copied").cyan(), self.copied, Some(self.copied_bytes)),
+            (style("Skipped").dim(), self.skipped, None),
+            (
+                if self.errors.is_empty() {
+                    style("Failed").dim()
+                } else {
+                    style("Failed").red().bold()
+                },
+                self.errors.len() as u64,
+                None,
+            ),
+        ];
+        // Files are only hardlinked when preserving links or sharing git
+        // objects, so most runs would show an empty row
+        if self.hardlinked > 0 {
+            rows.insert(
+                2,
+                (
+                    style("Hardlinked").green(),
+                    self.hardlinked,
+                    Some(self.hardlinked_bytes),
+                ),
+            );
+        }
+        let mut out = String::new();
+        for (label, files, bytes) in rows {
+            let bytes = bytes.map(|b| HumanBytes(b).to_string()).unwrap_or_default();
+            let row = format!("  {label:<10} {files:>8} files  {bytes:>10}");
+            out += row.trim_end();
+            out.push('\n');
+        }
+        out += &format!(
+            "  {:<10} {:>8} files  {:>10}  in {}{rate}\n",
+            style(if self.success { "Done" } else { "Incomplete" }).bold(),
+            self.files,
+            HumanBytes(self.bytes).to_string(),
+            HumanDuration(elapsed)
+        );
+        out
+    }
+}
+
+/// How many directories, extensions and files `--stats` lists
+const STATS_ROWS: usize = 10;
+
+/// The `--stats` breakdown of a clone: where the bytes are by top-level
+/// directory and by extension, biggest first, and the largest files
+fn breakdown(report: &CloneReport) -> String {
+    let mut out = String::new();
+    let mut section = |title: &str, mut rows: Vec<(String, u64, u64)>| {
+        if rows.is_empty() {
+            return;
+        }
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        out += &format!("\n  {}\n", style(title).bold());
+        for (name, files, bytes) in rows.into_iter().take(STATS_ROWS) {
+            let bytes = HumanBytes(bytes).to_string();
+            out += &format!("    {name:<24} {files:>8} files  {bytes:>10}\n");
+        }
+    };
+    section(
+        "By directory",
+        report
+            .by_directory
+            .iter()
+            .map(|(dir, t)| {
+                let name = if dir.as_os_str().is_empty() {
+                    "(top level)".to_string()
+                } else {
+                    format!("{}/", dir.display())
+                };
+                (name, t.files, t.bytes)
+            })
+            .collect(),
+    );
+    section(
+        "By extension",
+        report
+            .by_extension
+            .iter()
+            .map(|(ext, t)| {
+                let name = if ext.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    format!(".{ext}")
+                };
+                (name, t.files, t.bytes)
+            })
+            .collect(),
+    );
+    if !report.largest_files.is_empty() {
+        out += &format!("\n  {}\n", style("Largest files").bold());
+        for file in &report.largest_files {
+            let size = HumanBytes(file.size).to_string();
+            out += &format!("    {size:>10}  {}\n", file.path.display());
+        }
+    }
+    out
+}
 
-    if !args.quiet {
-        println!("Done!");
+/// A `cp -v` style line for a cloned file, or `None` if it should not be listed
+///
+/// Skipped files are only listed at `-vv`, where every line also carries the
+/// outcome.
+fn listing(from: &Path, to: &Path, outcome: Outcome, verbose: u8) -> Option<String> {
+    match (verbose, outcome) {
+        (1, Outcome::Skipped(_)) => None,
+        (1, _) => Some(format!("'{}' -> '{}'", from.display(), to.display())),
+        _ => Some(format!(
+            "'{}' -> '{}' ({})",
+            from.display(),
+            to.display(),
+            outcome_name(outcome)
+        )),
     }
+}
+
+/// Name of an outcome in machine-readable output
+fn outcome_name(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Reflinked => "reflinked",
+        Outcome::Copied => "copied",
+        Outcome::Hardlinked => "hardlinked",
+        Outcome::Skipped(_) => "skipped",
+    }
+}
 
+/// A byte-based progress bar showing throughput and ETA
+fn progress_bar(action: &str, total_bytes: u64) -> ProgressBar {
+    let bar = ProgressBar::new(total_bytes);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{prefix} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta}) {msg}",
+        )
+        .expect("valid progress template"),
+    );
+    bar.set_prefix(action.to_string());
+    bar
+}
+
+/// Process exit codes, so scripts can tell failures apart without parsing
+/// messages. Usage errors exit with 2, from clap.
+mod exit {
+    pub const FAILURE: u8 = 1;
+    pub const SOURCE_MISSING: u8 = 3;
+    pub const DESTINATION_EXISTS: u8 = 4;
+    pub const DESTINATION_LOCKED: u8 = 5;
+    pub const PARTIAL: u8 = 6;
+    pub const QUOTA: u8 = 7;
+    pub const TIMEOUT: u8 = 8;
+    pub const CANCELLED: u8 = 130;
+}
+
+/// The exit code for a failed run
+fn exit_code(error: &anyhow::Error) -> u8 {
+    use clonetree::Error;
+
+    match error.chain().find_map(|e| e.downcast_ref::<Error>()) {
+        Some(Error::SourceNotFound { .. } | Error::SourceNotDirectory { .. }) => {
+            exit::SOURCE_MISSING
+        }
+        Some(Error::DestinationExists { .. } | Error::FileExists { .. }) => {
+            exit::DESTINATION_EXISTS
+        }
+        Some(Error::DestinationLocked { .. }) => exit::DESTINATION_LOCKED,
+        Some(Error::Incomplete { .. }) => exit::PARTIAL,
+        Some(Error::QuotaExceeded { .. }) => exit::QUOTA,
+        Some(Error::Timeout { .. }) => exit::TIMEOUT,
+        Some(Error::Cancelled) => exit::CANCELLED,
+        _ => exit::FAILURE,
+    }
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitCode::from(exit_code(&error))
+        }
+    }
+}
+
+fn try_main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Sync(args)) => {
+            let compare = if args.checksum {
+                Compare::Hash
+            } else {
+                Compare::Metadata
+            };
+            let options = args
+                .copy
+                .apply(args.common.options()?)?
+                .overwrite(true)
+                .update(true)
+                .delete(true)
+                .preserve_times(true)
+                .state_cache(args.cache)
+                .compare(compare);
+            run(
+                "Syncing",
+                std::slice::from_ref(&args.src),
+                &args.dest,
+                &options,
+                &args.copy,
+                args.common.quiet,
+                Mode::Tree,
+            )
+        }
+        Some(Command::Dedup(args)) => {
+            let mut options = DedupOptions::new().min_size(args.min_size);
+            for glob in args.common.globs()? {
+                options = options.glob(glob);
+            }
+            if !args.common.quiet {
+                println!("Deduplicating '{}'...", args.dir);
+            }
+            let stats = dedup_tree(&args.dir, &options)
+                .with_context(|| format!("Failed to deduplicate '{}'", args.dir))?;
+            if !args.common.quiet {
+                println!(
+                    "Scanned {} files, deduplicated {}, reclaimed {} bytes",
+                    stats.files_scanned, stats.files_deduped, stats.bytes_reclaimed
+                );
+            }
+            Ok(())
+        }
+        Some(Command::Du(args)) => {
+            let usage = disk_usage(&args.dir, &args.common.options()?)
+                .with_context(|| format!("Failed to measure '{}'", args.dir))?;
+            println!("Files:      {}", usage.files);
+            println!("Apparent:   {} bytes", usage.apparent_bytes);
+            println!("Allocated:  {} bytes", usage.allocated_bytes);
+            if let (Some(shared), Some(exclusive)) = (usage.shared_bytes, usage.exclusive_bytes()) {
+                println!("Shared:     {shared} bytes");
+                println!("Exclusive:  {exclusive} bytes");
+            }
+            Ok(())
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), "ctree", &mut std::io::stdout());
+            Ok(())
+        }
+        Some(Command::Man { out_dir }) => man(out_dir.as_deref()),
+        Some(Command::Clone(args)) => clone(args),
+        None => clone(cli.clone),
+    }
+}
+
+/// Render the man page to stdout, or all pages into `out_dir`
+fn man(out_dir: Option<&Path>) -> Result<()> {
+    let command = Cli::command();
+    match out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+            clap_mangen::generate_to(command, dir)
+                .with_context(|| format!("Failed to write man pages to '{}'", dir.display()))?;
+        }
+        None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+    }
     Ok(())
 }
+
+/// Run the default clone command
+fn clone(args: CloneArgs) -> Result<()> {
+    let Some(dest) = args.dest else {
+        unreachable!("clap enforces both paths");
+    };
+    let mut options = args
+        .common
+        .options()?
+        .overwrite(args.overwrite)
+        .delete(args.delete)
+        .trash(args.trash);
+    if args.backup {
+        options = options.backup(Backup::Numbered);
+    }
+    if args.update {
+        options = options.overwrite(true).update(true).compare(Compare::Newer);
+    }
+    if args.replace_dest {
+        options = options.overwrite(Overwrite::Replace);
+    }
+    if args.no_clobber {
+        options = options.overwrite(Overwrite::NoClobber);
+    }
+    let options = args.copy.apply(options)?;
+
+    // A destination named like a tar or zip archive is written as one, and a
+    // single archive source is extracted
+    let mode = match args.srcs.as_slice() {
+        _ if Compression::from_path(&dest).is_some() => Mode::Pack,
+        _ if dest.to_ascii_lowercase().ends_with(".zip") => Mode::Zip,
+        [src] if Compression::from_path(src).is_some() && Path::new(src).is_file() => Mode::Unpack,
+        _ => Mode::Tree,
+    };
+    match mode {
+        Mode::Pack | Mode::Zip if args.srcs.len() > 1 => {
+            bail!("An archive destination takes a single source")
+        }
+        Mode::Pack | Mode::Zip if args.update || args.delete || args.copy.interactive => {
+            bail!("--update, --delete and --interactive don't apply to an archive destination")
+        }
+        Mode::Unpack if args.delete || args.copy.dry_run => {
+            bail!("--delete and --dry-run don't apply to an archive source")
+        }
+        _ => {}
+    }
+    run(
+        "Cloning",
+        &args.srcs,
+        &dest,
+        &options,
+        &args.copy,
+        args.common.quiet,
+        mode,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_flags() {
+        assert_eq!(
+            Attribute::Mode.flags(),
+            Preserve::MODE | Preserve::SPECIAL_BITS | Preserve::ACL
+        );
+        assert_eq!(Attribute::Ownership.flags(), Preserve::OWNER);
+        assert_eq!(
+            Attribute::Timestamps.flags(),
+            Preserve::TIMES | Preserve::BIRTH_TIME
+        );
+        assert_eq!(
+            Attribute::Xattr.flags(),
+            Preserve::XATTR | Preserve::CAPABILITIES | Preserve::STREAMS
+        );
+        assert_eq!(Attribute::Links.flags(), Preserve::HARDLINKS);
+        assert_eq!(Attribute::All.flags(), Preserve::all());
+
+        // Between them the named attributes cover all but chattr flags
+        let named = Attribute::value_variants()
+            .iter()
+            .filter(|a| **a != Attribute::All)
+            .fold(Preserve::empty(), |flags, a| flags | a.flags());
+        assert_eq!(named, Preserve::all() - Preserve::INODE_FLAGS);
+    }
+
+    #[test]
+    fn test_summary_json() -> Result<()> {
+        let mut summary = Summary::new(&["src".to_string()], "dest");
+        summary.record(Outcome::Reflinked, 300);
+        summary.record(Outcome::Copied, 100);
+        summary.record(Outcome::Hardlinked, 50);
+        summary.record(Outcome::Skipped(clonetree::SkipReason::Excluded), 0);
+        summary.success = false;
+        summary.elapsed_secs = 1.5;
+        summary.errors.push("failed".to_string());
+        assert_eq!(
+            serde_json::to_value(&summary)?,
+            json!({
+                "sources": ["src"],
+                "dest": "dest",
+                "success": false,
+                "files": 4,
+                "bytes": 450,
+                "reflinked": 1,
+                "copied": 1,
+                "hardlinked": 1,
+                "skipped": 1,
+                "reflinked_bytes": 300,
+                "copied_bytes": 100,
+                "hardlinked_bytes": 50,
+                "reflink_ratio": 0.75,
+                "elapsed_secs": 1.5,
+                "errors": ["failed"],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_fail() {
+        let summary = || {
+            let mut summary = Summary::new(&["src".to_string()], "dest");
+            summary.record(Outcome::Copied, 10);
+            summary
+        };
+
+        // Interrupted with Ctrl-C, the files so far are still summarized
+        let mut cancelled = summary();
+        assert!(cancelled.fail(&clonetree::Error::Cancelled));
+        assert!(!cancelled.success);
+        assert!(cancelled.errors.is_empty());
+        assert_eq!(cancelled.files, 1);
+        assert!(cancelled.table().contains("Incomplete"));
+        assert!(summary().fail(&clonetree::Error::Timeout {
+            timeout: Duration::from_secs(1)
+        }));
+
+        let mut incomplete = summary();
+        assert!(incomplete.fail(&clonetree::Error::Incomplete {
+            errors: vec![clonetree::Error::Other("failed".to_string())]
+        }));
+        assert_eq!(incomplete.errors.len(), 1);
+
+        // Nothing to summarize before the first file
+        let mut exists = Summary::new(&["src".to_string()], "dest");
+        assert!(!exists.fail(&clonetree::Error::DestinationExists {
+            path: PathBuf::from("dest")
+        }));
+        assert!(!exists.success);
+    }
+
+    #[test]
+    fn test_finish_event() {
+        let mut summary = Summary::new(&["src".to_string()], "dest");
+        summary.record(Outcome::Copied, 10);
+        assert_eq!(
+            summary.finish_event(None),
+            json!({
+                "event": "finish",
+                "success": true,
+                "files": 1,
+                "bytes": 10,
+                "error": null,
+            })
+        );
+        summary.success = false;
+        assert_eq!(
+            summary.finish_event(Some("failed")),
+            json!({
+                "event": "finish",
+                "success": false,
+                "files": 1,
+                "bytes": 10,
+                "error": "failed",
+            })
+        );
+    }
+
+    #[test]
+    fn test_env() -> Result<()> {
+        let globs: Vec<String> = env_globs("  *.rs\n!target/**\t")?
+            .iter()
+            .map(|p| p.as_str().to_string())
+            .collect();
+        assert_eq!(globs, ["*.rs", "!target/**"]);
+        assert!(env_globs("")?.is_empty());
+        assert!(env_globs("*.rs src/[abc").is_err());
+
+        // No other test parses the command line, so setting these can't
+        // leak into one
+        std::env::set_var("CTREE_THREADS", "3");
+        std::env::set_var("CTREE_NO_REFLINK", "yes");
+        std::env::set_var("CTREE_QUIET", "1");
+        let parsed = Cli::try_parse_from(["ctree", "src", "dest"]);
+        let flag = Cli::try_parse_from(["ctree", "-j", "5", "src", "dest"]);
+        std::env::set_var("CTREE_THREADS", "many");
+        let invalid = Cli::try_parse_from(["ctree", "src", "dest"]);
+        for name in ["CTREE_THREADS", "CTREE_NO_REFLINK", "CTREE_QUIET"] {
+            std::env::remove_var(name);
+        }
+
+        let cli = parsed?;
+        assert_eq!(cli.clone.copy.threads, 3);
+        assert!(cli.clone.copy.no_reflink);
+        assert!(cli.clone.common.quiet);
+        // Flags win over the environment
+        assert_eq!(flag?.clone.copy.threads, 5);
+        assert!(invalid.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_patterns() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let file = temp_dir.path().join("patterns");
+        std::fs::write(&file, "# build output\n\ntarget/**\n  *.log  \n\n#*.tmp\n")?;
+        let strings = |patterns: Vec<Pattern>| -> Vec<String> {
+            patterns.iter().map(|p| p.as_str().to_string()).collect()
+        };
+        assert_eq!(strings(read_patterns(&file, "")?), ["target/**", "*.log"]);
+        assert_eq!(
+            strings(read_patterns(&file, "!")?),
+            ["!target/**", "!*.log"]
+        );
+
+        std::fs::write(&file, "*.txt\nsrc/[abc\n")?;
+        assert!(read_patterns(&file, "").is_err());
+        assert!(read_patterns(&temp_dir.path().join("missing"), "").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Parsing human-friendly command-line values.
+
+use std::time::{Duration, SystemTime};
+
+/// Parse a size such as `500`, `64K`, `1.5G` or `10MB` into bytes. Bare
+/// suffixes and `KiB`-style suffixes are binary (`1K` is 1024 bytes); `KB`-style
+/// suffixes are decimal. Case is ignored.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{s}', expected a number with an optional unit"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(format!("unknown size unit '{unit}'")),
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parse a duration such as `30s`, `5m` or `1h 30m`. A bare number is in
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(s)
+        .map_err(|_| format!("invalid duration '{s}', expected something like 30s or 5m"))
+}
+
+/// Parse a point in time: either an age such as `7d`, `12h` or `2weeks`,
+/// counted back from now, or a UTC timestamp such as `2024-05-01` or
+/// `2024-05-01 12:30:00`.
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    parse_time_at(s, SystemTime::now())
+}
+
+fn parse_time_at(s: &str, now: SystemTime) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Ok(age) = humantime::parse_duration(s) {
+        return now
+            .checked_sub(age)
+            .ok_or_else(|| format!("age '{s}' is too large"));
+    }
+    let timestamp = if s.len() == 10 {
+        format!("{s} 00:00:00")
+    } else {
+        s.to_string()
+    };
+    humantime::parse_rfc3339_weak(&timestamp).map_err(|_| {
+        format!("invalid time '{s}', expected an age like 7d or a date like 2024-05-01")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5g"), Ok(3 << 29));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("2 KiB"), Ok(2048));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5X").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let day = Duration::from_secs(86400);
+        assert_eq!(parse_time_at("7d", now), Ok(now - 7 * day));
+        assert_eq!(parse_time_at("2weeks", now), Ok(now - 14 * day));
+        assert_eq!(
+            parse_time_at("12h 30m", now),
+            Ok(now - Duration::from_secs(12 * 3600 + 30 * 60))
+        );
+        assert_eq!(
+            parse_time_at("1970-01-02", now),
+            Ok(SystemTime::UNIX_EPOCH + day)
+        );
+        assert_eq!(
+            parse_time_at("1970-01-01T00:01:00Z", now),
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(60))
+        );
+        assert!(parse_time_at("last tuesday", now).is_err());
+    }
+}